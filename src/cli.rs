@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, IsTerminal, Write as _};
+use std::str::FromStr;
+
+/// One `--name [VALUE]` option. Options without a value are flags.
+pub struct Opt {
+    pub name: &'static str,
+    pub value: Option<&'static str>,
+    pub help: &'static str,
+}
+
+/// A subcommand and everything it accepts. Parsing, usage and help are all driven by this
+/// table, so they cannot drift apart.
+pub struct Command {
+    /// Empty for the default command, which takes no subcommand word.
+    pub name: &'static str,
+    pub args: &'static [&'static str],
+    pub about: &'static str,
+    pub options: &'static [Opt],
+}
+
+pub enum Parsed {
+    Help,
+    Args(Matches),
+}
+
+pub struct Matches {
+    pub positional: Vec<String>,
+    values: HashMap<&'static str, String>,
+}
+
+impl Matches {
+    pub fn flag(&self, name: &str) -> bool {
+        self.values.contains_key(name)
+    }
+
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+
+    pub fn parse<T: FromStr>(&self, name: &str) -> Result<Option<T>, String> {
+        match self.value(name) {
+            Some(value) => value
+                .parse()
+                .map(Some)
+                .map_err(|_| format!("invalid value '{}' for --{}", value, name)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Command {
+    pub fn parse(&self, args: &[String]) -> Result<Parsed, String> {
+        let mut matches = Matches { positional: Vec::new(), values: HashMap::new() };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if arg == "-h" || arg == "--help" {
+                return Ok(Parsed::Help);
+            }
+            let Some(name) = arg.strip_prefix("--") else {
+                matches.positional.push(arg.clone());
+                continue;
+            };
+
+            let (name, inline) = match name.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (name, None),
+            };
+            let opt = self
+                .options
+                .iter()
+                .find(|opt| opt.name == name)
+                .ok_or_else(|| format!("unknown option --{}", name))?;
+            let value = match (opt.value, inline) {
+                (Some(_), Some(value)) => value,
+                (Some(_), None) => {
+                    args.next().cloned().ok_or_else(|| format!("--{} needs a value", name))?
+                }
+                (None, Some(_)) => return Err(format!("--{} does not take a value", name)),
+                (None, None) => String::new(),
+            };
+            matches.values.insert(opt.name, value);
+        }
+
+        if matches.positional.len() != self.args.len() {
+            return Err(format!("expected {}", self.args.join(" ")));
+        }
+        Ok(Parsed::Args(matches))
+    }
+
+    pub fn usage_line(&self, program: &str) -> String {
+        let mut line = program.to_string();
+        if !self.name.is_empty() {
+            line += " ";
+            line += self.name;
+        }
+        for arg in self.args {
+            line += " ";
+            line += arg;
+        }
+        if !self.options.is_empty() {
+            line += " [options]";
+        }
+        line
+    }
+
+    pub fn help(&self, program: &str) -> String {
+        let mut text = format!("{}\n\nusage: {}\n", self.about, self.usage_line(program));
+        if self.options.is_empty() {
+            return text;
+        }
+
+        let specs: Vec<String> = self
+            .options
+            .iter()
+            .map(|opt| match opt.value {
+                Some(value) => format!("--{} {}", opt.name, value),
+                None => format!("--{}", opt.name),
+            })
+            .collect();
+        let width = specs.iter().map(String::len).max().unwrap_or(0);
+
+        text += "\noptions:\n";
+        for (spec, opt) in specs.iter().zip(self.options) {
+            let _ = writeln!(text, "  {:width$}  {}", spec, opt.help, width = width);
+        }
+        text
+    }
+}
+
+pub fn usage(program: &str, commands: &[Command]) -> String {
+    let mut text = String::from("usage:\n");
+    for command in commands {
+        let _ = writeln!(text, "  {}", command.usage_line(program));
+    }
+    let _ = write!(text, "\nrun '{} [command] --help' for the options of each command", program);
+    text
+}
+
+/// Feeds `execute` lines from stdin until it ends or `execute` returns false, prompting
+/// when stdin is a terminal. Errors are reported and the session goes on.
+pub fn repl(mut execute: impl FnMut(&str) -> Result<bool, String>) -> io::Result<()> {
+    let stdin = io::stdin();
+    let interactive = stdin.is_terminal();
+    let mut lines = stdin.lock().lines();
+    loop {
+        if interactive {
+            print!("> ");
+            io::stdout().flush()?;
+        }
+        let Some(line) = lines.next().transpose()? else {
+            return Ok(());
+        };
+        match execute(&line) {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            Err(message) => eprintln!("error: {}", message),
+        }
+    }
+}
@@ -0,0 +1,381 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use rusty_boy::utils::debugger::{
+    mapped_bank, registers_line, Access, Breakpoint, Debugger, Expr, Goal, Stop, WatchHit, Watchpoint,
+};
+use rusty_boy::utils::disasm::{disassemble_around, disassemble_from, Line};
+use rusty_boy::GameBoy;
+
+const HELP: &str = "\
+break [BANK:]ADDR [if COND]  stop before running ADDR, when BANK is mapped and COND holds
+                             e.g. break 3:4A10 if a == 0x3C && [hl] > 4
+breaks                       list breakpoints
+delete N, enable N, disable N
+watch r|w|rw ADDR[-END] [if COND]
+                             stop after an instruction reads or writes there; COND can use
+                             value and address, e.g. watch w FF40 if value & 0x80 == 0
+watches                      list watchpoints
+unwatch N
+step [N]                     run N instructions (default 1)
+next                         run one instruction, running calls through to their return
+finish                       run until the current function returns
+continue                     run until a breakpoint; Ctrl-C stops any run
+frame [N]                    run to the start of the Nth next VBlank (default 1)
+line N                       run until the PPU starts scanline N
+regs                         show the registers
+x ADDR [LEN]                 dump LEN bytes of memory (default 64)
+dis [ADDR] [N]               disassemble N instructions (default: around PC)
+print EXPR                   evaluate an expression, e.g. print [hl] + 1
+set REG VALUE                set a register: a-l, af, bc, de, hl, sp or pc
+save FILE, load FILE         save or load a state
+help, quit
+Addresses are hex; in expressions, write hex as 0x3C or $3C.";
+
+// Set by Ctrl-C while the machine runs.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// Catches Ctrl-C while alive, so it stops the run instead of the session, and puts the
+// previous handler back when dropped. Outside Unix, Ctrl-C still ends the session.
+struct CatchInterrupt {
+    #[cfg(unix)]
+    previous: usize,
+}
+
+#[cfg(unix)]
+mod signal {
+    pub const SIGINT: i32 = 2;
+
+    extern "C" {
+        // From the C library std already links; handlers are passed as addresses.
+        pub fn signal(signum: i32, handler: usize) -> usize;
+    }
+
+    pub extern "C" fn on_interrupt(_: i32) {
+        super::INTERRUPTED.store(true, super::Ordering::Relaxed);
+    }
+}
+
+impl CatchInterrupt {
+    fn new() -> CatchInterrupt {
+        INTERRUPTED.store(false, Ordering::Relaxed);
+        #[cfg(unix)]
+        {
+            let handler = signal::on_interrupt as extern "C" fn(i32) as usize;
+            // SAFETY: the handler only stores to an atomic.
+            let previous = unsafe { signal::signal(signal::SIGINT, handler) };
+            CatchInterrupt { previous }
+        }
+        #[cfg(not(unix))]
+        CatchInterrupt {}
+    }
+}
+
+impl Drop for CatchInterrupt {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        // SAFETY: puts back the handler that was there before.
+        unsafe {
+            signal::signal(signal::SIGINT, self.previous);
+        }
+    }
+}
+
+// A hex address, or any expression.
+fn parse_address(gameboy: &GameBoy, text: &str) -> Result<u16, String> {
+    let digits = text.trim_start_matches("0x").trim_start_matches('$');
+    if let Ok(address) = u16::from_str_radix(digits, 16) {
+        return Ok(address);
+    }
+    let expr = Expr::parse(text).map_err(|err| format!("'{}': {}", text, err))?;
+    Ok(expr.eval(gameboy.cpu()) as u16)
+}
+
+fn format_line(gameboy: &GameBoy, line: &Line) -> String {
+    let bank = match (line.address, mapped_bank(&gameboy.cpu().bus, line.address)) {
+        (0x0000..=0x7FFF, Some(bank)) => format!("{:02X}:", bank),
+        _ => "   ".to_string(),
+    };
+    let marker = if line.address == gameboy.cpu().pc { "=>" } else { "  " };
+    let bytes: String = line.bytes.iter().map(|byte| format!("{:02X} ", byte)).collect();
+    format!("{} {}{:04X}  {:9} {}", marker, bank, line.address, bytes, line.text)
+}
+
+/// The `debug` command's session.
+pub struct Session {
+    gameboy: GameBoy,
+    debugger: Debugger,
+}
+
+impl Session {
+    pub fn new(gameboy: GameBoy) -> Session {
+        let mut debugger = Debugger::new();
+        debugger.set_interrupt(&INTERRUPTED);
+        Session { gameboy, debugger }
+    }
+
+    fn breakpoint_index(&self, text: Option<&&str>) -> Result<usize, String> {
+        let text = text.ok_or("which breakpoint?")?;
+        match text.parse::<usize>() {
+            Ok(number) if (1..=self.debugger.breakpoints().len()).contains(&number) => Ok(number - 1),
+            _ => Err(format!("there is no breakpoint {}", text)),
+        }
+    }
+
+    /// Where the machine is: the registers and the instruction about to run.
+    pub fn show_position(&self) {
+        let cpu = self.gameboy.cpu();
+        println!("{}", registers_line(cpu));
+        let line = disassemble_from(&cpu.bus, cpu.pc, 1).remove(0);
+        println!("{}", format_line(&self.gameboy, &line));
+    }
+
+    fn report(stop: Stop) {
+        match stop {
+            Stop::Breakpoint(index) => println!("breakpoint {}", index + 1),
+            Stop::Watchpoint(WatchHit { watchpoint, address, value, write }) => {
+                let access = if write { "wrote" } else { "read" };
+                println!("watchpoint {}: {} ${:02X} at {:04X}", watchpoint + 1, access, value, address);
+            }
+            Stop::Interrupted => println!("interrupted"),
+            Stop::Done | Stop::Limit => {}
+        }
+    }
+
+    fn run(&mut self, goal: Goal) {
+        let _catch = CatchInterrupt::new();
+        Session::report(self.debugger.run(&mut self.gameboy, goal, u64::MAX));
+        self.show_position();
+    }
+
+    fn add_watchpoint(&mut self, rest: &[&str]) -> Result<(), String> {
+        let access = match rest.first() {
+            Some(&"r") => Access::Read,
+            Some(&"w") => Access::Write,
+            Some(&"rw") => Access::ReadWrite,
+            _ => return Err("watch r, w or rw?".to_string()),
+        };
+        let range = rest.get(1).ok_or("watch where?")?;
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (parse_address(&self.gameboy, start)?, parse_address(&self.gameboy, end)?),
+            None => {
+                let address = parse_address(&self.gameboy, range)?;
+                (address, address)
+            }
+        };
+        if end < start {
+            return Err(format!("{:04X}-{:04X} is backwards", start, end));
+        }
+        let mut watchpoint = Watchpoint::new(start, end, access);
+        match rest.get(2) {
+            Some(&"if") => {
+                let condition = rest[3..].join(" ");
+                watchpoint.condition = Some(Expr::parse(&condition).map_err(|err| format!("'{}': {}", condition, err))?);
+            }
+            Some(other) => return Err(format!("expected 'if', not '{}'", other)),
+            None => {}
+        }
+        let index = self.gameboy.cpu_mut().bus.watchpoints.add(watchpoint);
+        println!("watchpoint {} on {}", index + 1, range);
+        Ok(())
+    }
+
+    fn add_breakpoint(&mut self, rest: &[&str]) -> Result<(), String> {
+        let target = rest.first().ok_or("break where?")?;
+        let (bank, address) = match target.split_once(':') {
+            Some((bank, address)) => {
+                let bank = u16::from_str_radix(bank, 16).map_err(|_| format!("'{}' is not a bank", bank))?;
+                (Some(bank), address)
+            }
+            None => (None, *target),
+        };
+        let mut breakpoint = Breakpoint::new(parse_address(&self.gameboy, address)?);
+        breakpoint.bank = bank;
+        match rest.get(1) {
+            Some(&"if") => {
+                let condition = rest[2..].join(" ");
+                breakpoint.condition = Some(Expr::parse(&condition).map_err(|err| format!("'{}': {}", condition, err))?);
+            }
+            Some(other) => return Err(format!("expected 'if', not '{}'", other)),
+            None => {}
+        }
+        let index = self.debugger.add_breakpoint(breakpoint);
+        println!("breakpoint {} at {}", index + 1, target);
+        Ok(())
+    }
+
+    fn dump(&self, address: u16, length: usize) {
+        let bus = &self.gameboy.cpu().bus;
+        for row in (0..length).step_by(16) {
+            let start = address.wrapping_add(row as u16);
+            let bytes: Vec<u8> = (0..16.min(length - row)).map(|i| bus.read_byte(start.wrapping_add(i as u16))).collect();
+            let mut line = format!("{:04X}:", start);
+            for byte in &bytes {
+                let _ = write!(line, " {:02X}", byte);
+            }
+            let text: String = bytes.iter().map(|&byte| if byte.is_ascii_graphic() { byte as char } else { '.' }).collect();
+            println!("{:54} {}", line, text);
+        }
+    }
+
+    fn set_register(&mut self, name: &str, value: u16) -> Result<(), String> {
+        let cpu = self.gameboy.cpu_mut();
+        let registers = &mut cpu.registers;
+        match name {
+            "a" => registers.a = value as u8,
+            "b" => registers.b = value as u8,
+            "c" => registers.c = value as u8,
+            "d" => registers.d = value as u8,
+            "e" => registers.e = value as u8,
+            "f" => registers.f = (value as u8).into(),
+            "h" => registers.h = value as u8,
+            "l" => registers.l = value as u8,
+            "af" => registers.set_af(value),
+            "bc" => registers.set_bc(value),
+            "de" => registers.set_de(value),
+            "hl" => registers.set_hl(value),
+            "sp" => cpu.sp = value,
+            "pc" => cpu.pc = value,
+            _ => return Err(format!("'{}' is not a register", name)),
+        }
+        Ok(())
+    }
+
+    /// Runs one line of input. Returns false once the session should end.
+    pub fn execute(&mut self, line: &str) -> Result<bool, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((&command, rest)) = words.split_first() else {
+            return Ok(true);
+        };
+        let count = |default: u64| match rest.first() {
+            Some(text) => text.parse::<u64>().map_err(|_| format!("'{}' is not a count", text)),
+            None => Ok(default),
+        };
+        match command {
+            "break" | "b" => self.add_breakpoint(rest)?,
+            "breaks" => {
+                for (number, breakpoint) in self.debugger.breakpoints().iter().enumerate() {
+                    let mut line = format!("{:<3}", number + 1);
+                    if let Some(bank) = breakpoint.bank {
+                        let _ = write!(line, " {:X}:", bank);
+                    } else {
+                        line.push(' ');
+                    }
+                    let _ = write!(line, "{:04X}", breakpoint.address);
+                    if let Some(condition) = &breakpoint.condition {
+                        let _ = write!(line, " if {}", condition);
+                    }
+                    let state = if breakpoint.enabled { "" } else { ", disabled" };
+                    println!("{}  ({} hits{})", line, breakpoint.hits, state);
+                }
+            }
+            "delete" => {
+                let index = self.breakpoint_index(rest.first())?;
+                self.debugger.remove_breakpoint(index);
+            }
+            "enable" | "disable" => {
+                let index = self.breakpoint_index(rest.first())?;
+                self.debugger.set_enabled(index, command == "enable");
+            }
+            "watch" => self.add_watchpoint(rest)?,
+            "watches" => {
+                for (number, watchpoint) in self.gameboy.cpu().bus.watchpoints.list().iter().enumerate() {
+                    let access = match watchpoint.access {
+                        Access::Read => "r ",
+                        Access::Write => "w ",
+                        Access::ReadWrite => "rw",
+                    };
+                    let mut line = format!("{:<3} {} {:04X}", number + 1, access, watchpoint.start);
+                    if watchpoint.end != watchpoint.start {
+                        let _ = write!(line, "-{:04X}", watchpoint.end);
+                    }
+                    if let Some(condition) = &watchpoint.condition {
+                        let _ = write!(line, " if {}", condition);
+                    }
+                    println!("{}  ({} hits)", line, watchpoint.hits);
+                }
+            }
+            "unwatch" => {
+                let text = rest.first().ok_or("which watchpoint?")?;
+                let watchpoints = &mut self.gameboy.cpu_mut().bus.watchpoints;
+                let index = text.parse::<usize>().ok().filter(|&number| number >= 1 && number <= watchpoints.list().len());
+                watchpoints.remove(index.ok_or_else(|| format!("there is no watchpoint {}", text))? - 1);
+            }
+            "step" | "s" => self.run(Goal::Steps(count(1)?.max(1))),
+            "next" | "n" => self.run(Goal::StepOver),
+            "finish" | "out" => self.run(Goal::StepOut),
+            "continue" | "c" => self.run(Goal::Continue),
+            "frame" => {
+                let count = count(1)?;
+                let _catch = CatchInterrupt::new();
+                for _ in 0..count {
+                    let stop = self.debugger.run(&mut self.gameboy, Goal::Frame, u64::MAX);
+                    if stop != Stop::Done {
+                        Session::report(stop);
+                        break;
+                    }
+                }
+                self.show_position();
+            }
+            "line" => {
+                let line = rest.first().and_then(|text| text.parse::<u8>().ok()).filter(|&line| line <= 153);
+                self.run(Goal::Scanline(line.ok_or("which line, from 0 to 153?")?));
+            }
+            "regs" | "r" => self.show_position(),
+            "x" => {
+                let address = parse_address(&self.gameboy, rest.first().ok_or("dump where?")?)?;
+                let length = match rest.get(1) {
+                    Some(text) => text.parse::<usize>().map_err(|_| format!("'{}' is not a length", text))?,
+                    None => 64,
+                };
+                self.dump(address, length);
+            }
+            "dis" | "d" => {
+                let bus = &self.gameboy.cpu().bus;
+                let lines = match rest.first() {
+                    Some(text) => {
+                        let count = match rest.get(1) {
+                            Some(text) => text.parse::<usize>().map_err(|_| format!("'{}' is not a count", text))?,
+                            None => 10,
+                        };
+                        disassemble_from(bus, parse_address(&self.gameboy, text)?, count)
+                    }
+                    None => disassemble_around(bus, self.gameboy.cpu().pc, 4, 6),
+                };
+                for line in &lines {
+                    println!("{}", format_line(&self.gameboy, line));
+                }
+            }
+            "print" | "p" => {
+                let text = rest.join(" ");
+                let expr = Expr::parse(&text).map_err(|err| format!("'{}': {}", text, err))?;
+                let value = expr.eval(self.gameboy.cpu());
+                println!("{} (${:X})", value, value);
+            }
+            "set" => {
+                let name = rest.first().ok_or("set which register?")?.to_ascii_lowercase();
+                let text = rest.get(1..).unwrap_or_default().join(" ");
+                let expr = Expr::parse(&text).map_err(|err| format!("'{}': {}", text, err))?;
+                let value = expr.eval(self.gameboy.cpu()) as u16;
+                self.set_register(&name, value)?;
+            }
+            "save" => {
+                let path = Path::new(rest.first().ok_or("save to which file?")?);
+                fs::write(path, self.gameboy.save_state())
+                    .map_err(|err| format!("cannot write {}: {}", path.display(), err))?;
+            }
+            "load" => {
+                let path = Path::new(rest.first().ok_or("load which file?")?);
+                let state = fs::read(path).map_err(|err| format!("cannot read {}: {}", path.display(), err))?;
+                self.gameboy.load_state(&state).map_err(|err| format!("cannot load {}: {}", path.display(), err))?;
+                self.show_position();
+            }
+            "help" => println!("{}", HELP),
+            "quit" | "exit" | "q" => return Ok(false),
+            _ => return Err(format!("unknown command '{}'; try 'help'", command)),
+        }
+        Ok(true)
+    }
+}
@@ -1,5 +1,788 @@
+mod cli;
+mod debug;
+mod search;
 
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use cli::{Command, Matches, Opt, Parsed};
+use rusty_boy::utils::apu::Channel;
+use rusty_boy::utils::bk2;
+use rusty_boy::utils::cheats::Cheats;
+use rusty_boy::utils::export::{VgmExport, WavExport};
+use rusty_boy::utils::gdb::GdbStub;
+use rusty_boy::utils::image::Image;
+use rusty_boy::utils::link::SocketLink;
+use rusty_boy::utils::log::{self, Level};
+use rusty_boy::utils::movie::{ForeignMovie, Movie, MovieError, MovieFormat, MoviePlayer, MovieRecorder};
+use rusty_boy::utils::pacing::{Pacer, Speed, TurboAudio};
+use rusty_boy::utils::rewind::{Rewind, RewindConfig};
+use rusty_boy::utils::rom::Header;
+use rusty_boy::utils::terminal::{ColorMode, Key, TerminalFrontend};
+use rusty_boy::utils::vbm;
+use rusty_boy::{GameBoy, Model, ScreenshotOptions};
+
+const PROGRAM: &str = "rusty_boy";
+
+// Frames between battery save flushes, about ten seconds.
+const SAVE_INTERVAL: u64 = 600;
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "",
+        args: &["<rom>"],
+        about: "Run a Game Boy or Game Boy Color ROM.",
+        options: &[
+            Opt { name: "model", value: Some("dmg|cgb|auto"), help: "hardware to emulate (default: auto, from the header)" },
+            Opt { name: "boot-rom", value: Some("FILE"), help: "start from this boot ROM instead of the post-boot state" },
+            Opt { name: "headless", value: None, help: "run unpaced without drawing to the terminal; requires --frames or --play-movie" },
+            Opt { name: "frames", value: Some("N"), help: "stop after N frames" },
+            Opt { name: "screenshot", value: Some("FILE"), help: "write the last frame on exit, as .png or .ppm" },
+            Opt { name: "scale", value: Some("N"), help: "integer scale for screenshots (default: 1)" },
+            Opt { name: "record", value: Some("FILE"), help: "record video (.y4m, .rgb or .raw) plus a .wav beside it" },
+            Opt { name: "sgb-border", value: Some("FILE.ppm"), help: "frame screenshots with this 256x224 border" },
+            Opt { name: "load-state", value: Some("FILE"), help: "start from this save state" },
+            Opt { name: "record-movie", value: Some("FILE"), help: "record the input of every frame to a movie" },
+            Opt { name: "play-movie", value: Some("FILE"), help: "replay a movie (native, .bk2 or .vbm), stopping at its end or first desync" },
+            Opt { name: "save-dir", value: Some("DIR"), help: "where battery saves live (default: next to the ROM)" },
+            Opt { name: "cheats", value: Some("FILE"), help: "cheat list to apply (default: the ROM's .cht, if any); c toggles" },
+            Opt { name: "link-listen", value: Some("ADDR"), help: "wait for a link cable peer on HOST:PORT, or unix:PATH" },
+            Opt { name: "link-connect", value: Some("ADDR"), help: "plug the link cable into a peer listening on ADDR" },
+            Opt { name: "rewind", value: Some("SECONDS"), help: "keep this much history; hold Backspace to rewind" },
+            Opt { name: "rewind-interval", value: Some("N"), help: "frames between rewind snapshots (default: 10)" },
+            Opt { name: "rewind-budget", value: Some("MIB"), help: "memory cap for rewind history (default: 64)" },
+            Opt { name: "color", value: Some("auto|truecolor|256"), help: "terminal colours (default: auto, from COLORTERM)" },
+            Opt { name: "mute", value: None, help: "silence all sound channels" },
+            Opt { name: "speed", value: Some("X"), help: "speed multiplier, 0 for turbo (default: 1); keys: Tab turbo, +/- step, 0 normal, h pause, n next frame" },
+            Opt { name: "turbo-audio", value: Some("skip|stretch"), help: "sound away from 1x: drop it, or stretch it to real time (default: skip)" },
+            Opt { name: "audio-out", value: Some("FILE"), help: "stream the sound as played to FILE or a FIFO, as raw 16-bit stereo PCM at 48 kHz" },
+            Opt { name: "log-level", value: Some("LEVEL"), help: "off, error, warn, info, debug or trace (default: warn)" },
+        ],
+    },
+    Command {
+        name: "wav",
+        args: &["<rom|gbs>", "<output.wav>"],
+        about: "Render a ROM or GBS track to a WAV file as fast as possible.",
+        options: &[
+            Opt { name: "seconds", value: Some("N"), help: "length to render (default: 60)" },
+            Opt { name: "track", value: Some("N"), help: "GBS track number, starting at 1" },
+            Opt { name: "stems", value: None, help: "also write one WAV per channel" },
+            Opt { name: "rate", value: Some("HZ"), help: "sample rate (default: 48000)" },
+        ],
+    },
+    Command {
+        name: "vgm",
+        args: &["<rom|gbs>", "<output.vgm>"],
+        about: "Log the sound register writes of a ROM or GBS track to a VGM file.",
+        options: &[
+            Opt { name: "seconds", value: Some("N"), help: "length to log (default: 60)" },
+            Opt { name: "track", value: Some("N"), help: "GBS track number, starting at 1" },
+            Opt { name: "loop-at", value: Some("SECONDS"), help: "mark a loop point at this time" },
+        ],
+    },
+    Command {
+        name: "cheats",
+        args: &["<rom>"],
+        about: "List and edit the GameShark and Game Genie cheats kept for a ROM.",
+        options: &[
+            Opt { name: "add", value: Some("CODE"), help: "add a code: 01VVAAAA (also 8x/9x banks), ABC-DEF or ABC-DEF-GHI" },
+            Opt { name: "name", value: Some("TEXT"), help: "description for --add" },
+            Opt { name: "enable", value: Some("N"), help: "switch cheat N on" },
+            Opt { name: "disable", value: Some("N"), help: "switch cheat N off" },
+            Opt { name: "remove", value: Some("N"), help: "delete cheat N" },
+            Opt { name: "file", value: Some("FILE"), help: "cheat file (default: the ROM's .cht)" },
+            Opt { name: "save-dir", value: Some("DIR"), help: "where the .cht lives (default: next to the ROM)" },
+        ],
+    },
+    Command {
+        name: "search",
+        args: &["<rom>"],
+        about: "Search RAM for a value interactively, watch and freeze what turns up. Reads commands \
+                from stdin; 'help' lists them.",
+        options: &[
+            Opt { name: "model", value: Some("dmg|cgb|auto"), help: "hardware to emulate (default: auto, from the header)" },
+            Opt { name: "load-state", value: Some("FILE"), help: "start from this save state" },
+        ],
+    },
+    Command {
+        name: "debug",
+        args: &["<rom>"],
+        about: "Debug a ROM from the command line: breakpoints, stepping, registers, memory and \
+                disassembly. Reads commands from stdin; 'help' lists them.",
+        options: &[
+            Opt { name: "model", value: Some("dmg|cgb|auto"), help: "hardware to emulate (default: auto, from the header)" },
+            Opt { name: "boot-rom", value: Some("FILE"), help: "start from this boot ROM instead of the post-boot state" },
+            Opt { name: "load-state", value: Some("FILE"), help: "start from this save state" },
+        ],
+    },
+    Command {
+        name: "gdb",
+        args: &["<rom>"],
+        about: "Serve the GDB remote protocol on a local port, for an external debugger to attach \
+                to. Registers are af, bc, de, hl, sp and pc.",
+        options: &[
+            Opt { name: "port", value: Some("N"), help: "TCP port on 127.0.0.1 to listen on (default: 2345)" },
+            Opt { name: "model", value: Some("dmg|cgb|auto"), help: "hardware to emulate (default: auto, from the header)" },
+            Opt { name: "boot-rom", value: Some("FILE"), help: "start from this boot ROM instead of the post-boot state" },
+            Opt { name: "load-state", value: Some("FILE"), help: "start from this save state" },
+            Opt { name: "log-level", value: Some("LEVEL"), help: "off, error, warn, info, debug or trace (default: warn)" },
+        ],
+    },
+    Command {
+        name: "convert",
+        args: &["<rom>", "<input>", "<output>"],
+        about: "Convert an input movie between the native, BizHawk .bk2 and VBA .vbm formats.",
+        options: &[
+            Opt { name: "model", value: Some("dmg|cgb|auto"), help: "hardware, when the movie does not say (default: auto)" },
+        ],
+    },
+];
+
+enum Failure {
+    /// Bad arguments; reported with the command's help and exit code 2.
+    Usage(String),
+    /// Anything that went wrong while running; exit code 1.
+    Runtime(String),
+}
+
+/// A movie as read from disk; foreign ones are only tied to a cartridge once the machine
+/// exists.
+enum MovieFile {
+    Native(Movie),
+    Foreign(ForeignMovie),
+}
+
+impl MovieFile {
+    fn read(path: &Path) -> Result<MovieFile, Failure> {
+        let data = read_file(path, "movie")?;
+        let movie = match MovieFormat::from_path(path) {
+            MovieFormat::Native => Movie::decode(&data).map(MovieFile::Native),
+            MovieFormat::Bk2 => bk2::import(&data).map(MovieFile::Foreign),
+            MovieFormat::Vbm => vbm::import(&data).map(MovieFile::Foreign),
+        };
+        movie.map_err(|err| Failure::Runtime(format!("cannot read movie {}: {}", path.display(), err)))
+    }
+
+    fn model(&self) -> Option<Model> {
+        match self {
+            MovieFile::Native(movie) => Some(movie.model),
+            MovieFile::Foreign(movie) => movie.model,
+        }
+    }
+
+    fn into_movie(self, gameboy: &mut GameBoy) -> Result<Movie, MovieError> {
+        match self {
+            MovieFile::Native(movie) => movie.check(gameboy).map(|()| movie),
+            MovieFile::Foreign(movie) => movie.into_movie(gameboy),
+        }
+    }
+}
+
+fn parse_model(args: &Matches) -> Result<Option<Model>, Failure> {
+    match args.value("model") {
+        None | Some("auto") => Ok(None),
+        Some("dmg") => Ok(Some(Model::Dmg)),
+        Some("cgb") => Ok(Some(Model::Cgb)),
+        Some(other) => Err(Failure::Usage(format!("unknown model '{}'", other))),
+    }
+}
+
+fn read_file(path: &Path, what: &str) -> Result<Vec<u8>, Failure> {
+    fs::read(path).map_err(|err| Failure::Runtime(format!("cannot read {} {}: {}", what, path.display(), err)))
+}
+
+// Where the per-ROM file with `extension` lives: in `dir` if given, else next to the ROM.
+fn rom_data_path(rom: &Path, dir: Option<&str>, extension: &str) -> PathBuf {
+    match dir {
+        Some(dir) => Path::new(dir).join(Path::new(rom.file_name().unwrap_or_default()).with_extension(extension)),
+        None => rom.with_extension(extension),
+    }
+}
+
+// The ROM's cheat file, or an empty list if it has none yet.
+fn read_cheats(path: &Path) -> Result<Cheats, Failure> {
+    match fs::read_to_string(path) {
+        Ok(text) => Cheats::parse(&text).map_err(|err| Failure::Runtime(format!("{}: {}", path.display(), err))),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Cheats::new()),
+        Err(err) => Err(Failure::Runtime(format!("cannot read cheats {}: {}", path.display(), err))),
+    }
+}
+
+// Writes the battery save if it changed since the last flush.
+fn flush_save(gameboy: &GameBoy, path: &Path, last: &mut Vec<u8>) -> Result<(), Failure> {
+    let data = gameboy.cpu().bus.cartridge.battery_data();
+    if data == *last {
+        return Ok(());
+    }
+    let write = || -> io::Result<()> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, &data)
+    };
+    write().map_err(|err| Failure::Runtime(format!("cannot write save {}: {}", path.display(), err)))?;
+    log::debug(format_args!("saved {}", path.display()));
+    *last = data;
+    Ok(())
+}
+
+fn write_screenshot(gameboy: &GameBoy, path: &Path, options: &ScreenshotOptions) -> Result<(), Failure> {
+    gameboy
+        .screenshot_with(path, options)
+        .map_err(|err| Failure::Runtime(format!("cannot write screenshot {}: {}", path.display(), err)))?;
+    log::info(format_args!("wrote {}", path.display()));
+    Ok(())
+}
+
+// The first `<rom>-NNN.<extension>` in the current directory that does not exist yet.
+fn next_capture_path(rom: &Path, extension: &str) -> PathBuf {
+    let stem = rom.file_stem().unwrap_or_default().to_string_lossy();
+    (1..)
+        .map(|n| PathBuf::from(format!("{}-{:03}.{}", stem, n, extension)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+fn start_recording(gameboy: &mut GameBoy, path: &Path) -> Result<(), Failure> {
+    gameboy
+        .start_recording(path)
+        .map_err(|err| Failure::Runtime(format!("cannot record to {}: {}", path.display(), err)))?;
+    log::info(format_args!("recording to {}", path.display()));
+    Ok(())
+}
+
+fn stop_recording(gameboy: &mut GameBoy) -> Result<(), Failure> {
+    let frames = gameboy
+        .stop_recording()
+        .map_err(|err| Failure::Runtime(format!("recording failed: {}", err)))?;
+    if let Some(frames) = frames {
+        log::info(format_args!("recorded {} frames", frames));
+    }
+    Ok(())
+}
+
+// What the terminal shows under the screen: nothing at normal speed.
+fn speed_status(pacer: &Pacer, paused: bool) -> String {
+    match (paused, pacer.speed()) {
+        (true, _) => "paused (h: resume, n: next frame)".to_string(),
+        (false, Speed::Turbo) => format!("turbo ({:.1}x)", pacer.effective_speed()),
+        (false, Speed::Fixed(1.0)) => String::new(),
+        (false, Speed::Fixed(speed)) => format!("{}x", speed),
+    }
+}
+
+fn set_log_level(args: &Matches) -> Result<(), Failure> {
+    if let Some(name) = args.value("log-level") {
+        let level = Level::parse(name).ok_or_else(|| Failure::Usage(format!("unknown log level '{}'", name)))?;
+        log::set_level(level);
+    }
+    Ok(())
+}
+
+// The link cable from --link-listen or --link-connect, if either was given.
+fn open_link(args: &Matches) -> Result<Option<SocketLink>, Failure> {
+    let (address, listen) = match (args.value("link-listen"), args.value("link-connect")) {
+        (Some(_), Some(_)) => return Err(Failure::Usage("--link-listen and --link-connect cannot be combined".to_string())),
+        (Some(address), None) => (address, true),
+        (None, Some(address)) => (address, false),
+        (None, None) => return Ok(None),
+    };
+    let link = match (address.strip_prefix("unix:"), listen) {
+        #[cfg(unix)]
+        (Some(path), true) => SocketLink::listen_unix(path),
+        #[cfg(unix)]
+        (Some(path), false) => SocketLink::connect_unix(path),
+        #[cfg(not(unix))]
+        (Some(_), _) => return Err(Failure::Usage("unix: link addresses need a Unix system".to_string())),
+        (None, true) => SocketLink::listen_tcp(address),
+        (None, false) => SocketLink::connect_tcp(address),
+    };
+    let link = link.map_err(|err| Failure::Runtime(format!("cannot open link cable {}: {}", address, err)))?;
+    match listen {
+        true => log::info(format_args!("link cable waiting for a peer on {}", address)),
+        false => log::info(format_args!("link cable connected to {}", address)),
+    }
+    Ok(Some(link))
+}
+
+fn run(args: &Matches) -> Result<(), Failure> {
+    set_log_level(args)?;
+    let model = parse_model(args)?;
+    let frames: Option<u64> = args.parse("frames").map_err(Failure::Usage)?;
+    let speed: f64 = args.parse("speed").map_err(Failure::Usage)?.unwrap_or(1.0);
+    if !speed.is_finite() || speed < 0.0 {
+        return Err(Failure::Usage("--speed must be 0 or more".to_string()));
+    }
+    let turbo_audio = match args.value("turbo-audio") {
+        None | Some("skip") => TurboAudio::Skip,
+        Some("stretch") => TurboAudio::Stretch,
+        Some(other) => return Err(Failure::Usage(format!("unknown turbo audio mode '{}'", other))),
+    };
+    let scale: usize = args.parse("scale").map_err(Failure::Usage)?.unwrap_or(1);
+    if scale == 0 {
+        return Err(Failure::Usage("--scale must be at least 1".to_string()));
+    }
+    let sgb_border = match args.value("sgb-border").map(Path::new) {
+        Some(path) => {
+            let border = Image::decode_ppm(&read_file(path, "border")?);
+            Some(border.and_then(|border| border.check_sgb_border().map(|()| border)).map_err(|err| {
+                Failure::Runtime(format!("cannot use border {}: {}", path.display(), err))
+            })?)
+        }
+        None => None,
+    };
+    let screenshot = ScreenshotOptions { scale, sgb_border };
+    let headless = args.flag("headless");
+    let play_movie = args.value("play-movie").map(Path::new);
+    if headless && frames.is_none() && play_movie.is_none() {
+        return Err(Failure::Usage("--headless needs --frames or --play-movie".to_string()));
+    }
+    if play_movie.is_some() && (args.value("record-movie").is_some() || args.value("load-state").is_some()) {
+        return Err(Failure::Usage("--play-movie cannot be combined with --record-movie or --load-state".to_string()));
+    }
+    let mut rewind = match args.parse::<f64>("rewind").map_err(Failure::Usage)? {
+        Some(seconds) if seconds > 0.0 => {
+            let mut config = RewindConfig { seconds, ..RewindConfig::default() };
+            config.interval = args.parse("rewind-interval").map_err(Failure::Usage)?.unwrap_or(config.interval);
+            if let Some(mib) = args.parse::<usize>("rewind-budget").map_err(Failure::Usage)? {
+                config.budget = mib << 20;
+            }
+            Some(Rewind::new(config))
+        }
+        _ => None,
+    };
+    let color = match args.value("color") {
+        None | Some("auto") => ColorMode::detect(),
+        Some("truecolor") => ColorMode::TrueColor,
+        Some("256") => ColorMode::Ansi256,
+        Some(other) => return Err(Failure::Usage(format!("unknown colour mode '{}'", other))),
+    };
+
+    let rom_path = PathBuf::from(&args.positional[0]);
+    let rom = read_file(&rom_path, "ROM")?;
+    let not_a_rom = |err| Failure::Runtime(format!("{} is not a ROM this emulator can run: {}", rom_path.display(), err));
+    let header = Header::parse(&rom).map_err(not_a_rom)?;
+    let movie_file = play_movie.map(MovieFile::read).transpose()?;
+    let model = model
+        .or(movie_file.as_ref().and_then(MovieFile::model))
+        .unwrap_or(if header.supports_cgb() { Model::Cgb } else { Model::Dmg });
+    log::info(format_args!("running \"{}\" as {:?}", header.title, model));
+    let mut gameboy = GameBoy::new(model, rom).map_err(not_a_rom)?;
+
+    if let Some(path) = args.value("boot-rom").map(Path::new) {
+        let boot_rom = read_file(path, "boot ROM")?;
+        gameboy
+            .set_boot_rom(boot_rom)
+            .map_err(|err| Failure::Runtime(format!("cannot use {}: {}", path.display(), err)))?;
+    }
+
+    // Movies must start from the same cartridge RAM every time, so the battery save is
+    // left alone while one is recorded or played.
+    let movie = play_movie.is_some() || args.value("record-movie").is_some();
+    if movie && header.has_battery() {
+        log::info(format_args!("not loading or writing the battery save during a movie"));
+    }
+    let save = (header.has_battery() && !movie).then(|| rom_data_path(&rom_path, args.value("save-dir"), "sav"));
+    let mut saved = gameboy.cpu().bus.cartridge.battery_data();
+    if let Some(path) = &save {
+        match fs::read(path) {
+            Ok(data) => {
+                gameboy.cpu_mut().bus.cartridge.load_battery_data(&data).map_err(|err| {
+                    Failure::Runtime(format!("cannot load save {}: {}", path.display(), err))
+                })?;
+                saved = data;
+                log::info(format_args!("loaded {}", path.display()));
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                log::info(format_args!("no save at {}, starting fresh", path.display()));
+            }
+            Err(err) => return Err(Failure::Runtime(format!("cannot read save {}: {}", path.display(), err))),
+        }
+    }
+
+    if let Some(path) = args.value("load-state").map(Path::new) {
+        let state = read_file(path, "save state")?;
+        gameboy
+            .load_state(&state)
+            .map_err(|err| Failure::Runtime(format!("cannot load state {}: {}", path.display(), err)))?;
+        log::info(format_args!("loaded state {}", path.display()));
+    }
+    let movie_path = args.value("record-movie").map(Path::new);
+    let mut recorder = movie_path.map(|_| MovieRecorder::new(&gameboy, args.value("load-state").is_none()));
+    let mut player = match (play_movie, movie_file) {
+        (Some(path), Some(file)) => {
+            let cannot_play = |err| Failure::Runtime(format!("cannot play {}: {}", path.display(), err));
+            let movie = file.into_movie(&mut gameboy).map_err(cannot_play)?;
+            log::info(format_args!("playing {} frames recorded with {}", movie.frames(), movie.emulator_version));
+            Some(MoviePlayer::start(movie, &mut gameboy).map_err(cannot_play)?)
+        }
+        _ => None,
+    };
+
+    // Cheats patch what the game reads and movies do not record them, so like the battery
+    // save they stay off while one is recorded or played.
+    if movie && args.value("cheats").is_some() {
+        return Err(Failure::Usage("--cheats cannot be combined with a movie".to_string()));
+    }
+    let cheat_path = match args.value("cheats") {
+        Some(path) => PathBuf::from(path),
+        None => rom_data_path(&rom_path, args.value("save-dir"), "cht"),
+    };
+    if !movie {
+        *gameboy.cheats_mut() = read_cheats(&cheat_path)?;
+        if !gameboy.cheats().is_empty() {
+            log::info(format_args!("loaded {} cheats from {}", gameboy.cheats().len(), cheat_path.display()));
+        }
+    }
+
+    if let Some(link) = open_link(args)? {
+        gameboy.connect_serial(Box::new(link));
+    }
+
+    if args.flag("mute") {
+        for channel in Channel::ALL {
+            gameboy.cpu_mut().bus.apu.set_muted(channel, true);
+        }
+    }
+
+    if let Some(path) = args.value("record") {
+        start_recording(&mut gameboy, Path::new(path))?;
+    }
+    let mut terminal = match headless {
+        true => None,
+        false => Some(TerminalFrontend::new(color).map_err(|err| {
+            Failure::Runtime(format!("cannot set up the terminal: {}", err))
+        })?),
+    };
+    // Headless runs never wait; a --speed of 0 means turbo too.
+    let mut pacer = Pacer::new(Speed::Turbo);
+    if !headless {
+        pacer.set_speed(Speed::Fixed(speed));
+    }
+    pacer.turbo_audio = turbo_audio;
+    let mut audio_out = match args.value("audio-out") {
+        Some(path) => Some(BufWriter::new(
+            File::create(path).map_err(|err| Failure::Runtime(format!("cannot open {}: {}", path, err)))?,
+        )),
+        None => None,
+    };
+    let mut frame = 0;
+    let mut was_rewinding = false;
+    while frames.is_none_or(|limit| frame < limit) {
+        if let Some(terminal) = &mut terminal {
+            let keys = terminal.poll(&mut gameboy);
+            if keys.contains(&Key::Quit) {
+                break;
+            }
+            if keys.contains(&Key::Screenshot) {
+                write_screenshot(&gameboy, &next_capture_path(&rom_path, "png"), &screenshot)?;
+            }
+            if keys.contains(&Key::Record) {
+                if gameboy.is_recording() {
+                    stop_recording(&mut gameboy)?;
+                } else {
+                    start_recording(&mut gameboy, &next_capture_path(&rom_path, "y4m"))?;
+                }
+            }
+            for key in keys {
+                match key {
+                    Key::Pause => gameboy.toggle_pause(),
+                    Key::FrameAdvance => gameboy.advance_frame(),
+                    Key::Turbo => pacer.toggle_turbo(),
+                    Key::Faster => pacer.faster(),
+                    Key::Slower => pacer.slower(),
+                    Key::NormalSpeed => pacer.set_speed(Speed::Fixed(1.0)),
+                    Key::Cheats => {
+                        let enabled = !gameboy.cheats().enabled();
+                        gameboy.cheats_mut().set_all_enabled(enabled);
+                        log::info(format_args!("cheats {}", if enabled { "on" } else { "off" }));
+                    }
+                    _ => {}
+                }
+            }
+            terminal.set_status(&speed_status(&pacer, gameboy.paused()));
+        }
+        if !gameboy.frame_due() {
+            if let Some(terminal) = &mut terminal {
+                terminal.draw(gameboy.framebuffer()).map_err(|err| Failure::Runtime(format!("cannot draw: {}", err)))?;
+            }
+            // Paused: keys are polled once a frame, so frame advance reacts within a frame.
+            pacer.wait(true);
+            continue;
+        }
+        let rewinding = terminal.as_ref().is_some_and(TerminalFrontend::rewinding);
+        if let Some(player) = &mut player {
+            let played = player.run_frame(&mut gameboy).map_err(|err| Failure::Runtime(err.to_string()))?;
+            if !played {
+                break;
+            }
+        } else {
+            let stepping_back = rewinding && rewind.is_some();
+            if let Some(recorder) = &mut recorder {
+                // Going back while recording starts a new take over frames already recorded.
+                if !stepping_back {
+                    recorder.record_frame(&gameboy);
+                } else if !was_rewinding {
+                    recorder.rerecord();
+                }
+            }
+            match &mut rewind {
+                Some(rewind) if rewinding => {
+                    if rewind.step_back(&mut gameboy) {
+                        if let Some(recorder) = &mut recorder {
+                            recorder.truncate(recorder.frames().saturating_sub(1));
+                        }
+                    }
+                }
+                Some(rewind) => rewind.run_frame(&mut gameboy),
+                None => gameboy.run_frame(),
+            }
+            was_rewinding = stepping_back;
+        }
+        if let Some(terminal) = &mut terminal {
+            terminal.frame_done();
+            terminal.draw(gameboy.framebuffer()).map_err(|err| Failure::Runtime(format!("cannot draw: {}", err)))?;
+        }
+        // Recordings take their sound from the bus, in emulated time, so turbo and slow
+        // motion never stretch or drop it there; only the live stream follows --turbo-audio.
+        // It is drained even with nowhere to go, so it does not pile up.
+        let samples = pacer.audio(gameboy.audio_samples());
+        if let Some(out) = &mut audio_out {
+            let bytes: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+            out.write_all(&bytes).map_err(|err| Failure::Runtime(format!("cannot stream audio: {}", err)))?;
+        }
+        frame += 1;
+
+        if let Some(path) = &save {
+            if frame % SAVE_INTERVAL == 0 {
+                flush_save(&gameboy, path, &mut saved)?;
+            }
+        }
+        pacer.wait(gameboy.paused());
+    }
+    drop(terminal);
+    if let Some(mut out) = audio_out {
+        out.flush().map_err(|err| Failure::Runtime(format!("cannot stream audio: {}", err)))?;
+    }
+    stop_recording(&mut gameboy)?;
+    if let (Some(path), Some(recorder)) = (movie_path, recorder) {
+        let movie = recorder.finish();
+        movie
+            .save(path)
+            .map_err(|err| Failure::Runtime(format!("cannot write movie {}: {}", path.display(), err)))?;
+        log::info(format_args!("wrote {} frames to {}", movie.frames(), path.display()));
+    }
+    if let Some(player) = &player {
+        if player.finished() {
+            log::info(format_args!("movie ended, in sync"));
+        }
+    }
+    log::info(format_args!("ran {} frames", frame));
+
+    if let Some(path) = args.value("screenshot") {
+        write_screenshot(&gameboy, Path::new(path), &screenshot)?;
+    }
+    if let Some(path) = &save {
+        flush_save(&gameboy, path, &mut saved)?;
+    }
+    Ok(())
+}
+
+fn run_wav(args: &Matches) -> Result<(), Failure> {
+    let mut export = WavExport::new(PathBuf::from(&args.positional[0]), PathBuf::from(&args.positional[1]));
+    export.seconds = args.parse("seconds").map_err(Failure::Usage)?.unwrap_or(export.seconds);
+    export.sample_rate = args.parse("rate").map_err(Failure::Usage)?.unwrap_or(export.sample_rate);
+    export.track = args.parse("track").map_err(Failure::Usage)?;
+    export.stems = args.flag("stems");
+    export.run().map_err(|err| Failure::Runtime(err.to_string()))
+}
+
+fn run_vgm(args: &Matches) -> Result<(), Failure> {
+    let mut export = VgmExport::new(PathBuf::from(&args.positional[0]), PathBuf::from(&args.positional[1]));
+    export.seconds = args.parse("seconds").map_err(Failure::Usage)?.unwrap_or(export.seconds);
+    export.track = args.parse("track").map_err(Failure::Usage)?;
+    export.loop_at = args.parse("loop-at").map_err(Failure::Usage)?;
+    export.run().map_err(|err| Failure::Runtime(err.to_string()))
+}
+
+fn run_cheats(args: &Matches) -> Result<(), Failure> {
+    let rom_path = Path::new(&args.positional[0]);
+    let path = match args.value("file") {
+        Some(path) => PathBuf::from(path),
+        None => rom_data_path(rom_path, args.value("save-dir"), "cht"),
+    };
+    let mut cheats = read_cheats(&path)?;
+
+    // Cheats are numbered from 1 on the command line.
+    let index = |name: &str| -> Result<Option<usize>, Failure> {
+        match args.parse::<usize>(name).map_err(Failure::Usage)? {
+            Some(number) if (1..=cheats.len()).contains(&number) => Ok(Some(number - 1)),
+            Some(number) => Err(Failure::Usage(format!("there is no cheat {}", number))),
+            None => Ok(None),
+        }
+    };
+    let (enable, disable, remove) = (index("enable")?, index("disable")?, index("remove")?);
+    let mut changed = false;
+    if let Some(index) = enable {
+        changed |= cheats.set_enabled(index, true);
+    }
+    if let Some(index) = disable {
+        changed |= cheats.set_enabled(index, false);
+    }
+    if let Some(index) = remove {
+        changed |= cheats.remove(index).is_some();
+    }
+    if let Some(code) = args.value("add") {
+        cheats.add(code, args.value("name").unwrap_or("")).map_err(|err| Failure::Usage(err.to_string()))?;
+        changed = true;
+    }
+    if changed {
+        fs::write(&path, cheats.to_text())
+            .map_err(|err| Failure::Runtime(format!("cannot write cheats {}: {}", path.display(), err)))?;
+    }
+
+    if cheats.is_empty() {
+        println!("no cheats in {}", path.display());
+    }
+    for (number, cheat) in cheats.list().iter().enumerate() {
+        let state = if cheat.enabled { "on " } else { "off" };
+        let line = format!("{:3}  {}  {:11}  {}", number + 1, state, cheat.code, cheat.description);
+        println!("{}", line.trim_end());
+    }
+    Ok(())
+}
+
+// The machine for an interactive session, set up by --model, --boot-rom and --load-state.
+fn session_gameboy(args: &Matches) -> Result<GameBoy, Failure> {
+    let rom_path = Path::new(&args.positional[0]);
+    let rom = read_file(rom_path, "ROM")?;
+    let not_a_rom = |err| Failure::Runtime(format!("{} is not a ROM this emulator can run: {}", rom_path.display(), err));
+    let header = Header::parse(&rom).map_err(not_a_rom)?;
+    let model = parse_model(args)?.unwrap_or(if header.supports_cgb() { Model::Cgb } else { Model::Dmg });
+    let mut gameboy = GameBoy::new(model, rom).map_err(not_a_rom)?;
+    if let Some(path) = args.value("boot-rom").map(Path::new) {
+        let boot_rom = read_file(path, "boot ROM")?;
+        gameboy
+            .set_boot_rom(boot_rom)
+            .map_err(|err| Failure::Runtime(format!("cannot use {}: {}", path.display(), err)))?;
+    }
+    if let Some(path) = args.value("load-state").map(Path::new) {
+        let state = read_file(path, "save state")?;
+        gameboy
+            .load_state(&state)
+            .map_err(|err| Failure::Runtime(format!("cannot load state {}: {}", path.display(), err)))?;
+    }
+    Ok(gameboy)
+}
+
+fn run_search(args: &Matches) -> Result<(), Failure> {
+    let mut session = search::Session::new(session_gameboy(args)?);
+    cli::repl(|line| session.execute(line)).map_err(|err| Failure::Runtime(format!("cannot read commands: {}", err)))
+}
+
+fn run_debug(args: &Matches) -> Result<(), Failure> {
+    let mut session = debug::Session::new(session_gameboy(args)?);
+    session.show_position();
+    cli::repl(|line| session.execute(line)).map_err(|err| Failure::Runtime(format!("cannot read commands: {}", err)))
+}
+
+fn run_gdb(args: &Matches) -> Result<(), Failure> {
+    set_log_level(args)?;
+    let port = args.parse::<u16>("port").map_err(Failure::Usage)?.unwrap_or(2345);
+    let mut stub = GdbStub::new(session_gameboy(args)?);
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .map_err(|err| Failure::Runtime(format!("cannot listen on port {}: {}", port, err)))?;
+    if let Ok(address) = listener.local_addr() {
+        log::info(format_args!("waiting for a debugger on {}", address));
+    }
+    // Clients that detach can attach again; one that kills the target ends the session.
+    loop {
+        let (stream, peer) = listener.accept().map_err(|err| Failure::Runtime(format!("cannot accept: {}", err)))?;
+        log::info(format_args!("debugger attached from {}", peer));
+        match stub.serve(stream) {
+            Ok(true) => return Ok(()),
+            Ok(false) => log::info(format_args!("debugger detached")),
+            Err(err) => log::warn(format_args!("debugger connection lost: {}", err)),
+        }
+    }
+}
+
+fn run_convert(args: &Matches) -> Result<(), Failure> {
+    let rom_path = Path::new(&args.positional[0]);
+    let input = Path::new(&args.positional[1]);
+    let output = Path::new(&args.positional[2]);
+    let model = parse_model(args)?;
+
+    let rom = read_file(rom_path, "ROM")?;
+    let not_a_rom = |err| Failure::Runtime(format!("{} is not a ROM this emulator can run: {}", rom_path.display(), err));
+    let header = Header::parse(&rom).map_err(not_a_rom)?;
+    let file = MovieFile::read(input)?;
+    let model = model.or(file.model()).unwrap_or(if header.supports_cgb() { Model::Cgb } else { Model::Dmg });
+    let mut gameboy = GameBoy::new(model, rom).map_err(not_a_rom)?;
+    let movie = file
+        .into_movie(&mut gameboy)
+        .map_err(|err| Failure::Runtime(format!("cannot convert {}: {}", input.display(), err)))?;
+
+    let data = match MovieFormat::from_path(output) {
+        MovieFormat::Native => Ok(movie.encode()),
+        MovieFormat::Bk2 => bk2::export(&movie, gameboy.cpu().bus.cartridge.rom()),
+        MovieFormat::Vbm => vbm::export(&movie),
+    };
+    let data = data.map_err(|err| Failure::Runtime(format!("cannot convert {}: {}", input.display(), err)))?;
+    fs::write(output, data).map_err(|err| Failure::Runtime(format!("cannot write {}: {}", output.display(), err)))?;
+    log::info(format_args!("wrote {} frames to {}", movie.frames(), output.display()));
+    Ok(())
+}
 
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.is_empty() {
+        eprintln!("{}", cli::usage(PROGRAM, COMMANDS));
+        process::exit(2);
+    }
+
+    let named = COMMANDS.iter().find(|command| !command.name.is_empty() && command.name == args[0]);
+    let (command, rest) = match named {
+        Some(command) => (command, &args[1..]),
+        None => (&COMMANDS[0], &args[..]),
+    };
+
+    let result = match command.parse(rest) {
+        Ok(Parsed::Help) => {
+            println!("{}", command.help(PROGRAM));
+            if command.name.is_empty() {
+                println!("{}", cli::usage(PROGRAM, COMMANDS));
+            }
+            return;
+        }
+        Ok(Parsed::Args(matches)) => match command.name {
+            "wav" => run_wav(&matches),
+            "vgm" => run_vgm(&matches),
+            "cheats" => run_cheats(&matches),
+            "search" => run_search(&matches),
+            "debug" => run_debug(&matches),
+            "gdb" => run_gdb(&matches),
+            "convert" => run_convert(&matches),
+            _ => run(&matches),
+        },
+        Err(message) => Err(Failure::Usage(message)),
+    };
+
+    match result {
+        Ok(()) => {}
+        Err(Failure::Usage(message)) => {
+            let invocation = command.usage_line(PROGRAM);
+            let prefix = invocation.split(" <").next().unwrap_or(PROGRAM);
+            eprintln!("error: {}\n\nusage: {}\nrun '{} --help' for details", message, invocation, prefix);
+            process::exit(2);
+        }
+        Err(Failure::Runtime(message)) => {
+            eprintln!("error: {}", message);
+            process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,240 @@
+use std::fs;
+use std::path::Path;
+
+use rusty_boy::utils::ramsearch::{Filter, Location, RamSearch, ValueType, Watch};
+use rusty_boy::{Button, GameBoy};
+
+const HELP: &str = "\
+run [N]                  run N frames (default 1)
+hold [BUTTONS]           hold buttons while running, e.g. a+right; nothing releases them
+new [u8|i8|u16|i16]      start a search over all RAM (default u8)
+= V, != V, > V, < V      keep values equal to, other than, above or below V
+changed, unchanged       keep values that changed, or did not, since the last filter
+inc [N], dec [N]         keep values that went up or down, by exactly N if given
+list [N]                 show the first N candidates (default 20)
+watch ADDR|#N [TYPE] [NAME]
+                         watch an address, or candidate N of the search
+watches                  show the watch list
+freeze I [V]             keep watch I at V (default: its value now)
+thaw I                   stop freezing watch I
+unwatch I                remove watch I
+code I                   the GameShark code that freezes watch I
+poke ADDR V [TYPE]       write V to an address
+save FILE, load FILE     save or load a state
+help, quit";
+
+// The mapped RAM an address points at, in hex with an optional 0x or $ prefix.
+fn parse_address(gameboy: &GameBoy, text: &str) -> Result<Location, String> {
+    let digits = text.trim_start_matches("0x").trim_start_matches('$');
+    let address = u16::from_str_radix(digits, 16).map_err(|_| format!("'{}' is not an address", text))?;
+    Location::from_address(&gameboy.cpu().bus, address).ok_or_else(|| format!("{:04X} is not RAM", address))
+}
+
+// Decimal, or hex with 0x or $.
+fn parse_value(text: &str) -> Result<i32, String> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+    let value = match digits.strip_prefix("0x").or_else(|| digits.strip_prefix('$')) {
+        Some(hex) => i32::from_str_radix(hex, 16),
+        None => digits.parse(),
+    };
+    let value = value.map_err(|_| format!("'{}' is not a number", text))?;
+    Ok(if negative { -value } else { value })
+}
+
+fn parse_type(text: Option<&str>) -> Result<ValueType, String> {
+    match text {
+        None => Ok(ValueType::U8),
+        Some(name) => ValueType::parse(name).ok_or_else(|| format!("'{}' is not u8, i8, u16 or i16", name)),
+    }
+}
+
+fn parse_buttons(text: &str) -> Result<u8, String> {
+    let mut mask = 0;
+    for name in text.split('+').filter(|name| !name.is_empty() && *name != "none") {
+        let button = match name.to_ascii_lowercase().as_str() {
+            "right" => Button::Right,
+            "left" => Button::Left,
+            "up" => Button::Up,
+            "down" => Button::Down,
+            "a" => Button::A,
+            "b" => Button::B,
+            "select" => Button::Select,
+            "start" => Button::Start,
+            _ => return Err(format!("'{}' is not a button", name)),
+        };
+        mask |= button.mask();
+    }
+    Ok(mask)
+}
+
+/// The `search` command's session: a machine run a few frames at a time between filters.
+pub struct Session {
+    gameboy: GameBoy,
+    search: Option<RamSearch>,
+}
+
+impl Session {
+    pub fn new(gameboy: GameBoy) -> Session {
+        Session { gameboy, search: None }
+    }
+
+    fn search(&self) -> Result<&RamSearch, String> {
+        self.search.as_ref().ok_or_else(|| "no search yet; start one with 'new'".to_string())
+    }
+
+    fn watch_index(&self, text: Option<&str>) -> Result<usize, String> {
+        let text = text.ok_or("which watch?")?;
+        match text.parse::<usize>() {
+            Ok(number) if (1..=self.gameboy.watches().len()).contains(&number) => Ok(number - 1),
+            _ => Err(format!("there is no watch {}", text)),
+        }
+    }
+
+    fn list(&self, count: usize) {
+        let Some(search) = &self.search else { return };
+        let bus = &self.gameboy.cpu().bus;
+        for (number, &location) in search.candidates().iter().enumerate().take(count) {
+            let previous = search.previous(location).unwrap_or(0);
+            let current = search.current(bus, location).unwrap_or(0);
+            println!("#{:<5} {:>7}  {:>6} -> {}", number + 1, location.to_string(), previous, current);
+        }
+        if search.len() > count {
+            println!("... {} more", search.len() - count);
+        }
+    }
+
+    fn filter(&mut self, filter: Filter) -> Result<(), String> {
+        let bus = &self.gameboy.cpu().bus;
+        let search = self.search.as_mut().ok_or("no search yet; start one with 'new'")?;
+        let left = search.filter(bus, filter);
+        println!("{} candidates", left);
+        Ok(())
+    }
+
+    /// Runs one line of input. Returns false once the session should end.
+    pub fn execute(&mut self, line: &str) -> Result<bool, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((&command, rest)) = words.split_first() else {
+            return Ok(true);
+        };
+        let value = |index: usize| rest.get(index).copied().ok_or("missing value".to_string()).and_then(parse_value);
+        match command {
+            "run" => {
+                let frames = rest.first().map_or(Ok(1), |text| text.parse::<u32>().map_err(|_| "bad frame count"))?;
+                for _ in 0..frames {
+                    self.gameboy.run_frame();
+                }
+            }
+            "hold" => {
+                let mask = parse_buttons(rest.first().copied().unwrap_or(""))?;
+                self.gameboy.cpu_mut().bus.joypad.set_mask(mask);
+            }
+            "new" => {
+                let value_type = parse_type(rest.first().copied())?;
+                let search = RamSearch::new(&self.gameboy.cpu().bus, value_type);
+                println!("{} candidates as {}", search.len(), value_type.name());
+                self.search = Some(search);
+            }
+            "=" => self.filter(Filter::Equal(value(0)?))?,
+            "!=" => self.filter(Filter::NotEqual(value(0)?))?,
+            ">" => self.filter(Filter::Greater(value(0)?))?,
+            "<" => self.filter(Filter::Less(value(0)?))?,
+            "changed" => self.filter(Filter::Changed)?,
+            "unchanged" => self.filter(Filter::Unchanged)?,
+            "inc" if rest.is_empty() => self.filter(Filter::Increased)?,
+            "inc" => self.filter(Filter::ChangedBy(value(0)?))?,
+            "dec" if rest.is_empty() => self.filter(Filter::Decreased)?,
+            "dec" => self.filter(Filter::ChangedBy(-value(0)?))?,
+            "list" => {
+                self.search()?;
+                let count = rest.first().map_or(Ok(20), |text| text.parse::<usize>().map_err(|_| "bad count"))?;
+                self.list(count);
+            }
+            "watch" => {
+                let target = rest.first().ok_or("watch what?")?;
+                let (location, value_type) = match target.strip_prefix('#') {
+                    Some(number) => {
+                        let search = self.search()?;
+                        let index = number.parse::<usize>().ok().filter(|&number| number >= 1);
+                        let location = index.and_then(|number| search.candidates().get(number - 1));
+                        let location = *location.ok_or_else(|| format!("there is no candidate {}", target))?;
+                        let value_type = match rest.get(1) {
+                            Some(name) => parse_type(Some(name))?,
+                            None => search.value_type(),
+                        };
+                        (location, value_type)
+                    }
+                    None => (parse_address(&self.gameboy, target)?, parse_type(rest.get(1).copied())?),
+                };
+                let name = rest.get(2..).unwrap_or_default().join(" ");
+                self.gameboy.watches_mut().add(Watch { location, value_type, name, frozen: None });
+                println!("watch {}: {}", self.gameboy.watches().len(), location);
+            }
+            "watches" => {
+                let bus = &self.gameboy.cpu().bus;
+                for (number, watch) in self.gameboy.watches().list().iter().enumerate() {
+                    let frozen = if watch.frozen.is_some() { "frozen" } else { "" };
+                    let line = format!(
+                        "{:<3} {:>7} {:<3} {:>6} {:<6} {}",
+                        number + 1,
+                        watch.location.to_string(),
+                        watch.value_type.name(),
+                        watch.value(bus).unwrap_or(0),
+                        frozen,
+                        watch.name
+                    );
+                    println!("{}", line.trim_end());
+                }
+            }
+            "freeze" => {
+                let index = self.watch_index(rest.first().copied())?;
+                let value = match rest.get(1) {
+                    Some(_) => value(1)?,
+                    None => self.gameboy.watches().list()[index].value(&self.gameboy.cpu().bus).unwrap_or(0),
+                };
+                self.gameboy.watches_mut().freeze(index, Some(value));
+            }
+            "thaw" => {
+                let index = self.watch_index(rest.first().copied())?;
+                self.gameboy.watches_mut().freeze(index, None);
+            }
+            "unwatch" => {
+                let index = self.watch_index(rest.first().copied())?;
+                self.gameboy.watches_mut().remove(index);
+            }
+            "code" => {
+                let index = self.watch_index(rest.first().copied())?;
+                let watch = &self.gameboy.watches().list()[index];
+                let value = watch.frozen.or(watch.value(&self.gameboy.cpu().bus)).unwrap_or(0);
+                let bytes = watch.value_type.encode(value);
+                // One code per byte, for 16-bit values.
+                let codes: Vec<String> = (0..bytes.len())
+                    .map(|i| Location { offset: watch.location.offset + i, ..watch.location }.gameshark_code(bytes[i]))
+                    .collect();
+                println!("{}", codes.join(" "));
+            }
+            "poke" => {
+                let location = parse_address(&self.gameboy, rest.first().ok_or("poke where?")?)?;
+                let value_type = parse_type(rest.get(2).copied())?;
+                location.write(&mut self.gameboy.cpu_mut().bus, &value_type.encode(value(1)?));
+            }
+            "save" => {
+                let path = Path::new(rest.first().ok_or("save to which file?")?);
+                fs::write(path, self.gameboy.save_state())
+                    .map_err(|err| format!("cannot write {}: {}", path.display(), err))?;
+            }
+            "load" => {
+                let path = Path::new(rest.first().ok_or("load which file?")?);
+                let state = fs::read(path).map_err(|err| format!("cannot read {}: {}", path.display(), err))?;
+                self.gameboy.load_state(&state).map_err(|err| format!("cannot load {}: {}", path.display(), err))?;
+            }
+            "help" => println!("{}", HELP),
+            "quit" | "exit" => return Ok(false),
+            _ => return Err(format!("unknown command '{}'; try 'help'", command)),
+        }
+        Ok(true)
+    }
+}
@@ -0,0 +1,1237 @@
+use crate::utils::state::{StateError, StateReader, StateWriter};
+
+// Bits ORed into each register on read, from NR10 (0xFF10) to 0xFF2F. Unused and
+// write-only bits read back as 1.
+const READ_MASKS: [u8; 0x20] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR20-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR40-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 0],
+];
+
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// The frame sequencer steps on a falling edge of DIV bit 4 (bit 5 in double speed).
+const FRAME_SEQUENCER_BIT: u16 = 1 << 12;
+
+pub const NR10: u16 = 0xFF10;
+pub const NR52: u16 = 0xFF26;
+pub const WAVE_RAM: u16 = 0xFF30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Wave,
+    Noise,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 4] = [Channel::Pulse1, Channel::Pulse2, Channel::Wave, Channel::Noise];
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Length {
+    pub counter: u16,
+    pub enabled: bool,
+    max: u16,
+}
+
+impl Length {
+    fn new(max: u16) -> Length {
+        Length { counter: 0, enabled: false, max }
+    }
+
+    fn load(&mut self, value: u16) {
+        self.counter = self.max - value;
+    }
+
+    // Returns true when the counter runs out and the channel should turn off.
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+
+    fn save_state(&self, out: &mut StateWriter) {
+        out.put_u16(self.counter);
+        out.put_bool(self.enabled);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.counter = input.take_u16()?.min(self.max);
+        self.enabled = input.take_bool()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Envelope {
+    pub initial: u8,
+    pub increasing: bool,
+    pub period: u8,
+    pub volume: u8,
+    timer: u8,
+    running: bool,
+}
+
+impl Envelope {
+    fn dac_enabled(&self) -> bool {
+        self.initial != 0 || self.increasing
+    }
+
+    // "Zombie mode": rewriting NRx2 while the channel plays nudges the current volume
+    // instead of reloading it, the way the DMG's envelope circuit does.
+    fn write(&mut self, value: u8, channel_enabled: bool) {
+        if channel_enabled {
+            if self.period == 0 && self.running {
+                self.volume = self.volume.wrapping_add(1);
+            } else if !self.increasing {
+                self.volume = self.volume.wrapping_add(2);
+            }
+            if self.increasing != (value & 0x08 != 0) {
+                self.volume = 16u8.wrapping_sub(self.volume);
+            }
+            self.volume &= 0x0F;
+        }
+
+        self.initial = value >> 4;
+        self.increasing = value & 0x08 != 0;
+        self.period = value & 0x07;
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial;
+        self.timer = if self.period == 0 { 8 } else { self.period };
+        self.running = true;
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = self.period;
+        if !self.running {
+            return;
+        }
+        if self.increasing && self.volume < 15 {
+            self.volume += 1;
+        } else if !self.increasing && self.volume > 0 {
+            self.volume -= 1;
+        } else {
+            self.running = false;
+        }
+    }
+
+    fn save_state(&self, out: &mut StateWriter) {
+        out.put_u8(self.initial);
+        out.put_bool(self.increasing);
+        out.put_u8(self.period);
+        out.put_u8(self.volume);
+        out.put_u8(self.timer);
+        out.put_bool(self.running);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.initial = input.take_u8()? & 0x0F;
+        self.increasing = input.take_bool()?;
+        self.period = input.take_u8()? & 0x07;
+        self.volume = input.take_u8()? & 0x0F;
+        self.timer = input.take_u8()?;
+        self.running = input.take_bool()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Sweep {
+    pub period: u8,
+    pub negate: bool,
+    pub shift: u8,
+    timer: u8,
+    shadow: u16,
+    enabled: bool,
+    negate_used: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Square {
+    pub enabled: bool,
+    pub duty: u8,
+    pub duty_position: u8,
+    pub frequency: u16,
+    timer: u32,
+    pub length: Length,
+    pub envelope: Envelope,
+    pub sweep: Option<Sweep>,
+}
+
+impl Square {
+    fn new(with_sweep: bool) -> Square {
+        Square {
+            enabled: false,
+            duty: 0,
+            duty_position: 0,
+            frequency: 0,
+            timer: 0,
+            length: Length::new(64),
+            envelope: Envelope::default(),
+            sweep: if with_sweep { Some(Sweep::default()) } else { None },
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 4
+    }
+
+    fn step(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.duty_position = (self.duty_position + 1) & 0x07;
+        }
+        self.timer -= cycles;
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.enabled && DUTY_TABLE[self.duty as usize][self.duty_position as usize] != 0 {
+            self.envelope.volume
+        } else {
+            0
+        }
+    }
+
+    fn sweep_calculate(&mut self) -> u16 {
+        let sweep = self.sweep.as_mut().unwrap();
+        let delta = sweep.shadow >> sweep.shift;
+        let frequency = if sweep.negate {
+            sweep.negate_used = true;
+            sweep.shadow.wrapping_sub(delta)
+        } else {
+            sweep.shadow + delta
+        };
+        if frequency > 2047 {
+            self.enabled = false;
+        }
+        frequency
+    }
+
+    fn trigger_sweep(&mut self) {
+        let Some(sweep) = self.sweep.as_mut() else {
+            return;
+        };
+        sweep.shadow = self.frequency;
+        sweep.timer = if sweep.period == 0 { 8 } else { sweep.period };
+        sweep.enabled = sweep.period != 0 || sweep.shift != 0;
+        sweep.negate_used = false;
+        if sweep.shift != 0 {
+            self.sweep_calculate();
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        let Some(sweep) = self.sweep.as_mut() else {
+            return;
+        };
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer > 0 {
+            return;
+        }
+        sweep.timer = if sweep.period == 0 { 8 } else { sweep.period };
+        if !sweep.enabled || sweep.period == 0 {
+            return;
+        }
+
+        let frequency = self.sweep_calculate();
+        let sweep = self.sweep.as_mut().unwrap();
+        if frequency <= 2047 && sweep.shift != 0 {
+            sweep.shadow = frequency;
+            self.frequency = frequency;
+            self.sweep_calculate();
+        }
+    }
+
+    fn write_sweep(&mut self, value: u8) {
+        let Some(sweep) = self.sweep.as_mut() else {
+            return;
+        };
+        let negate = value & 0x08 != 0;
+        // Leaving negate mode after a negated calculation since the last trigger
+        // silences the channel.
+        if sweep.negate && !negate && sweep.negate_used {
+            self.enabled = false;
+        }
+        sweep.period = (value >> 4) & 0x07;
+        sweep.negate = negate;
+        sweep.shift = value & 0x07;
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        // The low two bits of the frequency timer survive a trigger.
+        self.timer = (self.period() & !0x03) | (self.timer & 0x03);
+        self.envelope.trigger();
+        self.trigger_sweep();
+    }
+
+    fn save_state(&self, out: &mut StateWriter) {
+        out.put_bool(self.enabled);
+        out.put_u8(self.duty);
+        out.put_u8(self.duty_position);
+        out.put_u16(self.frequency);
+        out.put_u32(self.timer);
+        self.length.save_state(out);
+        self.envelope.save_state(out);
+        if let Some(sweep) = &self.sweep {
+            out.put_u8(sweep.period);
+            out.put_bool(sweep.negate);
+            out.put_u8(sweep.shift);
+            out.put_u8(sweep.timer);
+            out.put_u16(sweep.shadow);
+            out.put_bool(sweep.enabled);
+            out.put_bool(sweep.negate_used);
+        }
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = input.take_bool()?;
+        self.duty = input.take_u8()? & 0x03;
+        self.duty_position = input.take_u8()? & 0x07;
+        self.frequency = input.take_u16()? & 0x7FF;
+        self.timer = input.take_u32()?;
+        self.length.load_state(input)?;
+        self.envelope.load_state(input)?;
+        if let Some(sweep) = &mut self.sweep {
+            sweep.period = input.take_u8()? & 0x07;
+            sweep.negate = input.take_bool()?;
+            sweep.shift = input.take_u8()? & 0x07;
+            sweep.timer = input.take_u8()?;
+            sweep.shadow = input.take_u16()?;
+            sweep.enabled = input.take_bool()?;
+            sweep.negate_used = input.take_bool()?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Wave {
+    pub enabled: bool,
+    pub dac_enabled: bool,
+    pub volume_code: u8,
+    pub frequency: u16,
+    timer: u32,
+    pub position: u8,
+    sample_buffer: u8,
+    pub length: Length,
+    pub ram: [u8; 16],
+    // The channel fetched from wave RAM during the current M-cycle.
+    just_read: bool,
+}
+
+impl Wave {
+    fn new() -> Wave {
+        Wave {
+            enabled: false,
+            dac_enabled: false,
+            volume_code: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            sample_buffer: 0,
+            length: Length::new(256),
+            // Power-on wave RAM contents vary by unit; this is a common DMG pattern.
+            ram: [
+                0x84, 0x40, 0x43, 0xAA, 0x2D, 0x78, 0x92, 0x3C, 0x60, 0x59, 0x59, 0xB0, 0x34,
+                0xB8, 0x2E, 0xDA,
+            ],
+            just_read: false,
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 2
+    }
+
+    fn step(&mut self, cycles: u32) {
+        if !self.enabled {
+            return;
+        }
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) & 0x1F;
+            self.sample_buffer = self.ram[self.position as usize / 2];
+            self.just_read = true;
+        }
+        self.timer -= cycles;
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        let sample = if self.position & 0x01 == 0 {
+            self.sample_buffer >> 4
+        } else {
+            self.sample_buffer & 0x0F
+        };
+        match self.volume_code {
+            0 => 0,
+            code => sample >> (code - 1),
+        }
+    }
+
+    fn trigger(&mut self, cgb: bool) {
+        // Retriggering on DMG just as the channel fetches a sample corrupts the first
+        // bytes of wave RAM with the block being read.
+        if !cgb && self.enabled && self.timer == 2 {
+            let index = ((self.position as usize + 1) & 0x1F) / 2;
+            if index < 4 {
+                self.ram[0] = self.ram[index];
+            } else {
+                let block = index & !0x03;
+                self.ram.copy_within(block..block + 4, 0);
+            }
+        }
+
+        self.enabled = self.dac_enabled;
+        self.position = 0;
+        // The first sample is fetched a few cycles late after a trigger.
+        self.timer = self.period() + 6;
+    }
+
+    // While the channel plays, the CPU only reaches the byte the channel is reading. DMG
+    // only allows even that in the cycle the channel fetched it.
+    fn ram_index(&self, address: u16, cgb: bool) -> Option<usize> {
+        if !self.enabled {
+            Some((address - WAVE_RAM) as usize)
+        } else if cgb || self.just_read {
+            Some(self.position as usize / 2)
+        } else {
+            None
+        }
+    }
+
+    fn save_state(&self, out: &mut StateWriter) {
+        out.put_bool(self.enabled);
+        out.put_bool(self.dac_enabled);
+        out.put_u8(self.volume_code);
+        out.put_u16(self.frequency);
+        out.put_u32(self.timer);
+        out.put_u8(self.position);
+        out.put_u8(self.sample_buffer);
+        self.length.save_state(out);
+        out.put_bytes(&self.ram);
+        out.put_bool(self.just_read);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = input.take_bool()?;
+        self.dac_enabled = input.take_bool()?;
+        self.volume_code = input.take_u8()? & 0x03;
+        self.frequency = input.take_u16()? & 0x7FF;
+        self.timer = input.take_u32()?;
+        self.position = input.take_u8()? & 0x1F;
+        self.sample_buffer = input.take_u8()?;
+        self.length.load_state(input)?;
+        input.take_bytes(&mut self.ram)?;
+        self.just_read = input.take_bool()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Noise {
+    pub enabled: bool,
+    pub shift: u8,
+    pub width_7bit: bool,
+    pub divisor_code: u8,
+    timer: u32,
+    pub lfsr: u16,
+    pub length: Length,
+    pub envelope: Envelope,
+}
+
+impl Noise {
+    fn new() -> Noise {
+        Noise {
+            enabled: false,
+            shift: 0,
+            width_7bit: false,
+            divisor_code: 0,
+            timer: 8,
+            lfsr: 0x7FFF,
+            length: Length::new(64),
+            envelope: Envelope::default(),
+        }
+    }
+
+    fn period(&self) -> u32 {
+        NOISE_DIVISORS[self.divisor_code as usize] << self.shift
+    }
+
+    fn step(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            // Shifts of 14 and 15 stop the LFSR.
+            if self.shift < 14 {
+                let bit = (self.lfsr ^ (self.lfsr >> 1)) & 0x01;
+                self.lfsr = (self.lfsr >> 1) | (bit << 14);
+                if self.width_7bit {
+                    self.lfsr = (self.lfsr & !0x40) | (bit << 6);
+                }
+            }
+        }
+        self.timer -= cycles;
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.enabled && self.lfsr & 0x01 == 0 {
+            self.envelope.volume
+        } else {
+            0
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.timer = self.period();
+        self.lfsr = 0x7FFF;
+        self.envelope.trigger();
+    }
+
+    fn save_state(&self, out: &mut StateWriter) {
+        out.put_bool(self.enabled);
+        out.put_u8(self.shift);
+        out.put_bool(self.width_7bit);
+        out.put_u8(self.divisor_code);
+        out.put_u32(self.timer);
+        out.put_u16(self.lfsr);
+        self.length.save_state(out);
+        self.envelope.save_state(out);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = input.take_bool()?;
+        self.shift = input.take_u8()? & 0x0F;
+        self.width_7bit = input.take_bool()?;
+        self.divisor_code = input.take_u8()? & 0x07;
+        self.timer = input.take_u32()?;
+        self.lfsr = input.take_u16()?;
+        self.length.load_state(input)?;
+        self.envelope.load_state(input)?;
+        Ok(())
+    }
+}
+
+const NOTE_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+/// Names the equal-tempered note nearest `frequency`, e.g. "A4" for 440 Hz.
+pub fn note_name(frequency: f32) -> Option<String> {
+    if !(16.0..=20_000.0).contains(&frequency) {
+        return None;
+    }
+    let midi = (69.0 + 12.0 * (frequency / 440.0).log2()).round() as i32;
+    Some(format!("{}{}", NOTE_NAMES[midi.rem_euclid(12) as usize], midi / 12 - 1))
+}
+
+/// A snapshot of one channel for sound driver debugging.
+#[derive(Debug, Clone)]
+pub struct ChannelState {
+    pub channel: Channel,
+    pub enabled: bool,
+    pub dac_enabled: bool,
+    pub muted: bool,
+    /// Tone frequency in Hz; for noise, the LFSR clock rate.
+    pub frequency: f32,
+    /// Nearest note to `frequency`. Noise has none.
+    pub note: Option<String>,
+    /// Current envelope volume, 0-15. For the wave channel, the output level as a fraction
+    /// of 15: 15, 7, 3 or 0.
+    pub volume: u8,
+    /// Envelope as (initial volume, increasing, period). The wave channel has none.
+    pub envelope: Option<(u8, bool, u8)>,
+    pub duty: Option<u8>,
+    /// Length counter steps left when the length counter is enabled.
+    pub length_remaining: Option<u16>,
+}
+
+/// Recent per-channel DAC output for oscilloscope views.
+pub struct Scope {
+    samples: Vec<[f32; 4]>,
+    head: usize,
+    /// T-cycles between recorded samples.
+    pub interval: u32,
+    elapsed: u32,
+}
+
+impl Scope {
+    pub fn new(capacity: usize, interval: u32) -> Scope {
+        Scope {
+            samples: vec![[0.0; 4]; capacity.max(1)],
+            head: 0,
+            interval: interval.max(1),
+            elapsed: 0,
+        }
+    }
+
+    fn advance(&mut self, cycles: u32, levels: [f32; 4]) {
+        self.elapsed += cycles;
+        while self.elapsed >= self.interval {
+            self.elapsed -= self.interval;
+            self.samples[self.head] = levels;
+            self.head = (self.head + 1) % self.samples.len();
+        }
+    }
+
+    /// The buffered samples for `channel`, oldest first, each in -1.0..=1.0.
+    pub fn channel(&self, channel: Channel) -> Vec<f32> {
+        let (newer, older) = self.samples.split_at(self.head);
+        older.iter().chain(newer).map(|levels| levels[channel as usize]).collect()
+    }
+}
+
+/// The sound hardware at 0xFF10-0xFF3F.
+pub struct Apu {
+    cgb: bool,
+    pub powered: bool,
+    registers: [u8; 0x20],
+
+    pub pulse1: Square,
+    pub pulse2: Square,
+    pub wave: Wave,
+    pub noise: Noise,
+
+    // The frame sequencer step that will run next, 0-7.
+    pub frame_step: u8,
+    div_bit_high: bool,
+
+    // Mixer controls for debugging; they only affect `output`.
+    muted: [bool; 4],
+    solo: Option<Channel>,
+    /// Records per-channel output while set.
+    pub scope: Option<Scope>,
+}
+
+impl Apu {
+    pub fn new(cgb: bool) -> Apu {
+        let mut apu = Apu {
+            cgb,
+            powered: true,
+            registers: [0; 0x20],
+            pulse1: Square::new(true),
+            pulse2: Square::new(false),
+            wave: Wave::new(),
+            noise: Noise::new(),
+            frame_step: 0,
+            div_bit_high: false,
+            muted: [false; 4],
+            solo: None,
+            scope: None,
+        };
+
+        // State left by the boot ROM's startup chime.
+        for (address, value) in [(0xFF10, 0x80), (0xFF11, 0xBF), (0xFF12, 0xF3), (0xFF14, 0xBF)] {
+            apu.write(address, value);
+        }
+        apu.pulse1.enabled = true;
+        apu.pulse1.envelope.volume = 0;
+        for (address, value) in [(0xFF24, 0x77), (0xFF25, 0xF3)] {
+            apu.write(address, value);
+        }
+        apu
+    }
+
+    /// Advances the APU by `cycles` T-cycles. `counter` is the timer's internal counter,
+    /// whose DIV bit drives the frame sequencer.
+    pub fn tick(&mut self, cycles: u32, counter: u16, double_speed: bool) {
+        self.wave.just_read = false;
+
+        let bit = if double_speed { FRAME_SEQUENCER_BIT << 1 } else { FRAME_SEQUENCER_BIT };
+        let high = counter & bit != 0;
+        let falling_edge = self.div_bit_high && !high;
+        self.div_bit_high = high;
+
+        if self.powered {
+            if falling_edge {
+                self.step_frame_sequencer();
+            }
+
+            self.pulse1.step(cycles);
+            self.pulse2.step(cycles);
+            self.wave.step(cycles);
+            self.noise.step(cycles);
+        }
+
+        if self.scope.is_some() {
+            let levels = Channel::ALL.map(|channel| self.dac_output(channel));
+            if let Some(scope) = &mut self.scope {
+                scope.advance(cycles, levels);
+            }
+        }
+    }
+
+    fn step_frame_sequencer(&mut self) {
+        let step = self.frame_step;
+        self.frame_step = (self.frame_step + 1) & 0x07;
+
+        if step.is_multiple_of(2) {
+            if self.pulse1.length.clock() {
+                self.pulse1.enabled = false;
+            }
+            if self.pulse2.length.clock() {
+                self.pulse2.enabled = false;
+            }
+            if self.wave.length.clock() {
+                self.wave.enabled = false;
+            }
+            if self.noise.length.clock() {
+                self.noise.enabled = false;
+            }
+        }
+        if step == 2 || step == 6 {
+            self.pulse1.clock_sweep();
+        }
+        if step == 7 {
+            self.pulse1.envelope.clock();
+            self.pulse2.envelope.clock();
+            self.noise.envelope.clock();
+        }
+    }
+
+    // Whether the frame sequencer's next step clocks the length counters.
+    fn length_clock_next(&self) -> bool {
+        self.frame_step.is_multiple_of(2)
+    }
+
+    /// The 4-bit DAC input of each channel, in `Channel` order.
+    pub fn channel_outputs(&self) -> [u8; 4] {
+        [
+            self.pulse1.output(),
+            self.pulse2.output(),
+            self.wave.output(),
+            self.noise.output(),
+        ]
+    }
+
+    pub fn dac_enabled(&self, channel: Channel) -> bool {
+        match channel {
+            Channel::Pulse1 => self.pulse1.envelope.dac_enabled(),
+            Channel::Pulse2 => self.pulse2.envelope.dac_enabled(),
+            Channel::Wave => self.wave.dac_enabled,
+            Channel::Noise => self.noise.envelope.dac_enabled(),
+        }
+    }
+
+    pub fn channel_enabled(&self, channel: Channel) -> bool {
+        match channel {
+            Channel::Pulse1 => self.pulse1.enabled,
+            Channel::Pulse2 => self.pulse2.enabled,
+            Channel::Wave => self.wave.enabled,
+            Channel::Noise => self.noise.enabled,
+        }
+    }
+
+    /// NR51 routing as (left, right) for `channel`.
+    pub fn panning(&self, channel: Channel) -> (bool, bool) {
+        let nr51 = self.registers[0x15];
+        let bit = channel as u8;
+        (nr51 & (0x10 << bit) != 0, nr51 & (0x01 << bit) != 0)
+    }
+
+    /// NR50 master volume as (left, right), 1-8.
+    pub fn master_volume(&self) -> (u8, u8) {
+        let nr50 = self.registers[0x14];
+        (((nr50 >> 4) & 0x07) + 1, (nr50 & 0x07) + 1)
+    }
+
+    /// Current analog output as (left, right), each in -1.0..=1.0.
+    pub fn output(&self) -> (f32, f32) {
+        Channel::ALL
+            .iter()
+            .filter(|&&channel| self.audible(channel))
+            .fold((0.0, 0.0), |(left, right), &channel| {
+                let (l, r) = self.channel_output(channel);
+                (left + l, right + r)
+            })
+    }
+
+    // A channel's DAC output, or 0.0 with the DAC off.
+    fn dac_output(&self, channel: Channel) -> f32 {
+        if !self.dac_enabled(channel) {
+            return 0.0;
+        }
+        self.channel_outputs()[channel as usize] as f32 / 7.5 - 1.0
+    }
+
+    /// One channel's share of `output`, after its DAC, NR51 panning and NR50 volume.
+    /// Mute and solo don't apply here.
+    pub fn channel_output(&self, channel: Channel) -> (f32, f32) {
+        let analog = self.dac_output(channel);
+        let (to_left, to_right) = self.panning(channel);
+        let (left_volume, right_volume) = self.master_volume();
+        let scale = |routed: bool, volume: u8| {
+            if routed {
+                analog / 4.0 * volume as f32 / 8.0
+            } else {
+                0.0
+            }
+        };
+        (scale(to_left, left_volume), scale(to_right, right_volume))
+    }
+
+    pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+        self.muted[channel as usize] = muted;
+    }
+
+    pub fn is_muted(&self, channel: Channel) -> bool {
+        self.muted[channel as usize]
+    }
+
+    /// Plays only `channel`, or every unmuted channel again with `None`.
+    pub fn set_solo(&mut self, channel: Option<Channel>) {
+        self.solo = channel;
+    }
+
+    pub fn solo(&self) -> Option<Channel> {
+        self.solo
+    }
+
+    /// Whether `channel` reaches the mix, given mute and solo.
+    pub fn audible(&self, channel: Channel) -> bool {
+        match self.solo {
+            Some(solo) => solo == channel,
+            None => !self.muted[channel as usize],
+        }
+    }
+
+    pub fn channel_state(&self, channel: Channel) -> ChannelState {
+        let length = |length: &Length| if length.enabled { Some(length.counter) } else { None };
+        let envelope = |envelope: &Envelope| Some((envelope.initial, envelope.increasing, envelope.period));
+        let tone = |frequency: u16, base: f32| base / (2048 - frequency) as f32;
+
+        let (frequency, volume, envelope, duty, length_remaining) = match channel {
+            Channel::Pulse1 | Channel::Pulse2 => {
+                let square = if channel == Channel::Pulse1 { &self.pulse1 } else { &self.pulse2 };
+                (
+                    tone(square.frequency, 131_072.0),
+                    square.envelope.volume,
+                    envelope(&square.envelope),
+                    Some(square.duty),
+                    length(&square.length),
+                )
+            }
+            Channel::Wave => {
+                let volume = match self.wave.volume_code {
+                    0 => 0,
+                    code => 15 >> (code - 1),
+                };
+                (tone(self.wave.frequency, 65_536.0), volume, None, None, length(&self.wave.length))
+            }
+            Channel::Noise => {
+                let divisor = match self.noise.divisor_code {
+                    0 => 0.5,
+                    code => code as f32,
+                };
+                let frequency = 262_144.0 / divisor / (1u32 << self.noise.shift) as f32;
+                (
+                    frequency,
+                    self.noise.envelope.volume,
+                    envelope(&self.noise.envelope),
+                    None,
+                    length(&self.noise.length),
+                )
+            }
+        };
+
+        ChannelState {
+            channel,
+            enabled: self.channel_enabled(channel),
+            dac_enabled: self.dac_enabled(channel),
+            muted: !self.audible(channel),
+            frequency,
+            note: if channel == Channel::Noise { None } else { note_name(frequency) },
+            volume,
+            envelope,
+            duty,
+            length_remaining,
+        }
+    }
+
+    /// Wave RAM as 32 4-bit samples in playback order.
+    pub fn wave_samples(&self) -> [u8; 32] {
+        let mut samples = [0; 32];
+        for (i, byte) in self.wave.ram.iter().enumerate() {
+            samples[i * 2] = byte >> 4;
+            samples[i * 2 + 1] = byte & 0x0F;
+        }
+        samples
+    }
+
+    /// The last value written to a register in 0xFF10-0xFF25, without read masks.
+    pub fn register(&self, address: u16) -> u8 {
+        self.registers[(address - NR10) as usize & 0x1F]
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            NR52 => {
+                let mut value = READ_MASKS[0x16];
+                if self.powered {
+                    value |= 0x80;
+                }
+                for channel in Channel::ALL {
+                    if self.channel_enabled(channel) {
+                        value |= 1 << channel as u8;
+                    }
+                }
+                value
+            }
+            0xFF10..=0xFF2F => {
+                let index = (address - NR10) as usize;
+                self.registers[index] | READ_MASKS[index]
+            }
+            0xFF30..=0xFF3F => match self.wave.ram_index(address, self.cgb) {
+                Some(index) => self.wave.ram[index],
+                None => 0xFF,
+            },
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        if let 0xFF30..=0xFF3F = address {
+            if let Some(index) = self.wave.ram_index(address, self.cgb) {
+                self.wave.ram[index] = value;
+            }
+            return;
+        }
+        if address == NR52 {
+            self.write_power(value);
+            return;
+        }
+        if !(0xFF10..=0xFF25).contains(&address) {
+            return;
+        }
+
+        if !self.powered {
+            // DMG keeps its length counters powered, so their loads still land.
+            if !self.cgb {
+                match address {
+                    0xFF11 => self.pulse1.length.load((value & 0x3F) as u16),
+                    0xFF16 => self.pulse2.length.load((value & 0x3F) as u16),
+                    0xFF1B => self.wave.length.load(value as u16),
+                    0xFF20 => self.noise.length.load((value & 0x3F) as u16),
+                    _ => {}
+                }
+            }
+            return;
+        }
+
+        self.registers[(address - NR10) as usize] = value;
+        match address {
+            0xFF10 => self.pulse1.write_sweep(value),
+            0xFF11 => {
+                self.pulse1.duty = value >> 6;
+                self.pulse1.length.load((value & 0x3F) as u16);
+            }
+            0xFF12 => {
+                self.pulse1.envelope.write(value, self.pulse1.enabled);
+                if !self.pulse1.envelope.dac_enabled() {
+                    self.pulse1.enabled = false;
+                }
+            }
+            0xFF13 => self.pulse1.frequency = (self.pulse1.frequency & 0x700) | value as u16,
+            0xFF14 => {
+                self.pulse1.frequency = (self.pulse1.frequency & 0xFF) | (((value & 0x07) as u16) << 8);
+                let length_clock_next = self.length_clock_next();
+                if write_length_control(&mut self.pulse1.length, value, length_clock_next) {
+                    self.pulse1.enabled = false;
+                }
+                if value & 0x80 != 0 {
+                    self.pulse1.trigger();
+                    trigger_length(&mut self.pulse1.length, length_clock_next);
+                }
+            }
+            0xFF16 => {
+                self.pulse2.duty = value >> 6;
+                self.pulse2.length.load((value & 0x3F) as u16);
+            }
+            0xFF17 => {
+                self.pulse2.envelope.write(value, self.pulse2.enabled);
+                if !self.pulse2.envelope.dac_enabled() {
+                    self.pulse2.enabled = false;
+                }
+            }
+            0xFF18 => self.pulse2.frequency = (self.pulse2.frequency & 0x700) | value as u16,
+            0xFF19 => {
+                self.pulse2.frequency = (self.pulse2.frequency & 0xFF) | (((value & 0x07) as u16) << 8);
+                let length_clock_next = self.length_clock_next();
+                if write_length_control(&mut self.pulse2.length, value, length_clock_next) {
+                    self.pulse2.enabled = false;
+                }
+                if value & 0x80 != 0 {
+                    self.pulse2.trigger();
+                    trigger_length(&mut self.pulse2.length, length_clock_next);
+                }
+            }
+            0xFF1A => {
+                self.wave.dac_enabled = value & 0x80 != 0;
+                if !self.wave.dac_enabled {
+                    self.wave.enabled = false;
+                }
+            }
+            0xFF1B => self.wave.length.load(value as u16),
+            0xFF1C => self.wave.volume_code = (value >> 5) & 0x03,
+            0xFF1D => self.wave.frequency = (self.wave.frequency & 0x700) | value as u16,
+            0xFF1E => {
+                self.wave.frequency = (self.wave.frequency & 0xFF) | (((value & 0x07) as u16) << 8);
+                let length_clock_next = self.length_clock_next();
+                if write_length_control(&mut self.wave.length, value, length_clock_next) {
+                    self.wave.enabled = false;
+                }
+                if value & 0x80 != 0 {
+                    self.wave.trigger(self.cgb);
+                    trigger_length(&mut self.wave.length, length_clock_next);
+                }
+            }
+            0xFF20 => self.noise.length.load((value & 0x3F) as u16),
+            0xFF21 => {
+                self.noise.envelope.write(value, self.noise.enabled);
+                if !self.noise.envelope.dac_enabled() {
+                    self.noise.enabled = false;
+                }
+            }
+            0xFF22 => {
+                self.noise.shift = value >> 4;
+                self.noise.width_7bit = value & 0x08 != 0;
+                self.noise.divisor_code = value & 0x07;
+            }
+            0xFF23 => {
+                let length_clock_next = self.length_clock_next();
+                if write_length_control(&mut self.noise.length, value, length_clock_next) {
+                    self.noise.enabled = false;
+                }
+                if value & 0x80 != 0 {
+                    self.noise.trigger();
+                    trigger_length(&mut self.noise.length, length_clock_next);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn write_power(&mut self, value: u8) {
+        let powered = value & 0x80 != 0;
+        if self.powered && !powered {
+            // Clearing NR11, NR21, NR31 and NR41 would reload the length counters, which
+            // a DMG keeps through power-off and a CGB zeroes.
+            let lengths = [
+                self.pulse1.length.counter,
+                self.pulse2.length.counter,
+                self.wave.length.counter,
+                self.noise.length.counter,
+            ];
+            for address in 0xFF10..=0xFF25 {
+                self.write(address, 0);
+            }
+            self.pulse1.enabled = false;
+            self.pulse2.enabled = false;
+            self.wave.enabled = false;
+            self.noise.enabled = false;
+            let [pulse1, pulse2, wave, noise] = if self.cgb { [0; 4] } else { lengths };
+            self.pulse1.length.counter = pulse1;
+            self.pulse2.length.counter = pulse2;
+            self.wave.length.counter = wave;
+            self.noise.length.counter = noise;
+        } else if !self.powered && powered {
+            self.frame_step = 0;
+            self.pulse1.duty_position = 0;
+            self.pulse2.duty_position = 0;
+            self.wave.sample_buffer = 0;
+        }
+        self.powered = powered;
+    }
+
+    /// Mute, solo and the scope are debugging aids and are not saved.
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.put_bool(self.powered);
+        out.put_bytes(&self.registers);
+        self.pulse1.save_state(out);
+        self.pulse2.save_state(out);
+        self.wave.save_state(out);
+        self.noise.save_state(out);
+        out.put_u8(self.frame_step);
+        out.put_bool(self.div_bit_high);
+    }
+
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.powered = input.take_bool()?;
+        input.take_bytes(&mut self.registers)?;
+        self.pulse1.load_state(input)?;
+        self.pulse2.load_state(input)?;
+        self.wave.load_state(input)?;
+        self.noise.load_state(input)?;
+        self.frame_step = input.take_u8()? & 0x07;
+        self.div_bit_high = input.take_bool()?;
+        Ok(())
+    }
+}
+
+// Enabling the length counter while the frame sequencer's next step won't clock it gives
+// it an extra clock. Returns true if that runs the counter out on a write that doesn't
+// also trigger the channel.
+fn write_length_control(length: &mut Length, value: u8, length_clock_next: bool) -> bool {
+    let was_enabled = length.enabled;
+    length.enabled = value & 0x40 != 0;
+
+    if !length_clock_next && !was_enabled && length.enabled && length.counter > 0 {
+        length.counter -= 1;
+        return length.counter == 0 && value & 0x80 == 0;
+    }
+    false
+}
+
+// A trigger reloads an expired length counter, minus the same extra clock as above.
+fn trigger_length(length: &mut Length, length_clock_next: bool) {
+    if length.counter == 0 {
+        length.counter = length.max;
+        if length.enabled && !length_clock_next {
+            length.counter -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Loads every length counter with a length of one, then turns the APU off and on.
+    fn power_cycle(cgb: bool) -> Apu {
+        let mut apu = Apu::new(cgb);
+        apu.write(NR52, 0x80);
+        apu.write(0xFF11, 0x3F);
+        apu.write(0xFF16, 0x3F);
+        apu.write(0xFF1B, 0xFF);
+        apu.write(0xFF20, 0x3F);
+        apu.write(NR52, 0x00);
+        apu.write(NR52, 0x80);
+        apu
+    }
+
+    #[test]
+    fn dmg_length_counters_survive_power_off() {
+        let apu = power_cycle(false);
+        assert_eq!(apu.pulse1.length.counter, 1);
+        assert_eq!(apu.pulse2.length.counter, 1);
+        assert_eq!(apu.wave.length.counter, 1);
+        assert_eq!(apu.noise.length.counter, 1);
+        assert!(!apu.pulse1.length.enabled);
+    }
+
+    #[test]
+    fn cgb_length_counters_are_cleared_by_power_off() {
+        let apu = power_cycle(true);
+        assert_eq!(apu.pulse1.length.counter, 0);
+        assert_eq!(apu.pulse2.length.counter, 0);
+        assert_eq!(apu.wave.length.counter, 0);
+        assert_eq!(apu.noise.length.counter, 0);
+    }
+
+    #[test]
+    fn dmg_length_loads_land_while_powered_off() {
+        let mut apu = Apu::new(false);
+        apu.write(NR52, 0x00);
+        apu.write(0xFF11, 0x3E);
+        apu.write(NR52, 0x80);
+        assert_eq!(apu.pulse1.length.counter, 2);
+        assert_eq!(apu.read(0xFF11) & 0x3F, 0x3F);
+    }
+
+    // Pulse 2 at full volume playing A4.
+    fn playing_a4() -> Apu {
+        let mut apu = Apu::new(false);
+        apu.write(0xFF16, 0x80);
+        apu.write(0xFF17, 0xF0);
+        apu.write(0xFF18, 0xD6);
+        apu.write(0xFF19, 0x86);
+        apu
+    }
+
+    #[test]
+    fn note_names() {
+        assert_eq!(note_name(440.0).as_deref(), Some("A4"));
+        assert_eq!(note_name(261.63).as_deref(), Some("C4"));
+        assert_eq!(note_name(27.5).as_deref(), Some("A0"));
+        assert_eq!(note_name(8.0), None);
+    }
+
+    #[test]
+    fn channel_state_reports_the_tone() {
+        let state = playing_a4().channel_state(Channel::Pulse2);
+        assert!(state.enabled && state.dac_enabled && !state.muted);
+        assert!((state.frequency - 439.8).abs() < 0.1, "{}", state.frequency);
+        assert_eq!(state.note.as_deref(), Some("A4"));
+        assert_eq!((state.volume, state.envelope, state.duty), (15, Some((15, false, 0)), Some(2)));
+        assert_eq!(state.length_remaining, None);
+    }
+
+    #[test]
+    fn mute_and_solo_only_change_the_mix() {
+        let mut apu = playing_a4();
+        let pulse2 = apu.channel_output(Channel::Pulse2);
+        let others = [Channel::Pulse1, Channel::Wave, Channel::Noise].iter().fold((0.0, 0.0), |(l, r), &channel| {
+            let (cl, cr) = apu.channel_output(channel);
+            (l + cl, r + cr)
+        });
+
+        apu.set_muted(Channel::Pulse2, true);
+        assert_eq!(apu.output(), others);
+        assert_eq!(apu.channel_output(Channel::Pulse2), pulse2);
+        assert!(apu.channel_state(Channel::Pulse2).muted);
+
+        // Solo wins over mute.
+        apu.set_solo(Some(Channel::Pulse2));
+        assert_eq!(apu.output(), pulse2);
+        assert!(!apu.audible(Channel::Pulse1));
+
+        apu.set_solo(None);
+        apu.set_muted(Channel::Pulse2, false);
+        assert!(Channel::ALL.iter().all(|&channel| apu.audible(channel)));
+    }
+
+    #[test]
+    fn scope_keeps_the_latest_samples_oldest_first() {
+        let mut scope = Scope::new(3, 4);
+        for level in 1..=4 {
+            scope.advance(4, [level as f32, 0.0, 0.0, 0.0]);
+        }
+        assert_eq!(scope.channel(Channel::Pulse1), [2.0, 3.0, 4.0]);
+
+        // Ten cycles record two samples and carry the rest over.
+        scope.advance(10, [5.0, 0.0, 0.0, 0.0]);
+        assert_eq!(scope.channel(Channel::Pulse1), [4.0, 5.0, 5.0]);
+        scope.advance(2, [6.0, 0.0, 0.0, 0.0]);
+        assert_eq!(scope.channel(Channel::Pulse1), [5.0, 5.0, 6.0]);
+    }
+}
@@ -0,0 +1,320 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+use crate::utils::bus::Model;
+
+/// The APU's clock: T-cycles per second, independent of CGB double speed.
+pub const CLOCK_RATE: f64 = 4_194_304.0;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 48_000;
+
+// Each amplitude change is drawn as a windowed-sinc step, precomputed at this many
+// sub-sample phases and spread over this many output samples.
+const PHASES: usize = 64;
+const KERNEL_WIDTH: usize = 16;
+// Pass band as a fraction of the output Nyquist frequency, leaving the kernel room to roll off.
+const CUTOFF: f64 = 0.9;
+
+// How far dynamic rate control may stretch the output, either way.
+const MAX_RATE_ADJUST: f64 = 0.005;
+
+// Convert deltas to samples once this many are final, so the buffers stay short even
+// when nobody is reading.
+const FLUSH_THRESHOLD: usize = 1024;
+
+/// One channel of band-limited step synthesis: amplitude changes go in at fractional
+/// sample times, smooth samples come out.
+struct StepSynth {
+    deltas: Vec<f32>,
+    integrator: f32,
+}
+
+impl StepSynth {
+    fn new() -> StepSynth {
+        StepSynth { deltas: Vec::new(), integrator: 0.0 }
+    }
+
+    fn add_step(&mut self, kernels: &[[f32; KERNEL_WIDTH]], time: f64, delta: f32) {
+        let position = time as usize;
+        let phase = ((time - position as f64) * PHASES as f64) as usize;
+        let end = position + KERNEL_WIDTH;
+        if self.deltas.len() < end {
+            self.deltas.resize(end, 0.0);
+        }
+        for (slot, weight) in self.deltas[position..end].iter_mut().zip(kernels[phase].iter()) {
+            *slot += delta * weight;
+        }
+    }
+
+    fn read(&mut self, count: usize, out: &mut Vec<f32>) {
+        if self.deltas.len() < count {
+            self.deltas.resize(count, 0.0);
+        }
+        for delta in self.deltas.drain(..count) {
+            self.integrator += delta;
+            out.push(self.integrator);
+        }
+    }
+}
+
+/// The high-pass formed by the capacitor on the console's audio output.
+struct DcBlocker {
+    capacitor: f32,
+    charge: f32,
+}
+
+impl DcBlocker {
+    fn new(model: Model, sample_rate: u32) -> DcBlocker {
+        // Per-T-cycle charge factors measured on hardware, scaled to one output sample.
+        let factor: f64 = match model {
+            Model::Dmg => 0.999958,
+            Model::Cgb => 0.998943,
+        };
+        DcBlocker {
+            capacitor: 0.0,
+            charge: factor.powf(CLOCK_RATE / sample_rate as f64) as f32,
+        }
+    }
+
+    fn filter(&mut self, input: f32) -> f32 {
+        let output = input - self.capacitor;
+        self.capacitor = input - output * self.charge;
+        output
+    }
+}
+
+fn build_kernels() -> Vec<[f32; KERNEL_WIDTH]> {
+    let mut kernels = vec![[0.0; KERNEL_WIDTH]; PHASES];
+    let centre = KERNEL_WIDTH as f64 / 2.0;
+
+    for (phase, kernel) in kernels.iter_mut().enumerate() {
+        let offset = phase as f64 / PHASES as f64;
+        let mut sum = 0.0;
+        for (i, weight) in kernel.iter_mut().enumerate() {
+            let x = i as f64 + 1.0 - offset - centre;
+            let sinc = if x == 0.0 { 1.0 } else { (PI * CUTOFF * x).sin() / (PI * CUTOFF * x) };
+            // Blackman window over the kernel's span.
+            let w = (x + centre) / KERNEL_WIDTH as f64;
+            let window = 0.42 - 0.5 * (2.0 * PI * w).cos() + 0.08 * (4.0 * PI * w).cos();
+            *weight = (sinc * window) as f32;
+            sum += *weight as f64;
+        }
+        // Every step must settle at exactly its height, whatever its phase.
+        for weight in kernel.iter_mut() {
+            *weight = (*weight as f64 / sum) as f32;
+        }
+    }
+    kernels
+}
+
+/// Turns the APU's output into interleaved stereo samples at a host rate such as
+/// 32000, 44100, 48000 or 96000 Hz.
+pub struct AudioOutput {
+    model: Model,
+    sample_rate: u32,
+    rate_adjust: f64,
+    samples_per_clock: f64,
+    kernels: Vec<[f32; KERNEL_WIDTH]>,
+
+    // Position of the next amplitude change, in output samples from the start of `left`/`right`.
+    time: f64,
+    last: (f32, f32),
+    left: StepSynth,
+    right: StepSynth,
+    dc_left: DcBlocker,
+    dc_right: DcBlocker,
+    filter_dc: bool,
+
+    // Finished samples, interleaved left/right.
+    ready: VecDeque<f32>,
+    /// Most stereo frames kept waiting for the host; older ones are dropped past this.
+    pub max_buffered: usize,
+}
+
+impl AudioOutput {
+    pub fn new(model: Model, sample_rate: u32) -> AudioOutput {
+        let mut output = AudioOutput {
+            model,
+            sample_rate,
+            rate_adjust: 1.0,
+            samples_per_clock: 0.0,
+            kernels: build_kernels(),
+            time: 0.0,
+            last: (0.0, 0.0),
+            left: StepSynth::new(),
+            right: StepSynth::new(),
+            dc_left: DcBlocker::new(model, sample_rate),
+            dc_right: DcBlocker::new(model, sample_rate),
+            filter_dc: true,
+            ready: VecDeque::new(),
+            max_buffered: sample_rate as usize,
+        };
+        output.update_ratio();
+        output
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.dc_left = DcBlocker::new(self.model, sample_rate);
+        self.dc_right = DcBlocker::new(self.model, sample_rate);
+        self.max_buffered = sample_rate as usize;
+        self.update_ratio();
+    }
+
+    /// Turns the output capacitor emulation on or off. Off keeps the DC offset, which
+    /// is what a raw channel dump wants.
+    pub fn set_dc_filter(&mut self, enabled: bool) {
+        self.filter_dc = enabled;
+    }
+
+    fn update_ratio(&mut self) {
+        self.samples_per_clock = self.sample_rate as f64 * self.rate_adjust / CLOCK_RATE;
+    }
+
+    /// Dynamic rate control. Given how full the host's queue is, nudges the output rate
+    /// by up to half a percent so the queue drifts back towards half full instead of
+    /// running dry or overflowing. Call it whenever the frontend queues audio.
+    pub fn update_buffer_level(&mut self, queued_frames: usize, capacity_frames: usize) {
+        if capacity_frames == 0 {
+            return;
+        }
+        let fill = (queued_frames as f64 / capacity_frames as f64).min(1.0);
+        self.rate_adjust = 1.0 + (1.0 - 2.0 * fill) * MAX_RATE_ADJUST;
+        self.update_ratio();
+    }
+
+    /// The current stretch applied by dynamic rate control, 1.0 when idle.
+    pub fn rate_adjust(&self) -> f64 {
+        self.rate_adjust
+    }
+
+    /// Advances `t_cycles` of APU time whose output was `level` as (left, right).
+    pub fn push(&mut self, t_cycles: u32, level: (f32, f32)) {
+        if level.0 != self.last.0 {
+            self.left.add_step(&self.kernels, self.time, level.0 - self.last.0);
+        }
+        if level.1 != self.last.1 {
+            self.right.add_step(&self.kernels, self.time, level.1 - self.last.1);
+        }
+        self.last = level;
+        self.time += t_cycles as f64 * self.samples_per_clock;
+
+        if self.time as usize >= FLUSH_THRESHOLD {
+            self.flush();
+        }
+    }
+
+    // Moves every sample no future step can touch into `ready`.
+    fn flush(&mut self) {
+        let count = self.time as usize;
+        if count == 0 {
+            return;
+        }
+        let mut left = Vec::with_capacity(count);
+        let mut right = Vec::with_capacity(count);
+        self.left.read(count, &mut left);
+        self.right.read(count, &mut right);
+        self.time -= count as f64;
+
+        for (l, r) in left.into_iter().zip(right) {
+            let (l, r) = if self.filter_dc {
+                (self.dc_left.filter(l), self.dc_right.filter(r))
+            } else {
+                (l, r)
+            };
+            self.ready.push_back(l);
+            self.ready.push_back(r);
+        }
+
+        let limit = self.max_buffered * 2;
+        if self.ready.len() > limit {
+            let excess = self.ready.len() - limit;
+            self.ready.drain(..excess);
+        }
+    }
+
+    /// Stereo frames ready to be read.
+    pub fn available(&mut self) -> usize {
+        self.flush();
+        self.ready.len() / 2
+    }
+
+    /// Takes every finished sample as interleaved f32 in -1.0..=1.0.
+    pub fn read_f32(&mut self) -> Vec<f32> {
+        self.flush();
+        self.ready.drain(..).collect()
+    }
+
+    /// Takes every finished sample as interleaved signed 16-bit PCM.
+    pub fn read_i16(&mut self) -> Vec<i16> {
+        self.flush();
+        self.ready
+            .drain(..)
+            .map(|sample| (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Pushes `seconds` of a constant level in 4-cycle steps.
+    fn hold(output: &mut AudioOutput, level: (f32, f32), seconds: f64) {
+        for _ in 0..(CLOCK_RATE * seconds / 4.0) as usize {
+            output.push(4, level);
+        }
+    }
+
+    #[test]
+    fn produces_the_host_rate() {
+        for rate in [32_000, 44_100, 48_000, 96_000] {
+            let mut output = AudioOutput::new(Model::Dmg, rate);
+            hold(&mut output, (0.0, 0.0), 1.0);
+            let frames = output.available() as i64;
+            assert!((frames - rate as i64).abs() <= 1, "{} Hz gave {} frames", rate, frames);
+        }
+    }
+
+    #[test]
+    fn steps_settle_at_their_height() {
+        let mut output = AudioOutput::new(Model::Dmg, 48_000);
+        output.set_dc_filter(false);
+        hold(&mut output, (0.5, -0.25), 0.01);
+        let samples = output.read_f32();
+        let last = &samples[samples.len() - 2..];
+        assert!((last[0] - 0.5).abs() < 1e-3 && (last[1] + 0.25).abs() < 1e-3, "{:?}", last);
+        assert!(samples.iter().all(|sample| sample.abs() <= 0.55));
+    }
+
+    #[test]
+    fn dc_filter_drains_a_constant_level() {
+        let mut output = AudioOutput::new(Model::Cgb, 48_000);
+        hold(&mut output, (0.5, 0.5), 0.5);
+        let samples = output.read_i16();
+        assert!(samples[samples.len() - 1].abs() < 100, "{}", samples[samples.len() - 1]);
+    }
+
+    #[test]
+    fn rate_control_steers_towards_half_full() {
+        let mut output = AudioOutput::new(Model::Dmg, 48_000);
+        output.update_buffer_level(0, 1000);
+        assert!((output.rate_adjust() - 1.005).abs() < 1e-9);
+        output.update_buffer_level(2000, 1000);
+        assert!((output.rate_adjust() - 0.995).abs() < 1e-9);
+        output.update_buffer_level(500, 1000);
+        assert_eq!(output.rate_adjust(), 1.0);
+    }
+
+    #[test]
+    fn keeps_at_most_max_buffered_frames() {
+        let mut output = AudioOutput::new(Model::Dmg, 48_000);
+        output.max_buffered = 1000;
+        hold(&mut output, (0.0, 0.0), 0.1);
+        assert_eq!(output.available(), 1000);
+    }
+}
@@ -0,0 +1,296 @@
+use std::fmt::Write as _;
+
+use crate::utils::bus::Model;
+use crate::utils::joypad::Button;
+use crate::utils::movie::{ForeignMovie, Movie, MovieError, MovieStart, RomId};
+use crate::utils::zip::{read_zip, write_zip, ZipEntry};
+
+const HEADER: &str = "Header.txt";
+const SYNC_SETTINGS: &str = "SyncSettings.json";
+const INPUT_LOG: &str = "Input Log.txt";
+const SAVE_RAM: &str = "SaveRam";
+
+// Gambatte's controls, in the order its input log lists them.
+const LOG_KEY: &str = "#Up|Down|Left|Right|Start|Select|B|A|Power|";
+const MNEMONICS: [(&str, char); 9] = [
+    ("Up", 'U'),
+    ("Down", 'D'),
+    ("Left", 'L'),
+    ("Right", 'R'),
+    ("Start", 'S'),
+    ("Select", 's'),
+    ("B", 'B'),
+    ("A", 'A'),
+    ("Power", 'P'),
+];
+
+/// SHA-1, which BizHawk names ROMs by.
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut words = [0u32; 80];
+        for (word, bytes) in words.iter_mut().zip(block.chunks(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..80 {
+            words[i] = (words[i - 3] ^ words[i - 8] ^ words[i - 14] ^ words[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (i, &word) in words.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let next = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(word);
+            (e, d, c, b, a) = (d, c, b.rotate_left(30), a, next);
+        }
+        for (value, add) in state.iter_mut().zip([a, b, c, d, e]) {
+            *value = value.wrapping_add(add);
+        }
+    }
+
+    let mut out = [0; 20];
+    for (bytes, value) in out.chunks_mut(4).zip(state) {
+        bytes.copy_from_slice(&value.to_be_bytes());
+    }
+    out
+}
+
+fn parse_hex(text: &str) -> Option<[u8; 20]> {
+    let mut out = [0; 20];
+    if text.len() != 40 {
+        return None;
+    }
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(text.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(out)
+}
+
+// Header lines are a key, a space and the value.
+fn header_value<'a>(header: &'a str, key: &str) -> Option<&'a str> {
+    header.lines().find_map(|line| {
+        let (name, value) = line.split_once(' ').unwrap_or((line, ""));
+        (name == key).then(|| value.trim())
+    })
+}
+
+// Just enough JSON to pick a scalar out of BizHawk's sync settings.
+fn json_value<'a>(json: &'a str, key: &str) -> Option<&'a str> {
+    let start = json.find(&format!("\"{}\"", key))? + key.len() + 2;
+    let rest = json[start..].trim_start().strip_prefix(':')?;
+    let end = rest.find([',', '}']).unwrap_or(rest.len());
+    Some(rest[..end].trim())
+}
+
+fn button(name: &str) -> Option<Button> {
+    match name {
+        "Up" => Some(Button::Up),
+        "Down" => Some(Button::Down),
+        "Left" => Some(Button::Left),
+        "Right" => Some(Button::Right),
+        "Start" => Some(Button::Start),
+        "Select" => Some(Button::Select),
+        "B" => Some(Button::B),
+        "A" => Some(Button::A),
+        _ => None,
+    }
+}
+
+fn parse_input_log(log: &str) -> Result<Vec<u8>, MovieError> {
+    let mut keys: Vec<String> = Vec::new();
+    let mut inputs = Vec::new();
+    for line in log.lines().map(str::trim_end) {
+        if let Some(key) = line.strip_prefix("LogKey:") {
+            // Groups start with '#', names are separated by '|'; some cores prefix them
+            // with the player.
+            keys = key
+                .split(['#', '|'])
+                .filter(|name| !name.is_empty())
+                .map(|name| name.strip_prefix("P1 ").unwrap_or(name).to_string())
+                .collect();
+            continue;
+        }
+        if !line.starts_with('|') {
+            continue;
+        }
+        if keys.is_empty() {
+            keys = LOG_KEY.split(['#', '|']).filter(|name| !name.is_empty()).map(str::to_string).collect();
+        }
+
+        let states: Vec<char> = line.chars().filter(|&c| c != '|').collect();
+        if states.len() != keys.len() {
+            return Err(MovieError::Invalid("input log line does not match its LogKey"));
+        }
+        let mut mask = 0;
+        for (name, state) in keys.iter().zip(states) {
+            if state == '.' || state == ' ' {
+                continue;
+            }
+            match button(name) {
+                Some(button) => mask |= button.mask(),
+                // Pressing power on the first frame is just the power-on itself.
+                None if (name == "Power" || name == "Reset") && !inputs.is_empty() => {
+                    return Err(MovieError::Unsupported("resets during a movie"))
+                }
+                None => {}
+            }
+        }
+        inputs.push(mask);
+    }
+    Ok(inputs)
+}
+
+/// Reads a BizHawk movie. Movies that start from a BizHawk save state cannot be
+/// reproduced, since those states are specific to BizHawk's cores; ones that start from
+/// save RAM can.
+pub fn import(data: &[u8]) -> Result<ForeignMovie, MovieError> {
+    let entries = read_zip(data)?;
+    let text = |name: &str| {
+        entries.iter().find(|entry| entry.name == name).map(|entry| String::from_utf8_lossy(&entry.data).into_owned())
+    };
+    let header = text(HEADER).ok_or(MovieError::Invalid("no Header.txt"))?;
+    let sync = text(SYNC_SETTINGS).unwrap_or_default();
+    let log = text(INPUT_LOG).ok_or(MovieError::Invalid("no Input Log.txt"))?;
+
+    let platform = header_value(&header, "Platform").unwrap_or("GB");
+    if !matches!(platform, "GB" | "GBC" | "SGB") {
+        return Err(MovieError::Unsupported("movies for systems other than the Game Boy"));
+    }
+    let is_true = |value: &str| value.eq_ignore_ascii_case("true") || value == "1";
+    if header_value(&header, "StartsFromSavestate").is_some_and(is_true) {
+        return Err(MovieError::Unsupported("BizHawk save state starts"));
+    }
+    let save_ram = match header_value(&header, "StartsFromSaveRam").is_some_and(is_true) {
+        true => {
+            let entry = entries.iter().find(|entry| entry.name.starts_with(SAVE_RAM));
+            Some(entry.ok_or(MovieError::Invalid("no SaveRam for a save RAM start"))?.data.clone())
+        }
+        false => None,
+    };
+
+    // The header records the mode the core actually ran in; the sync settings only what
+    // was asked for, where 0 is automatic, 1 the DMG and 2 or 3 the CGB.
+    let model = match (header_value(&header, "IsCGBMode"), json_value(&sync, "ConsoleMode")) {
+        (Some(value), _) => Some(if is_true(value) { Model::Cgb } else { Model::Dmg }),
+        (None, Some("1")) => Some(Model::Dmg),
+        (None, Some("2" | "3")) => Some(Model::Cgb),
+        _ if platform == "GBC" => Some(Model::Cgb),
+        _ => None,
+    };
+
+    // Gambatte can end frames elsewhere than at VBlank, and run its clock from the host's
+    // time or from a chosen start; this core does none of that.
+    let frame_length = json_value(&sync, "FrameLength");
+    if frame_length.is_some_and(|value| !matches!(value, "0" | "\"VBlankDrivenFrames\""))
+        || json_value(&sync, "EqualLengthFrames") == Some("true")
+    {
+        return Err(MovieError::Unsupported("Gambatte frame lengths other than VBlank-driven"));
+    }
+    if json_value(&sync, "RealTimeRTC") == Some("true") {
+        return Err(MovieError::Unsupported("real-time clocks that follow the host"));
+    }
+    if ["RTCInitialTime", "RTCDivisorOffset"].iter().any(|key| json_value(&sync, key).is_some_and(|value| value != "0")) {
+        return Err(MovieError::Unsupported("clock start times or divisor offsets other than 0"));
+    }
+
+    Ok(ForeignMovie {
+        emulator: format!(
+            "BizHawk {} ({})",
+            header_value(&header, "emuVersion").unwrap_or("?"),
+            header_value(&header, "Core").unwrap_or("?")
+        ),
+        rom: header_value(&header, "SHA1").and_then(parse_hex).map_or(RomId::Unknown, RomId::Sha1),
+        model,
+        boot_rom: json_value(&sync, "EnableBIOS") == Some("true"),
+        rerecords: header_value(&header, "rerecordCount").and_then(|count| count.parse().ok()),
+        save_ram,
+        inputs: parse_input_log(&log)?,
+    })
+}
+
+/// Writes `movie` as a BizHawk movie for the Gambatte core. `rom` is the ROM it was
+/// recorded on, which BizHawk checks by hash.
+pub fn export(movie: &Movie, rom: &[u8]) -> Result<Vec<u8>, MovieError> {
+    if movie.start != MovieStart::PowerOn {
+        return Err(MovieError::Unsupported("exports of movies that start from a save state"));
+    }
+    let cgb = movie.model == Model::Cgb;
+
+    let mut header = String::new();
+    let _ = writeln!(header, "MovieVersion BizHawk v2.0.0");
+    let _ = writeln!(header, "emuVersion Rusty-Boy {}", movie.emulator_version);
+    let _ = writeln!(header, "Platform {}", if cgb { "GBC" } else { "GB" });
+    let _ = writeln!(header, "GameName {}", movie.title);
+    let hash: String = sha1(rom).iter().map(|byte| format!("{:02X}", byte)).collect();
+    let _ = writeln!(header, "SHA1 {}", hash);
+    let _ = writeln!(header, "Core Gambatte");
+    let _ = writeln!(header, "rerecordCount {}", movie.rerecords.unwrap_or(0));
+    let _ = writeln!(header, "IsCGBMode {}", if cgb { "True" } else { "False" });
+
+    let sync = format!(
+        "{{\"o\":{{\"$type\":\"BizHawk.Emulation.Cores.Nintendo.Gameboy.Gameboy+GambatteSyncSettings, \
+         BizHawk.Emulation.Cores\",\"EnableBIOS\":{},\"ConsoleMode\":{},\"RealTimeRTC\":false,\"FrameLength\":0}}}}",
+        movie.boot_rom,
+        if cgb { 2 } else { 1 }
+    );
+
+    let mut log = format!("[Input]\nLogKey:{}\n", LOG_KEY);
+    for &mask in &movie.inputs {
+        log.push('|');
+        for (name, mnemonic) in MNEMONICS {
+            let held = button(name).is_some_and(|button| mask & button.mask() != 0);
+            log.push(if held { mnemonic } else { '.' });
+        }
+        log += "|\n";
+    }
+    log += "[/Input]\n";
+
+    let entry = |name: &str, data: String| ZipEntry { name: name.to_string(), data: data.into_bytes() };
+    Ok(write_zip(&[entry(HEADER, header), entry(SYNC_SETTINGS, sync), entry(INPUT_LOG, log)]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn movie_with_sync(sync: &str) -> Vec<u8> {
+        let entry = |name: &str, data: &str| ZipEntry { name: name.to_string(), data: data.as_bytes().to_vec() };
+        write_zip(&[
+            entry(HEADER, "Platform GB\nCore Gambatte\n"),
+            entry(SYNC_SETTINGS, sync),
+            entry(INPUT_LOG, &format!("[Input]\nLogKey:{}\n|.........|\n[/Input]\n", LOG_KEY)),
+        ])
+    }
+
+    #[test]
+    fn reads_the_boot_rom_and_console_settings() {
+        let movie = import(&movie_with_sync(r#"{"o":{"EnableBIOS":true,"ConsoleMode":2,"FrameLength":0}}"#)).unwrap();
+        assert!(movie.boot_rom);
+        assert_eq!(movie.model, Some(Model::Cgb));
+        assert_eq!(movie.inputs, [0]);
+    }
+
+    #[test]
+    fn rejects_sync_settings_this_core_cannot_follow() {
+        for sync in [
+            r#"{"o":{"FrameLength":1}}"#,
+            r#"{"o":{"EqualLengthFrames":true}}"#,
+            r#"{"o":{"RealTimeRTC":true}}"#,
+            r#"{"o":{"RTCInitialTime":159200}}"#,
+            r#"{"o":{"RTCDivisorOffset":3}}"#,
+        ] {
+            assert!(matches!(import(&movie_with_sync(sync)), Err(MovieError::Unsupported(_))), "{}", sync);
+        }
+    }
+}
@@ -0,0 +1,507 @@
+use crate::utils::apu::{Apu, Channel};
+use crate::utils::audio::{AudioOutput, DEFAULT_SAMPLE_RATE};
+use crate::utils::capture::Capture;
+use crate::utils::cheats::{Cheats, RamBank};
+use crate::utils::debugger::Watchpoints;
+use crate::utils::ramsearch::WatchList;
+use crate::utils::dma::{BusKind, Hdma, HdmaStart, OamDma};
+use crate::utils::joypad::Joypad;
+use crate::utils::ppu::Ppu;
+use crate::utils::rom::Cartridge;
+use crate::utils::serial::Serial;
+use crate::utils::state::{StateError, StateReader, StateWriter};
+use crate::utils::timer::Timer;
+use crate::utils::vgm::VgmLogger;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Model {
+    Dmg,
+    Cgb,
+}
+
+impl Model {
+    /// The inverse of `model as u8`, for reading saved files.
+    pub fn from_id(id: u8) -> Option<Model> {
+        match id {
+            0 => Some(Model::Dmg),
+            1 => Some(Model::Cgb),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    VBlank,
+    LcdStat,
+    Timer,
+    Serial,
+    Joypad,
+}
+
+impl Interrupt {
+    pub fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+// GDMA and HDMA move 16 bytes every 8 µs, which is twice as many M-cycles in double speed.
+const HDMA_BLOCK_CYCLES: u32 = 8;
+
+pub struct Bus {
+    pub model: Model,
+    pub cartridge: Cartridge,
+    pub ppu: Ppu,
+    pub oam_dma: OamDma,
+    pub hdma: Hdma,
+    pub timer: Timer,
+    pub joypad: Joypad,
+    pub serial: Serial,
+    pub apu: Apu,
+    pub audio: AudioOutput,
+    /// One output per APU channel, in `Channel` order, when rendering separate stems.
+    pub stems: Option<Box<[AudioOutput; 4]>>,
+    /// Records sound register writes while set.
+    pub vgm: Option<VgmLogger>,
+    /// Records video and audio while set.
+    pub capture: Option<Box<Capture>>,
+    pub cheats: Cheats,
+    /// Frozen watches are written back at every VBlank, after the cheats.
+    pub watches: WatchList,
+    pub watchpoints: Watchpoints,
+
+    wram: [u8; 0x8000],
+    wram_bank: u8,
+    hram: [u8; 0x7F],
+    // Backing store for I/O registers that are not modelled by a subsystem yet.
+    io: [u8; 0x80],
+
+    pub interrupt_flag: u8,
+    pub interrupt_enable: u8,
+
+    pub double_speed: bool,
+    pub speed_switch_armed: bool,
+
+    /// M-cycles elapsed since power-on.
+    pub cycles: u64,
+
+    /// Mapped over the cartridge until the boot ROM writes 0xFF50.
+    pub boot_rom: Option<Vec<u8>>,
+}
+
+impl Bus {
+    pub fn new(model: Model, cartridge: Cartridge) -> Bus {
+        Bus {
+            model,
+            cartridge,
+            ppu: Ppu::new(model == Model::Cgb),
+            oam_dma: OamDma::new(),
+            hdma: Hdma::new(),
+            timer: Timer::new(),
+            joypad: Joypad::new(),
+            serial: Serial::new(model == Model::Cgb),
+            apu: Apu::new(model == Model::Cgb),
+            audio: AudioOutput::new(model, DEFAULT_SAMPLE_RATE),
+            stems: None,
+            vgm: None,
+            capture: None,
+            cheats: Cheats::new(),
+            watches: WatchList::new(),
+            watchpoints: Watchpoints::default(),
+            wram: [0; 0x8000],
+            wram_bank: 1,
+            hram: [0; 0x7F],
+            io: [0xFF; 0x80],
+            interrupt_flag: 0xE1,
+            interrupt_enable: 0x00,
+            double_speed: false,
+            speed_switch_armed: false,
+            cycles: 0,
+            boot_rom: None,
+        }
+    }
+
+    pub fn cgb(&self) -> bool {
+        self.model == Model::Cgb
+    }
+
+    pub fn request_interrupt(&mut self, interrupt: Interrupt) {
+        self.interrupt_flag |= interrupt.bit();
+    }
+
+    /// Interrupts that are both requested and enabled.
+    pub fn pending_interrupts(&self) -> u8 {
+        self.interrupt_flag & self.interrupt_enable & 0x1F
+    }
+
+    /// Advances every component by one CPU M-cycle.
+    pub fn tick(&mut self) {
+        self.tick_components();
+
+        if self.ppu.entered_hblank && self.hdma.hblank_active {
+            self.run_hdma_block();
+        }
+    }
+
+    fn tick_components(&mut self) {
+        let dots = if self.double_speed { 2 } else { 4 };
+
+        self.cycles += 1;
+        self.interrupt_flag |= self.timer.tick();
+        self.interrupt_flag |= self.joypad.tick();
+        self.interrupt_flag |= self.serial.tick(self.timer.counter);
+        self.apu.tick(dots as u32, self.timer.counter, self.double_speed);
+        self.audio.push(dots as u32, self.apu.output());
+        if let Some(stems) = &mut self.stems {
+            for (stem, channel) in stems.iter_mut().zip(Channel::ALL) {
+                stem.push(dots as u32, self.apu.channel_output(channel));
+            }
+        }
+        if let Some(vgm) = &mut self.vgm {
+            vgm.tick(dots as u32);
+        }
+        self.cartridge.tick(dots as u32);
+        let requested = self.ppu.tick(dots);
+        self.interrupt_flag |= requested;
+        if requested & Interrupt::VBlank.bit() != 0 {
+            if !self.cheats.writes().is_empty() {
+                self.apply_cheat_writes();
+            }
+            if self.watches.any_frozen() {
+                let watches = std::mem::take(&mut self.watches);
+                watches.apply(self);
+                self.watches = watches;
+            }
+        }
+        if let Some(capture) = &mut self.capture {
+            capture.tick(dots as u32, self.apu.output(), &self.ppu);
+        }
+
+        if let Some((source, index)) = self.oam_dma.tick() {
+            let value = self.dma_source_read(source);
+            self.ppu.oam[index] = value;
+            self.oam_dma.last_byte = value;
+        }
+    }
+
+    // GameShark codes poke RAM once a frame, as the real device does from its VBlank hook.
+    fn apply_cheat_writes(&mut self) {
+        for index in 0..self.cheats.writes().len() {
+            let (bank, address, value) = self.cheats.writes()[index];
+            match (bank, address) {
+                (RamBank::Cartridge(bank), 0xA000..=0xBFFF) => {
+                    let offset = bank as usize * 0x2000 + (address - 0xA000) as usize;
+                    if let Some(byte) = self.cartridge.ram.get_mut(offset) {
+                        *byte = value;
+                    }
+                }
+                (RamBank::Work(bank), 0xD000..=0xDFFF) if self.cgb() => {
+                    self.wram[bank.max(1) as usize * 0x1000 + (address & 0x0FFF) as usize] = value;
+                }
+                _ => self.write_byte(address, value),
+            }
+        }
+    }
+
+    fn dma_source_read(&self, address: u16) -> u8 {
+        match address {
+            // Sources above 0xDFFF read through to work RAM rather than echo/OAM/IO.
+            0xE000..=0xFFFF => self.read_byte(address - 0x2000),
+            _ => self.read_byte(address),
+        }
+    }
+
+    /// Performs one M-cycle and then reads `address` the way the CPU sees it.
+    pub fn cpu_read(&mut self, address: u16) -> u8 {
+        self.tick();
+        let value = self.cpu_visible_read(address);
+        if self.watchpoints.armed() {
+            self.watchpoints.check(address, value, false);
+        }
+        value
+    }
+
+    fn cpu_visible_read(&self, address: u16) -> u8 {
+        if self.oam_dma.active {
+            if let Some(value) = self.dma_conflict_read(address) {
+                return value;
+            }
+        }
+        match address {
+            0x8000..=0x9FFF if !self.ppu.vram_accessible() => 0xFF,
+            0xFE00..=0xFE9F if !self.ppu.oam_accessible() => 0xFF,
+            _ => self.read_byte(address),
+        }
+    }
+
+    /// Performs one M-cycle and then writes `address` the way the CPU sees it.
+    pub fn cpu_write(&mut self, address: u16, value: u8) {
+        self.tick();
+        if self.watchpoints.armed() {
+            self.watchpoints.check(address, value, true);
+        }
+
+        if self.oam_dma.active && self.dma_blocks(address) {
+            return;
+        }
+        match address {
+            0x8000..=0x9FFF if !self.ppu.vram_accessible() => {}
+            0xFE00..=0xFE9F if !self.ppu.oam_accessible() => {}
+            _ => self.write_byte(address, value),
+        }
+    }
+
+    /// Whether a CPU access to `address` collides with the running OAM DMA.
+    fn dma_blocks(&self, address: u16) -> bool {
+        match address {
+            0xFE00..=0xFEFF => true,
+            _ => BusKind::of(address, self.cgb()) == self.oam_dma.source_bus(self.cgb()),
+        }
+    }
+
+    // While OAM DMA owns a bus the CPU sees whatever byte the DMA put on it; OAM itself
+    // reads back as 0xFF. HRAM and I/O stay reachable.
+    fn dma_conflict_read(&self, address: u16) -> Option<u8> {
+        match address {
+            0xFE00..=0xFEFF => Some(0xFF),
+            _ if self.dma_blocks(address) => Some(self.oam_dma.last_byte),
+            _ => None,
+        }
+    }
+
+    fn run_hdma_block(&mut self) {
+        for (source, destination) in self.hdma.next_block() {
+            let value = self.dma_source_read(source);
+            self.ppu.write_vram(destination, value);
+        }
+
+        let stall = if self.double_speed { HDMA_BLOCK_CYCLES * 2 } else { HDMA_BLOCK_CYCLES };
+        for _ in 0..stall {
+            self.tick_components();
+        }
+    }
+
+    fn write_hdma_control(&mut self, value: u8) {
+        match self.hdma.write_control(value) {
+            HdmaStart::General => {
+                for _ in 0..self.hdma.blocks_remaining() {
+                    self.run_hdma_block();
+                }
+            }
+            // With the LCD off there is no HBlank to wait for, so the first block goes now.
+            HdmaStart::HBlank if !self.ppu.lcd_enabled() => self.run_hdma_block(),
+            HdmaStart::HBlank | HdmaStart::Cancelled => {}
+        }
+    }
+
+    /// Arms by KEY1 and performed by STOP. Returns whether the speed actually changed.
+    pub fn try_speed_switch(&mut self) -> bool {
+        if !self.cgb() || !self.speed_switch_armed {
+            return false;
+        }
+        self.speed_switch_armed = false;
+        self.double_speed = !self.double_speed;
+        true
+    }
+
+    /// All of work RAM: two banks on the DMG, eight on the CGB.
+    pub fn wram(&self) -> &[u8] {
+        &self.wram[..if self.cgb() { 0x8000 } else { 0x2000 }]
+    }
+
+    pub fn wram_mut(&mut self) -> &mut [u8] {
+        let length = if self.cgb() { 0x8000 } else { 0x2000 };
+        &mut self.wram[..length]
+    }
+
+    pub fn hram(&self) -> &[u8] {
+        &self.hram
+    }
+
+    pub fn hram_mut(&mut self) -> &mut [u8] {
+        &mut self.hram
+    }
+
+    /// Where a 0xC000-0xFDFF address lands in `wram()` with the current bank.
+    pub fn wram_offset(&self, address: u16) -> usize {
+        let address = address as usize & 0x1FFF;
+        if address < 0x1000 {
+            address
+        } else {
+            self.wram_bank as usize * 0x1000 + (address & 0x0FFF)
+        }
+    }
+
+    /// Reads memory without advancing time or applying access restrictions.
+    pub fn read_byte(&self, address: u16) -> u8 {
+        if let Some(boot_rom) = &self.boot_rom {
+            // The CGB boot ROM leaves a gap for the cartridge header at 0x0100-0x01FF.
+            let mapped = address < 0x0100 || (0x0200..0x0900).contains(&address);
+            if mapped && (address as usize) < boot_rom.len() {
+                return boot_rom[address as usize];
+            }
+        }
+        match address {
+            0x0000..=0x7FFF => self.cheats.patch_rom(address, self.cartridge.read_rom(address)),
+            0x8000..=0x9FFF => self.ppu.read_vram(address),
+            0xA000..=0xBFFF => self.cartridge.read_ram(address),
+            0xC000..=0xFDFF => self.wram[self.wram_offset(address)],
+            0xFE00..=0xFE9F => self.ppu.oam[(address - 0xFE00) as usize],
+            0xFEA0..=0xFEFF => 0xFF,
+            0xFF00..=0xFF7F => self.read_io(address),
+            0xFF80..=0xFFFE => self.hram[(address - 0xFF80) as usize],
+            0xFFFF => self.interrupt_enable,
+        }
+    }
+
+    /// Writes memory without advancing time or applying access restrictions.
+    pub fn write_byte(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x7FFF => self.cartridge.write_rom(address, value),
+            0x8000..=0x9FFF => self.ppu.write_vram(address, value),
+            0xA000..=0xBFFF => self.cartridge.write_ram(address, value),
+            0xC000..=0xFDFF => {
+                let offset = self.wram_offset(address);
+                self.wram[offset] = value;
+            }
+            0xFE00..=0xFE9F => self.ppu.oam[(address - 0xFE00) as usize] = value,
+            0xFEA0..=0xFEFF => {}
+            0xFF00..=0xFF7F => self.write_io(address, value),
+            0xFF80..=0xFFFE => self.hram[(address - 0xFF80) as usize] = value,
+            0xFFFF => self.interrupt_enable = value,
+        }
+    }
+
+    fn read_io(&self, address: u16) -> u8 {
+        let cgb = self.cgb();
+        match address {
+            0xFF00 => self.joypad.read(),
+            0xFF01 => self.serial.sb,
+            0xFF02 => self.serial.read_control(),
+            0xFF04 => self.timer.div(),
+            0xFF05 => self.timer.tima,
+            0xFF06 => self.timer.tma,
+            0xFF07 => self.timer.read_tac(),
+            0xFF0F => self.interrupt_flag | 0xE0,
+            0xFF50 => 0xFF,
+            0xFF10..=0xFF3F => self.apu.read(address),
+            0xFF40 => self.ppu.lcdc,
+            0xFF41 => self.ppu.read_stat(),
+            0xFF42 => self.ppu.scy,
+            0xFF43 => self.ppu.scx,
+            0xFF44 => self.ppu.ly,
+            0xFF45 => self.ppu.lyc,
+            0xFF46 => self.oam_dma.register,
+            0xFF47 => self.ppu.bgp,
+            0xFF48 => self.ppu.obp0,
+            0xFF49 => self.ppu.obp1,
+            0xFF4A => self.ppu.wy,
+            0xFF4B => self.ppu.wx,
+            0xFF4D if cgb => {
+                0x7E | (if self.double_speed { 0x80 } else { 0 })
+                    | (if self.speed_switch_armed { 0x01 } else { 0 })
+            }
+            0xFF4F if cgb => 0xFE | self.ppu.vram_bank,
+            0xFF51..=0xFF54 => 0xFF,
+            0xFF55 if cgb => self.hdma.read_control(),
+            0xFF68 if cgb => 0x40 | self.ppu.bcps,
+            0xFF69 if cgb => self.ppu.read_bcpd(),
+            0xFF6A if cgb => 0x40 | self.ppu.ocps,
+            0xFF6B if cgb => self.ppu.read_ocpd(),
+            0xFF70 if cgb => 0xF8 | self.wram_bank,
+            0xFF4D | 0xFF4F | 0xFF55 | 0xFF68..=0xFF6B | 0xFF70 => 0xFF,
+            _ => self.io[(address - 0xFF00) as usize],
+        }
+    }
+
+    fn write_io(&mut self, address: u16, value: u8) {
+        let cgb = self.cgb();
+        match address {
+            0xFF00 => self.joypad.write(value),
+            0xFF01 => self.serial.sb = value,
+            0xFF02 => self.serial.write_control(value),
+            0xFF04 => self.timer.write_div(),
+            0xFF05 => self.timer.write_tima(value),
+            0xFF06 => self.timer.write_tma(value),
+            0xFF07 => self.timer.write_tac(value),
+            0xFF0F => self.interrupt_flag = value & 0x1F,
+            0xFF50 => {
+                if value & 0x01 != 0 {
+                    self.boot_rom = None;
+                }
+            }
+            0xFF10..=0xFF3F => {
+                if let Some(vgm) = &mut self.vgm {
+                    vgm.write(address, value);
+                }
+                self.apu.write(address, value);
+            }
+            0xFF40 => self.ppu.write_lcdc(value),
+            0xFF41 => self.ppu.write_stat(value),
+            0xFF42 => self.ppu.scy = value,
+            0xFF43 => self.ppu.scx = value,
+            0xFF44 => {}
+            0xFF45 => self.ppu.lyc = value,
+            0xFF46 => self.oam_dma.start(value),
+            0xFF47 => self.ppu.bgp = value,
+            0xFF48 => self.ppu.obp0 = value,
+            0xFF49 => self.ppu.obp1 = value,
+            0xFF4A => self.ppu.wy = value,
+            0xFF4B => self.ppu.wx = value,
+            0xFF4D if cgb => self.speed_switch_armed = value & 0x01 != 0,
+            0xFF4F if cgb => self.ppu.vram_bank = value & 0x01,
+            0xFF51 if cgb => self.hdma.write_source_high(value),
+            0xFF52 if cgb => self.hdma.write_source_low(value),
+            0xFF53 if cgb => self.hdma.write_destination_high(value),
+            0xFF54 if cgb => self.hdma.write_destination_low(value),
+            0xFF55 if cgb => self.write_hdma_control(value),
+            0xFF68 if cgb => self.ppu.bcps = value & 0xBF,
+            0xFF69 if cgb => self.ppu.write_bcpd(value),
+            0xFF6A if cgb => self.ppu.ocps = value & 0xBF,
+            0xFF6B if cgb => self.ppu.write_ocpd(value),
+            0xFF70 if cgb => self.wram_bank = if value & 0x07 == 0 { 1 } else { value & 0x07 },
+            0xFF4D | 0xFF4F | 0xFF51..=0xFF55 | 0xFF68..=0xFF6B | 0xFF70 => {}
+            _ => self.io[(address - 0xFF00) as usize] = value,
+        }
+    }
+
+    /// Saves memory and the bus's own registers; each component saves its own state.
+    /// Host-side outputs (audio resampling, stems, VGM logging, capture) and the serial
+    /// device are not part of it.
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.put_u8(self.model as u8);
+        out.put_bytes(&self.wram);
+        out.put_u8(self.wram_bank);
+        out.put_bytes(&self.hram);
+        out.put_bytes(&self.io);
+        out.put_u8(self.interrupt_flag);
+        out.put_u8(self.interrupt_enable);
+        out.put_bool(self.double_speed);
+        out.put_bool(self.speed_switch_armed);
+        out.put_u64(self.cycles);
+        out.put_bool(self.boot_rom.is_some());
+    }
+
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        if input.take_u8()? != self.model as u8 {
+            return Err(StateError::Mismatch("model"));
+        }
+        input.take_bytes(&mut self.wram)?;
+        self.wram_bank = input.take_u8()?.clamp(1, 7);
+        input.take_bytes(&mut self.hram)?;
+        input.take_bytes(&mut self.io)?;
+        self.interrupt_flag = input.take_u8()?;
+        self.interrupt_enable = input.take_u8()?;
+        self.double_speed = input.take_bool()?;
+        self.speed_switch_armed = input.take_bool()?;
+        self.cycles = input.take_u64()?;
+        if input.take_bool()? {
+            if self.boot_rom.is_none() {
+                return Err(StateError::Mismatch("boot ROM"));
+            }
+        } else {
+            self.boot_rom = None;
+        }
+        Ok(())
+    }
+}
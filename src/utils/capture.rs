@@ -0,0 +1,237 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::utils::audio::AudioOutput;
+use crate::utils::bus::Model;
+use crate::utils::ppu::{Ppu, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::utils::wav::WavWriter;
+
+// One frame is 70224 dots, so the exact frame rate is 4194304 / 70224 ≈ 59.7275 Hz.
+const DOTS_PER_FRAME: u32 = 70224;
+const Y4M_HEADER: &str = "YUV4MPEG2 W160 H144 F4194304:70224 Ip A1:1 C444\n";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoFormat {
+    /// YUV4MPEG2 with full-resolution (4:4:4) BT.601 chroma, which players and encoders
+    /// accept directly.
+    Y4m,
+    /// Bare 24-bit RGB frames, back to back: bit-exact, but the reader must know the size.
+    Rgb,
+}
+
+impl VideoFormat {
+    /// `.y4m` for Y4M; `.rgb` or `.raw` for bare frames.
+    pub fn from_path(path: &Path) -> Option<VideoFormat> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "y4m" => Some(VideoFormat::Y4m),
+            "rgb" | "raw" => Some(VideoFormat::Rgb),
+            _ => None,
+        }
+    }
+}
+
+/// Streams every emulated frame to a video file and the matching audio to a WAV beside it.
+/// It is driven by emulated time, so the output is the same however fast the host runs.
+pub struct Capture {
+    format: VideoFormat,
+    video: BufWriter<File>,
+    audio: AudioOutput,
+    wav: WavWriter<BufWriter<File>>,
+    last_frame: u64,
+    // Dots since the last frame was written, to fill in frames while the LCD is off.
+    dots: u32,
+    frames: u64,
+    // Writes happen inside the emulation loop, so the first failure is kept for `finish`.
+    error: Option<io::Error>,
+}
+
+impl Capture {
+    /// Starts recording at the next completed frame. The audio goes to `video` with a
+    /// .wav extension.
+    pub fn start(video: &Path, model: Model, sample_rate: u32, ppu: &Ppu) -> io::Result<Capture> {
+        let format = VideoFormat::from_path(video).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "video must be .y4m, .rgb or .raw")
+        })?;
+        let mut out = BufWriter::new(File::create(video)?);
+        if format == VideoFormat::Y4m {
+            out.write_all(Y4M_HEADER.as_bytes())?;
+        }
+        Ok(Capture {
+            format,
+            video: out,
+            audio: AudioOutput::new(model, sample_rate),
+            wav: WavWriter::create(Capture::audio_path(video), sample_rate, 2)?,
+            last_frame: ppu.frames,
+            dots: 0,
+            frames: 0,
+            error: None,
+        })
+    }
+
+    pub fn audio_path(video: &Path) -> PathBuf {
+        video.with_extension("wav")
+    }
+
+    /// Frames written so far.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Carries on from `ppu` after it jumped to another frame count, as loading a state
+    /// does, without taking the jump for a new frame.
+    pub fn resync(&mut self, ppu: &Ppu) {
+        self.last_frame = ppu.frames;
+    }
+
+    /// Feeds one step of `dots` PPU dots. Call it after the PPU has run.
+    pub fn tick(&mut self, dots: u32, level: (f32, f32), ppu: &Ppu) {
+        self.audio.push(dots, level);
+        self.dots += dots;
+
+        // A disabled LCD produces no frames, but time goes on; keep one frame per
+        // frame's worth of dots so the audio stays in step.
+        if ppu.frames != self.last_frame || self.dots >= DOTS_PER_FRAME {
+            self.last_frame = ppu.frames;
+            self.dots = 0;
+            self.write_frame(&ppu.framebuffer);
+        }
+    }
+
+    fn write_frame(&mut self, framebuffer: &[u32]) {
+        if self.error.is_some() {
+            return;
+        }
+        let result = self.encode_frame(framebuffer).and_then(|()| {
+            self.wav.write_samples(&self.audio.read_i16())
+        });
+        match result {
+            Ok(()) => self.frames += 1,
+            Err(err) => self.error = Some(err),
+        }
+    }
+
+    fn encode_frame(&mut self, framebuffer: &[u32]) -> io::Result<()> {
+        let pixels = &framebuffer[..SCREEN_WIDTH * SCREEN_HEIGHT];
+        let rgb = |pixel: u32| ((pixel >> 16) as i32 & 0xFF, (pixel >> 8) as i32 & 0xFF, pixel as i32 & 0xFF);
+        match self.format {
+            VideoFormat::Rgb => {
+                let bytes: Vec<u8> = pixels
+                    .iter()
+                    .flat_map(|&pixel| [(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8])
+                    .collect();
+                self.video.write_all(&bytes)
+            }
+            VideoFormat::Y4m => {
+                // Limited-range BT.601, the Y4M default.
+                let plane = |convert: &dyn Fn((i32, i32, i32)) -> i32| -> Vec<u8> {
+                    pixels.iter().map(|&pixel| convert(rgb(pixel)).clamp(0, 255) as u8).collect()
+                };
+                let y = plane(&|(r, g, b)| ((66 * r + 129 * g + 25 * b + 128) >> 8) + 16);
+                let u = plane(&|(r, g, b)| ((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128);
+                let v = plane(&|(r, g, b)| ((112 * r - 94 * g - 18 * b + 128) >> 8) + 128);
+                self.video.write_all(b"FRAME\n")?;
+                self.video.write_all(&y)?;
+                self.video.write_all(&u)?;
+                self.video.write_all(&v)
+            }
+        }
+    }
+
+    /// Flushes both files and returns the number of frames. Audio after the last frame is
+    /// dropped so both streams end together. Reports the first write error, if any
+    /// happened while recording.
+    pub fn finish(mut self) -> io::Result<u64> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        self.wav.finish()?;
+        self.video.flush()?;
+        Ok(self.frames)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const FRAME_PIXELS: usize = SCREEN_WIDTH * SCREEN_HEIGHT;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rusty_boy_capture_{}_{}", std::process::id(), name))
+    }
+
+    // Records `frames` frames of `colour`, one completed frame per 70224 dots, and returns
+    // the video and audio files.
+    fn record(name: &str, frames: u64, colour: u32) -> (u64, Vec<u8>, Vec<u8>) {
+        let path = temp_path(name);
+        let mut ppu = Ppu::new(false);
+        ppu.framebuffer.fill(colour);
+        let mut capture = Capture::start(&path, Model::Dmg, 48_000, &ppu).unwrap();
+        for _ in 0..frames {
+            ppu.frames += 1;
+            capture.tick(DOTS_PER_FRAME, (0.0, 0.0), &ppu);
+        }
+        let written = capture.finish().unwrap();
+        let video = fs::read(&path).unwrap();
+        let audio = fs::read(Capture::audio_path(&path)).unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(Capture::audio_path(&path)).unwrap();
+        (written, video, audio)
+    }
+
+    #[test]
+    fn y4m_frames_follow_the_header() {
+        let (frames, video, _) = record("white.y4m", 3, 0xFFFFFF);
+        assert_eq!(frames, 3);
+        let (header, body) = video.split_at(Y4M_HEADER.len());
+        assert_eq!(header, Y4M_HEADER.as_bytes());
+        let frame_size = b"FRAME\n".len() + FRAME_PIXELS * 3;
+        assert_eq!(body.len(), 3 * frame_size);
+
+        let frame = &body[2 * frame_size..];
+        assert_eq!(&frame[..6], b"FRAME\n");
+        let planes = &frame[6..];
+        assert!(planes[..FRAME_PIXELS].iter().all(|&y| y == 235));
+        assert!(planes[FRAME_PIXELS..].iter().all(|&chroma| chroma == 128));
+    }
+
+    #[test]
+    fn rgb_frames_are_bare_pixels() {
+        let (frames, video, _) = record("colour.rgb", 2, 0x123456);
+        assert_eq!(frames, 2);
+        assert_eq!(video.len(), 2 * FRAME_PIXELS * 3);
+        assert!(video.chunks(3).all(|pixel| pixel == [0x12, 0x34, 0x56]));
+    }
+
+    #[test]
+    fn audio_keeps_pace_with_the_frames() {
+        let (_, _, audio) = record("paced.rgb", 60, 0);
+        // 60 frames are 1.0046 s of 48 kHz stereo 16-bit sound, after a 44-byte header.
+        let samples = (audio.len() - 44) / 4;
+        assert!((48_200..=48_230).contains(&samples), "{} samples", samples);
+    }
+
+    #[test]
+    fn lcd_off_still_writes_a_frame_per_frame_time() {
+        let path = temp_path("off.rgb");
+        let ppu = Ppu::new(false);
+        let mut capture = Capture::start(&path, Model::Dmg, 48_000, &ppu).unwrap();
+        for _ in 0..DOTS_PER_FRAME * 5 / 4 {
+            capture.tick(4, (0.0, 0.0), &ppu);
+        }
+        assert_eq!(capture.frames(), 5);
+        assert_eq!(capture.finish().unwrap(), 5);
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(Capture::audio_path(&path)).unwrap();
+    }
+
+    #[test]
+    fn format_from_the_extension() {
+        assert_eq!(VideoFormat::from_path(Path::new("a.Y4M")), Some(VideoFormat::Y4m));
+        assert_eq!(VideoFormat::from_path(Path::new("a.raw")), Some(VideoFormat::Rgb));
+        assert_eq!(VideoFormat::from_path(Path::new("a.mp4")), None);
+        assert!(Capture::start(Path::new("a.mp4"), Model::Dmg, 48_000, &Ppu::new(false)).is_err());
+    }
+}
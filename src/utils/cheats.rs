@@ -0,0 +1,318 @@
+use std::fmt;
+use std::fmt::Write as _;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheatError {
+    /// Neither a GameShark nor a Game Genie code.
+    BadCode(String),
+    /// A line of a cheat file that cannot be read, counting from 1.
+    BadLine(usize),
+}
+
+impl fmt::Display for CheatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheatError::BadCode(code) => write!(
+                f,
+                "'{}' is not a GameShark (01VVAAAA) or Game Genie (ABC-DEF or ABC-DEF-GHI) code",
+                code
+            ),
+            CheatError::BadLine(line) => write!(f, "cheat file line {} is not 'on|off CODE [description]'", line),
+        }
+    }
+}
+
+impl std::error::Error for CheatError {}
+
+/// Which RAM bank a GameShark write goes to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RamBank {
+    /// Whatever is mapped at the address when the write happens.
+    Current,
+    /// A cartridge RAM bank, for 0xA000-0xBFFF.
+    Cartridge(u8),
+    /// A CGB work RAM bank, for 0xD000-0xDFFF.
+    Work(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Effect {
+    /// GameShark: writes `value` to `address` at every VBlank.
+    Write { bank: RamBank, address: u16, value: u8 },
+    /// Game Genie: ROM reads of `address` return `value` instead, if the ROM holds
+    /// `compare` there.
+    Patch { address: u16, value: u8, compare: Option<u8> },
+}
+
+impl Effect {
+    /// Parses a code, returning it in its usual spelling along with what it does.
+    ///
+    /// GameShark codes are `TTVVAAAA`: the address little-endian, and a type of 01 for
+    /// the current mapping, 8x for cartridge RAM bank x or 9x for work RAM bank x. Game
+    /// Genie codes are `ABC-DEF` or `ABC-DEF-GHI`, with the compare byte in G and I.
+    pub fn parse(code: &str) -> Result<(String, Effect), CheatError> {
+        let bad = || CheatError::BadCode(code.to_string());
+        let digits: String = code.chars().filter(|&c| c != '-').collect::<String>().to_ascii_uppercase();
+        if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(bad());
+        }
+        let nibble = |i: usize| u8::from_str_radix(&digits[i..i + 1], 16).unwrap();
+        let byte = |i: usize| nibble(i) << 4 | nibble(i + 1);
+
+        match digits.len() {
+            8 if !code.contains('-') => {
+                let kind = byte(0);
+                let bank = match kind {
+                    0x01 => RamBank::Current,
+                    0x80..=0x8F => RamBank::Cartridge(kind & 0x0F),
+                    0x90..=0x97 => RamBank::Work(kind & 0x07),
+                    _ => return Err(bad()),
+                };
+                let address = u16::from_le_bytes([byte(4), byte(6)]);
+                Ok((digits.clone(), Effect::Write { bank, address, value: byte(2) }))
+            }
+            6 | 9 => {
+                // The address is FCDE with F inverted, so codes can only point into ROM.
+                let address = ((nibble(5) ^ 0xF) as u16) << 12
+                    | (nibble(2) as u16) << 8
+                    | (nibble(3) as u16) << 4
+                    | nibble(4) as u16;
+                if address >= 0x8000 {
+                    return Err(bad());
+                }
+                let compare = (digits.len() == 9).then(|| (nibble(6) << 4 | nibble(8)).rotate_right(2) ^ 0xBA);
+                let spelled = match digits.len() {
+                    6 => format!("{}-{}", &digits[..3], &digits[3..]),
+                    _ => format!("{}-{}-{}", &digits[..3], &digits[3..6], &digits[6..]),
+                };
+                Ok((spelled, Effect::Patch { address, value: byte(0), compare }))
+            }
+            _ => Err(bad()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cheat {
+    pub code: String,
+    pub description: String,
+    pub enabled: bool,
+    pub effect: Effect,
+}
+
+/// A ROM's list of cheats. Each can be switched on and off, and all of them at once.
+#[derive(Debug, Clone)]
+pub struct Cheats {
+    cheats: Vec<Cheat>,
+    enabled: bool,
+    // The active Game Genie patches, kept apart since every ROM read looks through them.
+    patches: Vec<(u16, u8, Option<u8>)>,
+    writes: Vec<(RamBank, u16, u8)>,
+}
+
+impl Default for Cheats {
+    fn default() -> Cheats {
+        Cheats::new()
+    }
+}
+
+impl Cheats {
+    pub fn new() -> Cheats {
+        Cheats { cheats: Vec::new(), enabled: true, patches: Vec::new(), writes: Vec::new() }
+    }
+
+    /// Reads a cheat file: one `on` or `off`, code and optional description per line.
+    /// Blank lines and lines starting with '#' are skipped.
+    pub fn parse(text: &str) -> Result<Cheats, CheatError> {
+        let mut cheats = Cheats::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.splitn(3, char::is_whitespace);
+            let enabled = match words.next() {
+                Some("on") => true,
+                Some("off") => false,
+                _ => return Err(CheatError::BadLine(number + 1)),
+            };
+            let code = words.next().ok_or(CheatError::BadLine(number + 1))?;
+            cheats.add(code, words.next().unwrap_or("").trim())?;
+            cheats.set_enabled(cheats.len() - 1, enabled);
+        }
+        Ok(cheats)
+    }
+
+    /// The cheat file `parse` reads back.
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for cheat in &self.cheats {
+            let _ = write!(text, "{} {}", if cheat.enabled { "on" } else { "off" }, cheat.code);
+            if !cheat.description.is_empty() {
+                let _ = write!(text, " {}", cheat.description);
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    pub fn list(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    pub fn len(&self) -> usize {
+        self.cheats.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cheats.is_empty()
+    }
+
+    /// Adds an enabled cheat at the end.
+    pub fn add(&mut self, code: &str, description: &str) -> Result<(), CheatError> {
+        let (code, effect) = Effect::parse(code)?;
+        self.cheats.push(Cheat { code, description: description.to_string(), enabled: true, effect });
+        self.rebuild();
+        Ok(())
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Cheat> {
+        let cheat = (index < self.cheats.len()).then(|| self.cheats.remove(index));
+        self.rebuild();
+        cheat
+    }
+
+    /// Returns false if there is no cheat at `index`.
+    pub fn set_enabled(&mut self, index: usize, enabled: bool) -> bool {
+        let Some(cheat) = self.cheats.get_mut(index) else {
+            return false;
+        };
+        cheat.enabled = enabled;
+        self.rebuild();
+        true
+    }
+
+    /// The master switch, which leaves each cheat's own setting alone.
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_all_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.rebuild();
+    }
+
+    fn rebuild(&mut self) {
+        self.patches.clear();
+        self.writes.clear();
+        for cheat in self.cheats.iter().filter(|cheat| cheat.enabled && self.enabled) {
+            match cheat.effect {
+                Effect::Patch { address, value, compare } => self.patches.push((address, value, compare)),
+                Effect::Write { bank, address, value } => self.writes.push((bank, address, value)),
+            }
+        }
+    }
+
+    /// What a ROM read of `address` returns, given the byte the cartridge put out.
+    pub fn patch_rom(&self, address: u16, value: u8) -> u8 {
+        for &(patched, replacement, compare) in &self.patches {
+            if patched == address && compare.is_none_or(|compare| compare == value) {
+                return replacement;
+            }
+        }
+        value
+    }
+
+    /// The active GameShark writes, for the bus to make at VBlank.
+    pub fn writes(&self) -> &[(RamBank, u16, u8)] {
+        &self.writes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::rom::test_rom;
+    use crate::{GameBoy, Model};
+
+    #[test]
+    fn gameshark_codes() {
+        assert_eq!(
+            Effect::parse("01ff16d0"),
+            Ok(("01FF16D0".to_string(), Effect::Write { bank: RamBank::Current, address: 0xD016, value: 0xFF }))
+        );
+        assert_eq!(
+            Effect::parse("91051AD2").unwrap().1,
+            Effect::Write { bank: RamBank::Work(1), address: 0xD21A, value: 0x05 }
+        );
+        assert_eq!(
+            Effect::parse("83630BA0").unwrap().1,
+            Effect::Write { bank: RamBank::Cartridge(3), address: 0xA00B, value: 0x63 }
+        );
+        for code in ["02FF16D0", "01FF16D", "01FF16DG"] {
+            assert_eq!(Effect::parse(code), Err(CheatError::BadCode(code.to_string())));
+        }
+    }
+
+    #[test]
+    fn game_genie_codes() {
+        assert_eq!(
+            Effect::parse("3e14fee6a"),
+            Ok(("3E1-4FE-E6A".to_string(), Effect::Patch { address: 0x114F, value: 0x3E, compare: Some(0x00) }))
+        );
+        assert_eq!(
+            Effect::parse("00A-17B").unwrap(),
+            ("00A-17B".to_string(), Effect::Patch { address: 0x4A17, value: 0x00, compare: None })
+        );
+        // F is inverted, so F = 7 would point past ROM.
+        assert!(Effect::parse("00A-177").is_err());
+    }
+
+    #[test]
+    fn cheat_files_round_trip() {
+        let text = "# Tetris\non 01FF16D0 Max lines\n\noff 3E1-4FE-E6A\n";
+        let cheats = Cheats::parse(text).unwrap();
+        assert_eq!(cheats.len(), 2);
+        assert_eq!(cheats.list()[0].description, "Max lines");
+        assert!(!cheats.list()[1].enabled);
+        assert_eq!(cheats.to_text(), "on 01FF16D0 Max lines\noff 3E1-4FE-E6A\n");
+        assert_eq!(Cheats::parse(&cheats.to_text()).unwrap().list(), cheats.list());
+
+        assert_eq!(Cheats::parse("on 01FF16D0\nmaybe 01FF16D0").err(), Some(CheatError::BadLine(2)));
+        assert_eq!(Cheats::parse("on").err(), Some(CheatError::BadLine(1)));
+    }
+
+    #[test]
+    fn switches_decide_what_applies() {
+        let mut cheats = Cheats::new();
+        cheats.add("01FF16D0", "").unwrap();
+        cheats.add("3E1-4FE-E6A", "").unwrap();
+        assert_eq!(cheats.writes().len(), 1);
+        assert_eq!(cheats.patch_rom(0x114F, 0x00), 0x3E);
+        assert_eq!(cheats.patch_rom(0x114F, 0x01), 0x01);
+        assert_eq!(cheats.patch_rom(0x1150, 0x00), 0x00);
+
+        cheats.set_enabled(1, false);
+        assert_eq!(cheats.patch_rom(0x114F, 0x00), 0x00);
+        cheats.set_all_enabled(false);
+        assert!(cheats.writes().is_empty());
+        cheats.set_all_enabled(true);
+        assert_eq!(cheats.writes().len(), 1);
+        assert!(!cheats.set_enabled(5, true));
+        assert!(cheats.remove(0).is_some());
+        assert!(cheats.writes().is_empty());
+    }
+
+    #[test]
+    fn the_bus_applies_cheats() {
+        let mut gameboy = GameBoy::new(Model::Dmg, test_rom(&[0x18, 0xFE])).unwrap();
+        gameboy.cpu_mut().bus.cheats.add("014200C0", "").unwrap();
+        assert_eq!(gameboy.cpu().bus.read_byte(0xC000), 0x00);
+        gameboy.run_frame();
+        assert_eq!(gameboy.cpu().bus.read_byte(0xC000), 0x42);
+
+        // 0x77 at 0x0100 if the ROM holds 0x18 there, which it does.
+        gameboy.cpu_mut().bus.cheats.add("771-00F-8EA", "").unwrap();
+        assert_eq!(gameboy.cpu().bus.read_byte(0x0100), 0x77);
+    }
+}
@@ -1,7 +1,12 @@
+use crate::utils::bus::{Bus, Model};
+use crate::utils::state::{StateError, StateReader, StateWriter};
 
-enum Instruction {
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
     ADD(AritmaticTarget),
-    ADDHL(AritmaticTarget),
+    ADDHL(WideTarget),
+    ADDSP,
     ADC(AritmaticTarget),
     SUB(AritmaticTarget),
     SBC(AritmaticTarget),
@@ -11,18 +16,22 @@ enum Instruction {
     CP(AritmaticTarget),
     INC(AritmaticTarget),
     DEC(AritmaticTarget),
-    CCF(AritmaticTarget),
-    SCF(AritmaticTarget),
-
-    // unimplemented instructions for future use
-    RRA(AritmaticTarget),
-    RLA(AritmaticTarget),
-    RRCA(AritmaticTarget),
-    RRLA(AritmaticTarget),
-    CPL(AritmaticTarget),
-    BIT(AritmaticTarget),
-    RESET(AritmaticTarget),
-    SET(AritmaticTarget),
+    INC16(WideTarget),
+    DEC16(WideTarget),
+    CCF,
+    SCF,
+    DAA,
+    CPL,
+
+    RRA,
+    RLA,
+    RRCA,
+    RLCA,
+
+    // 0xCB-prefixed instructions
+    BIT(u8, AritmaticTarget),
+    RESET(u8, AritmaticTarget),
+    SET(u8, AritmaticTarget),
     SRL(AritmaticTarget),
     RR(AritmaticTarget),
     RL(AritmaticTarget),
@@ -31,9 +40,28 @@ enum Instruction {
     SRA(AritmaticTarget),
     SLA(AritmaticTarget),
     SWAP(AritmaticTarget),
+
+    JP(JumpTest),
+    JPHL,
+    JR(JumpTest),
+    CALL(JumpTest),
+    RET(JumpTest),
+    RETI,
+    RST(u8),
+
+    LD(LoadType),
+    PUSH(StackTarget),
+    POP(StackTarget),
+
+    NOP,
+    HALT,
+    STOP,
+    DI,
+    EI,
 }
 
-enum AritmaticTarget {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AritmaticTarget {
     A,
     B,
     C,
@@ -41,24 +69,251 @@ enum AritmaticTarget {
     E,
     H,
     L,
+    // The byte at (HL)
+    HLI,
+    // The byte following the opcode
+    D8,
 }
 
-struct Registers {
-    a: u8,
-    b: u8,
-    c: u8,
-    d: u8,
-    e: u8,
-    f: FlagsRegister,
-    h: u8,
-    l: u8,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WideTarget {
+    BC,
+    DE,
+    HL,
+    SP,
 }
 
-struct FlagsRegister {
-    zero: bool,
-    subtract: bool,
-    half_carry: bool,
-    carry: bool,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackTarget {
+    BC,
+    DE,
+    HL,
+    AF,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JumpTest {
+    NotZero,
+    Zero,
+    NotCarry,
+    Carry,
+    Always,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Indirect {
+    BCIndirect,
+    DEIndirect,
+    HLIndirectPlus,
+    HLIndirectMinus,
+    // (a16)
+    WordIndirect,
+    // (0xFF00 + a8)
+    ByteIndirect,
+    // (0xFF00 + C)
+    LastByteIndirect,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadType {
+    Byte(AritmaticTarget, AritmaticTarget),
+    Word(WideTarget),
+    AFromIndirect(Indirect),
+    IndirectFromA(Indirect),
+    IndirectFromSP,
+    SPFromHL,
+    HLFromSPOffset,
+}
+
+impl AritmaticTarget {
+    // Register order used by the operand bits of most opcodes
+    fn from_index(index: u8) -> AritmaticTarget {
+        match index & 0x07 {
+            0 => AritmaticTarget::B,
+            1 => AritmaticTarget::C,
+            2 => AritmaticTarget::D,
+            3 => AritmaticTarget::E,
+            4 => AritmaticTarget::H,
+            5 => AritmaticTarget::L,
+            6 => AritmaticTarget::HLI,
+            _ => AritmaticTarget::A,
+        }
+    }
+}
+
+impl WideTarget {
+    fn from_index(index: u8) -> WideTarget {
+        match index & 0x03 {
+            0 => WideTarget::BC,
+            1 => WideTarget::DE,
+            2 => WideTarget::HL,
+            _ => WideTarget::SP,
+        }
+    }
+}
+
+impl StackTarget {
+    fn from_index(index: u8) -> StackTarget {
+        match index & 0x03 {
+            0 => StackTarget::BC,
+            1 => StackTarget::DE,
+            2 => StackTarget::HL,
+            _ => StackTarget::AF,
+        }
+    }
+}
+
+impl JumpTest {
+    fn from_index(index: u8) -> JumpTest {
+        match index & 0x03 {
+            0 => JumpTest::NotZero,
+            1 => JumpTest::Zero,
+            2 => JumpTest::NotCarry,
+            _ => JumpTest::Carry,
+        }
+    }
+}
+
+impl Instruction {
+    pub fn from_byte(byte: u8, prefixed: bool) -> Option<Instruction> {
+        if prefixed {
+            Some(Instruction::from_byte_prefixed(byte))
+        } else {
+            Instruction::from_byte_not_prefixed(byte)
+        }
+    }
+
+    fn from_byte_prefixed(byte: u8) -> Instruction {
+        let target = AritmaticTarget::from_index(byte);
+        let bit = (byte >> 3) & 0x07;
+
+        match byte >> 6 {
+            0 => match bit {
+                0 => Instruction::RLC(target),
+                1 => Instruction::RRC(target),
+                2 => Instruction::RL(target),
+                3 => Instruction::RR(target),
+                4 => Instruction::SLA(target),
+                5 => Instruction::SRA(target),
+                6 => Instruction::SWAP(target),
+                _ => Instruction::SRL(target),
+            },
+            1 => Instruction::BIT(bit, target),
+            2 => Instruction::RESET(bit, target),
+            _ => Instruction::SET(bit, target),
+        }
+    }
+
+    fn from_byte_not_prefixed(byte: u8) -> Option<Instruction> {
+        let source = AritmaticTarget::from_index(byte);
+        let destination = AritmaticTarget::from_index(byte >> 3);
+        let wide = WideTarget::from_index(byte >> 4);
+        let condition = JumpTest::from_index(byte >> 3);
+
+        let instruction = match byte {
+            0x00 => Instruction::NOP,
+            0x10 => Instruction::STOP,
+            0x76 => Instruction::HALT,
+            0xF3 => Instruction::DI,
+            0xFB => Instruction::EI,
+
+            0x01 | 0x11 | 0x21 | 0x31 => Instruction::LD(LoadType::Word(wide)),
+            0x02 => Instruction::LD(LoadType::IndirectFromA(Indirect::BCIndirect)),
+            0x12 => Instruction::LD(LoadType::IndirectFromA(Indirect::DEIndirect)),
+            0x22 => Instruction::LD(LoadType::IndirectFromA(Indirect::HLIndirectPlus)),
+            0x32 => Instruction::LD(LoadType::IndirectFromA(Indirect::HLIndirectMinus)),
+            0x0A => Instruction::LD(LoadType::AFromIndirect(Indirect::BCIndirect)),
+            0x1A => Instruction::LD(LoadType::AFromIndirect(Indirect::DEIndirect)),
+            0x2A => Instruction::LD(LoadType::AFromIndirect(Indirect::HLIndirectPlus)),
+            0x3A => Instruction::LD(LoadType::AFromIndirect(Indirect::HLIndirectMinus)),
+            0xE0 => Instruction::LD(LoadType::IndirectFromA(Indirect::ByteIndirect)),
+            0xF0 => Instruction::LD(LoadType::AFromIndirect(Indirect::ByteIndirect)),
+            0xE2 => Instruction::LD(LoadType::IndirectFromA(Indirect::LastByteIndirect)),
+            0xF2 => Instruction::LD(LoadType::AFromIndirect(Indirect::LastByteIndirect)),
+            0xEA => Instruction::LD(LoadType::IndirectFromA(Indirect::WordIndirect)),
+            0xFA => Instruction::LD(LoadType::AFromIndirect(Indirect::WordIndirect)),
+            0x08 => Instruction::LD(LoadType::IndirectFromSP),
+            0xF8 => Instruction::LD(LoadType::HLFromSPOffset),
+            0xF9 => Instruction::LD(LoadType::SPFromHL),
+            0x06 | 0x0E | 0x16 | 0x1E | 0x26 | 0x2E | 0x36 | 0x3E => {
+                Instruction::LD(LoadType::Byte(destination, AritmaticTarget::D8))
+            }
+            0x40..=0x7F => Instruction::LD(LoadType::Byte(destination, source)),
+
+            0x03 | 0x13 | 0x23 | 0x33 => Instruction::INC16(wide),
+            0x0B | 0x1B | 0x2B | 0x3B => Instruction::DEC16(wide),
+            0x09 | 0x19 | 0x29 | 0x39 => Instruction::ADDHL(wide),
+            0xE8 => Instruction::ADDSP,
+            0x04 | 0x0C | 0x14 | 0x1C | 0x24 | 0x2C | 0x34 | 0x3C => Instruction::INC(destination),
+            0x05 | 0x0D | 0x15 | 0x1D | 0x25 | 0x2D | 0x35 | 0x3D => Instruction::DEC(destination),
+
+            0x07 => Instruction::RLCA,
+            0x0F => Instruction::RRCA,
+            0x17 => Instruction::RLA,
+            0x1F => Instruction::RRA,
+            0x27 => Instruction::DAA,
+            0x2F => Instruction::CPL,
+            0x37 => Instruction::SCF,
+            0x3F => Instruction::CCF,
+
+            0x80..=0x87 => Instruction::ADD(source),
+            0x88..=0x8F => Instruction::ADC(source),
+            0x90..=0x97 => Instruction::SUB(source),
+            0x98..=0x9F => Instruction::SBC(source),
+            0xA0..=0xA7 => Instruction::AND(source),
+            0xA8..=0xAF => Instruction::XOR(source),
+            0xB0..=0xB7 => Instruction::OR(source),
+            0xB8..=0xBF => Instruction::CP(source),
+            0xC6 => Instruction::ADD(AritmaticTarget::D8),
+            0xCE => Instruction::ADC(AritmaticTarget::D8),
+            0xD6 => Instruction::SUB(AritmaticTarget::D8),
+            0xDE => Instruction::SBC(AritmaticTarget::D8),
+            0xE6 => Instruction::AND(AritmaticTarget::D8),
+            0xEE => Instruction::XOR(AritmaticTarget::D8),
+            0xF6 => Instruction::OR(AritmaticTarget::D8),
+            0xFE => Instruction::CP(AritmaticTarget::D8),
+
+            0x18 => Instruction::JR(JumpTest::Always),
+            0x20 | 0x28 | 0x30 | 0x38 => Instruction::JR(condition),
+            0xC3 => Instruction::JP(JumpTest::Always),
+            0xC2 | 0xCA | 0xD2 | 0xDA => Instruction::JP(condition),
+            0xE9 => Instruction::JPHL,
+            0xCD => Instruction::CALL(JumpTest::Always),
+            0xC4 | 0xCC | 0xD4 | 0xDC => Instruction::CALL(condition),
+            0xC9 => Instruction::RET(JumpTest::Always),
+            0xC0 | 0xC8 | 0xD0 | 0xD8 => Instruction::RET(condition),
+            0xD9 => Instruction::RETI,
+            0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => Instruction::RST(byte & 0x38),
+
+            0xC1 | 0xD1 | 0xE1 | 0xF1 => Instruction::POP(StackTarget::from_index(byte >> 4)),
+            0xC5 | 0xD5 | 0xE5 | 0xF5 => Instruction::PUSH(StackTarget::from_index(byte >> 4)),
+
+            // 0xCB is decoded by the caller; the rest are unused opcodes that lock up the CPU
+            _ => return None,
+        };
+
+        Some(instruction)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Registers {
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub f: FlagsRegister,
+    pub h: u8,
+    pub l: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FlagsRegister {
+    pub zero: bool,
+    pub subtract: bool,
+    pub half_carry: bool,
+    pub carry: bool,
 }
 
 const ZERO_FLAG_BYTE_POSSITION: u8 = 7;
@@ -68,10 +323,10 @@ const CARRY_FLAG_BYTE_POSSITION: u8 = 4;
 
 impl std::convert::From<FlagsRegister> for u8 {
     fn from(flags: FlagsRegister) -> u8 {
-        (if flags.zero          { 1 } else { 0 }) |
-        (if flags.subtract      { 1 } else { 0 }) |
-        (if flags.half_carry    { 1 } else { 0 }) |
-        (if flags.carry         { 1 } else { 0 })
+        (if flags.zero          { 1 } else { 0 }) << ZERO_FLAG_BYTE_POSSITION |
+        (if flags.subtract      { 1 } else { 0 }) << SUBTRACT_FLAG_BYTE_POSSITION |
+        (if flags.half_carry    { 1 } else { 0 }) << HALF_CARRY_FLAG_BYTE_POSSITION |
+        (if flags.carry         { 1 } else { 0 }) << CARRY_FLAG_BYTE_POSSITION
     }
 }
 
@@ -93,625 +348,603 @@ impl std::convert::From<u8> for FlagsRegister {
 
 impl Registers {
 
+    // Register values left behind by the boot ROM
+    pub fn new(model: Model) -> Registers {
+        let mut registers = Registers::default();
+        match model {
+            Model::Dmg => {
+                registers.set_af(0x01B0);
+                registers.set_bc(0x0013);
+                registers.set_de(0x00D8);
+                registers.set_hl(0x014D);
+            }
+            Model::Cgb => {
+                registers.set_af(0x1180);
+                registers.set_bc(0x0000);
+                registers.set_de(0xFF56);
+                registers.set_hl(0x000D);
+            }
+        }
+        registers
+    }
+
     // AF Register Pair Accessors
-    fn get_af(&self) -> u16 {
-        ((self.a as u16) << 8) | (self.f as u16)
+    pub fn get_af(&self) -> u16 {
+        ((self.a as u16) << 8) | (u8::from(self.f) as u16)
     }
-    fn set_af(&mut self, value: u16) {
+    pub fn set_af(&mut self, value: u16) {
         self.a = (value >> 8) as u8;
-        self.f = (value & 0xFF) as u8;
+        self.f = FlagsRegister::from((value & 0xFF) as u8);
     }
 
     // BC Register Pair Accessors
-    fn get_bc(&self) -> u16 {
+    pub fn get_bc(&self) -> u16 {
         ((self.b as u16) << 8) | (self.c as u16)
     }
-    fn set_bc(&mut self, value: u16) {
+    pub fn set_bc(&mut self, value: u16) {
         self.b = (value >> 8) as u8;
         self.c = (value & 0xFF) as u8;
     }
 
     // DE Register Pair Accessors
-    fn get_de(&self) -> u16 {
+    pub fn get_de(&self) -> u16 {
         ((self.d as u16) << 8) | (self.e as u16)
     }
-    fn set_de(&mut self, value: u16) {
+    pub fn set_de(&mut self, value: u16) {
         self.d = (value >> 8) as u8;
         self.e = (value & 0xFF) as u8;
     }
 
     // HL Register Pair Accessors
-    fn get_hl(&self) -> u16 {
+    pub fn get_hl(&self) -> u16 {
         ((self.h as u16) << 8) | (self.l as u16)
     }
-    fn set_hl(&mut self, value: u16) {
+    pub fn set_hl(&mut self, value: u16) {
         self.h = (value >> 8) as u8;
         self.l = (value & 0xFF) as u8;
     }
 
 }
 
+#[allow(clippy::upper_case_acronyms)]
+pub struct CPU {
+    pub registers: Registers,
+    pub pc: u16,
+    pub sp: u16,
+    pub bus: Bus,
+
+    pub ime: bool,
+    pub halted: bool,
+    // Set by an unused opcode; the CPU stops executing until reset
+    pub locked: bool,
+    // EI takes effect after the instruction that follows it
+    pub ei_delay: u8,
+    // HALT with IME clear and an interrupt pending fails to increment PC on the next fetch
+    pub halt_bug: bool,
+}
+
 impl CPU {
+    pub fn new(bus: Bus) -> CPU {
+        CPU {
+            registers: Registers::new(bus.model),
+            pc: 0x0100,
+            sp: 0xFFFE,
+            bus,
+            ime: false,
+            halted: false,
+            locked: false,
+            ei_delay: 0,
+            halt_bug: false,
+        }
+    }
+
+    /// Runs one instruction, interrupt dispatch or halted M-cycle.
+    pub fn step(&mut self) {
+        if self.ei_delay > 0 {
+            self.ei_delay -= 1;
+            if self.ei_delay == 0 {
+                self.ime = true;
+            }
+        }
+
+        if self.locked {
+            self.bus.tick();
+            return;
+        }
+
+        let pending = self.bus.pending_interrupts();
+        if self.halted {
+            if pending == 0 {
+                self.bus.tick();
+                return;
+            }
+            self.halted = false;
+            if self.ime {
+                self.bus.tick();
+            }
+        }
+
+        if self.ime && pending != 0 {
+            self.dispatch_interrupt();
+            return;
+        }
+
+        let mut opcode = self.fetch_byte();
+        let prefixed = opcode == 0xCB;
+        if prefixed {
+            opcode = self.fetch_byte();
+        }
+
+        match Instruction::from_byte(opcode, prefixed) {
+            Some(instruction) => self.execute(instruction),
+            None => self.locked = true,
+        }
+    }
+
+    fn dispatch_interrupt(&mut self) {
+        self.ime = false;
+        self.bus.tick();
+        self.bus.tick();
+
+        let [low, high] = self.pc.to_le_bytes();
+        self.sp = self.sp.wrapping_sub(1);
+        self.write(self.sp, high);
+
+        // IE is sampled after the high byte is pushed, so a push that lands on 0xFFFF can
+        // cancel the dispatch and send the CPU to 0x0000.
+        let pending = self.bus.pending_interrupts();
+        self.sp = self.sp.wrapping_sub(1);
+        self.write(self.sp, low);
+
+        self.pc = if pending == 0 {
+            0x0000
+        } else {
+            let index = pending.trailing_zeros() as u16;
+            self.bus.interrupt_flag &= !(1 << index);
+            0x0040 + index * 8
+        };
+        self.bus.tick();
+    }
+
+    fn read(&mut self, address: u16) -> u8 {
+        self.bus.cpu_read(address)
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.bus.cpu_write(address, value);
+    }
+
+    fn fetch_byte(&mut self) -> u8 {
+        let value = self.read(self.pc);
+        if self.halt_bug {
+            self.halt_bug = false;
+        } else {
+            self.pc = self.pc.wrapping_add(1);
+        }
+        value
+    }
+
+    fn fetch_word(&mut self) -> u16 {
+        let low = self.fetch_byte();
+        let high = self.fetch_byte();
+        u16::from_le_bytes([low, high])
+    }
+
+    fn push(&mut self, value: u16) {
+        let [low, high] = value.to_le_bytes();
+        self.sp = self.sp.wrapping_sub(1);
+        self.write(self.sp, high);
+        self.sp = self.sp.wrapping_sub(1);
+        self.write(self.sp, low);
+    }
+
+    fn pop(&mut self) -> u16 {
+        let low = self.read(self.sp);
+        self.sp = self.sp.wrapping_add(1);
+        let high = self.read(self.sp);
+        self.sp = self.sp.wrapping_add(1);
+        u16::from_le_bytes([low, high])
+    }
+
+    fn read_target(&mut self, target: AritmaticTarget) -> u8 {
+        match target {
+            AritmaticTarget::A => self.registers.a,
+            AritmaticTarget::B => self.registers.b,
+            AritmaticTarget::C => self.registers.c,
+            AritmaticTarget::D => self.registers.d,
+            AritmaticTarget::E => self.registers.e,
+            AritmaticTarget::H => self.registers.h,
+            AritmaticTarget::L => self.registers.l,
+            AritmaticTarget::HLI => self.read(self.registers.get_hl()),
+            AritmaticTarget::D8 => self.fetch_byte(),
+        }
+    }
+
+    fn write_target(&mut self, target: AritmaticTarget, value: u8) {
+        match target {
+            AritmaticTarget::A => self.registers.a = value,
+            AritmaticTarget::B => self.registers.b = value,
+            AritmaticTarget::C => self.registers.c = value,
+            AritmaticTarget::D => self.registers.d = value,
+            AritmaticTarget::E => self.registers.e = value,
+            AritmaticTarget::H => self.registers.h = value,
+            AritmaticTarget::L => self.registers.l = value,
+            AritmaticTarget::HLI => self.write(self.registers.get_hl(), value),
+            AritmaticTarget::D8 => unreachable!("immediate operands are never written"),
+        }
+    }
+
+    fn read_wide(&self, target: WideTarget) -> u16 {
+        match target {
+            WideTarget::BC => self.registers.get_bc(),
+            WideTarget::DE => self.registers.get_de(),
+            WideTarget::HL => self.registers.get_hl(),
+            WideTarget::SP => self.sp,
+        }
+    }
+
+    fn write_wide(&mut self, target: WideTarget, value: u16) {
+        match target {
+            WideTarget::BC => self.registers.set_bc(value),
+            WideTarget::DE => self.registers.set_de(value),
+            WideTarget::HL => self.registers.set_hl(value),
+            WideTarget::SP => self.sp = value,
+        }
+    }
+
+    fn indirect_address(&mut self, indirect: Indirect) -> u16 {
+        match indirect {
+            Indirect::BCIndirect => self.registers.get_bc(),
+            Indirect::DEIndirect => self.registers.get_de(),
+            Indirect::HLIndirectPlus => {
+                let hl = self.registers.get_hl();
+                self.registers.set_hl(hl.wrapping_add(1));
+                hl
+            }
+            Indirect::HLIndirectMinus => {
+                let hl = self.registers.get_hl();
+                self.registers.set_hl(hl.wrapping_sub(1));
+                hl
+            }
+            Indirect::WordIndirect => self.fetch_word(),
+            Indirect::ByteIndirect => 0xFF00 | self.fetch_byte() as u16,
+            Indirect::LastByteIndirect => 0xFF00 | self.registers.c as u16,
+        }
+    }
+
+    fn condition(&self, test: JumpTest) -> bool {
+        match test {
+            JumpTest::NotZero => !self.registers.f.zero,
+            JumpTest::Zero => self.registers.f.zero,
+            JumpTest::NotCarry => !self.registers.f.carry,
+            JumpTest::Carry => self.registers.f.carry,
+            JumpTest::Always => true,
+        }
+    }
+
     fn execute(&mut self, instruction: Instruction) {
         match instruction {
 
             Instruction::ADD(target) => {
-                match target {
-                    AritmaticTarget::A => {
-                        let value = self.registers.a;
-                        let new_value = self.add(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::B => {
-                        let value = self.registers.b;
-                        let new_value = self.add(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::C => {
-                        let value = self.registers.c;
-                        let new_value = self.add(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::D => {
-                        let value = self.registers.d;
-                        let new_value = self.add(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::E => {
-                        let value = self.registers.e;
-                        let new_value = self.add(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::H => {
-                        let value = self.registers.h;
-                        let new_value = self.add(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::L => {
-                        let value = self.registers.l;
-                        let new_value = self.add(value);
-                        self.registers.a = new_value;
-                    }
-                }
+                let value = self.read_target(target);
+                self.registers.a = self.add(value);
             }
 
             Instruction::ADDHL(target) => {
-                match target {
-                    AritmaticTarget::A => {
-                        let value = self.registers.a as u16;
-                        let new_value = self.add_hl(value);
-                        self.registers.set_hl(new_value);
-                    }
-                    AritmaticTarget::B => {
-                        let value = self.registers.b as u16;
-                        let new_value = self.add_hl(value);
-                        self.registers.set_hl(new_value);
-                    }
-                    AritmaticTarget::C => {
-                        let value = self.registers.c as u16;
-                        let new_value = self.add_hl(value);
-                        self.registers.set_hl(new_value);
-                    }
-                    AritmaticTarget::D => {
-                        let value = self.registers.d as u16;
-                        let new_value = self.add_hl(value);
-                        self.registers.set_hl(new_value);
-                    }
-                    AritmaticTarget::E => {
-                        let value = self.registers.e as u16;
-                        let new_value = self.add_hl(value);
-                        self.registers.set_hl(new_value);
-                    }
-                    AritmaticTarget::H => {
-                        let value = self.registers.h as u16;
-                        let new_value = self.add_hl(value);
-                        self.registers.set_hl(new_value);
-                    }
-                    AritmaticTarget::L => {
-                        let value = self.registers.l as u16;
-                        let new_value = self.add_hl(value);
-                        self.registers.set_hl(new_value);
-                    }
-                }
+                let value = self.read_wide(target);
+                let new_value = self.add_hl(value);
+                self.registers.set_hl(new_value);
+                self.bus.tick();
+            }
+
+            Instruction::ADDSP => {
+                let offset = self.fetch_byte();
+                self.sp = self.add_sp(offset);
+                self.bus.tick();
+                self.bus.tick();
             }
 
             Instruction::ADC(target) => {
-                match target {
-                    AritmaticTarget::A => {
-                        let value = self.registers.a;
-                        let new_value = self.add_c(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::B => {
-                        let value = self.registers.b;
-                        let new_value = self.add_c(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::C => {
-                        let value = self.registers.c;
-                        let new_value = self.add_c(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::D => {
-                        let value = self.registers.d;
-                        let new_value = self.add_c(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::E => {
-                        let value = self.registers.e;
-                        let new_value = self.add_c(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::H => {
-                        let value = self.registers.h;
-                        let new_value = self.add_c(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::L => {
-                        let value = self.registers.l;
-                        let new_value = self.add_c(value);
-                        self.registers.a = new_value;
-                    }
-                }
+                let value = self.read_target(target);
+                self.registers.a = self.add_c(value);
             }
 
             Instruction::SUB(target) => {
-                match target {
-                    AritmaticTarget::A => {
-                        let value = self.registers.a;
-                        let new_value = self.sub(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::B => {
-                        let value = self.registers.b;
-                        let new_value = self.sub(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::C => {
-                        let value = self.registers.c;
-                        let new_value = self.sub(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::D => {
-                        let value = self.registers.d;
-                        let new_value = self.sub(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::E => {
-                        let value = self.registers.e;
-                        let new_value = self.sub(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::H => {
-                        let value = self.registers.h;
-                        let new_value = self.sub(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::L => {
-                        let value = self.registers.l;
-                        let new_value = self.sub(value);
-                        self.registers.a = new_value;
-                    }
-                }
+                let value = self.read_target(target);
+                self.registers.a = self.sub(value);
             }
 
             Instruction::SBC(target) => {
-                match target {
-                    AritmaticTarget::A => {
-                        let value = self.registers.a;
-                        let new_value = self.sub_c(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::B => {
-                        let value = self.registers.b;
-                        let new_value = self.sub_c(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::C => {
-                        let value = self.registers.c;
-                        let new_value = self.sub_c(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::D => {
-                        let value = self.registers.d;
-                        let new_value = self.sub_c(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::E => {
-                        let value = self.registers.e;
-                        let new_value = self.sub_c(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::H => {
-                        let value = self.registers.h;
-                        let new_value = self.sub_c(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::L => {
-                        let value = self.registers.l;
-                        let new_value = self.sub_c(value);
-                        self.registers.a = new_value;
-                    }
-                }
+                let value = self.read_target(target);
+                self.registers.a = self.sub_c(value);
             }
 
             Instruction::AND(target) => {
-                match target {
-                    AritmaticTarget::A => {
-                        let value = self.registers.a;
-                        let new_value = self.and(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::B => {
-                        let value = self.registers.b;
-                        let new_value = self.and(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::C => {
-                        let value = self.registers.c;
-                        let new_value = self.and(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::D => {
-                        let value = self.registers.d;
-                        let new_value = self.and(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::E => {
-                        let value = self.registers.e;
-                        let new_value = self.and(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::H => {
-                        let value = self.registers.h;
-                        let new_value = self.and(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::L => {
-                        let value = self.registers.l;
-                        let new_value = self.and(value);
-                        self.registers.a = new_value;
-                    }
-                }
+                let value = self.read_target(target);
+                self.registers.a = self.and(value);
             }
 
             Instruction::OR(target) => {
-                match target {
-                    AritmaticTarget::A => {
-                        let value = self.registers.a;
-                        let new_value = self.or(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::B => {
-                        let value = self.registers.b;
-                        let new_value = self.or(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::C => {
-                        let value = self.registers.c;
-                        let new_value = self.or(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::D => {
-                        let value = self.registers.d;
-                        let new_value = self.or(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::E => {
-                        let value = self.registers.e;
-                        let new_value = self.or(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::H => {
-                        let value = self.registers.h;
-                        let new_value = self.or(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::L => {
-                        let value = self.registers.l;
-                        let new_value = self.or(value);
-                        self.registers.a = new_value;
-                    }
-                }
+                let value = self.read_target(target);
+                self.registers.a = self.or(value);
             }
 
             Instruction::XOR(target) => {
-                match target {
-                    AritmaticTarget::A => {
-                        let value = self.registers.a;
-                        let new_value = self.xor(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::B => {
-                        let value = self.registers.b;
-                        let new_value = self.xor(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::C => {
-                        let value = self.registers.c;
-                        let new_value = self.xor(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::D => {
-                        let value = self.registers.d;
-                        let new_value = self.xor(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::E => {
-                        let value = self.registers.e;
-                        let new_value = self.xor(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::H => {
-                        let value = self.registers.h;
-                        let new_value = self.xor(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::L => {
-                        let value = self.registers.l;
-                        let new_value = self.xor(value);
-                        self.registers.a = new_value;
-                    }
-                }
+                let value = self.read_target(target);
+                self.registers.a = self.xor(value);
             }
 
             Instruction::CP(target) => {
-                match target {
-                    AritmaticTarget::A => {
-                        let value = self.registers.a;
-                        self.cp(value);
-                    }
-                    AritmaticTarget::B => {
-                        let value = self.registers.b;
-                        self.cp(value);
-                    }
-                    AritmaticTarget::C => {
-                        let value = self.registers.c;
-                        self.cp(value);
-                    }
-                    AritmaticTarget::D => {
-                        let value = self.registers.d;
-                        self.cp(value);
-                    }
-                    AritmaticTarget::E => {
-                        let value = self.registers.e;
-                        self.cp(value);
-                    }
-                    AritmaticTarget::H => {
-                        let value = self.registers.h;
-                        self.cp(value);
-                    }
-                    AritmaticTarget::L => {
-                        let value = self.registers.l;
-                        self.cp(value);
-                    }
-                }
+                let value = self.read_target(target);
+                self.cp(value);
             }
 
             Instruction::INC(target) => {
-                match target {
-                    AritmaticTarget::A => {
-                        let value = self.registers.a;
-                        let new_value = self.inc(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::B => {
-                        let value = self.registers.b;
-                        let new_value = self.inc(value);
-                        self.registers.b = new_value;
-                    }
-                    AritmaticTarget::C => {
-                        let value = self.registers.c;
-                        let new_value = self.inc(value);
-                        self.registers.c = new_value;
-                    }
-                    AritmaticTarget::D => {
-                        let value = self.registers.d;
-                        let new_value = self.inc(value);
-                        self.registers.d = new_value;
-                    }
-                    AritmaticTarget::E => {
-                        let value = self.registers.e;
-                        let new_value = self.inc(value);
-                        self.registers.e = new_value;
-                    }
-                    AritmaticTarget::H => {
-                        let value = self.registers.h;
-                        let new_value = self.inc(value);
-                        self.registers.h = new_value;
-                    }
-                    AritmaticTarget::L => {
-                        let value = self.registers.l;
-                        let new_value = self.inc(value);
-                        self.registers.l = new_value;
-                    }
-                }
+                let value = self.read_target(target);
+                let new_value = self.inc(value);
+                self.write_target(target, new_value);
             }
 
             Instruction::DEC(target) => {
-                match target {
-                    AritmaticTarget::A => {
-                        let value = self.registers.a;
-                        let new_value = self.dec(value);
-                        self.registers.a = new_value;
-                    }
-                    AritmaticTarget::B => {
-                        let value = self.registers.b;
-                        let new_value = self.dec(value);
-                        self.registers.b = new_value;
-                    }
-                    AritmaticTarget::C => {
-                        let value = self.registers.c;
-                        let new_value = self.dec(value);
-                        self.registers.c = new_value;
-                    }
-                    AritmaticTarget::D => {
-                        let value = self.registers.d;
-                        let new_value = self.dec(value);
-                        self.registers.d = new_value;
-                    }
-                    AritmaticTarget::E => {
-                        let value = self.registers.e;
-                        let new_value = self.dec(value);
-                        self.registers.e = new_value;
-                    }
-                    AritmaticTarget::H => {
-                        let value = self.registers.h;
-                        let new_value = self.dec(value);
-                        self.registers.h = new_value;
-                    }
-                    AritmaticTarget::L => {
-                        let value = self.registers.l;
-                        let new_value = self.dec(value);
-                        self.registers.l = new_value;
-                    }
+                let value = self.read_target(target);
+                let new_value = self.dec(value);
+                self.write_target(target, new_value);
+            }
+
+            Instruction::INC16(target) => {
+                let value = self.read_wide(target);
+                self.write_wide(target, value.wrapping_add(1));
+                self.bus.tick();
+            }
+
+            Instruction::DEC16(target) => {
+                let value = self.read_wide(target);
+                self.write_wide(target, value.wrapping_sub(1));
+                self.bus.tick();
+            }
+
+            Instruction::CCF => self.ccf(),
+            Instruction::SCF => self.scf(),
+            Instruction::DAA => self.daa(),
+            Instruction::CPL => self.cpl(),
+            Instruction::RRA => self.rra(),
+            Instruction::RLA => self.rla(),
+            Instruction::RRCA => self.rrca(),
+            Instruction::RLCA => self.rlca(),
+
+            Instruction::BIT(bit, target) => {
+                let value = self.read_target(target);
+                self.registers.f.zero = value & (1 << bit) == 0;
+                self.registers.f.subtract = false;
+                self.registers.f.half_carry = true;
+            }
+
+            Instruction::RESET(bit, target) => {
+                let value = self.read_target(target);
+                self.write_target(target, value & !(1 << bit));
+            }
+
+            Instruction::SET(bit, target) => {
+                let value = self.read_target(target);
+                self.write_target(target, value | (1 << bit));
+            }
+
+            Instruction::SRL(target) => self.shift(target, CPU::srl),
+            Instruction::RR(target) => self.shift(target, CPU::rr),
+            Instruction::RL(target) => self.shift(target, CPU::rl),
+            Instruction::RRC(target) => self.shift(target, CPU::rrc),
+            Instruction::RLC(target) => self.shift(target, CPU::rlc),
+            Instruction::SRA(target) => self.shift(target, CPU::sra),
+            Instruction::SLA(target) => self.shift(target, CPU::sla),
+            Instruction::SWAP(target) => self.shift(target, CPU::swap),
+
+            Instruction::JP(test) => {
+                let address = self.fetch_word();
+                if self.condition(test) {
+                    self.bus.tick();
+                    self.pc = address;
                 }
             }
 
-            Instruction::CCF(target) => {
-                match target {
-                    AritmaticTarget::A |
-                    AritmaticTarget::B |
-                    AritmaticTarget::C |
-                    AritmaticTarget::D |
-                    AritmaticTarget::E |
-                    AritmaticTarget::H |
-                    AritmaticTarget::L => {
-                        self.ccf();
-                    }
+            Instruction::JPHL => {
+                self.pc = self.registers.get_hl();
+            }
+
+            Instruction::JR(test) => {
+                let offset = self.fetch_byte() as i8;
+                if self.condition(test) {
+                    self.bus.tick();
+                    self.pc = self.pc.wrapping_add(offset as u16);
                 }
             }
 
-            Instruction::SCF(target) => {
-                match target {
-                    AritmaticTarget::A |
-                    AritmaticTarget::B |
-                    AritmaticTarget::C |
-                    AritmaticTarget::D |
-                    AritmaticTarget::E |
-                    AritmaticTarget::H |
-                    AritmaticTarget::L => {
-                        self.scf();
-                    }
+            Instruction::CALL(test) => {
+                let address = self.fetch_word();
+                if self.condition(test) {
+                    self.bus.tick();
+                    self.push(self.pc);
+                    self.pc = address;
                 }
             }
 
-            Instruction::RRA(target) => {
-                match target {
-                    AritmaticTarget::A |
-                    AritmaticTarget::B |
-                    AritmaticTarget::C |
-                    AritmaticTarget::D |
-                    AritmaticTarget::E |
-                    AritmaticTarget::H |
-                    AritmaticTarget::L => {
-                        self.rra();
-                    }
+            Instruction::RET(JumpTest::Always) => {
+                self.pc = self.pop();
+                self.bus.tick();
+            }
+
+            Instruction::RET(test) => {
+                self.bus.tick();
+                if self.condition(test) {
+                    self.pc = self.pop();
+                    self.bus.tick();
                 }
             }
 
-            Instruction::RLA(target) => {
+            Instruction::RETI => {
+                self.pc = self.pop();
+                self.bus.tick();
+                self.ime = true;
+            }
+
+            Instruction::RST(vector) => {
+                self.bus.tick();
+                self.push(self.pc);
+                self.pc = vector as u16;
+            }
+
+            Instruction::LD(load_type) => self.load(load_type),
+
+            Instruction::PUSH(target) => {
+                let value = match target {
+                    StackTarget::BC => self.registers.get_bc(),
+                    StackTarget::DE => self.registers.get_de(),
+                    StackTarget::HL => self.registers.get_hl(),
+                    StackTarget::AF => self.registers.get_af(),
+                };
+                self.bus.tick();
+                self.push(value);
+            }
+
+            Instruction::POP(target) => {
+                let value = self.pop();
                 match target {
-                    AritmaticTarget::A |
-                    AritmaticTarget::B |
-                    AritmaticTarget::C |
-                    AritmaticTarget::D |
-                    AritmaticTarget::E |
-                    AritmaticTarget::H |
-                    AritmaticTarget::L => {
-                        self.rla();
-                    }
+                    StackTarget::BC => self.registers.set_bc(value),
+                    StackTarget::DE => self.registers.set_de(value),
+                    StackTarget::HL => self.registers.set_hl(value),
+                    StackTarget::AF => self.registers.set_af(value & 0xFFF0),
                 }
             }
 
-            Instruction::RRCA(target) => {
-                match target {
-                    AritmaticTarget::A |
-                    AritmaticTarget::B |
-                    AritmaticTarget::C |
-                    AritmaticTarget::D |
-                    AritmaticTarget::E |
-                    AritmaticTarget::H |
-                    AritmaticTarget::L => {
-                        self.rrca();
-                    }
+            Instruction::NOP => {}
+
+            Instruction::HALT => {
+                if !self.ime && self.bus.pending_interrupts() != 0 {
+                    self.halt_bug = true;
+                } else {
+                    self.halted = true;
                 }
             }
 
-            Instruction::RRLA(target) => {
-                match target {
-                    AritmaticTarget::A |
-                    AritmaticTarget::B |
-                    AritmaticTarget::C |
-                    AritmaticTarget::D |
-                    AritmaticTarget::E |
-                    AritmaticTarget::H |
-                    AritmaticTarget::L => {
-                        self.rrla();
-                    }
+            Instruction::STOP => {
+                self.fetch_byte();
+                self.bus.timer.write_div();
+                if !self.bus.try_speed_switch() {
+                    self.halted = true;
                 }
             }
 
-            Instruction::CPL(target) => {
-                match target {
-                    AritmaticTarget::A |
-                    AritmaticTarget::B |
-                    AritmaticTarget::C |
-                    AritmaticTarget::D |
-                    AritmaticTarget::E |
-                    AritmaticTarget::H |
-                    AritmaticTarget::L => {
-                        self.cpl();
-                    }
+            Instruction::DI => {
+                self.ime = false;
+                self.ei_delay = 0;
+            }
+
+            Instruction::EI => {
+                if !self.ime && self.ei_delay == 0 {
+                    self.ei_delay = 2;
                 }
             }
         }
     }
 
+    fn load(&mut self, load_type: LoadType) {
+        match load_type {
+            LoadType::Byte(target, source) => {
+                let value = self.read_target(source);
+                self.write_target(target, value);
+            }
+            LoadType::Word(target) => {
+                let value = self.fetch_word();
+                self.write_wide(target, value);
+            }
+            LoadType::AFromIndirect(indirect) => {
+                let address = self.indirect_address(indirect);
+                self.registers.a = self.read(address);
+            }
+            LoadType::IndirectFromA(indirect) => {
+                let address = self.indirect_address(indirect);
+                self.write(address, self.registers.a);
+            }
+            LoadType::IndirectFromSP => {
+                let address = self.fetch_word();
+                let [low, high] = self.sp.to_le_bytes();
+                self.write(address, low);
+                self.write(address.wrapping_add(1), high);
+            }
+            LoadType::SPFromHL => {
+                self.sp = self.registers.get_hl();
+                self.bus.tick();
+            }
+            LoadType::HLFromSPOffset => {
+                let offset = self.fetch_byte();
+                let value = self.add_sp(offset);
+                self.registers.set_hl(value);
+                self.bus.tick();
+            }
+        }
+    }
+
+    fn shift(&mut self, target: AritmaticTarget, operation: fn(&mut CPU, u8) -> u8) {
+        let value = self.read_target(target);
+        let new_value = operation(self, value);
+        self.write_target(target, new_value);
+    }
+
     fn add(&mut self, value: u8) -> u8{
         let (new_value, did_overflow) = self.registers.a.overflowing_add(value);
-        
+
         self.registers.f.zero = new_value == 0;
         self.registers.f.subtract = false;
         self.registers.f.half_carry = ((self.registers.a & 0xF) + (value & 0xF)) > 0xF;
         self.registers.f.carry = did_overflow;
-        
+
         new_value
     }
 
     fn add_hl(&mut self, value: u16) -> u16 {
         let hl = self.registers.get_hl();
         let (new_hl, did_overflow) = hl.overflowing_add(value);
-        
-        self.registers.f.zero = false;
+
         self.registers.f.subtract = false;
         self.registers.f.half_carry = ((hl & 0x0FFF) + (value & 0x0FFF)) > 0x0FFF;
         self.registers.f.carry = did_overflow;
-        
+
         new_hl
     }
 
+    // Shared by ADD SP,e8 and LD HL,SP+e8: flags come from the unsigned low-byte addition
+    fn add_sp(&mut self, offset: u8) -> u16 {
+        let sp = self.sp;
+        let value = offset as i8 as i16 as u16;
+
+        self.registers.f.zero = false;
+        self.registers.f.subtract = false;
+        self.registers.f.half_carry = (sp & 0xF) + (value & 0xF) > 0xF;
+        self.registers.f.carry = (sp & 0xFF) + (value & 0xFF) > 0xFF;
+
+        sp.wrapping_add(value)
+    }
+
     fn add_c(&mut self, value: u8) -> u8 {
         let carry = if self.registers.f.carry { 1 } else { 0 };
         let (intermediate_value, did_overflow1) = self.registers.a.overflowing_add(value);
         let (new_value, did_overflow2) = intermediate_value.overflowing_add(carry);
-        
+
         self.registers.f.zero = new_value == 0;
         self.registers.f.subtract = false;
         self.registers.f.half_carry = ((self.registers.a & 0xF) + (value & 0xF) + carry) > 0xF;
         self.registers.f.carry = did_overflow1 || did_overflow2;
-        
+
         new_value
     }
 
     fn sub(&mut self, value: u8) -> u8 {
         let (new_value, did_overflow) = self.registers.a.overflowing_sub(value);
-        
+
         self.registers.f.zero = new_value == 0;
         self.registers.f.subtract = true;
         self.registers.f.half_carry = (self.registers.a & 0xF) < (value & 0xF);
         self.registers.f.carry = did_overflow;
-        
+
         new_value
     }
 
@@ -719,51 +952,51 @@ impl CPU {
         let carry = if self.registers.f.carry { 1 } else { 0 };
         let (intermediate_value, did_overflow1) = self.registers.a.overflowing_sub(value);
         let (new_value, did_overflow2) = intermediate_value.overflowing_sub(carry);
-        
+
         self.registers.f.zero = new_value == 0;
         self.registers.f.subtract = true;
         self.registers.f.half_carry = (self.registers.a & 0xF) < ((value & 0xF) + carry);
         self.registers.f.carry = did_overflow1 || did_overflow2;
-        
+
         new_value
     }
 
     fn and(&mut self, value: u8) -> u8 {
         let new_value = self.registers.a & value;
-        
+
         self.registers.f.zero = new_value == 0;
         self.registers.f.subtract = false;
         self.registers.f.half_carry = true;
         self.registers.f.carry = false;
-        
+
         new_value
     }
 
     fn or(&mut self, value: u8) -> u8 {
         let new_value = self.registers.a | value;
-        
+
         self.registers.f.zero = new_value == 0;
         self.registers.f.subtract = false;
         self.registers.f.half_carry = false;
         self.registers.f.carry = false;
-        
+
         new_value
     }
 
     fn xor(&mut self, value: u8) -> u8 {
         let new_value = self.registers.a ^ value;
-        
+
         self.registers.f.zero = new_value == 0;
         self.registers.f.subtract = false;
         self.registers.f.half_carry = false;
         self.registers.f.carry = false;
-        
+
         new_value
     }
 
     fn cp(&mut self, value: u8) {
         let (result, did_overflow) = self.registers.a.overflowing_sub(value);
-        
+
         self.registers.f.zero = result == 0;
         self.registers.f.subtract = true;
         self.registers.f.half_carry = (self.registers.a & 0xF) < (value & 0xF);
@@ -771,22 +1004,22 @@ impl CPU {
     }
 
     fn inc(&mut self, value: u8) -> u8 {
-        let (new_value, did_overflow) = value.overflowing_add(1);
-        
+        let new_value = value.wrapping_add(1);
+
         self.registers.f.zero = new_value == 0;
         self.registers.f.subtract = false;
         self.registers.f.half_carry = (value & 0xF) + 1 > 0xF;
-        
+
         new_value
     }
 
     fn dec(&mut self, value: u8) -> u8 {
-        let (new_value, did_overflow) = value.overflowing_sub(1);
-        
+        let new_value = value.wrapping_sub(1);
+
         self.registers.f.zero = new_value == 0;
         self.registers.f.subtract = true;
         self.registers.f.half_carry = (value & 0xF) == 0;
-        
+
         new_value
     }
 
@@ -802,23 +1035,289 @@ impl CPU {
         self.registers.f.carry = true;
     }
 
+    fn daa(&mut self) {
+        let mut a = self.registers.a;
+        let mut carry = self.registers.f.carry;
+
+        if !self.registers.f.subtract {
+            if carry || a > 0x99 {
+                a = a.wrapping_add(0x60);
+                carry = true;
+            }
+            if self.registers.f.half_carry || (a & 0x0F) > 0x09 {
+                a = a.wrapping_add(0x06);
+            }
+        } else {
+            if carry {
+                a = a.wrapping_sub(0x60);
+            }
+            if self.registers.f.half_carry {
+                a = a.wrapping_sub(0x06);
+            }
+        }
+
+        self.registers.a = a;
+        self.registers.f.zero = a == 0;
+        self.registers.f.half_carry = false;
+        self.registers.f.carry = carry;
+    }
+
+    // The accumulator rotates always clear the zero flag, unlike their 0xCB counterparts
     fn rra(&mut self) {
-        // Implementation for RRA instruction
+        self.registers.a = self.rr(self.registers.a);
+        self.registers.f.zero = false;
     }
 
     fn rla(&mut self) {
-        // Implementation for RLA instruction
+        self.registers.a = self.rl(self.registers.a);
+        self.registers.f.zero = false;
     }
 
     fn rrca(&mut self) {
-        // Implementation for RRCA instruction
+        self.registers.a = self.rrc(self.registers.a);
+        self.registers.f.zero = false;
     }
 
-    fn rrla(&mut self) {
-        // Implementation for RRLA instruction
+    fn rlca(&mut self) {
+        self.registers.a = self.rlc(self.registers.a);
+        self.registers.f.zero = false;
     }
 
     fn cpl(&mut self) {
-        // Implementation for CPL instruction
+        self.registers.a = !self.registers.a;
+        self.registers.f.subtract = true;
+        self.registers.f.half_carry = true;
+    }
+
+    fn set_shift_flags(&mut self, new_value: u8, carry: bool) {
+        self.registers.f.zero = new_value == 0;
+        self.registers.f.subtract = false;
+        self.registers.f.half_carry = false;
+        self.registers.f.carry = carry;
+    }
+
+    fn rlc(&mut self, value: u8) -> u8 {
+        let new_value = value.rotate_left(1);
+        self.set_shift_flags(new_value, value & 0x80 != 0);
+        new_value
+    }
+
+    fn rrc(&mut self, value: u8) -> u8 {
+        let new_value = value.rotate_right(1);
+        self.set_shift_flags(new_value, value & 0x01 != 0);
+        new_value
+    }
+
+    fn rl(&mut self, value: u8) -> u8 {
+        let carry = if self.registers.f.carry { 1 } else { 0 };
+        let new_value = (value << 1) | carry;
+        self.set_shift_flags(new_value, value & 0x80 != 0);
+        new_value
+    }
+
+    fn rr(&mut self, value: u8) -> u8 {
+        let carry = if self.registers.f.carry { 0x80 } else { 0 };
+        let new_value = (value >> 1) | carry;
+        self.set_shift_flags(new_value, value & 0x01 != 0);
+        new_value
+    }
+
+    fn sla(&mut self, value: u8) -> u8 {
+        let new_value = value << 1;
+        self.set_shift_flags(new_value, value & 0x80 != 0);
+        new_value
+    }
+
+    fn sra(&mut self, value: u8) -> u8 {
+        let new_value = (value >> 1) | (value & 0x80);
+        self.set_shift_flags(new_value, value & 0x01 != 0);
+        new_value
+    }
+
+    fn srl(&mut self, value: u8) -> u8 {
+        let new_value = value >> 1;
+        self.set_shift_flags(new_value, value & 0x01 != 0);
+        new_value
+    }
+
+    fn swap(&mut self, value: u8) -> u8 {
+        let new_value = value.rotate_left(4);
+        self.set_shift_flags(new_value, false);
+        new_value
+    }
+
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.put_u16(self.registers.get_af());
+        out.put_u16(self.registers.get_bc());
+        out.put_u16(self.registers.get_de());
+        out.put_u16(self.registers.get_hl());
+        out.put_u16(self.pc);
+        out.put_u16(self.sp);
+        out.put_bool(self.ime);
+        out.put_bool(self.halted);
+        out.put_bool(self.locked);
+        out.put_u8(self.ei_delay);
+        out.put_bool(self.halt_bug);
+    }
+
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.registers.set_af(input.take_u16()?);
+        self.registers.set_bc(input.take_u16()?);
+        self.registers.set_de(input.take_u16()?);
+        self.registers.set_hl(input.take_u16()?);
+        self.pc = input.take_u16()?;
+        self.sp = input.take_u16()?;
+        self.ime = input.take_bool()?;
+        self.halted = input.take_bool()?;
+        self.locked = input.take_bool()?;
+        self.ei_delay = input.take_u8()?;
+        self.halt_bug = input.take_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::bus::Interrupt;
+    use crate::utils::rom::Cartridge;
+
+    // A DMG running `code` from the entry point, in the post-boot state.
+    fn cpu(code: &[u8]) -> CPU {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + code.len()].copy_from_slice(code);
+        rom[0x14D] = rom[0x134..0x14D].iter().fold(0u8, |sum, &byte| sum.wrapping_sub(byte).wrapping_sub(1));
+        CPU::new(Bus::new(Model::Dmg, Cartridge::new(rom).unwrap()))
+    }
+
+    // Runs one step and returns the M-cycles it took.
+    fn step(cpu: &mut CPU) -> u64 {
+        let start = cpu.bus.cycles;
+        cpu.step();
+        cpu.bus.cycles - start
+    }
+
+    #[test]
+    fn instruction_timings() {
+        let cases: &[(&[u8], u64)] = &[
+            (&[0x00], 1),             // NOP
+            (&[0x01, 0x34, 0x12], 3), // LD BC,d16
+            (&[0x36, 0x42], 3),       // LD (HL),d8
+            (&[0x34], 3),             // INC (HL)
+            (&[0xC5], 4),             // PUSH BC
+            (&[0xC1], 3),             // POP BC
+            (&[0xC3, 0x00, 0x02], 4), // JP a16
+            (&[0xCD, 0x00, 0x02], 6), // CALL a16
+            (&[0xC9], 4),             // RET
+            (&[0xE8, 0x01], 4),       // ADD SP,r8
+            (&[0xF8, 0x01], 3),       // LD HL,SP+r8
+            (&[0xCB, 0x46], 3),       // BIT 0,(HL)
+            (&[0xCB, 0xC6], 4),       // SET 0,(HL)
+            (&[0xCB, 0x11], 2),       // RL C
+        ];
+        for &(code, cycles) in cases {
+            let mut cpu = cpu(code);
+            cpu.registers.set_hl(0xC000);
+            assert_eq!(step(&mut cpu), cycles, "opcode {:02X?}", code);
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn conditional_branches_take_longer_when_taken() {
+        // After boot Z is set: JR NZ falls through, JR Z jumps.
+        let mut cpu = cpu(&[0x20, 0x05, 0x28, 0x05]);
+        assert_eq!(step(&mut cpu), 2);
+        assert_eq!(cpu.pc, 0x0102);
+        assert_eq!(step(&mut cpu), 3);
+        assert_eq!(cpu.pc, 0x0109);
+    }
+
+    #[test]
+    fn add_sets_zero_half_carry_and_carry() {
+        // LD A,3A; ADD A,C6
+        let mut cpu = cpu(&[0x3E, 0x3A, 0xC6, 0xC6]);
+        step(&mut cpu);
+        step(&mut cpu);
+        assert_eq!(cpu.registers.a, 0x00);
+        assert_eq!(u8::from(cpu.registers.f), 0xB0);
+    }
+
+    #[test]
+    fn daa_adjusts_bcd_addition_and_subtraction() {
+        // LD A,15; ADD A,27; DAA; SUB 08; DAA
+        let mut cpu = cpu(&[0x3E, 0x15, 0xC6, 0x27, 0x27, 0xD6, 0x08, 0x27]);
+        for _ in 0..3 {
+            step(&mut cpu);
+        }
+        assert_eq!(cpu.registers.a, 0x42);
+        step(&mut cpu);
+        step(&mut cpu);
+        assert_eq!(cpu.registers.a, 0x34);
+        assert!(cpu.registers.f.subtract);
+    }
+
+    #[test]
+    fn interrupt_dispatch_takes_five_cycles() {
+        let mut cpu = cpu(&[0x00]);
+        cpu.ime = true;
+        cpu.bus.interrupt_enable = Interrupt::Timer.bit();
+        cpu.bus.interrupt_flag = Interrupt::Timer.bit();
+        assert_eq!(step(&mut cpu), 5);
+        assert_eq!(cpu.pc, 0x0050);
+        assert_eq!(cpu.sp, 0xFFFC);
+        assert_eq!(cpu.bus.read_byte(0xFFFC), 0x00);
+        assert_eq!(cpu.bus.read_byte(0xFFFD), 0x01);
+        assert!(!cpu.ime);
+        assert_eq!(cpu.bus.interrupt_flag & Interrupt::Timer.bit(), 0);
+    }
+
+    #[test]
+    fn ei_takes_effect_after_the_next_instruction() {
+        // EI; NOP; NOP with the timer interrupt already pending.
+        let mut cpu = cpu(&[0xFB, 0x00, 0x00]);
+        cpu.bus.interrupt_enable = Interrupt::Timer.bit();
+        cpu.bus.interrupt_flag = Interrupt::Timer.bit();
+        step(&mut cpu);
+        step(&mut cpu);
+        assert_eq!(cpu.pc, 0x0102);
+        step(&mut cpu);
+        assert_eq!(cpu.pc, 0x0050);
+    }
+
+    #[test]
+    fn halt_bug_runs_the_next_byte_twice() {
+        // HALT; INC A with IME clear and an interrupt already pending.
+        let mut cpu = cpu(&[0x76, 0x3C]);
+        cpu.registers.a = 0;
+        cpu.bus.interrupt_enable = Interrupt::Timer.bit();
+        cpu.bus.interrupt_flag = Interrupt::Timer.bit();
+        step(&mut cpu);
+        assert!(!cpu.halted);
+        step(&mut cpu);
+        step(&mut cpu);
+        assert_eq!(cpu.registers.a, 2);
+        assert_eq!(cpu.pc, 0x0102);
+    }
+
+    #[test]
+    fn halt_wakes_on_an_interrupt_without_ime() {
+        let mut cpu = cpu(&[0x76, 0x3C]);
+        cpu.bus.interrupt_enable = Interrupt::Timer.bit();
+        step(&mut cpu);
+        assert!(cpu.halted);
+        assert_eq!(step(&mut cpu), 1);
+        cpu.bus.interrupt_flag |= Interrupt::Timer.bit();
+        step(&mut cpu);
+        assert!(!cpu.halted);
+        assert_eq!(cpu.pc, 0x0102);
+    }
+
+    #[test]
+    fn unused_opcode_locks_the_cpu() {
+        let mut cpu = cpu(&[0xD3, 0x00]);
+        step(&mut cpu);
+        assert!(cpu.locked);
+        step(&mut cpu);
+        assert_eq!(cpu.pc, 0x0101);
+    }
+}
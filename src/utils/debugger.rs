@@ -0,0 +1,797 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::utils::bus::Bus;
+use crate::utils::cpu::CPU;
+use crate::utils::disasm::disassemble;
+use crate::{GameBoy, CYCLES_PER_FRAME};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExprError {
+    /// Byte offset into the expression.
+    pub position: usize,
+    pub message: &'static str,
+}
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at column {}", self.message, self.position + 1)
+    }
+}
+
+impl std::error::Error for ExprError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operand {
+    A,
+    B,
+    C,
+    D,
+    E,
+    F,
+    H,
+    L,
+    Af,
+    Bc,
+    De,
+    Hl,
+    Sp,
+    Pc,
+    Zero,
+    Subtract,
+    HalfCarry,
+    Carry,
+    Ime,
+    Ly,
+    // The byte and address a watchpoint saw; 0 elsewhere.
+    Value,
+    Address,
+}
+
+impl Operand {
+    fn parse(name: &str) -> Option<Operand> {
+        let operand = match name {
+            "a" => Operand::A,
+            "b" => Operand::B,
+            "c" => Operand::C,
+            "d" => Operand::D,
+            "e" => Operand::E,
+            "f" => Operand::F,
+            "h" => Operand::H,
+            "l" => Operand::L,
+            "af" => Operand::Af,
+            "bc" => Operand::Bc,
+            "de" => Operand::De,
+            "hl" => Operand::Hl,
+            "sp" => Operand::Sp,
+            "pc" => Operand::Pc,
+            "zf" => Operand::Zero,
+            "nf" => Operand::Subtract,
+            "hf" => Operand::HalfCarry,
+            "cf" => Operand::Carry,
+            "ime" => Operand::Ime,
+            "ly" => Operand::Ly,
+            "value" => Operand::Value,
+            "address" => Operand::Address,
+            _ => return None,
+        };
+        Some(operand)
+    }
+
+    fn value(self, cpu: &CPU, access: Option<&WatchHit>) -> i64 {
+        let registers = &cpu.registers;
+        let value = match self {
+            Operand::A => registers.a as u16,
+            Operand::B => registers.b as u16,
+            Operand::C => registers.c as u16,
+            Operand::D => registers.d as u16,
+            Operand::E => registers.e as u16,
+            Operand::F => u8::from(registers.f) as u16,
+            Operand::H => registers.h as u16,
+            Operand::L => registers.l as u16,
+            Operand::Af => registers.get_af(),
+            Operand::Bc => registers.get_bc(),
+            Operand::De => registers.get_de(),
+            Operand::Hl => registers.get_hl(),
+            Operand::Sp => cpu.sp,
+            Operand::Pc => cpu.pc,
+            Operand::Zero => registers.f.zero as u16,
+            Operand::Subtract => registers.f.subtract as u16,
+            Operand::HalfCarry => registers.f.half_carry as u16,
+            Operand::Carry => registers.f.carry as u16,
+            Operand::Ime => cpu.ime as u16,
+            Operand::Ly => cpu.bus.ppu.ly as u16,
+            Operand::Value => access.map_or(0, |access| access.value as u16),
+            Operand::Address => access.map_or(0, |access| access.address),
+        };
+        value as i64
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinaryOp {
+    Or,
+    And,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    BitOr,
+    BitXor,
+    BitAnd,
+    ShiftLeft,
+    ShiftRight,
+    Add,
+    Subtract,
+}
+
+impl BinaryOp {
+    // Rust's precedence: arithmetic binds tightest, then bitwise, comparisons, && and ||.
+    const LEVELS: [&'static [(&'static str, BinaryOp)]; 8] = [
+        &[("||", BinaryOp::Or)],
+        &[("&&", BinaryOp::And)],
+        &[
+            ("==", BinaryOp::Equal),
+            ("!=", BinaryOp::NotEqual),
+            ("<=", BinaryOp::LessEqual),
+            (">=", BinaryOp::GreaterEqual),
+            ("<", BinaryOp::Less),
+            (">", BinaryOp::Greater),
+        ],
+        &[("|", BinaryOp::BitOr)],
+        &[("^", BinaryOp::BitXor)],
+        &[("&", BinaryOp::BitAnd)],
+        &[("<<", BinaryOp::ShiftLeft), (">>", BinaryOp::ShiftRight)],
+        &[("+", BinaryOp::Add), ("-", BinaryOp::Subtract)],
+    ];
+
+    fn apply(self, left: i64, right: i64) -> i64 {
+        match self {
+            BinaryOp::Or => (left != 0 || right != 0) as i64,
+            BinaryOp::And => (left != 0 && right != 0) as i64,
+            BinaryOp::Equal => (left == right) as i64,
+            BinaryOp::NotEqual => (left != right) as i64,
+            BinaryOp::Less => (left < right) as i64,
+            BinaryOp::LessEqual => (left <= right) as i64,
+            BinaryOp::Greater => (left > right) as i64,
+            BinaryOp::GreaterEqual => (left >= right) as i64,
+            BinaryOp::BitOr => left | right,
+            BinaryOp::BitXor => left ^ right,
+            BinaryOp::BitAnd => left & right,
+            BinaryOp::ShiftLeft => left.wrapping_shl(right as u32),
+            BinaryOp::ShiftRight => left.wrapping_shr(right as u32),
+            BinaryOp::Add => left.wrapping_add(right),
+            BinaryOp::Subtract => left.wrapping_sub(right),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Number(i64),
+    Operand(Operand),
+    /// The byte at an address.
+    Memory(Box<Node>),
+    Not(Box<Node>),
+    Negate(Box<Node>),
+    Complement(Box<Node>),
+    Binary(BinaryOp, Box<Node>, Box<Node>),
+}
+
+impl Node {
+    fn eval(&self, cpu: &CPU, access: Option<&WatchHit>) -> i64 {
+        match self {
+            Node::Number(value) => *value,
+            Node::Operand(operand) => operand.value(cpu, access),
+            Node::Memory(address) => cpu.bus.read_byte(address.eval(cpu, access) as u16) as i64,
+            Node::Not(node) => (node.eval(cpu, access) == 0) as i64,
+            Node::Negate(node) => node.eval(cpu, access).wrapping_neg(),
+            Node::Complement(node) => !node.eval(cpu, access),
+            Node::Binary(op, left, right) => op.apply(left.eval(cpu, access), right.eval(cpu, access)),
+        }
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &'static str) -> ExprError {
+        ExprError { position: self.position, message }
+    }
+
+    fn skip_space(&mut self) {
+        let rest = &self.text[self.position..];
+        self.position += rest.len() - rest.trim_start().len();
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_space();
+        let rest = &self.text[self.position..];
+        // `&` and `|` must not match the start of `&&` and `||`, nor `<` the start of `<<`.
+        if !rest.starts_with(token) {
+            return false;
+        }
+        let doubled = token.len() == 1 && "&|<>".contains(token) && rest[1..].starts_with(token);
+        if !doubled {
+            self.position += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Node, ExprError> {
+        let Some(operators) = BinaryOp::LEVELS.get(level) else {
+            return self.unary();
+        };
+        let mut left = self.binary(level + 1)?;
+        'outer: loop {
+            for &(token, op) in operators.iter() {
+                if self.eat(token) {
+                    let right = self.binary(level + 1)?;
+                    left = Node::Binary(op, Box::new(left), Box::new(right));
+                    continue 'outer;
+                }
+            }
+            return Ok(left);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Node, ExprError> {
+        if self.eat("!") {
+            return Ok(Node::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("-") {
+            return Ok(Node::Negate(Box::new(self.unary()?)));
+        }
+        if self.eat("~") {
+            return Ok(Node::Complement(Box::new(self.unary()?)));
+        }
+        if self.eat("(") {
+            let node = self.binary(0)?;
+            return if self.eat(")") { Ok(node) } else { Err(self.error("expected ')'")) };
+        }
+        if self.eat("[") {
+            let node = self.binary(0)?;
+            return if self.eat("]") { Ok(Node::Memory(Box::new(node))) } else { Err(self.error("expected ']'")) };
+        }
+        self.atom()
+    }
+
+    fn atom(&mut self) -> Result<Node, ExprError> {
+        self.skip_space();
+        let start = self.position;
+        let rest = &self.text[start..];
+        let length = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '$').unwrap_or(rest.len());
+        let word = &rest[..length];
+        if word.is_empty() {
+            return Err(self.error("expected a value"));
+        }
+        let node = if let Some(hex) = word.strip_prefix("0x").or_else(|| word.strip_prefix('$')) {
+            i64::from_str_radix(hex, 16).map(Node::Number).map_err(|_| self.error("bad hex number"))?
+        } else if word.starts_with(|c: char| c.is_ascii_digit()) {
+            word.parse().map(Node::Number).map_err(|_| self.error("bad number"))?
+        } else {
+            let operand = Operand::parse(&word.to_ascii_lowercase()).ok_or(self.error("unknown register or flag"))?;
+            Node::Operand(operand)
+        };
+        self.position += length;
+        Ok(node)
+    }
+}
+
+/// A condition or value over the CPU's registers and memory, such as
+/// `a == 0x3C && [hl] > 4`.
+///
+/// Values are registers (`a` to `l`, `af` to `hl`, `sp`, `pc`), flags (`zf`, `nf`, `hf`,
+/// `cf`), `ime`, `ly`, numbers (decimal, `0x` or `$` hex) and `[address]` for the byte there.
+/// Watchpoint conditions can also use `value` and `address` for the access.
+/// Operators are those of Rust, with comparisons and logic giving 0 or 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expr {
+    source: String,
+    node: Node,
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Expr, ExprError> {
+        let mut parser = Parser { text, position: 0 };
+        let node = parser.binary(0)?;
+        parser.skip_space();
+        if parser.position != text.len() {
+            return Err(parser.error("unexpected text"));
+        }
+        Ok(Expr { source: text.trim().to_string(), node })
+    }
+
+    pub fn eval(&self, cpu: &CPU) -> i64 {
+        self.node.eval(cpu, None)
+    }
+
+    /// Evaluates with `value` and `address` taken from a watchpoint hit.
+    pub fn eval_access(&self, cpu: &CPU, access: &WatchHit) -> i64 {
+        self.node.eval(cpu, Some(access))
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+/// The bank mapped at `address` right now: the ROM bank for 0x0000-0x7FFF, and the VRAM,
+/// cartridge RAM or work RAM bank above. None where nothing is banked.
+pub fn mapped_bank(bus: &Bus, address: u16) -> Option<u16> {
+    let bank = match address {
+        0x0000..=0x3FFF => bus.cartridge.low_bank(),
+        0x4000..=0x7FFF => bus.cartridge.high_bank(),
+        0x8000..=0x9FFF => bus.ppu.vram_bank as usize,
+        0xA000..=0xBFFF => bus.cartridge.ram_offset(address)? / 0x2000,
+        0xD000..=0xDFFF => bus.wram_offset(address) / 0x1000,
+        _ => return None,
+    };
+    Some(bank as u16)
+}
+
+/// The registers as `AF=01B0 BC=0013 DE=00D8 HL=014D SP=FFFE PC=0100`, then the flags
+/// and IME.
+pub fn registers_line(cpu: &CPU) -> String {
+    let registers = &cpu.registers;
+    let flags = registers.f;
+    let flag = |set: bool, letter: char| if set { letter } else { '-' };
+    format!(
+        "AF={:04X} BC={:04X} DE={:04X} HL={:04X} SP={:04X} PC={:04X}  {}{}{}{}  IME={}",
+        registers.get_af(),
+        registers.get_bc(),
+        registers.get_de(),
+        registers.get_hl(),
+        cpu.sp,
+        cpu.pc,
+        flag(flags.zero, 'Z'),
+        flag(flags.subtract, 'N'),
+        flag(flags.half_carry, 'H'),
+        flag(flags.carry, 'C'),
+        cpu.ime as u8
+    )
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub address: u16,
+    /// Only stop when this bank is mapped at `address`.
+    pub bank: Option<u16>,
+    /// Only stop when this is not zero.
+    pub condition: Option<Expr>,
+    pub enabled: bool,
+    pub hits: u64,
+}
+
+impl Breakpoint {
+    pub fn new(address: u16) -> Breakpoint {
+        Breakpoint { address, bank: None, condition: None, enabled: true, hits: 0 }
+    }
+}
+
+/// The kinds of memory access a watchpoint stops on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    fn matches(self, write: bool) -> bool {
+        match self {
+            Access::Read => !write,
+            Access::Write => write,
+            Access::ReadWrite => true,
+        }
+    }
+}
+
+/// Stops after an instruction that reads or writes memory in `start..=end`, such as a
+/// write to ROM space, which is a mapper command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub access: Access,
+    /// Only stop when this is not zero; `value` and `address` name the access, so
+    /// `value & 0x80 == 0` on a write to 0xFF40 catches the LCD being switched off.
+    pub condition: Option<Expr>,
+    pub enabled: bool,
+    pub hits: u64,
+}
+
+impl Watchpoint {
+    pub fn new(start: u16, end: u16, access: Access) -> Watchpoint {
+        Watchpoint { start, end, access, condition: None, enabled: true, hits: 0 }
+    }
+}
+
+/// A CPU access that fell in a watchpoint's range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub watchpoint: usize,
+    pub address: u16,
+    pub value: u8,
+    pub write: bool,
+}
+
+// No instruction makes more accesses than this, and it bounds the list while the machine
+// runs without a debugger draining it.
+const MAX_PENDING_HITS: usize = 16;
+
+/// The watchpoints, which the bus checks on every CPU access while any is enabled.
+/// Conditions are left to the debugger, which looks at the hits after each instruction.
+#[derive(Debug, Clone, Default)]
+pub struct Watchpoints {
+    list: Vec<Watchpoint>,
+    armed: bool,
+    pending: Vec<WatchHit>,
+}
+
+impl Watchpoints {
+    pub fn list(&self) -> &[Watchpoint] {
+        &self.list
+    }
+
+    /// Returns the new watchpoint's index.
+    pub fn add(&mut self, watchpoint: Watchpoint) -> usize {
+        self.list.push(watchpoint);
+        self.rearm();
+        self.list.len() - 1
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Watchpoint> {
+        let watchpoint = (index < self.list.len()).then(|| self.list.remove(index));
+        self.pending.clear();
+        self.rearm();
+        watchpoint
+    }
+
+    /// Returns false if there is no watchpoint at `index`.
+    pub fn set_enabled(&mut self, index: usize, enabled: bool) -> bool {
+        let Some(watchpoint) = self.list.get_mut(index) else {
+            return false;
+        };
+        watchpoint.enabled = enabled;
+        self.rearm();
+        true
+    }
+
+    fn rearm(&mut self) {
+        self.armed = self.list.iter().any(|watchpoint| watchpoint.enabled);
+    }
+
+    /// Whether the bus has to look at accesses at all.
+    #[inline]
+    pub fn armed(&self) -> bool {
+        self.armed
+    }
+
+    /// Records a CPU access if it falls in an enabled watchpoint.
+    pub fn check(&mut self, address: u16, value: u8, write: bool) {
+        for (index, watchpoint) in self.list.iter().enumerate() {
+            let inside = (watchpoint.start..=watchpoint.end).contains(&address);
+            if watchpoint.enabled && inside && watchpoint.access.matches(write) && self.pending.len() < MAX_PENDING_HITS {
+                self.pending.push(WatchHit { watchpoint: index, address, value, write });
+            }
+        }
+    }
+
+    /// The accesses recorded since the last call.
+    pub fn take_hits(&mut self) -> Vec<WatchHit> {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+/// Where to run to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Goal {
+    /// Until a breakpoint.
+    Continue,
+    /// This many instructions. Waiting in HALT until an interrupt counts as one.
+    Steps(u64),
+    /// One instruction, running any CALL or RST through to its return.
+    StepOver,
+    /// Until the current function returns.
+    StepOut,
+    /// Until the next VBlank starts, as `GameBoy::run_frame`.
+    Frame,
+    /// Until the PPU starts this line.
+    Scanline(u8),
+}
+
+/// Why a run ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// At the breakpoint with this index, before running the instruction there.
+    Breakpoint(usize),
+    /// Just after the instruction that made this access.
+    Watchpoint(WatchHit),
+    /// The goal was reached.
+    Done,
+    /// The instruction limit ran out first.
+    Limit,
+    /// The interrupt flag was raised, e.g. by Ctrl-C.
+    Interrupted,
+}
+
+// Opcodes that return: RET, RETI and the conditional RETs.
+fn is_return(opcode: u8) -> bool {
+    matches!(opcode, 0xC9 | 0xD9 | 0xC0 | 0xC8 | 0xD0 | 0xD8)
+}
+
+// CALL, the conditional CALLs and RST, which step-over runs through.
+fn is_call(opcode: u8) -> bool {
+    matches!(opcode, 0xCD | 0xC4 | 0xCC | 0xD4 | 0xDC) || opcode & 0xC7 == 0xC7
+}
+
+/// Breakpoints and the stepping built on them. The machine only ever stops between
+/// instructions.
+#[derive(Debug, Clone, Default)]
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    interrupt: Option<&'static AtomicBool>,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger::default()
+    }
+
+    /// Stops runs between instructions whenever `flag` is set, clearing it again. A signal
+    /// handler can set it, as storing to an atomic is signal safe.
+    pub fn set_interrupt(&mut self, flag: &'static AtomicBool) {
+        self.interrupt = Some(flag);
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    /// Returns the new breakpoint's index.
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> usize {
+        self.breakpoints.push(breakpoint);
+        self.breakpoints.len() - 1
+    }
+
+    pub fn remove_breakpoint(&mut self, index: usize) -> Option<Breakpoint> {
+        (index < self.breakpoints.len()).then(|| self.breakpoints.remove(index))
+    }
+
+    /// Returns false if there is no breakpoint at `index`.
+    pub fn set_enabled(&mut self, index: usize, enabled: bool) -> bool {
+        match self.breakpoints.get_mut(index) {
+            Some(breakpoint) => {
+                breakpoint.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // The first breakpoint that applies where the CPU is about to run, counting the hit.
+    fn breakpoint_hit(&mut self, cpu: &CPU) -> Option<usize> {
+        // A halted CPU is not about to run anything.
+        if self.breakpoints.is_empty() || cpu.halted {
+            return None;
+        }
+        let index = self.breakpoints.iter().position(|breakpoint| {
+            breakpoint.enabled
+                && breakpoint.address == cpu.pc
+                && breakpoint.bank.is_none_or(|bank| mapped_bank(&cpu.bus, cpu.pc) == Some(bank))
+                && breakpoint.condition.as_ref().is_none_or(|condition| condition.eval(cpu) != 0)
+        })?;
+        self.breakpoints[index].hits += 1;
+        Some(index)
+    }
+
+    // The first access since the last instruction whose watchpoint condition holds,
+    // counting the hit.
+    fn watchpoint_hit(gameboy: &mut GameBoy) -> Option<WatchHit> {
+        let bus = &mut gameboy.cpu_mut().bus;
+        if !bus.watchpoints.armed() {
+            return None;
+        }
+        let hits = bus.watchpoints.take_hits();
+        let cpu = gameboy.cpu();
+        let hit = hits.into_iter().find(|hit| {
+            let watchpoint = &cpu.bus.watchpoints.list()[hit.watchpoint];
+            watchpoint.condition.as_ref().is_none_or(|condition| condition.eval_access(cpu, hit) != 0)
+        })?;
+        gameboy.cpu_mut().bus.watchpoints.list[hit.watchpoint].hits += 1;
+        Some(hit)
+    }
+
+    /// Runs towards `goal` for at most `limit` instructions, stopping early at any
+    /// breakpoint. The instruction at the starting PC always runs, so a run can leave the
+    /// breakpoint it stopped at.
+    pub fn run(&mut self, gameboy: &mut GameBoy, goal: Goal, limit: u64) -> Stop {
+        let cpu = gameboy.cpu();
+        let start_sp = cpu.sp;
+        let start_frame = cpu.bus.ppu.frames;
+        let start_cycles = cpu.bus.cycles;
+        let opcode = cpu.bus.read_byte(cpu.pc);
+        let return_to = match goal {
+            Goal::StepOver if is_call(opcode) && !cpu.halted => Some(disassemble(&cpu.bus, cpu.pc).next()),
+            _ => None,
+        };
+        let mut left_line = match goal {
+            Goal::Scanline(line) => cpu.bus.ppu.ly != line,
+            _ => false,
+        };
+
+        // Accesses made while running without the debugger are not this run's business.
+        gameboy.cpu_mut().bus.watchpoints.take_hits();
+        let mut steps = 0;
+        for _ in 0..limit {
+            let cpu = gameboy.cpu();
+            let returning = !cpu.halted && is_return(cpu.bus.read_byte(cpu.pc));
+            gameboy.step_instruction();
+            let cpu = gameboy.cpu();
+            let bus = &cpu.bus;
+            if !cpu.halted {
+                steps += 1;
+            }
+
+            let done = match goal {
+                Goal::Continue => false,
+                Goal::Steps(count) => steps >= count,
+                Goal::StepOver => match return_to {
+                    Some(address) => cpu.pc == address && cpu.sp >= start_sp,
+                    None => steps >= 1,
+                },
+                Goal::StepOut => returning && cpu.sp > start_sp,
+                Goal::Frame => {
+                    let limit = if bus.double_speed { CYCLES_PER_FRAME * 2 } else { CYCLES_PER_FRAME };
+                    bus.ppu.frames != start_frame || (!bus.ppu.lcd_enabled() && bus.cycles - start_cycles >= limit)
+                }
+                Goal::Scanline(line) => {
+                    let reached = left_line && bus.ppu.ly == line;
+                    left_line |= bus.ppu.ly != line;
+                    reached
+                }
+            };
+            if let Some(hit) = Debugger::watchpoint_hit(gameboy) {
+                return Stop::Watchpoint(hit);
+            }
+            if let Some(index) = self.breakpoint_hit(gameboy.cpu()) {
+                return Stop::Breakpoint(index);
+            }
+            if done {
+                return Stop::Done;
+            }
+            if self.interrupt.is_some_and(|flag| flag.load(Ordering::Relaxed) && flag.swap(false, Ordering::Relaxed)) {
+                return Stop::Interrupted;
+            }
+        }
+        Stop::Limit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::rom::test_rom;
+    use crate::Model;
+
+    #[test]
+    fn interrupt_stops_a_run_between_instructions() {
+        static FLAG: AtomicBool = AtomicBool::new(false);
+        // JR -2 forever, which only an interrupt can stop.
+        let mut gameboy = GameBoy::new(Model::Dmg, test_rom(&[0x18, 0xFE])).unwrap();
+        let mut debugger = Debugger::new();
+        debugger.set_interrupt(&FLAG);
+        assert_eq!(debugger.run(&mut gameboy, Goal::Continue, 1000), Stop::Limit);
+
+        FLAG.store(true, Ordering::Relaxed);
+        assert_eq!(debugger.run(&mut gameboy, Goal::Continue, u64::MAX), Stop::Interrupted);
+        assert!(!FLAG.load(Ordering::Relaxed));
+        assert_eq!(gameboy.cpu().pc, 0x0100);
+    }
+
+    // Stores 0x42 at 0xC000, reads it back, then loops.
+    fn store_and_load() -> GameBoy {
+        let code = [
+            0x3E, 0x42, 0xEA, 0x00, 0xC0, // ld a, $42; ld [$C000], a
+            0xFA, 0x00, 0xC0, // ld a, [$C000]
+            0x18, 0xFE, // jr @
+        ];
+        GameBoy::new(Model::Dmg, test_rom(&code)).unwrap()
+    }
+
+    fn eval(text: &str, cpu: &CPU) -> i64 {
+        Expr::parse(text).unwrap().eval(cpu)
+    }
+
+    #[test]
+    fn expressions_read_registers_and_memory() {
+        let mut gameboy = store_and_load();
+        let cpu = gameboy.cpu_mut();
+        cpu.registers.a = 0x3C;
+        cpu.registers.set_hl(0xC000);
+        cpu.bus.write_byte(0xC000, 5);
+        assert_eq!(eval("a == 0x3C && [hl] > 4", cpu), 1);
+        assert_eq!(eval("1 + 2 << 1", cpu), 6);
+        assert_eq!(eval("1 | 2 + 1", cpu), 3);
+        assert_eq!(eval("(1 | 2) + 1", cpu), 4);
+        assert_eq!(eval("!nf && ~0 == -1", cpu), 1);
+        assert_eq!(eval("$10 | 1 << 2", cpu), 0x14);
+        assert_eq!(eval("[$C000] - 6", cpu), -1);
+        assert_eq!(eval("pc", cpu), 0x0100);
+        assert_eq!(Expr::parse(" a == 1 ").unwrap().to_string(), "a == 1");
+
+        assert_eq!(Expr::parse("a +").unwrap_err().position, 3);
+        assert_eq!(Expr::parse("q == 1").unwrap_err().message, "unknown register or flag");
+        assert_eq!(Expr::parse("a 1").unwrap_err().message, "unexpected text");
+    }
+
+    #[test]
+    fn watchpoints_stop_after_the_access() {
+        let mut gameboy = store_and_load();
+        gameboy.cpu_mut().bus.watchpoints.add(Watchpoint::new(0xC000, 0xC000, Access::Read));
+        gameboy.cpu_mut().bus.watchpoints.add(Watchpoint::new(0xC000, 0xC0FF, Access::Write));
+        let mut debugger = Debugger::new();
+
+        let write = WatchHit { watchpoint: 1, address: 0xC000, value: 0x42, write: true };
+        assert_eq!(debugger.run(&mut gameboy, Goal::Continue, 100), Stop::Watchpoint(write));
+        assert_eq!(gameboy.cpu().pc, 0x0105);
+        let read = WatchHit { watchpoint: 0, address: 0xC000, value: 0x42, write: false };
+        assert_eq!(debugger.run(&mut gameboy, Goal::Continue, 100), Stop::Watchpoint(read));
+        assert_eq!(gameboy.cpu().pc, 0x0108);
+        assert_eq!(debugger.run(&mut gameboy, Goal::Continue, 100), Stop::Limit);
+        let hits: Vec<_> = gameboy.cpu().bus.watchpoints.list().iter().map(|watchpoint| watchpoint.hits).collect();
+        assert_eq!(hits, [1, 1]);
+    }
+
+    #[test]
+    fn watchpoint_conditions_see_the_access() {
+        for (condition, stops) in [("value == 0x42", true), ("value == 0x43", false), ("address == 0xC001", false)] {
+            let mut gameboy = store_and_load();
+            let mut watchpoint = Watchpoint::new(0xC000, 0xC001, Access::ReadWrite);
+            watchpoint.condition = Some(Expr::parse(condition).unwrap());
+            gameboy.cpu_mut().bus.watchpoints.add(watchpoint);
+            let stop = Debugger::new().run(&mut gameboy, Goal::Continue, 100);
+            assert_eq!(matches!(stop, Stop::Watchpoint(_)), stops, "{}", condition);
+        }
+
+        // Disabled watchpoints see nothing.
+        let mut gameboy = store_and_load();
+        let watchpoints = &mut gameboy.cpu_mut().bus.watchpoints;
+        watchpoints.add(Watchpoint::new(0xC000, 0xC000, Access::ReadWrite));
+        watchpoints.set_enabled(0, false);
+        assert!(!watchpoints.armed());
+        assert_eq!(Debugger::new().run(&mut gameboy, Goal::Continue, 100), Stop::Limit);
+    }
+
+    #[test]
+    fn breakpoints_stop_before_the_instruction() {
+        let mut gameboy = store_and_load();
+        let mut debugger = Debugger::new();
+        let mut conditional = Breakpoint::new(0x0105);
+        conditional.condition = Some(Expr::parse("a == 0").unwrap());
+        debugger.add_breakpoint(conditional);
+        debugger.add_breakpoint(Breakpoint::new(0x0108));
+
+        assert_eq!(debugger.run(&mut gameboy, Goal::Continue, 100), Stop::Breakpoint(1));
+        assert_eq!(gameboy.cpu().pc, 0x0108);
+        assert_eq!(debugger.breakpoints()[0].hits, 0);
+        // The instruction at a breakpoint runs, but JR @ comes straight back to it.
+        assert_eq!(debugger.run(&mut gameboy, Goal::Continue, 100), Stop::Breakpoint(1));
+        assert_eq!(debugger.breakpoints()[1].hits, 2);
+        assert!(debugger.set_enabled(1, false));
+        assert_eq!(debugger.run(&mut gameboy, Goal::Steps(3), 100), Stop::Done);
+        assert_eq!(debugger.run(&mut gameboy, Goal::Continue, 100), Stop::Limit);
+        assert!(debugger.remove_breakpoint(1).is_some());
+        assert!(!debugger.set_enabled(1, true));
+    }
+}
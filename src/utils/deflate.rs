@@ -0,0 +1,370 @@
+use std::fmt;
+
+// Deflate LZ77 parameters. The search is greedy with a bounded chain, so the same input
+// always produces the same bytes.
+const WINDOW_SIZE: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 15;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115,
+    131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] =
+    [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// The CRC-32 used by PNG and zip, over the concatenation of `chunks`.
+pub fn crc32(chunks: &[&[u8]]) -> u32 {
+    let mut table = [0u32; 256];
+    for (n, entry) in table.iter_mut().enumerate() {
+        let mut c = n as u32;
+        for _ in 0..8 {
+            c = if c & 1 != 0 { 0xEDB88320 ^ (c >> 1) } else { c >> 1 };
+        }
+        *entry = c;
+    }
+
+    let mut crc = 0xFFFFFFFF;
+    for chunk in chunks {
+        for &byte in *chunk {
+            crc = table[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+        }
+    }
+    crc ^ 0xFFFFFFFF
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+// Deflate packs bits starting from the least significant one.
+#[derive(Default)]
+struct BitWriter {
+    out: Vec<u8>,
+    buffer: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn bits(&mut self, value: u32, count: u32) {
+        self.buffer |= value << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    // Huffman codes are stored most significant bit first.
+    fn code(&mut self, code: u32, length: u32) {
+        for bit in (0..length).rev() {
+            self.bits((code >> bit) & 1, 1);
+        }
+    }
+
+    fn literal_or_length(&mut self, symbol: u32) {
+        match symbol {
+            0..=143 => self.code(0x30 + symbol, 8),
+            144..=255 => self.code(0x190 + symbol - 144, 9),
+            256..=279 => self.code(symbol - 256, 7),
+            _ => self.code(0xC0 + symbol - 280, 8),
+        }
+    }
+
+    fn copy(&mut self, length: usize, distance: usize) {
+        let index = LENGTH_BASE.iter().rposition(|&base| base as usize <= length).unwrap();
+        self.literal_or_length(257 + index as u32);
+        self.bits((length - LENGTH_BASE[index] as usize) as u32, LENGTH_EXTRA[index] as u32);
+
+        let index = DISTANCE_BASE.iter().rposition(|&base| base as usize <= distance).unwrap();
+        self.code(index as u32, 5);
+        self.bits((distance - DISTANCE_BASE[index] as usize) as u32, DISTANCE_EXTRA[index] as u32);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.buffer as u8);
+        }
+        self.out
+    }
+}
+
+fn hash(data: &[u8], i: usize) -> usize {
+    let key = (data[i] as u32) << 16 | (data[i + 1] as u32) << 8 | data[i + 2] as u32;
+    (key.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+// Links position `i` into the hash chains so later matches can find it.
+fn insert(data: &[u8], i: usize, head: &mut [usize], previous: &mut [usize]) {
+    if i + MIN_MATCH <= data.len() {
+        let h = hash(data, i);
+        previous[i] = head[h];
+        head[h] = i;
+    }
+}
+
+/// A raw deflate stream holding one fixed-Huffman block.
+pub fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut bits = BitWriter::default();
+    bits.bits(1, 1); // final block
+    bits.bits(1, 2); // fixed Huffman codes
+
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut previous = vec![usize::MAX; data.len()];
+
+    let mut i = 0;
+    while i < data.len() {
+        let (mut best_length, mut best_distance) = (0, 0);
+        if i + MIN_MATCH <= data.len() {
+            let limit = (data.len() - i).min(MAX_MATCH);
+            let mut candidate = head[hash(data, i)];
+            let mut chain = 0;
+            while candidate != usize::MAX && i - candidate <= WINDOW_SIZE && chain < MAX_CHAIN {
+                let length = (0..limit).take_while(|&k| data[candidate + k] == data[i + k]).count();
+                if length > best_length {
+                    (best_length, best_distance) = (length, i - candidate);
+                    if length == limit {
+                        break;
+                    }
+                }
+                candidate = previous[candidate];
+                chain += 1;
+            }
+        }
+
+        if best_length >= MIN_MATCH {
+            bits.copy(best_length, best_distance);
+            for position in i..i + best_length {
+                insert(data, position, &mut head, &mut previous);
+            }
+            i += best_length;
+        } else {
+            bits.literal_or_length(data[i] as u32);
+            insert(data, i, &mut head, &mut previous);
+            i += 1;
+        }
+    }
+    bits.literal_or_length(256); // end of block
+    bits.finish()
+}
+
+/// `deflate` wrapped as a zlib stream, as PNG wants it.
+pub fn zlib(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    out.extend_from_slice(&deflate(data));
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+// Order in which dynamic blocks list the code lengths of the code length alphabet.
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InflateError(pub &'static str);
+
+impl fmt::Display for InflateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bad deflate data: {}", self.0)
+    }
+}
+
+impl std::error::Error for InflateError {}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    buffer: u64,
+    count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader { data, position: 0, buffer: 0, count: 0 }
+    }
+
+    fn bits(&mut self, count: u32) -> Result<u32, InflateError> {
+        while self.count < count {
+            let byte = *self.data.get(self.position).ok_or(InflateError("truncated"))?;
+            self.position += 1;
+            self.buffer |= (byte as u64) << self.count;
+            self.count += 8;
+        }
+        let value = (self.buffer & ((1 << count) - 1)) as u32;
+        self.buffer >>= count;
+        self.count -= count;
+        Ok(value)
+    }
+
+    // Stored blocks start on a byte boundary.
+    fn align(&mut self) {
+        let partial = self.count % 8;
+        self.buffer >>= partial;
+        self.count -= partial;
+    }
+}
+
+// A canonical Huffman code, decoded a bit at a time.
+struct Huffman {
+    // How many codes there are of each length.
+    counts: [u16; 16],
+    // Symbols ordered by code.
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0u16; 16];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut offsets = [0u16; 16];
+        for length in 1..15 {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, bits: &mut BitReader) -> Result<u16, InflateError> {
+        // `code` is the bits read so far; `first` the first code of that length, and
+        // `index` where its symbols start.
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for length in 1..16 {
+            code |= bits.bits(1)? as i32;
+            let count = self.counts[length] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(InflateError("bad code"))
+    }
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [8u8; 288];
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+fn dynamic_codes(bits: &mut BitReader) -> Result<(Huffman, Huffman), InflateError> {
+    let literal_count = bits.bits(5)? as usize + 257;
+    let distance_count = bits.bits(5)? as usize + 1;
+    let code_count = bits.bits(4)? as usize + 4;
+
+    let mut code_lengths = [0u8; 19];
+    for &symbol in &CODE_LENGTH_ORDER[..code_count] {
+        code_lengths[symbol] = bits.bits(3)? as u8;
+    }
+    let code = Huffman::new(&code_lengths);
+
+    let total = literal_count + distance_count;
+    let mut lengths = Vec::with_capacity(total);
+    while lengths.len() < total {
+        let (length, repeat) = match code.decode(bits)? {
+            length @ 0..=15 => (length as u8, 1),
+            16 => (*lengths.last().ok_or(InflateError("repeat with nothing before it"))?, 3 + bits.bits(2)?),
+            17 => (0, 3 + bits.bits(3)?),
+            _ => (0, 11 + bits.bits(7)?),
+        };
+        lengths.extend(std::iter::repeat_n(length, repeat as usize));
+    }
+    if lengths.len() > total {
+        return Err(InflateError("code lengths overrun"));
+    }
+    Ok((Huffman::new(&lengths[..literal_count]), Huffman::new(&lengths[literal_count..])))
+}
+
+fn inflate_block(
+    bits: &mut BitReader,
+    out: &mut Vec<u8>,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> Result<(), InflateError> {
+    loop {
+        let index = match literals.decode(bits)? {
+            literal @ 0..=255 => {
+                out.push(literal as u8);
+                continue;
+            }
+            256 => return Ok(()),
+            symbol => symbol as usize - 257,
+        };
+        if index >= LENGTH_BASE.len() {
+            return Err(InflateError("bad length"));
+        }
+        let length = LENGTH_BASE[index] as usize + bits.bits(LENGTH_EXTRA[index] as u32)? as usize;
+
+        let index = distances.decode(bits)? as usize;
+        if index >= DISTANCE_BASE.len() {
+            return Err(InflateError("bad distance"));
+        }
+        let distance = DISTANCE_BASE[index] as usize + bits.bits(DISTANCE_EXTRA[index] as u32)? as usize;
+        if distance > out.len() {
+            return Err(InflateError("distance before the start"));
+        }
+        let start = out.len() - distance;
+        for i in start..start + length {
+            out.push(out[i]);
+        }
+    }
+}
+
+/// Decompresses a raw deflate stream, as stored in zip files.
+pub fn inflate(data: &[u8]) -> Result<Vec<u8>, InflateError> {
+    let mut bits = BitReader::new(data);
+    let mut out = Vec::new();
+    loop {
+        let last = bits.bits(1)? == 1;
+        match bits.bits(2)? {
+            0 => {
+                bits.align();
+                let length = bits.bits(16)?;
+                if bits.bits(16)? != !length & 0xFFFF {
+                    return Err(InflateError("stored block length"));
+                }
+                for _ in 0..length {
+                    out.push(bits.bits(8)? as u8);
+                }
+            }
+            1 => {
+                let (literals, distances) = fixed_codes();
+                inflate_block(&mut bits, &mut out, &literals, &distances)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(&mut bits)?;
+                inflate_block(&mut bits, &mut out, &literals, &distances)?;
+            }
+            _ => return Err(InflateError("reserved block type")),
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use crate::utils::bus::Bus;
+use crate::utils::cpu::{AritmaticTarget, Indirect, Instruction, JumpTest, LoadType, StackTarget, WideTarget};
+
+/// One decoded instruction, in RGBDS syntax.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub text: String,
+}
+
+impl Line {
+    pub fn len(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Where the following instruction starts.
+    pub fn next(&self) -> u16 {
+        self.address.wrapping_add(self.len())
+    }
+}
+
+// Reads operands as the CPU would fetch them, without side effects.
+struct Reader<'a> {
+    bus: &'a Bus,
+    address: u16,
+    bytes: Vec<u8>,
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> u8 {
+        let value = self.bus.read_byte(self.address.wrapping_add(self.bytes.len() as u16));
+        self.bytes.push(value);
+        value
+    }
+
+    fn word(&mut self) -> u16 {
+        let low = self.byte();
+        u16::from_le_bytes([low, self.byte()])
+    }
+
+    fn target(&mut self, target: AritmaticTarget) -> String {
+        match target {
+            AritmaticTarget::A => "a".to_string(),
+            AritmaticTarget::B => "b".to_string(),
+            AritmaticTarget::C => "c".to_string(),
+            AritmaticTarget::D => "d".to_string(),
+            AritmaticTarget::E => "e".to_string(),
+            AritmaticTarget::H => "h".to_string(),
+            AritmaticTarget::L => "l".to_string(),
+            AritmaticTarget::HLI => "[hl]".to_string(),
+            AritmaticTarget::D8 => format!("${:02X}", self.byte()),
+        }
+    }
+
+    fn indirect(&mut self, indirect: Indirect) -> String {
+        match indirect {
+            Indirect::BCIndirect => "[bc]".to_string(),
+            Indirect::DEIndirect => "[de]".to_string(),
+            Indirect::HLIndirectPlus => "[hl+]".to_string(),
+            Indirect::HLIndirectMinus => "[hl-]".to_string(),
+            Indirect::WordIndirect => format!("[${:04X}]", self.word()),
+            Indirect::ByteIndirect => format!("[${:04X}]", 0xFF00 | self.byte() as u16),
+            Indirect::LastByteIndirect => "[$FF00+c]".to_string(),
+        }
+    }
+
+    // The jump target of a relative offset read now.
+    fn relative(&mut self) -> String {
+        let offset = self.byte() as i8;
+        let next = self.address.wrapping_add(self.bytes.len() as u16);
+        format!("${:04X}", next.wrapping_add(offset as u16))
+    }
+}
+
+fn wide(target: WideTarget) -> &'static str {
+    match target {
+        WideTarget::BC => "bc",
+        WideTarget::DE => "de",
+        WideTarget::HL => "hl",
+        WideTarget::SP => "sp",
+    }
+}
+
+fn stack(target: StackTarget) -> &'static str {
+    match target {
+        StackTarget::BC => "bc",
+        StackTarget::DE => "de",
+        StackTarget::HL => "hl",
+        StackTarget::AF => "af",
+    }
+}
+
+// The condition followed by the comma before the operand, or nothing.
+fn condition(test: JumpTest) -> &'static str {
+    match test {
+        JumpTest::NotZero => "nz, ",
+        JumpTest::Zero => "z, ",
+        JumpTest::NotCarry => "nc, ",
+        JumpTest::Carry => "c, ",
+        JumpTest::Always => "",
+    }
+}
+
+/// Decodes the instruction at `address`. Unused opcodes come out as `db`.
+pub fn disassemble(bus: &Bus, address: u16) -> Line {
+    let mut reader = Reader { bus, address, bytes: Vec::new() };
+    let mut opcode = reader.byte();
+    let prefixed = opcode == 0xCB;
+    if prefixed {
+        opcode = reader.byte();
+    }
+    let Some(instruction) = Instruction::from_byte(opcode, prefixed) else {
+        return Line { address, bytes: reader.bytes, text: format!("db ${:02X}", opcode) };
+    };
+
+    let mut unary = |name: &str, target: AritmaticTarget| format!("{} {}", name, reader.target(target));
+    let text = match instruction {
+        Instruction::ADD(target) => format!("add a, {}", reader.target(target)),
+        Instruction::ADC(target) => format!("adc a, {}", reader.target(target)),
+        Instruction::SUB(target) => unary("sub", target),
+        Instruction::SBC(target) => format!("sbc a, {}", reader.target(target)),
+        Instruction::AND(target) => unary("and", target),
+        Instruction::OR(target) => unary("or", target),
+        Instruction::XOR(target) => unary("xor", target),
+        Instruction::CP(target) => unary("cp", target),
+        Instruction::INC(target) => unary("inc", target),
+        Instruction::DEC(target) => unary("dec", target),
+        Instruction::SRL(target) => unary("srl", target),
+        Instruction::RR(target) => unary("rr", target),
+        Instruction::RL(target) => unary("rl", target),
+        Instruction::RRC(target) => unary("rrc", target),
+        Instruction::RLC(target) => unary("rlc", target),
+        Instruction::SRA(target) => unary("sra", target),
+        Instruction::SLA(target) => unary("sla", target),
+        Instruction::SWAP(target) => unary("swap", target),
+        Instruction::BIT(bit, target) => format!("bit {}, {}", bit, reader.target(target)),
+        Instruction::RESET(bit, target) => format!("res {}, {}", bit, reader.target(target)),
+        Instruction::SET(bit, target) => format!("set {}, {}", bit, reader.target(target)),
+        Instruction::ADDHL(target) => format!("add hl, {}", wide(target)),
+        Instruction::ADDSP => format!("add sp, {}", reader.byte() as i8),
+        Instruction::INC16(target) => format!("inc {}", wide(target)),
+        Instruction::DEC16(target) => format!("dec {}", wide(target)),
+        Instruction::CCF => "ccf".to_string(),
+        Instruction::SCF => "scf".to_string(),
+        Instruction::DAA => "daa".to_string(),
+        Instruction::CPL => "cpl".to_string(),
+        Instruction::RRA => "rra".to_string(),
+        Instruction::RLA => "rla".to_string(),
+        Instruction::RRCA => "rrca".to_string(),
+        Instruction::RLCA => "rlca".to_string(),
+        Instruction::JP(test) => format!("jp {}${:04X}", condition(test), reader.word()),
+        Instruction::JPHL => "jp hl".to_string(),
+        Instruction::JR(test) => format!("jr {}{}", condition(test), reader.relative()),
+        Instruction::CALL(test) => format!("call {}${:04X}", condition(test), reader.word()),
+        Instruction::RET(JumpTest::Always) => "ret".to_string(),
+        Instruction::RET(test) => format!("ret {}", condition(test).trim_end_matches(", ")),
+        Instruction::RETI => "reti".to_string(),
+        Instruction::RST(vector) => format!("rst ${:02X}", vector),
+        Instruction::LD(load) => match load {
+            LoadType::Byte(destination, source) => {
+                let destination = reader.target(destination);
+                format!("ld {}, {}", destination, reader.target(source))
+            }
+            LoadType::Word(target) => format!("ld {}, ${:04X}", wide(target), reader.word()),
+            LoadType::AFromIndirect(indirect @ Indirect::ByteIndirect) => {
+                format!("ldh a, {}", reader.indirect(indirect))
+            }
+            LoadType::IndirectFromA(indirect @ Indirect::ByteIndirect) => {
+                format!("ldh {}, a", reader.indirect(indirect))
+            }
+            LoadType::AFromIndirect(indirect) => format!("ld a, {}", reader.indirect(indirect)),
+            LoadType::IndirectFromA(indirect) => format!("ld {}, a", reader.indirect(indirect)),
+            LoadType::IndirectFromSP => format!("ld [${:04X}], sp", reader.word()),
+            LoadType::SPFromHL => "ld sp, hl".to_string(),
+            LoadType::HLFromSPOffset => format!("ld hl, sp{:+}", reader.byte() as i8),
+        },
+        Instruction::PUSH(target) => format!("push {}", stack(target)),
+        Instruction::POP(target) => format!("pop {}", stack(target)),
+        Instruction::NOP => "nop".to_string(),
+        Instruction::HALT => "halt".to_string(),
+        Instruction::STOP => {
+            reader.byte();
+            "stop".to_string()
+        }
+        Instruction::DI => "di".to_string(),
+        Instruction::EI => "ei".to_string(),
+    };
+    Line { address, bytes: reader.bytes, text }
+}
+
+/// `count` instructions from `address` on.
+pub fn disassemble_from(bus: &Bus, address: u16, count: usize) -> Vec<Line> {
+    let mut lines = Vec::with_capacity(count);
+    let mut address = address;
+    for _ in 0..count {
+        let line = disassemble(bus, address);
+        address = line.next();
+        lines.push(line);
+    }
+    lines
+}
+
+/// Up to `before` instructions leading to `address`, then `after` from it. Code cannot be
+/// decoded backwards, so this looks for the furthest start before `address` whose
+/// instructions land on it.
+pub fn disassemble_around(bus: &Bus, address: u16, before: usize, after: usize) -> Vec<Line> {
+    let mut lines: Vec<Line> = Vec::new();
+    for distance in (1..=before as u16 * 3).rev() {
+        let mut decoded = Vec::new();
+        let mut at = address.wrapping_sub(distance);
+        while at != address && address.wrapping_sub(at) <= distance {
+            let line = disassemble(bus, at);
+            at = line.next();
+            decoded.push(line);
+        }
+        if at == address && decoded.len() > lines.len() {
+            let skip = decoded.len().saturating_sub(before);
+            lines = decoded.split_off(skip);
+            if lines.len() == before {
+                break;
+            }
+        }
+    }
+    lines.extend(disassemble_from(bus, address, after));
+    lines
+}
@@ -0,0 +1,303 @@
+use crate::utils::state::{StateError, StateReader, StateWriter};
+
+pub const OAM_SIZE: usize = 0xA0;
+
+const HDMA_BLOCK_SIZE: u16 = 0x10;
+
+/// The bus a DMA source or CPU access is routed over. Accesses on the same bus as a running
+/// OAM DMA conflict with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusKind {
+    External,
+    Video,
+    WorkRam,
+    Internal,
+}
+
+impl BusKind {
+    pub fn of(address: u16, cgb: bool) -> BusKind {
+        match address {
+            0x8000..=0x9FFF => BusKind::Video,
+            // On CGB work RAM sits on its own bus, on DMG it shares the cartridge bus.
+            0xC000..=0xFDFF if cgb => BusKind::WorkRam,
+            0x0000..=0xFDFF => BusKind::External,
+            _ => BusKind::Internal,
+        }
+    }
+}
+
+/// OAM DMA started by writing the source page to 0xFF46.
+///
+/// The transfer begins one M-cycle after the write and then copies one byte per M-cycle for
+/// 160 M-cycles. Writing 0xFF46 again while a transfer runs restarts it; the old transfer keeps
+/// going (and keeps OAM locked) until the new one takes over.
+pub struct OamDma {
+    pub register: u8,
+    pub source: u16,
+    pub index: u8,
+    pub active: bool,
+    pub pending: Option<u16>,
+    pub last_byte: u8,
+}
+
+impl OamDma {
+    pub fn new() -> OamDma {
+        OamDma {
+            register: 0xFF,
+            source: 0,
+            index: 0,
+            active: false,
+            pending: None,
+            last_byte: 0xFF,
+        }
+    }
+
+    pub fn start(&mut self, value: u8) {
+        self.register = value;
+        self.pending = Some((value as u16) << 8);
+    }
+
+    /// Advances the engine by one M-cycle and returns the source address and OAM index of the
+    /// byte to copy during this cycle, if any.
+    pub fn tick(&mut self) -> Option<(u16, usize)> {
+        let transfer = if self.active {
+            let index = self.index as usize;
+            self.index += 1;
+            if self.index as usize == OAM_SIZE {
+                self.active = false;
+            }
+            Some((self.source.wrapping_add(index as u16), index))
+        } else {
+            None
+        };
+
+        if let Some(source) = self.pending.take() {
+            self.source = source;
+            self.index = 0;
+            self.active = true;
+        }
+
+        transfer
+    }
+
+    pub fn source_bus(&self, cgb: bool) -> BusKind {
+        BusKind::of(self.source, cgb)
+    }
+
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.put_u8(self.register);
+        out.put_u16(self.source);
+        out.put_u8(self.index);
+        out.put_bool(self.active);
+        out.put_bool(self.pending.is_some());
+        out.put_u16(self.pending.unwrap_or(0));
+        out.put_u8(self.last_byte);
+    }
+
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.register = input.take_u8()?;
+        self.source = input.take_u16()?;
+        self.index = input.take_u8()?;
+        self.active = input.take_bool()?;
+        let has_pending = input.take_bool()?;
+        let pending = input.take_u16()?;
+        self.pending = if has_pending { Some(pending) } else { None };
+        self.last_byte = input.take_u8()?;
+        Ok(())
+    }
+}
+
+impl Default for OamDma {
+    fn default() -> Self {
+        OamDma::new()
+    }
+}
+
+/// CGB VRAM DMA driven by HDMA1-HDMA5 (0xFF51-0xFF55).
+///
+/// A general-purpose transfer copies everything at once while the CPU is stalled. An HBlank
+/// transfer copies one 16-byte block at the start of each HBlank until it runs out or is
+/// cancelled by writing HDMA5 with bit 7 cleared.
+pub struct Hdma {
+    pub source: u16,
+    pub destination: u16,
+    // Blocks left minus one, as reported in the low bits of HDMA5. Wraps to 0xFF once done.
+    pub length: u8,
+    pub hblank_active: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HdmaStart {
+    General,
+    HBlank,
+    Cancelled,
+}
+
+impl Hdma {
+    pub fn new() -> Hdma {
+        Hdma {
+            source: 0,
+            destination: 0x8000,
+            length: 0xFF,
+            hblank_active: false,
+        }
+    }
+
+    pub fn write_source_high(&mut self, value: u8) {
+        self.source = (self.source & 0x00F0) | ((value as u16) << 8);
+    }
+
+    pub fn write_source_low(&mut self, value: u8) {
+        self.source = (self.source & 0xFF00) | (value as u16 & 0xF0);
+    }
+
+    pub fn write_destination_high(&mut self, value: u8) {
+        self.destination = 0x8000 | (self.destination & 0x00F0) | (((value & 0x1F) as u16) << 8);
+    }
+
+    pub fn write_destination_low(&mut self, value: u8) {
+        self.destination = (self.destination & 0xFF00) | (value as u16 & 0xF0);
+    }
+
+    pub fn write_control(&mut self, value: u8) -> HdmaStart {
+        if self.hblank_active && value & 0x80 == 0 {
+            self.hblank_active = false;
+            return HdmaStart::Cancelled;
+        }
+
+        self.length = value & 0x7F;
+        if value & 0x80 != 0 {
+            self.hblank_active = true;
+            HdmaStart::HBlank
+        } else {
+            HdmaStart::General
+        }
+    }
+
+    pub fn read_control(&self) -> u8 {
+        if self.hblank_active {
+            self.length & 0x7F
+        } else {
+            0x80 | self.length
+        }
+    }
+
+    pub fn blocks_remaining(&self) -> u16 {
+        self.length.wrapping_add(1) as u16
+    }
+
+    /// Returns the (source, destination) pairs of the next block and advances the addresses.
+    pub fn next_block(&mut self) -> impl Iterator<Item = (u16, u16)> {
+        let source = self.source;
+        let destination = self.destination;
+
+        self.source = self.source.wrapping_add(HDMA_BLOCK_SIZE);
+        self.destination = 0x8000 | (self.destination.wrapping_add(HDMA_BLOCK_SIZE) & 0x1FFF);
+        self.length = self.length.wrapping_sub(1);
+        if self.length == 0xFF {
+            self.hblank_active = false;
+        }
+
+        (0..HDMA_BLOCK_SIZE).map(move |offset| {
+            (
+                source.wrapping_add(offset),
+                0x8000 | (destination.wrapping_add(offset) & 0x1FFF),
+            )
+        })
+    }
+
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.put_u16(self.source);
+        out.put_u16(self.destination);
+        out.put_u8(self.length);
+        out.put_bool(self.hblank_active);
+    }
+
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.source = input.take_u16()?;
+        self.destination = input.take_u16()?;
+        self.length = input.take_u8()?;
+        self.hblank_active = input.take_bool()?;
+        Ok(())
+    }
+}
+
+impl Default for Hdma {
+    fn default() -> Self {
+        Hdma::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oam_dma_starts_a_cycle_after_the_write() {
+        let mut dma = OamDma::new();
+        dma.start(0xC1);
+        assert_eq!(dma.tick(), None);
+        assert!(dma.active);
+        for index in 0..OAM_SIZE {
+            assert_eq!(dma.tick(), Some((0xC100 + index as u16, index)));
+        }
+        assert!(!dma.active);
+        assert_eq!(dma.tick(), None);
+    }
+
+    #[test]
+    fn restart_keeps_the_old_transfer_running_for_a_cycle() {
+        let mut dma = OamDma::new();
+        dma.start(0xC1);
+        for _ in 0..11 {
+            dma.tick();
+        }
+        dma.start(0xD2);
+        assert_eq!(dma.register, 0xD2);
+        assert_eq!(dma.tick(), Some((0xC10A, 10)));
+        assert_eq!(dma.tick(), Some((0xD200, 0)));
+        assert_eq!(dma.source_bus(true), BusKind::WorkRam);
+        assert_eq!(dma.source_bus(false), BusKind::External);
+    }
+
+    #[test]
+    fn hdma_addresses_drop_the_low_nibble_and_stay_in_vram() {
+        let mut hdma = Hdma::new();
+        hdma.write_source_high(0x12);
+        hdma.write_source_low(0x3F);
+        hdma.write_destination_high(0xFF);
+        hdma.write_destination_low(0xF7);
+        assert_eq!((hdma.source, hdma.destination), (0x1230, 0x9FF0));
+
+        hdma.write_control(0x01);
+        let block: Vec<_> = hdma.next_block().collect();
+        assert_eq!(block[0], (0x1230, 0x9FF0));
+        assert_eq!(block[15], (0x123F, 0x9FFF));
+        assert_eq!(hdma.next_block().next(), Some((0x1240, 0x8000)));
+    }
+
+    #[test]
+    fn hdma5_reads_back_the_blocks_left() {
+        let mut hdma = Hdma::new();
+        assert_eq!(hdma.read_control(), 0xFF);
+
+        assert_eq!(hdma.write_control(0x82), HdmaStart::HBlank);
+        assert_eq!(hdma.read_control(), 0x02);
+        assert_eq!(hdma.blocks_remaining(), 3);
+        for left in [0x01, 0x00, 0xFF] {
+            let _ = hdma.next_block();
+            assert_eq!(hdma.read_control(), left);
+        }
+        assert!(!hdma.hblank_active);
+
+        // Cancelling keeps the count and sets bit 7.
+        hdma.write_control(0x85);
+        let _ = hdma.next_block();
+        assert_eq!(hdma.write_control(0x00), HdmaStart::Cancelled);
+        assert_eq!(hdma.read_control(), 0x84);
+
+        // Bit 7 clear with nothing running starts a general transfer.
+        assert_eq!(hdma.write_control(0x03), HdmaStart::General);
+        assert_eq!(hdma.blocks_remaining(), 4);
+    }
+}
@@ -0,0 +1,215 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::utils::apu::Channel;
+use crate::utils::audio::{AudioOutput, DEFAULT_SAMPLE_RATE};
+use crate::utils::bus::Model;
+use crate::utils::gbs::{Gbs, GbsError};
+use crate::utils::rom::Cartridge;
+use crate::utils::vgm::VgmLogger;
+use crate::utils::wav::WavWriter;
+use crate::GameBoy;
+
+// Instructions to run between draining the audio buffers.
+const STEPS_PER_CHUNK: usize = 4096;
+
+/// Loads a ROM, or a GBS rip wrapped in a player cartridge, and picks the model to run it on.
+pub fn load_source(path: &Path, track: Option<u8>) -> Result<(Model, Cartridge), Box<dyn Error>> {
+    let file = fs::read(path)?;
+    if file.starts_with(b"GBS") {
+        let gbs = Gbs::parse(&file)?;
+        let track = track.unwrap_or(gbs.first_song);
+        if !(1..=gbs.song_count).contains(&track) {
+            return Err(GbsError::NoSuchTrack { track, count: gbs.song_count }.into());
+        }
+        return Ok((Model::Dmg, gbs.cartridge(track - 1)?));
+    }
+
+    let cartridge = Cartridge::new(file)?;
+    let model = if cartridge.header.supports_cgb() { Model::Cgb } else { Model::Dmg };
+    Ok((model, cartridge))
+}
+
+/// Renders a ROM or a GBS track to WAV as fast as the host can run it.
+pub struct WavExport {
+    pub source: PathBuf,
+    pub output: PathBuf,
+    pub seconds: f64,
+    /// GBS song to play, counted from 1. Defaults to the rip's first song.
+    pub track: Option<u8>,
+    /// Also write pulse1/pulse2/wave/noise stems next to `output`.
+    pub stems: bool,
+    pub sample_rate: u32,
+}
+
+impl WavExport {
+    pub fn new(source: PathBuf, output: PathBuf) -> WavExport {
+        WavExport {
+            source,
+            output,
+            seconds: 60.0,
+            track: None,
+            stems: false,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+        }
+    }
+
+    pub fn run(&self) -> Result<(), Box<dyn Error>> {
+        let (model, cartridge) = load_source(&self.source, self.track)?;
+        let mut gameboy = GameBoy::from_cartridge(model, cartridge);
+        gameboy.set_sample_rate(self.sample_rate);
+        if self.stems {
+            gameboy.cpu_mut().bus.stems = Some(Box::new(
+                [0, 1, 2, 3].map(|_| AudioOutput::new(model, self.sample_rate)),
+            ));
+        }
+
+        let mut mix = WavWriter::create(&self.output, self.sample_rate, 2)?;
+        let mut stems = Vec::new();
+        if self.stems {
+            for channel in Channel::ALL {
+                let path = stem_path(&self.output, channel);
+                stems.push(WavWriter::create(path, self.sample_rate, 2)?);
+            }
+        }
+
+        let total = (self.seconds * self.sample_rate as f64) as usize * 2;
+        let mut written = 0;
+        // Each stem resamples on its own, so a chunk can end a few samples off the mix.
+        // What runs ahead waits here for the next chunk, and the export goes on until the
+        // stems have caught up, so every file ends up as long as the mix.
+        let mut pending = vec![Vec::new(); stems.len()];
+        let mut stems_written = vec![0; stems.len()];
+        while written < total || stems_written.iter().any(|&count| count < total) {
+            for _ in 0..STEPS_PER_CHUNK {
+                gameboy.step_instruction();
+            }
+
+            let samples = gameboy.audio_samples();
+            let count = samples.len().min(total - written);
+            mix.write_samples(&samples[..count])?;
+            written += count;
+            if let Some(outputs) = &mut gameboy.cpu_mut().bus.stems {
+                for (i, output) in outputs.iter_mut().enumerate() {
+                    pending[i].extend(output.read_i16());
+                    let count = pending[i].len().min(written - stems_written[i]);
+                    stems[i].write_samples(&pending[i][..count])?;
+                    pending[i].drain(..count);
+                    stems_written[i] += count;
+                }
+            }
+        }
+
+        mix.finish()?;
+        for writer in stems {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+}
+
+/// `song.wav` becomes `song.pulse1.wav` and so on.
+pub fn stem_path(output: &Path, channel: Channel) -> PathBuf {
+    let name = match channel {
+        Channel::Pulse1 => "pulse1",
+        Channel::Pulse2 => "pulse2",
+        Channel::Wave => "wave",
+        Channel::Noise => "noise",
+    };
+    let stem = output.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    output.with_file_name(format!("{}.{}.wav", stem, name))
+}
+
+/// Logs a ROM's or GBS track's sound register writes to a VGM file.
+pub struct VgmExport {
+    pub source: PathBuf,
+    pub output: PathBuf,
+    pub seconds: f64,
+    pub track: Option<u8>,
+    /// Seconds in at which the loop starts, if the music loops.
+    pub loop_at: Option<f64>,
+}
+
+impl VgmExport {
+    pub fn new(source: PathBuf, output: PathBuf) -> VgmExport {
+        VgmExport { source, output, seconds: 60.0, track: None, loop_at: None }
+    }
+
+    pub fn run(&self) -> Result<(), Box<dyn Error>> {
+        let (model, cartridge) = load_source(&self.source, self.track)?;
+        let game = cartridge.header.title.clone();
+        let mut gameboy = GameBoy::from_cartridge(model, cartridge);
+        let bus = &mut gameboy.cpu_mut().bus;
+        let mut logger = VgmLogger::new(&bus.apu);
+        logger.game = game;
+        bus.vgm = Some(logger);
+
+        let mut loop_at = self.loop_at;
+        loop {
+            gameboy.step_instruction();
+            let logger = gameboy.cpu_mut().bus.vgm.as_mut().unwrap();
+            let elapsed = logger.elapsed();
+            if loop_at.is_some_and(|at| elapsed >= at) {
+                logger.mark_loop();
+                loop_at = None;
+            }
+            if elapsed >= self.seconds {
+                break;
+            }
+        }
+
+        if let Some(logger) = gameboy.cpu_mut().bus.vgm.take() {
+            logger.save(&self.output)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gbs_track_must_be_in_range() {
+        // Three songs, loaded at 0x0400, with a single RET as the code.
+        let mut file = vec![0; 0x71];
+        file[0..4].copy_from_slice(b"GBS\x01");
+        file[0x04] = 3;
+        file[0x05] = 1;
+        file[0x06..0x08].copy_from_slice(&0x0400u16.to_le_bytes());
+        file[0x70] = 0xC9;
+        let path = std::env::temp_dir().join(format!("rusty_boy_track_test_{}.gbs", std::process::id()));
+        fs::write(&path, &file).unwrap();
+
+        assert!(load_source(&path, None).is_ok());
+        assert!(load_source(&path, Some(3)).is_ok());
+        for track in [0, 4] {
+            let err = load_source(&path, Some(track)).err().unwrap();
+            assert_eq!(err.to_string(), format!("GBS file has no track {}; pick one from 1 to 3", track));
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stems_are_as_long_as_the_mix() {
+        let dir = std::env::temp_dir();
+        let rom = dir.join(format!("rusty_boy_stems_test_{}.gb", std::process::id()));
+        fs::write(&rom, crate::utils::rom::test_rom(&[0x18, 0xFE])).unwrap();
+        let mut export = WavExport::new(rom.clone(), rom.with_extension("wav"));
+        export.seconds = 0.5;
+        export.stems = true;
+        export.sample_rate = 44_100;
+        export.run().unwrap();
+
+        let mix = fs::metadata(&export.output).unwrap().len();
+        assert_eq!(mix, 44 + 22_050 * 4);
+        for channel in Channel::ALL {
+            let path = stem_path(&export.output, channel);
+            assert_eq!(fs::metadata(&path).unwrap().len(), mix, "{:?}", channel);
+            fs::remove_file(path).unwrap();
+        }
+        fs::remove_file(&export.output).unwrap();
+        fs::remove_file(&rom).unwrap();
+    }
+}
@@ -0,0 +1,179 @@
+use std::fmt;
+
+use crate::utils::rom::{Cartridge, CartridgeError};
+
+const HEADER_SIZE: usize = 0x70;
+const ROM_BANK_SIZE: usize = 0x4000;
+
+// Where the player stub lives: the cartridge entry point jumps here, clear of the header.
+const DRIVER_ADDRESS: u16 = 0x0150;
+
+#[derive(Debug)]
+pub enum GbsError {
+    TooSmall(usize),
+    BadMagic,
+    UnsupportedVersion(u8),
+    BadLoadAddress(u16),
+    /// A track number, counted from 1, past the rip's song count.
+    NoSuchTrack { track: u8, count: u8 },
+    Cartridge(CartridgeError),
+}
+
+impl fmt::Display for GbsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GbsError::TooSmall(len) => write!(f, "GBS file is {} bytes, too small for a header", len),
+            GbsError::BadMagic => write!(f, "not a GBS file"),
+            GbsError::UnsupportedVersion(version) => write!(f, "unsupported GBS version {}", version),
+            GbsError::BadLoadAddress(address) => {
+                write!(f, "GBS load address 0x{:04X} is outside 0x0400-0x7FFF", address)
+            }
+            GbsError::NoSuchTrack { count: 0, .. } => write!(f, "GBS file has no tracks"),
+            GbsError::NoSuchTrack { track, count } => {
+                write!(f, "GBS file has no track {}; pick one from 1 to {}", track, count)
+            }
+            GbsError::Cartridge(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for GbsError {}
+
+impl From<CartridgeError> for GbsError {
+    fn from(err: CartridgeError) -> GbsError {
+        GbsError::Cartridge(err)
+    }
+}
+
+/// A Game Boy Sound System rip: a game's music driver and data without the game.
+#[derive(Debug, Clone)]
+pub struct Gbs {
+    pub song_count: u8,
+    /// The song to play when none is chosen, counted from 1.
+    pub first_song: u8,
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    pub stack_pointer: u16,
+    pub timer_modulo: u8,
+    pub timer_control: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+    data: Vec<u8>,
+}
+
+fn text(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .take_while(|&&byte| byte != 0)
+        .map(|&byte| byte as char)
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
+
+impl Gbs {
+    pub fn parse(file: &[u8]) -> Result<Gbs, GbsError> {
+        if file.len() < HEADER_SIZE {
+            return Err(GbsError::TooSmall(file.len()));
+        }
+        if &file[0..3] != b"GBS" {
+            return Err(GbsError::BadMagic);
+        }
+        if file[3] != 1 {
+            return Err(GbsError::UnsupportedVersion(file[3]));
+        }
+
+        let word = |offset: usize| u16::from_le_bytes([file[offset], file[offset + 1]]);
+        let load_address = word(0x06);
+        if !(0x0400..0x8000).contains(&load_address) {
+            return Err(GbsError::BadLoadAddress(load_address));
+        }
+
+        Ok(Gbs {
+            song_count: file[0x04],
+            first_song: file[0x05].max(1),
+            load_address,
+            init_address: word(0x08),
+            play_address: word(0x0A),
+            stack_pointer: word(0x0C),
+            timer_modulo: file[0x0E],
+            timer_control: file[0x0F],
+            title: text(&file[0x10..0x30]),
+            author: text(&file[0x30..0x50]),
+            copyright: text(&file[0x50..0x70]),
+            data: file[HEADER_SIZE..].to_vec(),
+        })
+    }
+
+    /// Whether `play` runs from the timer interrupt rather than VBlank.
+    pub fn uses_timer(&self) -> bool {
+        self.timer_control & 0x04 != 0
+    }
+
+    /// Builds an MBC5 cartridge that plays `song` (counted from 0): a small driver calls
+    /// `init` once, then `play` from the VBlank or timer interrupt forever.
+    pub fn cartridge(&self, song: u8) -> Result<Cartridge, GbsError> {
+        let length = self.load_address as usize + self.data.len();
+        let banks = length.div_ceil(ROM_BANK_SIZE).max(2).next_power_of_two();
+        let mut rom = vec![0xFF; banks * ROM_BANK_SIZE];
+        rom[self.load_address as usize..length].copy_from_slice(&self.data);
+
+        // RST vectors go to the rip's own table at the load address.
+        for rst in (0x00..0x40).step_by(8) {
+            let target = self.load_address + rst as u16;
+            rom[rst..rst + 3].copy_from_slice(&[0xC3, target as u8, (target >> 8) as u8]);
+        }
+
+        // VBlank and timer handlers: CALL play; RETI.
+        let [play_low, play_high] = self.play_address.to_le_bytes();
+        for vector in [0x40, 0x50] {
+            rom[vector..vector + 4].copy_from_slice(&[0xCD, play_low, play_high, 0xD9]);
+        }
+
+        // Entry point: NOP; JP driver.
+        let [driver_low, driver_high] = DRIVER_ADDRESS.to_le_bytes();
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, driver_low, driver_high]);
+
+        let [sp_low, sp_high] = self.stack_pointer.to_le_bytes();
+        let [init_low, init_high] = self.init_address.to_le_bytes();
+        let interrupts = if self.uses_timer() { 0x04 } else { 0x01 };
+        #[rustfmt::skip]
+        let driver = [
+            0xF3,                         // DI
+            0x31, sp_low, sp_high,        // LD SP, stack
+            0x3E, 0x0A,                   // LD A, 0x0A
+            0xEA, 0x00, 0x00,             // LD (0x0000), A ; enable cartridge RAM
+            0x3E, song,                   // LD A, song
+            0xCD, init_low, init_high,    // CALL init
+            0x3E, self.timer_modulo,      // LD A, TMA
+            0xE0, 0x06,                   // LDH (0x06), A
+            0x3E, self.timer_control,     // LD A, TAC
+            0xE0, 0x07,                   // LDH (0x07), A
+            0x3E, interrupts,             // LD A, interrupts
+            0xE0, 0xFF,                   // LDH (0xFF), A
+            0xAF,                         // XOR A
+            0xE0, 0x0F,                   // LDH (0x0F), A
+            0xFB,                         // EI
+            0x76,                         // HALT
+            0x18, 0xFD,                   // JR -3
+        ];
+        let start = DRIVER_ADDRESS as usize;
+        rom[start..start + driver.len()].copy_from_slice(&driver);
+
+        // MBC5 with RAM, so banked rips and ones that keep state at 0xA000 both work.
+        rom[0x134..0x144].fill(0);
+        let title = self.title.as_bytes();
+        let title_len = title.len().min(0x10);
+        rom[0x134..0x134 + title_len].copy_from_slice(&title[..title_len]);
+        rom[0x147] = 0x1A;
+        rom[0x148] = (banks.trailing_zeros() - 1) as u8;
+        rom[0x149] = 0x02;
+        rom[0x14D] = rom[0x134..0x14D]
+            .iter()
+            .fold(0u8, |sum, &byte| sum.wrapping_sub(byte).wrapping_sub(1));
+
+        Ok(Cartridge::new(rom)?)
+    }
+}
@@ -0,0 +1,535 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+use crate::utils::debugger::{Access, Breakpoint, Debugger, Goal, Stop, Watchpoint};
+use crate::GameBoy;
+
+/// What the client is told about the registers: the four pairs, then SP and PC, each
+/// 16 bits and sent little-endian. GDB has no SM83 architecture, so there is none to name.
+pub const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.rusty-boy.sm83.core">
+    <reg name="af" bitsize="16" type="uint16" regnum="0"/>
+    <reg name="bc" bitsize="16" type="uint16"/>
+    <reg name="de" bitsize="16" type="uint16"/>
+    <reg name="hl" bitsize="16" type="uint16"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+const REGISTERS: usize = 6;
+// Instructions run between checks for an interrupt from the client.
+const CHUNK: u64 = 20_000;
+const PACKET_SIZE: usize = 0x1000;
+
+// Signals in stop replies.
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+fn parse_hex_bytes(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+// "ADDR,LEN" as used by m, M and the breakpoint packets.
+fn parse_range(text: &str) -> Option<(u16, u32)> {
+    let (address, length) = text.split_once(',')?;
+    Some((parse_hex(address)? as u16, parse_hex(length)?))
+}
+
+enum Incoming {
+    Packet(String),
+    /// The client pressed Ctrl-C.
+    Interrupt,
+}
+
+// Packet framing over the socket: `$data#checksum`, acknowledged with + or - until the
+// client turns that off.
+struct Connection {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    acks: bool,
+}
+
+impl Connection {
+    // Reads more bytes into the buffer. Returns false at end of stream.
+    fn fill(&mut self) -> io::Result<bool> {
+        let mut chunk = [0; 1024];
+        let read = self.stream.read(&mut chunk)?;
+        self.buffer.extend_from_slice(&chunk[..read]);
+        Ok(read > 0)
+    }
+
+    // The next packet or interrupt in the buffer, discarding acks and noise before it.
+    fn parse(&mut self) -> io::Result<Option<Incoming>> {
+        loop {
+            let Some(start) = self.buffer.iter().position(|&byte| byte == b'$' || byte == 0x03) else {
+                self.buffer.clear();
+                return Ok(None);
+            };
+            if self.buffer[start] == 0x03 {
+                self.buffer.drain(..=start);
+                return Ok(Some(Incoming::Interrupt));
+            }
+            let Some(end) = self.buffer[start..].iter().position(|&byte| byte == b'#').map(|end| start + end) else {
+                self.buffer.drain(..start);
+                return Ok(None);
+            };
+            if self.buffer.len() < end + 3 {
+                self.buffer.drain(..start);
+                return Ok(None);
+            }
+            let data = self.buffer[start + 1..end].to_vec();
+            let sum = std::str::from_utf8(&self.buffer[end + 1..end + 3]).ok().and_then(|sum| u8::from_str_radix(sum, 16).ok());
+            self.buffer.drain(..end + 3);
+            if !self.acks {
+                return Ok(Some(Incoming::Packet(String::from_utf8_lossy(&data).into_owned())));
+            }
+            if sum == Some(checksum(&data)) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(Incoming::Packet(String::from_utf8_lossy(&data).into_owned())));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    // Blocks for the next packet or interrupt. None once the client has gone.
+    fn receive(&mut self) -> io::Result<Option<Incoming>> {
+        loop {
+            if let Some(incoming) = self.parse()? {
+                return Ok(Some(incoming));
+            }
+            if !self.fill()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    // Whether the client asked to stop while the machine runs. Packets other than the
+    // interrupt are not expected then and are dropped.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let filled = match self.fill() {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            Err(err) => Err(err),
+        };
+        self.stream.set_nonblocking(false)?;
+        filled?;
+        let interrupted = self.buffer.contains(&0x03);
+        self.buffer.clear();
+        Ok(interrupted)
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let mut packet = Vec::with_capacity(data.len() + 4);
+        packet.push(b'$');
+        // Bytes that mean something in the framing are escaped.
+        for &byte in data.as_bytes() {
+            match byte {
+                b'#' | b'$' | b'}' | b'*' => packet.extend_from_slice(&[b'}', byte ^ 0x20]),
+                _ => packet.push(byte),
+            }
+        }
+        let sum = checksum(&packet[1..]);
+        packet.extend_from_slice(format!("#{:02x}", sum).as_bytes());
+        loop {
+            self.stream.write_all(&packet)?;
+            if !self.acks {
+                return Ok(());
+            }
+            // Resend on -, give up if the client has gone.
+            let mut ack = [0];
+            loop {
+                if self.stream.read(&mut ack)? == 0 {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                }
+                match ack[0] {
+                    b'+' => return Ok(()),
+                    b'-' => break,
+                    // A packet sent without waiting for the ack; keep it for later.
+                    byte => self.buffer.push(byte),
+                }
+            }
+        }
+    }
+}
+
+/// A GDB remote serial protocol server for one machine. Breakpoints of both kinds are
+/// kept by the debugger rather than patched into memory, and watchpoints go on the bus.
+pub struct GdbStub {
+    gameboy: GameBoy,
+    debugger: Debugger,
+}
+
+impl GdbStub {
+    pub fn new(gameboy: GameBoy) -> GdbStub {
+        GdbStub { gameboy, debugger: Debugger::new() }
+    }
+
+    pub fn gameboy(&self) -> &GameBoy {
+        &self.gameboy
+    }
+
+    pub fn into_gameboy(self) -> GameBoy {
+        self.gameboy
+    }
+
+    /// Serves a client until it detaches, kills the target or disconnects. Returns true
+    /// if it asked to kill.
+    pub fn serve(&mut self, stream: TcpStream) -> io::Result<bool> {
+        stream.set_nodelay(true)?;
+        let mut connection = Connection { stream, buffer: Vec::new(), acks: true };
+        while let Some(incoming) = connection.receive()? {
+            let Incoming::Packet(packet) = incoming else {
+                // Already stopped; say so again.
+                connection.send(&format!("S{:02x}", SIGINT))?;
+                continue;
+            };
+            match packet.as_str() {
+                "k" => return Ok(true),
+                "D" => {
+                    connection.send("OK")?;
+                    return Ok(false);
+                }
+                "QStartNoAckMode" => {
+                    connection.send("OK")?;
+                    connection.acks = false;
+                }
+                _ if packet.starts_with('c') || packet.starts_with('s') => {
+                    let reply = self.resume(&mut connection, &packet)?;
+                    connection.send(&reply)?;
+                }
+                _ => {
+                    let reply = self.answer(&packet);
+                    connection.send(&reply)?;
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    // c [ADDR] and s [ADDR]: runs, then describes why the machine stopped.
+    fn resume(&mut self, connection: &mut Connection, packet: &str) -> io::Result<String> {
+        if let Some(address) = parse_hex(&packet[1..]) {
+            self.gameboy.cpu_mut().pc = address as u16;
+        }
+        if packet.starts_with('s') {
+            let stop = self.debugger.run(&mut self.gameboy, Goal::Steps(1), CHUNK);
+            return Ok(self.stop_reply(stop));
+        }
+        loop {
+            let stop = self.debugger.run(&mut self.gameboy, Goal::Continue, CHUNK);
+            if stop != Stop::Limit {
+                return Ok(self.stop_reply(stop));
+            }
+            if connection.interrupted()? {
+                return Ok(format!("S{:02x}", SIGINT));
+            }
+        }
+    }
+
+    fn stop_reply(&self, stop: Stop) -> String {
+        match stop {
+            Stop::Watchpoint(hit) => {
+                let kind = match self.gameboy.cpu().bus.watchpoints.list()[hit.watchpoint].access {
+                    Access::Write => "watch",
+                    Access::Read => "rwatch",
+                    Access::ReadWrite => "awatch",
+                };
+                format!("T{:02x}{}:{:04x};", SIGTRAP, kind, hit.address)
+            }
+            Stop::Breakpoint(_) | Stop::Done | Stop::Limit => format!("S{:02x}", SIGTRAP),
+            Stop::Interrupted => format!("S{:02x}", SIGINT),
+        }
+    }
+
+    fn register(&self, number: usize) -> u16 {
+        let cpu = self.gameboy.cpu();
+        match number {
+            0 => cpu.registers.get_af(),
+            1 => cpu.registers.get_bc(),
+            2 => cpu.registers.get_de(),
+            3 => cpu.registers.get_hl(),
+            4 => cpu.sp,
+            _ => cpu.pc,
+        }
+    }
+
+    fn set_register(&mut self, number: usize, value: u16) {
+        let cpu = self.gameboy.cpu_mut();
+        match number {
+            0 => cpu.registers.set_af(value),
+            1 => cpu.registers.set_bc(value),
+            2 => cpu.registers.set_de(value),
+            3 => cpu.registers.set_hl(value),
+            4 => cpu.sp = value,
+            _ => cpu.pc = value,
+        }
+    }
+
+    // Z and z: breakpoints of either kind at ADDR, or watchpoints over LEN bytes from it.
+    fn set_point(&mut self, kind: char, range: &str, insert: bool) -> Option<()> {
+        let (address, length) = parse_range(range.split(';').next()?)?;
+        let access = match kind {
+            '0' | '1' => None,
+            '2' => Some(Access::Write),
+            '3' => Some(Access::Read),
+            '4' => Some(Access::ReadWrite),
+            _ => return None,
+        };
+        match access {
+            None => {
+                let found = self.debugger.breakpoints().iter().position(|breakpoint| breakpoint.address == address);
+                match (insert, found) {
+                    (true, None) => {
+                        self.debugger.add_breakpoint(Breakpoint::new(address));
+                    }
+                    (false, Some(index)) => {
+                        self.debugger.remove_breakpoint(index);
+                    }
+                    _ => {}
+                }
+            }
+            Some(access) => {
+                let end = address.wrapping_add((length.max(1) - 1) as u16);
+                let watchpoints = &mut self.gameboy.cpu_mut().bus.watchpoints;
+                if insert {
+                    watchpoints.add(Watchpoint::new(address, end.max(address), access));
+                } else {
+                    let found = watchpoints.list().iter().position(|watchpoint| {
+                        watchpoint.start == address && watchpoint.end == end.max(address) && watchpoint.access == access
+                    });
+                    watchpoints.remove(found?);
+                }
+            }
+        }
+        Some(())
+    }
+
+    // The reply to any packet that does not run the machine. Empty means unsupported.
+    fn answer(&mut self, packet: &str) -> String {
+        let error = || "E01".to_string();
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => {
+                let bytes: Vec<u8> = (0..REGISTERS).flat_map(|number| self.register(number).to_le_bytes()).collect();
+                hex(&bytes)
+            }
+            "G" => match parse_hex_bytes(args) {
+                Some(bytes) if bytes.len() == REGISTERS * 2 => {
+                    for (number, value) in bytes.chunks(2).enumerate() {
+                        self.set_register(number, u16::from_le_bytes([value[0], value[1]]));
+                    }
+                    "OK".to_string()
+                }
+                _ => error(),
+            },
+            "p" => match parse_hex(args) {
+                Some(number) if (number as usize) < REGISTERS => hex(&self.register(number as usize).to_le_bytes()),
+                _ => error(),
+            },
+            "P" => {
+                let parsed = args.split_once('=').and_then(|(number, value)| {
+                    let number = parse_hex(number)? as usize;
+                    let bytes = parse_hex_bytes(value)?;
+                    (number < REGISTERS && bytes.len() == 2).then(|| (number, u16::from_le_bytes([bytes[0], bytes[1]])))
+                });
+                match parsed {
+                    Some((number, value)) => {
+                        self.set_register(number, value);
+                        "OK".to_string()
+                    }
+                    None => error(),
+                }
+            }
+            "m" => match parse_range(args) {
+                Some((address, length)) => {
+                    let bus = &self.gameboy.cpu().bus;
+                    let length = length.min(PACKET_SIZE as u32 / 2) as u16;
+                    let bytes: Vec<u8> = (0..length).map(|i| bus.read_byte(address.wrapping_add(i))).collect();
+                    hex(&bytes)
+                }
+                None => error(),
+            },
+            "M" => {
+                let parsed = args
+                    .split_once(':')
+                    .and_then(|(range, data)| Some((parse_range(range)?, parse_hex_bytes(data)?)));
+                match parsed {
+                    Some(((address, length), bytes)) if bytes.len() == length as usize => {
+                        let bus = &mut self.gameboy.cpu_mut().bus;
+                        for (i, &byte) in bytes.iter().enumerate() {
+                            bus.write_byte(address.wrapping_add(i as u16), byte);
+                        }
+                        "OK".to_string()
+                    }
+                    _ => error(),
+                }
+            }
+            "Z" | "z" => {
+                let Some((kind, range)) = args.split_once(',') else { return error() };
+                let kind = kind.chars().next().unwrap_or(' ');
+                match self.set_point(kind, range, command == "Z") {
+                    Some(()) => "OK".to_string(),
+                    // An unknown kind is unsupported rather than an error.
+                    None if !matches!(kind, '0'..='4') => String::new(),
+                    None => error(),
+                }
+            }
+            "H" | "T" => "OK".to_string(),
+            "q" => self.query(args),
+            _ => String::new(),
+        }
+    }
+
+    fn query(&self, query: &str) -> String {
+        if query.starts_with("Supported") {
+            return format!("PacketSize={:x};qXfer:features:read+;QStartNoAckMode+", PACKET_SIZE);
+        }
+        if let Some(range) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            let Some((offset, length)) = parse_range(range).map(|(offset, length)| (offset as usize, length as usize)) else {
+                return "E01".to_string();
+            };
+            let rest = TARGET_XML.get(offset.min(TARGET_XML.len())..).unwrap_or_default();
+            return match rest.get(..length) {
+                Some(part) if length < rest.len() => format!("m{}", part),
+                _ => format!("l{}", rest),
+            };
+        }
+        match query {
+            "Attached" => "1".to_string(),
+            "C" => "QC1".to_string(),
+            "fThreadInfo" => "m1".to_string(),
+            "sThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::rom::test_rom;
+    use crate::Model;
+    use std::net::TcpListener;
+    use std::thread;
+
+    // A client that acknowledges everything, as GDB does before no-ack mode.
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn read_byte(&mut self) -> u8 {
+            let mut byte = [0];
+            self.stream.read_exact(&mut byte).unwrap();
+            byte[0]
+        }
+
+        fn send(&mut self, packet: &str) {
+            let framed = format!("${}#{:02x}", packet, checksum(packet.as_bytes()));
+            self.stream.write_all(framed.as_bytes()).unwrap();
+            assert_eq!(self.read_byte(), b'+', "{} was not acknowledged", packet);
+        }
+
+        fn reply(&mut self) -> String {
+            assert_eq!(self.read_byte(), b'$');
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte() {
+                    b'#' => break,
+                    b'}' => data.push(self.read_byte() ^ 0x20),
+                    byte => data.push(byte),
+                }
+            }
+            let sum = [self.read_byte(), self.read_byte()];
+            assert_eq!(std::str::from_utf8(&sum).unwrap(), format!("{:02x}", checksum(&data)));
+            self.stream.write_all(b"+").unwrap();
+            String::from_utf8(data).unwrap()
+        }
+
+        fn ask(&mut self, packet: &str) -> String {
+            self.send(packet);
+            self.reply()
+        }
+    }
+
+    #[test]
+    fn scripted_session_over_loopback() {
+        let code = [
+            0x06, 0x05, // 0100: ld b, 5
+            0x05, // 0102: dec b
+            0x20, 0xFD, // 0103: jr nz, $0102
+            0x3E, 0x42, // 0105: ld a, $42
+            0xEA, 0x00, 0xC0, // 0107: ld [$C000], a
+            0x18, 0xFE, // 010A: jr @
+        ];
+        let gameboy = GameBoy::new(Model::Dmg, test_rom(&code)).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut stub = GdbStub::new(gameboy);
+            let (stream, _) = listener.accept().unwrap();
+            let killed = stub.serve(stream).unwrap();
+            (killed, stub.into_gameboy())
+        });
+        let stream = TcpStream::connect(address).unwrap();
+        stream.set_nodelay(true).unwrap();
+        let mut client = Client { stream };
+
+        assert!(client.ask("qSupported:swbreak+;hwbreak+").contains("qXfer:features:read+"));
+        let xml = client.ask("qXfer:features:read:target.xml:0,fff");
+        assert_eq!(xml, format!("l{}", TARGET_XML));
+        let first = client.ask("qXfer:features:read:target.xml:0,10");
+        assert_eq!(first, format!("m{}", &TARGET_XML[..0x10]));
+        assert_eq!(client.ask("?"), "S05");
+
+        // AF, BC, DE, HL, SP and PC after the DMG boot ROM, little-endian.
+        assert_eq!(client.ask("g"), "b0011300d8004d01feff0001");
+        assert_eq!(client.ask("m100,3"), "060505");
+
+        assert_eq!(client.ask("Z0,105,1"), "OK");
+        assert_eq!(client.ask("c"), "S05");
+        assert_eq!(client.ask("p5"), "0501");
+        assert_eq!(client.ask("p1"), "1300");
+        assert_eq!(client.ask("s"), "S05");
+        assert_eq!(client.ask("p5"), "0701");
+        assert_eq!(client.ask("z0,105,1"), "OK");
+
+        assert_eq!(client.ask("Z2,c000,1"), "OK");
+        assert_eq!(client.ask("c"), "T05watch:c000;");
+        assert_eq!(client.ask("mc000,1"), "42");
+        assert_eq!(client.ask("z2,c000,1"), "OK");
+
+        assert_eq!(client.ask("Mc001,2:abcd"), "OK");
+        assert_eq!(client.ask("P2=3412"), "OK");
+        assert_eq!(client.ask("vMustReplyEmpty"), "");
+
+        client.send("k");
+        let (killed, gameboy) = server.join().unwrap();
+        assert!(killed);
+        let cpu = gameboy.cpu();
+        assert_eq!(cpu.registers.get_de(), 0x1234);
+        assert_eq!([cpu.bus.read_byte(0xC001), cpu.bus.read_byte(0xC002)], [0xAB, 0xCD]);
+    }
+}
@@ -0,0 +1,352 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::utils::deflate::{crc32, zlib};
+use crate::utils::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+/// Size of a Super Game Boy border; the game screen sits inside it at `SGB_SCREEN_X`/`Y`.
+pub const SGB_BORDER_WIDTH: usize = 256;
+pub const SGB_BORDER_HEIGHT: usize = 224;
+pub const SGB_SCREEN_X: usize = 48;
+pub const SGB_SCREEN_Y: usize = 40;
+
+#[derive(Debug)]
+pub enum ImageError {
+    Io(io::Error),
+    UnknownFormat(String),
+    BadPpm(&'static str),
+    BadBorder { width: usize, height: usize },
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::Io(err) => write!(f, "{}", err),
+            ImageError::UnknownFormat(name) => {
+                write!(f, "cannot tell the image format of '{}'; use .png or .ppm", name)
+            }
+            ImageError::BadPpm(what) => write!(f, "not a binary PPM image: {}", what),
+            ImageError::BadBorder { width, height } => write!(
+                f,
+                "border is {}x{}, but an SGB border is {}x{}",
+                width, height, SGB_BORDER_WIDTH, SGB_BORDER_HEIGHT
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+impl From<io::Error> for ImageError {
+    fn from(err: io::Error) -> ImageError {
+        ImageError::Io(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Ppm,
+}
+
+impl ImageFormat {
+    pub fn from_path(path: &Path) -> Result<ImageFormat, ImageError> {
+        match path.extension().and_then(|ext| ext.to_str()).map(str::to_ascii_lowercase).as_deref() {
+            Some("png") => Ok(ImageFormat::Png),
+            Some("ppm") => Ok(ImageFormat::Ppm),
+            _ => Err(ImageError::UnknownFormat(path.display().to_string())),
+        }
+    }
+}
+
+/// An image as 0x00RRGGBB pixels, row by row.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u32>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize, pixels: Vec<u32>) -> Image {
+        assert_eq!(pixels.len(), width * height, "pixel count does not match the size");
+        Image { width, height, pixels }
+    }
+
+    /// Blows every pixel up to `factor` × `factor`.
+    pub fn scaled(&self, factor: usize) -> Image {
+        let factor = factor.max(1);
+        let mut pixels = Vec::with_capacity(self.pixels.len() * factor * factor);
+        for row in self.pixels.chunks(self.width.max(1)) {
+            let line: Vec<u32> =
+                row.iter().flat_map(|&pixel| std::iter::repeat_n(pixel, factor)).collect();
+            for _ in 0..factor {
+                pixels.extend_from_slice(&line);
+            }
+        }
+        Image::new(self.width * factor, self.height * factor, pixels)
+    }
+
+    /// Shrinks the image by `factor`, averaging each `factor` × `factor` block.
+    pub fn shrunk(&self, factor: usize) -> Image {
+        let factor = factor.max(1);
+        let (width, height) = (self.width / factor, self.height / factor);
+        let mut pixels = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let mut sums = [0u32; 3];
+                for dy in 0..factor {
+                    for dx in 0..factor {
+                        let pixel = self.pixels[(y * factor + dy) * self.width + x * factor + dx];
+                        sums[0] += (pixel >> 16) & 0xFF;
+                        sums[1] += (pixel >> 8) & 0xFF;
+                        sums[2] += pixel & 0xFF;
+                    }
+                }
+                let [r, g, b] = sums.map(|sum| sum / (factor * factor) as u32);
+                pixels.push(r << 16 | g << 8 | b);
+            }
+        }
+        Image::new(width, height, pixels)
+    }
+
+    /// Checks that the image has the size of a Super Game Boy border.
+    pub fn check_sgb_border(&self) -> Result<(), ImageError> {
+        if self.width != SGB_BORDER_WIDTH || self.height != SGB_BORDER_HEIGHT {
+            return Err(ImageError::BadBorder { width: self.width, height: self.height });
+        }
+        Ok(())
+    }
+
+    /// Places a `SCREEN_WIDTH` × `SCREEN_HEIGHT` frame inside a Super Game Boy border.
+    pub fn with_sgb_border(screen: &[u32], border: &Image) -> Result<Image, ImageError> {
+        border.check_sgb_border()?;
+        let mut framed = border.clone();
+        for (y, row) in screen.chunks(SCREEN_WIDTH).take(SCREEN_HEIGHT).enumerate() {
+            let start = (SGB_SCREEN_Y + y) * SGB_BORDER_WIDTH + SGB_SCREEN_X;
+            framed.pixels[start..start + SCREEN_WIDTH].copy_from_slice(row);
+        }
+        Ok(framed)
+    }
+
+    fn rgb_rows(&self) -> impl Iterator<Item = Vec<u8>> + '_ {
+        self.pixels.chunks(self.width.max(1)).map(|row| {
+            row.iter().flat_map(|&pixel| [(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8]).collect()
+        })
+    }
+
+    pub fn encode(&self, format: ImageFormat) -> Vec<u8> {
+        match format {
+            ImageFormat::Png => self.encode_png(),
+            ImageFormat::Ppm => self.encode_ppm(),
+        }
+    }
+
+    /// An 8-bit RGB PNG, compressed with fixed-Huffman deflate.
+    pub fn encode_png(&self) -> Vec<u8> {
+        let mut raw = Vec::with_capacity((self.width * 3 + 1) * self.height);
+        for row in self.rgb_rows() {
+            raw.push(0); // filter type: none
+            raw.extend_from_slice(&row);
+        }
+
+        let mut header = Vec::with_capacity(13);
+        header.extend_from_slice(&(self.width as u32).to_be_bytes());
+        header.extend_from_slice(&(self.height as u32).to_be_bytes());
+        header.extend_from_slice(&[8, 2, 0, 0, 0]); // 8-bit RGB, no interlace
+
+        let mut out = PNG_SIGNATURE.to_vec();
+        write_chunk(&mut out, b"IHDR", &header);
+        write_chunk(&mut out, b"IDAT", &zlib(&raw));
+        write_chunk(&mut out, b"IEND", &[]);
+        out
+    }
+
+    /// A binary (P6) PPM.
+    pub fn encode_ppm(&self) -> Vec<u8> {
+        let mut out = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        for row in self.rgb_rows() {
+            out.extend_from_slice(&row);
+        }
+        out
+    }
+
+    /// Reads a binary (P6) PPM with 8-bit samples, such as one written by `encode_ppm`.
+    pub fn decode_ppm(data: &[u8]) -> Result<Image, ImageError> {
+        let mut fields = Vec::new();
+        let mut position = 0;
+        while fields.len() < 4 {
+            while data.get(position).is_some_and(u8::is_ascii_whitespace) {
+                position += 1;
+            }
+            if data.get(position) == Some(&b'#') {
+                while data.get(position).is_some_and(|&byte| byte != b'\n') {
+                    position += 1;
+                }
+                continue;
+            }
+            let start = position;
+            while data.get(position).is_some_and(|byte| !byte.is_ascii_whitespace()) {
+                position += 1;
+            }
+            if start == position {
+                return Err(ImageError::BadPpm("header ends early"));
+            }
+            fields.push(&data[start..position]);
+        }
+        // Exactly one whitespace byte separates the header from the samples.
+        position += 1;
+
+        if fields[0] != b"P6" {
+            return Err(ImageError::BadPpm("wrong magic"));
+        }
+        let number = |field: &[u8]| -> Result<usize, ImageError> {
+            std::str::from_utf8(field)
+                .ok()
+                .and_then(|text| text.parse().ok())
+                .ok_or(ImageError::BadPpm("bad number in header"))
+        };
+        let (width, height) = (number(fields[1])?, number(fields[2])?);
+        if number(fields[3])? != 255 {
+            return Err(ImageError::BadPpm("only 8-bit samples are supported"));
+        }
+
+        let end = width
+            .checked_mul(height)
+            .and_then(|count| count.checked_mul(3))
+            .and_then(|size| size.checked_add(position))
+            .ok_or(ImageError::BadPpm("size too large"))?;
+        let samples = data.get(position..end).ok_or(ImageError::BadPpm("pixel data ends early"))?;
+        let pixels = samples
+            .chunks(3)
+            .map(|rgb| (rgb[0] as u32) << 16 | (rgb[1] as u32) << 8 | rgb[2] as u32)
+            .collect();
+        Ok(Image::new(width, height, pixels))
+    }
+
+    /// Writes the image in the format named by the file extension.
+    pub fn save(&self, path: &Path) -> Result<(), ImageError> {
+        let format = ImageFormat::from_path(path)?;
+        fs::write(path, self.encode(format))?;
+        Ok(())
+    }
+}
+
+fn write_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    out.extend_from_slice(&crc32(&[kind, data]).to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::deflate::inflate;
+
+    fn sample() -> Image {
+        Image::new(3, 2, vec![0xFF0000, 0x00FF00, 0x0000FF, 0x000000, 0x808080, 0xFFFFFF])
+    }
+
+    // Splits a PNG into (kind, data, stored CRC) after checking the signature.
+    fn png_chunks(png: &[u8]) -> Vec<([u8; 4], &[u8], u32)> {
+        assert_eq!(png[..8], PNG_SIGNATURE);
+        let mut chunks = Vec::new();
+        let mut rest = &png[8..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            let kind = rest[4..8].try_into().unwrap();
+            let crc = u32::from_be_bytes(rest[8 + len..12 + len].try_into().unwrap());
+            chunks.push((kind, &rest[8..8 + len], crc));
+            rest = &rest[12 + len..];
+        }
+        chunks
+    }
+
+    #[test]
+    fn png_is_deterministic_with_valid_chunks() {
+        let png = sample().encode_png();
+        assert_eq!(png, sample().encode_png());
+
+        let chunks = png_chunks(&png);
+        let kinds: Vec<_> = chunks.iter().map(|(kind, ..)| kind).collect();
+        assert_eq!(kinds, [b"IHDR", b"IDAT", b"IEND"]);
+        for (kind, data, crc) in &chunks {
+            assert_eq!(crc32(&[kind, data]), *crc);
+        }
+        // The standard check values, so the CRC itself is the right one.
+        assert_eq!(crc32(&[b"123456789"]), 0xCBF43926);
+        assert_eq!(chunks[2].2, 0xAE426082);
+
+        assert_eq!(chunks[0].1, [0, 0, 0, 3, 0, 0, 0, 2, 8, 2, 0, 0, 0]);
+        let idat = chunks[1].1;
+        let raw = inflate(&idat[2..idat.len() - 4]).unwrap();
+        assert_eq!(raw, [0, 255, 0, 0, 0, 255, 0, 0, 0, 255, 0, 0, 0, 0, 128, 128, 128, 255, 255, 255]);
+    }
+
+    #[test]
+    fn ppm_round_trips() {
+        let ppm = sample().encode_ppm();
+        assert!(ppm.starts_with(b"P6\n3 2\n255\n"));
+        assert_eq!(Image::decode_ppm(&ppm).unwrap(), sample());
+
+        let commented = b"P6 # made by hand\n1 1 255\n\x01\x02\x03";
+        assert_eq!(Image::decode_ppm(commented).unwrap().pixels, [0x010203]);
+    }
+
+    #[test]
+    fn bad_ppms_are_refused() {
+        for (data, error) in [
+            (&b"P3\n1 1\n255\n\0\0\0"[..], "wrong magic"),
+            (b"P6\n1 1\n65535\n\0\0\0", "only 8-bit samples are supported"),
+            (b"P6\n2 1\n255\n\0\0\0", "pixel data ends early"),
+            (b"P6\n1", "header ends early"),
+            (b"P6\nx 1\n255\n", "bad number in header"),
+        ] {
+            match Image::decode_ppm(data) {
+                Err(ImageError::BadPpm(what)) => assert_eq!(what, error),
+                other => panic!("{:?} for {:?}", other, error),
+            }
+        }
+    }
+
+    #[test]
+    fn scaling_and_shrinking() {
+        let scaled = sample().scaled(2);
+        assert_eq!((scaled.width, scaled.height), (6, 4));
+        assert_eq!(scaled.pixels[..6], [0xFF0000, 0xFF0000, 0x00FF00, 0x00FF00, 0x0000FF, 0x0000FF]);
+        assert_eq!(scaled.shrunk(2), sample());
+
+        let blend = Image::new(2, 2, vec![0xFFFFFF, 0x000000, 0x000000, 0xFFFFFF]).shrunk(2);
+        assert_eq!(blend.pixels, [0x7F7F7F]);
+    }
+
+    #[test]
+    fn sgb_border_frames_the_screen() {
+        let size = SGB_BORDER_WIDTH * SGB_BORDER_HEIGHT;
+        let border = Image::new(SGB_BORDER_WIDTH, SGB_BORDER_HEIGHT, vec![0x112233; size]);
+        let screen = vec![0xFFFFFF; SCREEN_WIDTH * SCREEN_HEIGHT];
+        let framed = Image::with_sgb_border(&screen, &border).unwrap();
+        let at = |x: usize, y: usize| framed.pixels[y * SGB_BORDER_WIDTH + x];
+        assert_eq!(at(SGB_SCREEN_X, SGB_SCREEN_Y), 0xFFFFFF);
+        assert_eq!(at(SGB_SCREEN_X + SCREEN_WIDTH - 1, SGB_SCREEN_Y + SCREEN_HEIGHT - 1), 0xFFFFFF);
+        assert_eq!(at(SGB_SCREEN_X - 1, SGB_SCREEN_Y), 0x112233);
+        assert_eq!(at(SGB_SCREEN_X, SGB_SCREEN_Y + SCREEN_HEIGHT), 0x112233);
+
+        assert!(matches!(
+            Image::with_sgb_border(&screen, &sample()),
+            Err(ImageError::BadBorder { width: 3, height: 2 })
+        ));
+    }
+
+    #[test]
+    fn format_from_the_extension() {
+        assert_eq!(ImageFormat::from_path(Path::new("shot.PNG")).unwrap(), ImageFormat::Png);
+        assert_eq!(ImageFormat::from_path(Path::new("shot.ppm")).unwrap(), ImageFormat::Ppm);
+        assert!(matches!(ImageFormat::from_path(Path::new("shot.bmp")), Err(ImageError::UnknownFormat(_))));
+    }
+}
@@ -0,0 +1,314 @@
+use crate::utils::bus::Interrupt;
+use crate::utils::state::{StateError, StateReader, StateWriter};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    pub const ALL: [Button; 8] = [
+        Button::Right,
+        Button::Left,
+        Button::Up,
+        Button::Down,
+        Button::A,
+        Button::B,
+        Button::Select,
+        Button::Start,
+    ];
+
+    /// Bit in the 8-bit pressed mask: directions in the low nibble, buttons in the high one.
+    pub fn mask(self) -> u8 {
+        1 << self as u8
+    }
+
+    fn opposite(self) -> Option<Button> {
+        match self {
+            Button::Right => Some(Button::Left),
+            Button::Left => Some(Button::Right),
+            Button::Up => Some(Button::Down),
+            Button::Down => Some(Button::Up),
+            _ => None,
+        }
+    }
+}
+
+const SELECT_DIRECTIONS: u8 = 0x10;
+const SELECT_BUTTONS: u8 = 0x20;
+
+// Contacts bounce for a few hundred microseconds after they change. The pattern comes from
+// an LFSR seeded per change so a given input sequence always bounces the same way.
+const BOUNCE_PERIOD: u32 = 16;
+
+/// The P1/JOYP register at 0xFF00.
+pub struct Joypad {
+    // Bits 4-5 as last written; a cleared bit selects that half of the matrix.
+    select: u8,
+    // Buttons the host is holding down.
+    held: u8,
+    // What the matrix reports after filtering and bounce.
+    pressed: u8,
+    // Low nibble currently driven onto P10-P13, used for edge detection.
+    lines: u8,
+    interrupt_pending: bool,
+
+    /// Let Left+Right and Up+Down be held together instead of the newer press winning.
+    pub allow_opposing: bool,
+    /// How many M-cycles a button keeps bouncing after it changes. Zero disables bouncing.
+    pub bounce_cycles: u32,
+    bouncing: u8,
+    bounce_remaining: u32,
+    bounce_lfsr: u16,
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad {
+            select: SELECT_DIRECTIONS | SELECT_BUTTONS,
+            held: 0,
+            pressed: 0,
+            lines: 0x0F,
+            interrupt_pending: false,
+            allow_opposing: false,
+            bounce_cycles: 0,
+            bouncing: 0,
+            bounce_remaining: 0,
+            bounce_lfsr: 0xACE1,
+        }
+    }
+
+    pub fn press(&mut self, button: Button) {
+        if self.held & button.mask() != 0 {
+            return;
+        }
+        self.held |= button.mask();
+
+        if !self.allow_opposing {
+            if let Some(opposite) = button.opposite() {
+                self.pressed &= !opposite.mask();
+            }
+        }
+        self.pressed |= button.mask();
+        self.start_bounce(button);
+        self.update_lines();
+    }
+
+    pub fn release(&mut self, button: Button) {
+        if self.held & button.mask() == 0 {
+            return;
+        }
+        self.held &= !button.mask();
+        self.pressed &= !button.mask();
+
+        // Let an opposite direction that was masked by this one through again.
+        if let Some(opposite) = button.opposite() {
+            if self.held & opposite.mask() != 0 {
+                self.pressed |= opposite.mask();
+            }
+        }
+        self.start_bounce(button);
+        self.update_lines();
+    }
+
+    pub fn set(&mut self, button: Button, pressed: bool) {
+        if pressed {
+            self.press(button);
+        } else {
+            self.release(button);
+        }
+    }
+
+    /// Replaces the whole input state at once, e.g. from a movie frame.
+    pub fn set_mask(&mut self, mask: u8) {
+        for button in Button::ALL {
+            self.set(button, mask & button.mask() != 0);
+        }
+    }
+
+    /// Buttons the host is holding down, in the form `set_mask` takes.
+    pub fn held_mask(&self) -> u8 {
+        self.held
+    }
+
+    /// Buttons the game currently sees as held.
+    pub fn pressed_mask(&self) -> u8 {
+        self.pressed
+    }
+
+    fn start_bounce(&mut self, button: Button) {
+        if self.bounce_cycles == 0 {
+            return;
+        }
+        self.bouncing = button.mask();
+        self.bounce_remaining = self.bounce_cycles;
+        self.bounce_lfsr ^= (button as u16 + 1) << 8;
+    }
+
+    fn visible(&self) -> u8 {
+        if self.bouncing != 0 && self.bounce_lfsr & 0x01 != 0 {
+            self.pressed ^ self.bouncing
+        } else {
+            self.pressed
+        }
+    }
+
+    fn update_lines(&mut self) {
+        let visible = self.visible();
+        let mut lines = 0x0F;
+        if self.select & SELECT_DIRECTIONS == 0 {
+            lines &= !(visible & 0x0F);
+        }
+        if self.select & SELECT_BUTTONS == 0 {
+            lines &= !(visible >> 4);
+        }
+
+        // Any line going from high to low requests the joypad interrupt.
+        if self.lines & !lines != 0 {
+            self.interrupt_pending = true;
+        }
+        self.lines = lines;
+    }
+
+    /// Advances contact bounce by one M-cycle and returns the interrupt flags raised.
+    pub fn tick(&mut self) -> u8 {
+        if self.bounce_remaining > 0 {
+            self.bounce_remaining -= 1;
+            if self.bounce_remaining == 0 {
+                self.bouncing = 0;
+                self.update_lines();
+            } else if self.bounce_remaining.is_multiple_of(BOUNCE_PERIOD) {
+                let bit = (self.bounce_lfsr ^ (self.bounce_lfsr >> 2)) & 0x01;
+                self.bounce_lfsr = (self.bounce_lfsr >> 1) | (bit << 15);
+                self.update_lines();
+            }
+        }
+
+        if self.interrupt_pending {
+            self.interrupt_pending = false;
+            return Interrupt::Joypad.bit();
+        }
+        0
+    }
+
+    pub fn read(&self) -> u8 {
+        0xC0 | self.select | self.lines
+    }
+
+    pub fn write(&mut self, value: u8) {
+        self.select = value & (SELECT_DIRECTIONS | SELECT_BUTTONS);
+        self.update_lines();
+    }
+
+    // Host-side settings (`allow_opposing`, `bounce_cycles`) are not part of the state.
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.put_u8(self.select);
+        out.put_u8(self.held);
+        out.put_u8(self.pressed);
+        out.put_u8(self.lines);
+        out.put_bool(self.interrupt_pending);
+        out.put_u8(self.bouncing);
+        out.put_u32(self.bounce_remaining);
+        out.put_u16(self.bounce_lfsr);
+    }
+
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.select = input.take_u8()?;
+        self.held = input.take_u8()?;
+        self.pressed = input.take_u8()?;
+        self.lines = input.take_u8()?;
+        self.interrupt_pending = input.take_bool()?;
+        self.bouncing = input.take_u8()?;
+        self.bounce_remaining = input.take_u32()?;
+        self.bounce_lfsr = input.take_u16()?;
+        Ok(())
+    }
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Joypad::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn select_lines_pick_which_half_reads_back() {
+        let mut joypad = Joypad::new();
+        joypad.press(Button::Left);
+        joypad.press(Button::Start);
+        assert_eq!(joypad.read(), 0xFF);
+
+        joypad.write(0x20);
+        assert_eq!(joypad.read(), 0xE0 | 0x0D);
+        joypad.write(0x10);
+        assert_eq!(joypad.read(), 0xD0 | 0x07);
+        joypad.write(0x00);
+        assert_eq!(joypad.read(), 0xC0 | 0x05);
+    }
+
+    #[test]
+    fn interrupt_on_a_line_going_low() {
+        let mut joypad = Joypad::new();
+        joypad.write(0x20);
+        assert_eq!(joypad.tick(), 0);
+
+        // A press on the unselected half drives nothing.
+        joypad.press(Button::A);
+        assert_eq!(joypad.tick(), 0);
+
+        joypad.press(Button::Down);
+        assert_eq!(joypad.tick(), Interrupt::Joypad.bit());
+        assert_eq!(joypad.tick(), 0);
+
+        // Releasing is a rising edge.
+        joypad.release(Button::Down);
+        assert_eq!(joypad.tick(), 0);
+
+        // Selecting the half with A held pulls P10 low.
+        joypad.write(0x10);
+        assert_eq!(joypad.tick(), Interrupt::Joypad.bit());
+    }
+
+    #[test]
+    fn newer_direction_wins_over_its_opposite() {
+        let mut joypad = Joypad::new();
+        joypad.press(Button::Left);
+        joypad.press(Button::Right);
+        assert_eq!(joypad.pressed_mask(), Button::Right.mask());
+        joypad.release(Button::Right);
+        assert_eq!(joypad.pressed_mask(), Button::Left.mask());
+
+        let mut joypad = Joypad::new();
+        joypad.allow_opposing = true;
+        joypad.press(Button::Up);
+        joypad.press(Button::Down);
+        assert_eq!(joypad.pressed_mask(), Button::Up.mask() | Button::Down.mask());
+    }
+
+    #[test]
+    fn bounce_settles_after_the_bounce_time() {
+        let mut joypad = Joypad::new();
+        joypad.bounce_cycles = 100;
+        joypad.write(0x20);
+        joypad.press(Button::Right);
+        let mut seen = Vec::new();
+        for _ in 0..100 {
+            joypad.tick();
+            seen.push(joypad.read() & 0x01);
+        }
+        assert!(seen.contains(&1));
+        assert_eq!(joypad.read() & 0x01, 0);
+        assert_eq!(joypad.held_mask(), Button::Right.mask());
+    }
+}
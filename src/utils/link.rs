@@ -0,0 +1,646 @@
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::utils::serial::SerialDevice;
+
+// A transfer completes on the same cycle on both machines: when the master's clock has
+// shifted the last bit, 1024 M-cycles after it starts at the normal 8192 Hz clock and 32 with
+// the CGB fast clock. Each side promises its peer that it will not start a transfer that
+// completes before some horizon ahead of its current cycle, so the peer may run freely up to
+// that point and is guaranteed to have heard about every transfer before it completes. The
+// horizon is at most LOOKAHEAD cycles out, one normal-speed byte, and shrinks to the length
+// of the last transfer so fast-clock games keep their timing. A transfer that would complete
+// inside a horizon already promised is held until the horizon, which only happens to the
+// first fast-clock byte after a stretch at the normal clock.
+const LOOKAHEAD: u64 = 1024;
+const RETRY_INTERVAL: u64 = 1 << 18;
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(50);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+const FRAME_SIZE: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Message {
+    // Sent once per connection with the sender's current cycle, to line the timelines up.
+    Hello(u64),
+    // The sender will not start a transfer that completes before this cycle.
+    Sync(u64),
+    // A byte clocked out by the sender, due at the given cycle.
+    Transfer(u8, u64),
+    // The answer to a Transfer, from whoever was on the other end at that cycle.
+    Reply(u8, u64),
+}
+
+impl Message {
+    fn encode(self) -> [u8; FRAME_SIZE] {
+        let (kind, byte, stamp) = match self {
+            Message::Hello(stamp) => (1, 0, stamp),
+            Message::Sync(stamp) => (2, 0, stamp),
+            Message::Transfer(byte, stamp) => (3, byte, stamp),
+            Message::Reply(byte, stamp) => (4, byte, stamp),
+        };
+        let mut frame = [0; FRAME_SIZE];
+        frame[0] = kind;
+        frame[1] = byte;
+        frame[2..].copy_from_slice(&stamp.to_le_bytes());
+        frame
+    }
+
+    fn decode(frame: &[u8]) -> Option<Message> {
+        let byte = frame[1];
+        let stamp = u64::from_le_bytes(frame[2..FRAME_SIZE].try_into().ok()?);
+        match frame[0] {
+            1 => Some(Message::Hello(stamp)),
+            2 => Some(Message::Sync(stamp)),
+            3 => Some(Message::Transfer(byte, stamp)),
+            4 => Some(Message::Reply(byte, stamp)),
+            _ => None,
+        }
+    }
+}
+
+trait LinkStream: Read + Write + Send {
+    fn nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+    fn read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl LinkStream for TcpStream {
+    fn nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+#[cfg(unix)]
+impl LinkStream for UnixStream {
+    fn nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+enum Endpoint {
+    TcpListen(TcpListener),
+    TcpConnect(SocketAddr),
+    #[cfg(unix)]
+    UnixListen(UnixListener),
+    #[cfg(unix)]
+    UnixConnect(PathBuf),
+}
+
+impl Endpoint {
+    fn establish(&self) -> io::Result<Option<Box<dyn LinkStream>>> {
+        let stream: Box<dyn LinkStream> = match self {
+            Endpoint::TcpListen(listener) => match listener.accept() {
+                Ok((stream, _)) => Box::new(tcp_stream(stream)?),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(err) => return Err(err),
+            },
+            Endpoint::TcpConnect(address) => {
+                Box::new(tcp_stream(TcpStream::connect_timeout(address, CONNECT_TIMEOUT)?)?)
+            }
+            #[cfg(unix)]
+            Endpoint::UnixListen(listener) => match listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(false)?;
+                    Box::new(stream)
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(err) => return Err(err),
+            },
+            #[cfg(unix)]
+            Endpoint::UnixConnect(path) => Box::new(UnixStream::connect(path)?),
+        };
+        Ok(Some(stream))
+    }
+}
+
+fn tcp_stream(stream: TcpStream) -> io::Result<TcpStream> {
+    stream.set_nonblocking(false)?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+struct Connection {
+    stream: Box<dyn LinkStream>,
+    inbox: Vec<u8>,
+    // Set once the Hello exchange has lined the timelines up.
+    synced: bool,
+    // Peer cycle minus our cycle, fixed at the handshake.
+    offset: i64,
+    // Our cycle up to which the peer has promised not to surprise us.
+    peer_horizon: u64,
+    // Our cycle up to which we have promised the peer the same.
+    horizon: u64,
+    next_sync: u64,
+}
+
+impl Connection {
+    fn new(stream: Box<dyn LinkStream>) -> Connection {
+        Connection {
+            stream,
+            inbox: Vec::new(),
+            synced: false,
+            offset: 0,
+            peer_horizon: 0,
+            horizon: 0,
+            next_sync: 0,
+        }
+    }
+
+    fn send(&mut self, message: Message) -> io::Result<()> {
+        self.stream.write_all(&message.encode())
+    }
+
+    fn local_cycle(&self, stamp: u64) -> u64 {
+        (stamp as i64 - self.offset).max(0) as u64
+    }
+
+    fn peer_cycle(&self, cycle: u64) -> u64 {
+        (cycle as i64 + self.offset).max(0) as u64
+    }
+
+    fn next_message(&mut self) -> Option<Message> {
+        if self.inbox.len() < FRAME_SIZE {
+            return None;
+        }
+        let frame: Vec<u8> = self.inbox.drain(..FRAME_SIZE).collect();
+        Message::decode(&frame)
+    }
+
+    // Reads whatever has arrived. With `wait` set, blocks until at least one byte arrives.
+    fn fill(&mut self, wait: Option<Duration>) -> io::Result<()> {
+        let mut buffer = [0; 256];
+        match wait {
+            Some(timeout) => {
+                self.stream.read_timeout(Some(timeout))?;
+                let read = self.stream.read(&mut buffer)?;
+                if read == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                self.inbox.extend_from_slice(&buffer[..read]);
+                Ok(())
+            }
+            None => {
+                self.stream.nonblocking(true)?;
+                let result = loop {
+                    match self.stream.read(&mut buffer) {
+                        Ok(0) => break Err(io::ErrorKind::UnexpectedEof.into()),
+                        Ok(read) => self.inbox.extend_from_slice(&buffer[..read]),
+                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+                        Err(err) => break Err(err),
+                    }
+                };
+                self.stream.nonblocking(false)?;
+                result
+            }
+        }
+    }
+}
+
+/// A link cable to another emulator process over TCP or a Unix socket.
+///
+/// Transfers are stamped with the cycle they complete at, and both sides keep within one
+/// transfer's length of each other, so an externally clocked transfer always lands on the
+/// same cycle no matter how the host schedules the two processes. If the peer goes away the
+/// port behaves as if the cable was unplugged until a new connection is made, at which
+/// point the two timelines are lined up again.
+pub struct SocketLink {
+    endpoint: Endpoint,
+    connection: Option<Connection>,
+    next_attempt: u64,
+
+    // A transfer the peer clocked, in our cycles: (byte, due).
+    incoming: Option<(u8, u64)>,
+    // A transfer we clocked and are waiting on: (due, reply once known).
+    outgoing: Option<(u64, Option<u8>)>,
+    // How far ahead we promise the peer, in M-cycles.
+    lookahead: u64,
+
+    /// How long to wait for the peer before treating it as disconnected.
+    pub timeout: Duration,
+}
+
+impl SocketLink {
+    fn new(endpoint: Endpoint, stream: Option<Box<dyn LinkStream>>) -> SocketLink {
+        SocketLink {
+            endpoint,
+            connection: stream.map(Connection::new),
+            next_attempt: 0,
+            incoming: None,
+            outgoing: None,
+            lookahead: LOOKAHEAD,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Waits for the peer to connect to `address`. Accepting happens in the background.
+    pub fn listen_tcp(address: impl ToSocketAddrs) -> io::Result<SocketLink> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        Ok(SocketLink::new(Endpoint::TcpListen(listener), None))
+    }
+
+    pub fn connect_tcp(address: impl ToSocketAddrs) -> io::Result<SocketLink> {
+        let address = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to"))?;
+        let endpoint = Endpoint::TcpConnect(address);
+        let stream = endpoint.establish()?;
+        Ok(SocketLink::new(endpoint, stream))
+    }
+
+    #[cfg(unix)]
+    pub fn listen_unix(path: impl AsRef<Path>) -> io::Result<SocketLink> {
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        Ok(SocketLink::new(Endpoint::UnixListen(listener), None))
+    }
+
+    #[cfg(unix)]
+    pub fn connect_unix(path: impl AsRef<Path>) -> io::Result<SocketLink> {
+        let endpoint = Endpoint::UnixConnect(path.as_ref().to_path_buf());
+        let stream = endpoint.establish()?;
+        Ok(SocketLink::new(endpoint, stream))
+    }
+
+    /// The local address of a TCP listener, useful when bound to port 0.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match &self.endpoint {
+            Endpoint::TcpListen(listener) => listener.local_addr().ok(),
+            _ => None,
+        }
+    }
+
+    /// Blocks until a peer is connected, e.g. before starting emulation on a listening side.
+    pub fn wait_for_peer(&mut self, timeout: Duration) -> io::Result<()> {
+        let deadline = Instant::now() + timeout;
+        while self.connection.is_none() {
+            if let Some(stream) = self.endpoint.establish()? {
+                self.connection = Some(Connection::new(stream));
+            } else if Instant::now() >= deadline {
+                return Err(io::ErrorKind::TimedOut.into());
+            } else {
+                std::thread::sleep(CONNECT_TIMEOUT);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a peer is connected and the timelines have been lined up.
+    pub fn is_connected(&self) -> bool {
+        self.connection.as_ref().is_some_and(|connection| connection.synced)
+    }
+
+    fn disconnect(&mut self, cycle: u64) {
+        self.connection = None;
+        self.incoming = None;
+        self.next_attempt = cycle + RETRY_INTERVAL;
+        // Nobody will answer, so a transfer we clocked reads the idle line.
+        if let Some((_, reply)) = &mut self.outgoing {
+            reply.get_or_insert(0xFF);
+        }
+    }
+
+    fn handshake(&mut self, cycle: u64) -> io::Result<()> {
+        let timeout = self.timeout;
+        let connection = match &mut self.connection {
+            Some(connection) => connection,
+            None => return Ok(()),
+        };
+
+        connection.send(Message::Hello(cycle))?;
+        let peer_cycle = loop {
+            match connection.next_message() {
+                Some(Message::Hello(peer_cycle)) => break peer_cycle,
+                Some(_) => continue,
+                None => connection.fill(Some(timeout))?,
+            }
+        };
+
+        connection.offset = peer_cycle as i64 - cycle as i64;
+        connection.peer_horizon = cycle + LOOKAHEAD;
+        connection.horizon = cycle + LOOKAHEAD;
+        connection.next_sync = cycle;
+        connection.synced = true;
+        self.incoming = None;
+        if let Some((_, reply)) = &mut self.outgoing {
+            reply.get_or_insert(0xFF);
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, message: Message) -> io::Result<()> {
+        let connection = match &mut self.connection {
+            Some(connection) => connection,
+            None => return Ok(()),
+        };
+        match message {
+            Message::Hello(_) => {}
+            Message::Sync(horizon) => {
+                connection.peer_horizon = connection.peer_horizon.max(connection.local_cycle(horizon));
+            }
+            Message::Transfer(byte, due) => {
+                self.incoming = Some((byte, connection.local_cycle(due)));
+            }
+            Message::Reply(byte, _) => {
+                if let Some((_, reply)) = &mut self.outgoing {
+                    *reply = Some(byte);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn drain(&mut self, wait: bool) -> io::Result<()> {
+        let timeout = self.timeout;
+        if let Some(connection) = &mut self.connection {
+            connection.fill(if wait { Some(timeout) } else { None })?;
+        }
+        while let Some(message) = self.connection.as_mut().and_then(Connection::next_message) {
+            self.handle(message)?;
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, message: Message) -> io::Result<()> {
+        match &mut self.connection {
+            Some(connection) => connection.send(message),
+            None => Ok(()),
+        }
+    }
+
+    fn send_sync(&mut self, cycle: u64) -> io::Result<()> {
+        if let Some(connection) = &mut self.connection {
+            connection.horizon = connection.horizon.max(cycle + self.lookahead);
+            connection.next_sync = cycle + self.lookahead / 2;
+            let horizon = connection.peer_cycle(connection.horizon);
+            connection.send(Message::Sync(horizon))?;
+        }
+        Ok(())
+    }
+
+    fn reply(&mut self, byte: u8, due: u64) -> io::Result<()> {
+        let stamp = self.connection.as_ref().map_or(due, |connection| connection.peer_cycle(due));
+        self.send(Message::Reply(byte, stamp))
+    }
+
+    // Keeps us within the peer's promise: before cycle + 1 runs we must have heard about
+    // every transfer due at that cycle.
+    fn keep_pace(&mut self, cycle: u64) -> io::Result<()> {
+        let next_sync = match &self.connection {
+            Some(connection) => connection.next_sync,
+            None => return Ok(()),
+        };
+        if cycle >= next_sync {
+            self.send_sync(cycle)?;
+            self.drain(false)?;
+        }
+        while self.connection.as_ref().is_some_and(|c| c.peer_horizon <= cycle + 1) {
+            self.drain(true)?;
+        }
+        Ok(())
+    }
+
+    // Whether a transfer the peer clocked, due at `due`, overlaps the one we are clocking.
+    fn colliding(&self, due: u64) -> bool {
+        let window = self.lookahead;
+        self.outgoing.is_some_and(|(ours, _)| due + window > ours && due < ours + window)
+    }
+
+    fn tick_connected(&mut self, cycle: u64) -> io::Result<()> {
+        if !self.is_connected() {
+            self.handshake(cycle)?;
+        }
+
+        // The peer clocked a byte at a cycle where we were not listening. If we were
+        // clocking one of our own at the same time, `finish_transfer` settles it instead.
+        if let Some((_, due)) = self.incoming {
+            if due <= cycle && !self.colliding(due) {
+                self.incoming = None;
+                self.reply(0xFF, due)?;
+            }
+        }
+
+        self.keep_pace(cycle)
+    }
+}
+
+impl SerialDevice for SocketLink {
+    fn start_transfer(&mut self, outgoing: u8, cycle: u64, duration: u64) {
+        self.lookahead = duration.clamp(1, LOOKAHEAD);
+        let horizon = self.connection.as_ref().filter(|c| c.synced).map_or(0, |c| c.horizon);
+        let due = (cycle + duration).max(horizon);
+        self.outgoing = Some((due, None));
+        if !self.is_connected() {
+            self.outgoing = Some((due, Some(0xFF)));
+            return;
+        }
+
+        let stamp = self.connection.as_ref().map_or(due, |connection| connection.peer_cycle(due));
+        if self.send(Message::Transfer(outgoing, stamp)).is_err() {
+            self.disconnect(cycle);
+        }
+    }
+
+    fn finish_transfer(&mut self, cycle: u64) -> Option<u8> {
+        let (due, reply) = self.outgoing?;
+        if cycle < due {
+            return None;
+        }
+        if let Some(byte) = reply {
+            self.outgoing = None;
+            return Some(byte);
+        }
+
+        // The peer can always reach `due` because our horizon is past it, so this wait ends:
+        // by then it has either answered or clocked a byte of its own. When both sides
+        // drive the clock, each shifts in what the other shifted out.
+        let result = self.send_sync(cycle).and_then(|_| loop {
+            if let Some((byte, _)) = self.incoming.filter(|&(_, peer_due)| self.colliding(peer_due)) {
+                self.incoming = None;
+                self.outgoing = Some((due, Some(byte)));
+            }
+            if let Some((_, Some(_))) = self.outgoing {
+                break Ok(());
+            }
+            self.drain(true)?;
+        });
+        if result.is_err() {
+            self.disconnect(cycle);
+        }
+
+        let byte = self.outgoing.and_then(|(_, reply)| reply).unwrap_or(0xFF);
+        self.outgoing = None;
+        Some(byte)
+    }
+
+    fn external_clock(&mut self, outgoing: u8, cycle: u64) -> Option<u8> {
+        match self.incoming {
+            Some((byte, due)) if due <= cycle => {
+                self.incoming = None;
+                if self.reply(outgoing, due).is_err() {
+                    self.disconnect(cycle);
+                }
+                Some(byte)
+            }
+            _ => None,
+        }
+    }
+
+    fn tick(&mut self, cycle: u64) {
+        if self.connection.is_none() {
+            if cycle < self.next_attempt {
+                return;
+            }
+            self.next_attempt = cycle + RETRY_INTERVAL;
+            match self.endpoint.establish() {
+                Ok(Some(stream)) => self.connection = Some(Connection::new(stream)),
+                Ok(None) | Err(_) => return,
+            }
+        }
+
+        if self.tick_connected(cycle).is_err() {
+            self.disconnect(cycle);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::rom::test_rom;
+    use crate::{GameBoy, Model};
+    use std::env;
+    use std::process::Command;
+    use std::thread;
+
+    // Set in the child process of `trades_with_another_process`.
+    const PEER_VAR: &str = "RUSTY_BOY_LINK_PEER";
+
+    // Puts `value` in SB, starts a transfer with `control`, waits for it and stores what
+    // came in at 0xC000.
+    fn exchange_rom(value: u8, control: u8) -> Vec<u8> {
+        let code = [
+            0x3E, value, 0xE0, 0x01, // ld a, value; ldh [$01], a
+            0x3E, control, 0xE0, 0x02, // ld a, control; ldh [$02], a
+            0xF0, 0x02, 0xCB, 0x7F, 0x20, 0xFA, // wait: ldh a, [$02]; bit 7, a; jr nz, wait
+            0xF0, 0x01, 0xEA, 0x00, 0xC0, // ldh a, [$01]; ld [$C000], a
+            0x18, 0xFE, // jr @
+        ];
+        test_rom(&code)
+    }
+
+    // Like `exchange_rom`, but on the CGB fast clock and twice, since the first byte after
+    // the handshake is held until the horizon already promised at the normal clock's pace.
+    // Counts the iterations (9 M-cycles each) of the second wait into 0xC001.
+    fn fast_exchange_rom(value: u8, control: u8) -> Vec<u8> {
+        let code = [
+            0x3E, value, 0xE0, 0x01, // ld a, value; ldh [$01], a
+            0x3E, control, 0xE0, 0x02, // ld a, control; ldh [$02], a
+            0xF0, 0x02, 0xCB, 0x7F, 0x20, 0xFA, // wait: ldh a, [$02]; bit 7, a; jr nz, wait
+            0x3E, value, 0xE0, 0x01, 0x06, 0x00, // ld a, value; ldh [$01], a; ld b, 0
+            0x3E, control, 0xE0, 0x02, // ld a, control; ldh [$02], a
+            0x04, 0xF0, 0x02, 0xCB, 0x7F, 0x20, 0xF9, // wait: inc b; ldh a, [$02]; bit 7, a; jr nz, wait
+            0x78, 0xEA, 0x01, 0xC0, // ld a, b; ld [$C001], a
+            0xF0, 0x01, 0xEA, 0x00, 0xC0, // ldh a, [$01]; ld [$C000], a
+            0x18, 0xFE, // jr @
+        ];
+        test_rom(&code)
+    }
+
+    fn run_on(model: Model, rom: Vec<u8>, link: SocketLink) -> GameBoy {
+        let mut gameboy = GameBoy::new(model, rom).unwrap();
+        gameboy.connect_serial(Box::new(link));
+        for _ in 0..10 {
+            gameboy.run_frame();
+        }
+        gameboy
+    }
+
+    fn run(rom: Vec<u8>, link: SocketLink) -> u8 {
+        run_on(Model::Dmg, rom, link).cpu().bus.read_byte(0xC000)
+    }
+
+    // Runs the two ROMs on two machines linked over loopback; returns what each received.
+    fn link_up(first: Vec<u8>, second: Vec<u8>) -> (u8, u8) {
+        let (first, second) = link_up_on(Model::Dmg, first, second);
+        (first.cpu().bus.read_byte(0xC000), second.cpu().bus.read_byte(0xC000))
+    }
+
+    fn link_up_on(model: Model, first: Vec<u8>, second: Vec<u8>) -> (GameBoy, GameBoy) {
+        let mut listener = SocketLink::listen_tcp("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let listening = thread::spawn(move || {
+            listener.wait_for_peer(Duration::from_secs(5)).unwrap();
+            run_on(model, first, listener)
+        });
+        let connecting = run_on(model, second, SocketLink::connect_tcp(address).unwrap());
+        (listening.join().unwrap(), connecting)
+    }
+
+    #[test]
+    fn internal_clock_trades_with_external_clock() {
+        assert_eq!(link_up(exchange_rom(0x42, 0x81), exchange_rom(0x99, 0x80)), (0x99, 0x42));
+        assert_eq!(link_up(exchange_rom(0x42, 0x80), exchange_rom(0x99, 0x81)), (0x99, 0x42));
+    }
+
+    #[test]
+    fn both_internal_clocks_trade_without_waiting_out_the_timeout() {
+        let start = Instant::now();
+        assert_eq!(link_up(exchange_rom(0x42, 0x81), exchange_rom(0x99, 0x81)), (0x99, 0x42));
+        assert!(start.elapsed() < DEFAULT_TIMEOUT);
+    }
+
+    #[test]
+    fn fast_clock_transfers_finish_at_the_fast_clock() {
+        let (master, slave) = link_up_on(Model::Cgb, fast_exchange_rom(0x42, 0x83), fast_exchange_rom(0x99, 0x80));
+        assert_eq!(master.cpu().bus.read_byte(0xC000), 0x99);
+        assert_eq!(slave.cpu().bus.read_byte(0xC000), 0x42);
+        // 32 M-cycles is under four trips round the loop; a normal-speed byte would be 114.
+        let waited = master.cpu().bus.read_byte(0xC001);
+        assert!((1..=5).contains(&waited), "waited {} iterations", waited);
+    }
+
+    // Threads share nothing but the socket, but a second process also proves nothing leans
+    // on a shared clock or address space: the test binary runs itself as the peer.
+    #[test]
+    fn trades_with_another_process() {
+        if let Ok(address) = env::var(PEER_VAR) {
+            let received = run(exchange_rom(0x99, 0x80), SocketLink::connect_tcp(address).unwrap());
+            println!("peer received {:#04x}", received);
+            return;
+        }
+        let mut listener = SocketLink::listen_tcp("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let peer = thread::spawn(move || {
+            Command::new(env::current_exe().unwrap())
+                .args(["--exact", "utils::link::tests::trades_with_another_process", "--nocapture"])
+                .env(PEER_VAR, address.to_string())
+                .output()
+        });
+        listener.wait_for_peer(Duration::from_secs(5)).unwrap();
+        assert_eq!(run(exchange_rom(0x42, 0x81), listener), 0x99);
+        let output = peer.join().unwrap().unwrap();
+        assert!(output.status.success());
+        assert!(String::from_utf8_lossy(&output.stdout).contains("peer received 0x42"));
+    }
+
+    #[test]
+    fn unplugged_port_reads_the_idle_line() {
+        let listener = SocketLink::listen_tcp("127.0.0.1:0").unwrap();
+        assert_eq!(run(exchange_rom(0x42, 0x81), listener), 0xFF);
+    }
+}
@@ -0,0 +1,66 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub const ALL: [Level; 6] =
+        [Level::Off, Level::Error, Level::Warn, Level::Info, Level::Debug, Level::Trace];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Off => "off",
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Level> {
+        Level::ALL.into_iter().find(|level| level.name().eq_ignore_ascii_case(name))
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Warn as u8);
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level != Level::Off && level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// Writes `message` to stderr if `level` is enabled. Callers pass `format_args!` so
+/// disabled messages cost nothing to format.
+pub fn log(level: Level, message: fmt::Arguments) {
+    if enabled(level) {
+        eprintln!("[{}] {}", level.name(), message);
+    }
+}
+
+pub fn error(message: fmt::Arguments) {
+    log(Level::Error, message);
+}
+
+pub fn warn(message: fmt::Arguments) {
+    log(Level::Warn, message);
+}
+
+pub fn info(message: fmt::Arguments) {
+    log(Level::Info, message);
+}
+
+pub fn debug(message: fmt::Arguments) {
+    log(Level::Debug, message);
+}
@@ -0,0 +1,32 @@
+pub mod apu;
+pub mod audio;
+pub mod bk2;
+pub mod bus;
+pub mod capture;
+pub mod cheats;
+pub mod cpu;
+pub mod debugger;
+pub mod deflate;
+pub mod disasm;
+pub mod dma;
+pub mod export;
+pub mod gbs;
+pub mod gdb;
+pub mod image;
+pub mod joypad;
+pub mod link;
+pub mod log;
+pub mod movie;
+pub mod pacing;
+pub mod ppu;
+pub mod ramsearch;
+pub mod rewind;
+pub mod rom;
+pub mod serial;
+pub mod state;
+pub mod terminal;
+pub mod timer;
+pub mod vbm;
+pub mod vgm;
+pub mod wav;
+pub mod zip;
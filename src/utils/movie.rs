@@ -0,0 +1,453 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::utils::bk2::sha1;
+use crate::utils::bus::Model;
+use crate::utils::state::{StateError, StateReader, StateWriter};
+use crate::utils::zip::ZipError;
+use crate::GameBoy;
+
+const MAGIC: &[u8; 4] = b"RBMV";
+const FORMAT_VERSION: u16 = 2;
+
+/// Frames between state hashes when recording, about one a second.
+pub const DEFAULT_HASH_INTERVAL: u32 = 60;
+
+#[derive(Debug)]
+pub enum MovieError {
+    Io(io::Error),
+    BadMagic,
+    NewerFormat(u16),
+    State(StateError),
+    Zip(ZipError),
+    /// A foreign movie that does not follow its format.
+    Invalid(&'static str),
+    /// Something a foreign movie does that cannot be reproduced here.
+    Unsupported(&'static str),
+    /// The movie was recorded on a different cartridge or model.
+    Mismatch(&'static str),
+    /// Playback no longer matches the recording, first noticed at `frame`.
+    Desync { frame: u64 },
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::Io(err) => write!(f, "{}", err),
+            MovieError::BadMagic => write!(f, "not a movie file"),
+            MovieError::NewerFormat(version) => {
+                write!(f, "movie format {} is newer than this emulator supports", version)
+            }
+            MovieError::State(err) => write!(f, "movie start state: {}", err),
+            MovieError::Zip(err) => write!(f, "{}", err),
+            MovieError::Invalid(what) => write!(f, "bad movie file: {}", what),
+            MovieError::Unsupported(what) => write!(f, "{} are not supported", what),
+            MovieError::Mismatch(what) => write!(f, "movie was recorded on a different {}", what),
+            MovieError::Desync { frame } => write!(f, "playback desynced at frame {}", frame),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+impl From<io::Error> for MovieError {
+    fn from(err: io::Error) -> MovieError {
+        MovieError::Io(err)
+    }
+}
+
+impl From<StateError> for MovieError {
+    fn from(err: StateError) -> MovieError {
+        MovieError::State(err)
+    }
+}
+
+impl From<ZipError> for MovieError {
+    fn from(err: ZipError) -> MovieError {
+        MovieError::Zip(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MovieFormat {
+    /// This emulator's own format.
+    Native,
+    /// BizHawk: a zip holding a text input log.
+    Bk2,
+    /// VisualBoyAdvance.
+    Vbm,
+}
+
+impl MovieFormat {
+    /// `.bk2` and `.vbm` by extension; anything else is taken to be a native movie.
+    pub fn from_path(path: &Path) -> MovieFormat {
+        match path.extension().and_then(|extension| extension.to_str()).map(str::to_ascii_lowercase).as_deref() {
+            Some("bk2") => MovieFormat::Bk2,
+            Some("vbm") => MovieFormat::Vbm,
+            _ => MovieFormat::Native,
+        }
+    }
+}
+
+/// Where a movie begins.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MovieStart {
+    /// A freshly created `GameBoy`.
+    PowerOn,
+    /// A snapshot taken when recording began.
+    State(Vec<u8>),
+}
+
+/// 64-bit FNV-1a, enough to tell two machine states apart.
+pub fn state_hash(state: &[u8]) -> u64 {
+    state.iter().fold(0xCBF29CE484222325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001B3))
+}
+
+/// The joypad state of every frame, plus what is needed to replay it exactly.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    pub title: String,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+    pub model: Model,
+    /// Whether a power-on start ran the boot ROM. Added in format version 2.
+    pub boot_rom: bool,
+    /// The version of the emulator that recorded it.
+    pub emulator_version: String,
+    pub rerecords: Option<u32>,
+    pub start: MovieStart,
+    /// One `Button::mask` set per frame.
+    pub inputs: Vec<u8>,
+    /// Hashes of the machine state at the start of some frames, as (frame, hash).
+    pub hashes: Vec<(u64, u64)>,
+}
+
+impl Movie {
+    /// An empty movie for the cartridge and model in `gameboy`.
+    pub fn new(gameboy: &GameBoy, start: MovieStart) -> Movie {
+        let header = &gameboy.cpu().bus.cartridge.header;
+        Movie {
+            title: header.title.clone(),
+            header_checksum: header.header_checksum,
+            global_checksum: header.global_checksum,
+            model: gameboy.model(),
+            boot_rom: gameboy.cpu().bus.boot_rom.is_some(),
+            emulator_version: env!("CARGO_PKG_VERSION").to_string(),
+            rerecords: None,
+            start,
+            inputs: Vec::new(),
+            hashes: Vec::new(),
+        }
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.inputs.len() as u64
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = StateWriter::new();
+        out.put_bytes(MAGIC);
+        out.put_u16(FORMAT_VERSION);
+        out.put_vec(self.title.as_bytes());
+        out.put_u8(self.header_checksum);
+        out.put_u16(self.global_checksum);
+        out.put_u8(self.model as u8);
+        out.put_bool(self.boot_rom);
+        out.put_vec(self.emulator_version.as_bytes());
+        out.put_bool(self.rerecords.is_some());
+        out.put_u32(self.rerecords.unwrap_or(0));
+        match &self.start {
+            MovieStart::PowerOn => out.put_u8(0),
+            MovieStart::State(state) => {
+                out.put_u8(1);
+                out.put_vec(state);
+            }
+        }
+        out.put_vec(&self.inputs);
+        out.put_u32(self.hashes.len() as u32);
+        for &(frame, hash) in &self.hashes {
+            out.put_u64(frame);
+            out.put_u64(hash);
+        }
+        out.into_bytes()
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Movie, MovieError> {
+        let mut input = StateReader::new(data);
+        let mut magic = [0; 4];
+        input.take_bytes(&mut magic).map_err(|_| MovieError::BadMagic)?;
+        if &magic != MAGIC {
+            return Err(MovieError::BadMagic);
+        }
+        let version = input.take_u16()?;
+        if version > FORMAT_VERSION {
+            return Err(MovieError::NewerFormat(version));
+        }
+
+        let text = |bytes: Vec<u8>| String::from_utf8(bytes).map_err(|_| StateError::Corrupt("text"));
+        let title = text(input.take_vec()?)?;
+        let header_checksum = input.take_u8()?;
+        let global_checksum = input.take_u16()?;
+        let model = Model::from_id(input.take_u8()?).ok_or(StateError::Corrupt("model"))?;
+        let boot_rom = if version >= 2 { input.take_bool()? } else { false };
+        let emulator_version = text(input.take_vec()?)?;
+        let has_rerecords = input.take_bool()?;
+        let rerecords = input.take_u32()?;
+        let start = match input.take_u8()? {
+            0 => MovieStart::PowerOn,
+            1 => MovieStart::State(input.take_vec()?),
+            _ => return Err(StateError::Corrupt("start").into()),
+        };
+        let inputs = input.take_vec()?;
+        let count = input.take_u32()?;
+        let mut hashes = Vec::new();
+        for _ in 0..count {
+            hashes.push((input.take_u64()?, input.take_u64()?));
+        }
+        if !input.is_empty() {
+            return Err(StateError::Corrupt("length").into());
+        }
+
+        Ok(Movie {
+            title,
+            header_checksum,
+            global_checksum,
+            model,
+            boot_rom,
+            emulator_version,
+            rerecords: has_rerecords.then_some(rerecords),
+            start,
+            inputs,
+            hashes,
+        })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Movie, MovieError> {
+        Movie::decode(&fs::read(path)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.encode())
+    }
+
+    /// Refuses to play on a different cartridge or model than the movie was made on, or
+    /// from power-on with the boot ROM when it was recorded without, or the other way round.
+    pub fn check(&self, gameboy: &GameBoy) -> Result<(), MovieError> {
+        let header = &gameboy.cpu().bus.cartridge.header;
+        if self.header_checksum != header.header_checksum || self.global_checksum != header.global_checksum {
+            return Err(MovieError::Mismatch("cartridge"));
+        }
+        if self.model != gameboy.model() {
+            return Err(MovieError::Mismatch("model"));
+        }
+        if self.start == MovieStart::PowerOn && self.boot_rom != gameboy.cpu().bus.boot_rom.is_some() {
+            return Err(MovieError::Mismatch("boot ROM setting"));
+        }
+        Ok(())
+    }
+}
+
+/// How a foreign movie identifies the ROM it was made on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RomId {
+    Sha1([u8; 20]),
+    /// The checksums from the cartridge header.
+    Checksums { header_checksum: u8, global_checksum: u16 },
+    Unknown,
+}
+
+/// A movie from another emulator, with its input mapped onto this joypad one frame per
+/// LCD frame, but not yet tied to a cartridge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForeignMovie {
+    pub emulator: String,
+    pub rom: RomId,
+    /// None when the emulator chose from the cartridge header.
+    pub model: Option<Model>,
+    /// Whether the recording ran the boot ROM; playing it without one will desync.
+    pub boot_rom: bool,
+    pub rerecords: Option<u32>,
+    /// Battery RAM the cartridge starts with, instead of a blank one.
+    pub save_ram: Option<Vec<u8>>,
+    pub inputs: Vec<u8>,
+}
+
+impl ForeignMovie {
+    /// Checks the movie against the cartridge and model in `gameboy`, which must have just
+    /// been created, and turns it into a movie starting from there.
+    pub fn into_movie(self, gameboy: &mut GameBoy) -> Result<Movie, MovieError> {
+        let header = &gameboy.cpu().bus.cartridge.header;
+        let same_rom = match self.rom {
+            RomId::Sha1(hash) => sha1(gameboy.cpu().bus.cartridge.rom()) == hash,
+            RomId::Checksums { header_checksum, global_checksum } => {
+                header_checksum == header.header_checksum && global_checksum == header.global_checksum
+            }
+            RomId::Unknown => true,
+        };
+        if !same_rom {
+            return Err(MovieError::Mismatch("cartridge"));
+        }
+        if self.model.is_some_and(|model| model != gameboy.model()) {
+            return Err(MovieError::Mismatch("model"));
+        }
+
+        let start = match &self.save_ram {
+            Some(data) => {
+                gameboy
+                    .cpu_mut()
+                    .bus
+                    .cartridge
+                    .load_battery_data(data)
+                    .map_err(|_| MovieError::Invalid("save RAM does not fit the cartridge"))?;
+                MovieStart::State(gameboy.snapshot())
+            }
+            None => MovieStart::PowerOn,
+        };
+        let mut movie = Movie::new(gameboy, start);
+        movie.boot_rom = self.boot_rom;
+        movie.emulator_version = self.emulator;
+        movie.rerecords = self.rerecords;
+        movie.inputs = self.inputs;
+        Ok(movie)
+    }
+}
+
+/// Records the joypad frame by frame, hashing the machine state every `hash_interval`
+/// frames so playback can detect desyncs.
+pub struct MovieRecorder {
+    movie: Movie,
+    pub hash_interval: u32,
+}
+
+impl MovieRecorder {
+    /// Starts from `gameboy` as it is. With `power_on`, it must have just been created;
+    /// otherwise a snapshot is taken here.
+    pub fn new(gameboy: &GameBoy, power_on: bool) -> MovieRecorder {
+        let start = if power_on { MovieStart::PowerOn } else { MovieStart::State(gameboy.snapshot()) };
+        MovieRecorder { movie: Movie::new(gameboy, start), hash_interval: DEFAULT_HASH_INTERVAL }
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.movie.frames()
+    }
+
+    /// Records the joypad as the frontend left it for the frame about to run. Call it right
+    /// before running each frame, however that frame is run.
+    pub fn record_frame(&mut self, gameboy: &GameBoy) {
+        let frame = self.movie.frames();
+        if frame.is_multiple_of(self.hash_interval.max(1) as u64) {
+            self.movie.hashes.push((frame, state_hash(&gameboy.snapshot())));
+        }
+        self.movie.inputs.push(gameboy.cpu().bus.joypad.held_mask());
+    }
+
+    /// Drops everything from `frame` on, after the machine went back there by loading a
+    /// state or rewinding.
+    pub fn truncate(&mut self, frame: u64) {
+        self.movie.inputs.truncate(frame as usize);
+        self.movie.hashes.retain(|&(hashed, _)| hashed < frame);
+    }
+
+    /// Counts one more take over already recorded frames.
+    pub fn rerecord(&mut self) {
+        self.movie.rerecords = Some(self.movie.rerecords.unwrap_or(0) + 1);
+    }
+
+    pub fn finish(self) -> Movie {
+        self.movie
+    }
+}
+
+/// Plays a movie back, checking every stored hash along the way.
+pub struct MoviePlayer {
+    movie: Movie,
+    frame: u64,
+    next_hash: usize,
+}
+
+impl MoviePlayer {
+    /// Puts `gameboy` at the start of the movie. A power-on movie expects a freshly
+    /// created `GameBoy`.
+    pub fn start(movie: Movie, gameboy: &mut GameBoy) -> Result<MoviePlayer, MovieError> {
+        movie.check(gameboy)?;
+        if let MovieStart::State(state) = &movie.start {
+            gameboy.load_state(state)?;
+        }
+        Ok(MoviePlayer { movie, frame: 0, next_hash: 0 })
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    /// Frames played so far.
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    pub fn finished(&self) -> bool {
+        self.frame >= self.movie.frames()
+    }
+
+    /// Plays the next frame. Returns false once the movie is over, and an error at the
+    /// first frame whose state does not match the recording.
+    pub fn run_frame(&mut self, gameboy: &mut GameBoy) -> Result<bool, MovieError> {
+        let Some(&input) = self.movie.inputs.get(self.frame as usize) else {
+            return Ok(false);
+        };
+        gameboy.cpu_mut().bus.joypad.set_mask(input);
+
+        while let Some(&(frame, hash)) = self.movie.hashes.get(self.next_hash) {
+            if frame > self.frame {
+                break;
+            }
+            self.next_hash += 1;
+            if frame == self.frame && state_hash(&gameboy.snapshot()) != hash {
+                return Err(MovieError::Desync { frame });
+            }
+        }
+
+        gameboy.run_frame();
+        self.frame += 1;
+        Ok(true)
+    }
+
+    /// Plays the rest of the movie as fast as possible.
+    pub fn run_to_end(&mut self, gameboy: &mut GameBoy) -> Result<(), MovieError> {
+        while self.run_frame(gameboy)? {}
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::rom::test_rom;
+
+    fn gameboy(boot_rom: bool) -> GameBoy {
+        let mut gameboy = GameBoy::new(Model::Dmg, test_rom(&[0x18, 0xFE])).unwrap();
+        if boot_rom {
+            gameboy.set_boot_rom(vec![0; 0x100]).unwrap();
+        }
+        gameboy
+    }
+
+    #[test]
+    fn boot_rom_flag_round_trips() {
+        let movie = Movie::new(&gameboy(true), MovieStart::PowerOn);
+        assert!(movie.boot_rom);
+        assert!(Movie::decode(&movie.encode()).unwrap().boot_rom);
+    }
+
+    #[test]
+    fn refuses_a_different_boot_rom_setting() {
+        let with = Movie::new(&gameboy(true), MovieStart::PowerOn);
+        let without = Movie::new(&gameboy(false), MovieStart::PowerOn);
+        assert!(matches!(with.check(&gameboy(false)), Err(MovieError::Mismatch("boot ROM setting"))));
+        assert!(matches!(without.check(&gameboy(true)), Err(MovieError::Mismatch("boot ROM setting"))));
+        assert!(with.check(&gameboy(true)).is_ok());
+        assert!(without.check(&gameboy(false)).is_ok());
+    }
+}
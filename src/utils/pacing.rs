@@ -0,0 +1,195 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The LCD refreshes every 70224 T-cycles, about 59.73 times a second.
+pub const FRAME_RATE: f64 = 4_194_304.0 / 70224.0;
+
+/// The multipliers `faster` and `slower` step through.
+pub const SPEED_STEPS: [f64; 6] = [0.25, 0.5, 1.0, 2.0, 4.0, 8.0];
+
+// How far pacing may fall behind before it gives up catching up.
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Speed {
+    /// A fixed multiple of real time; 1.0 is normal speed.
+    Fixed(f64),
+    /// As fast as the host can go.
+    Turbo,
+}
+
+/// What happens to the sound while not running at 1×.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TurboAudio {
+    /// Drop it.
+    Skip,
+    /// Resample each frame's sound to the real time the frame took, which shifts its pitch.
+    Stretch,
+}
+
+/// Resamples interleaved stereo so it lasts `1 / speed` as long.
+pub fn stretch_audio(samples: &[i16], speed: f64) -> Vec<i16> {
+    let frames = samples.len() / 2;
+    if frames == 0 || speed <= 0.0 {
+        return samples.to_vec();
+    }
+    let length = ((frames as f64 / speed).round() as usize).max(1);
+    let mut out = Vec::with_capacity(length * 2);
+    for i in 0..length {
+        let position = i as f64 * frames as f64 / length as f64;
+        let index = (position as usize).min(frames - 1);
+        let next = (index + 1).min(frames - 1);
+        let fraction = position - index as f64;
+        for channel in 0..2 {
+            let (a, b) = (samples[index * 2 + channel] as f64, samples[next * 2 + channel] as f64);
+            out.push((a + (b - a) * fraction).round() as i16);
+        }
+    }
+    out
+}
+
+/// Decides when frames run in real time: at a multiple of it or as fast as possible.
+/// Pausing and frame advance belong to the machine, see `GameBoy::frame_due`.
+pub struct Pacer {
+    speed: Speed,
+    // The fixed speed to go back to when turbo is switched off.
+    fixed: f64,
+    deadline: Instant,
+    last_frame: Instant,
+    // Real time between the last two frames, to tell how fast turbo actually runs.
+    frame_duration: Duration,
+    pub turbo_audio: TurboAudio,
+}
+
+impl Pacer {
+    pub fn new(speed: Speed) -> Pacer {
+        let now = Instant::now();
+        Pacer {
+            speed,
+            fixed: match speed {
+                Speed::Fixed(speed) => speed,
+                Speed::Turbo => 1.0,
+            },
+            deadline: now,
+            last_frame: now,
+            frame_duration: Duration::from_secs_f64(1.0 / FRAME_RATE),
+            turbo_audio: TurboAudio::Skip,
+        }
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    /// A fixed speed of 0 or less counts as turbo.
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.speed = match speed {
+            Speed::Fixed(speed) if speed > 0.0 => {
+                self.fixed = speed;
+                Speed::Fixed(speed)
+            }
+            _ => Speed::Turbo,
+        };
+        self.deadline = Instant::now();
+    }
+
+    /// Switches between turbo and the last fixed speed.
+    pub fn toggle_turbo(&mut self) {
+        match self.speed {
+            Speed::Turbo => self.set_speed(Speed::Fixed(self.fixed)),
+            Speed::Fixed(_) => self.set_speed(Speed::Turbo),
+        }
+    }
+
+    /// The next step of `SPEED_STEPS` up, leaving turbo alone.
+    pub fn faster(&mut self) {
+        if let Speed::Fixed(speed) = self.speed {
+            let next = SPEED_STEPS.iter().find(|&&step| step > speed).copied();
+            self.set_speed(Speed::Fixed(next.unwrap_or(SPEED_STEPS[SPEED_STEPS.len() - 1])));
+        }
+    }
+
+    /// The next step of `SPEED_STEPS` down; from turbo, the fastest one.
+    pub fn slower(&mut self) {
+        let next = match self.speed {
+            Speed::Fixed(speed) => SPEED_STEPS.iter().rev().find(|&&step| step < speed).copied(),
+            Speed::Turbo => None,
+        };
+        let fallback = if self.speed == Speed::Turbo { SPEED_STEPS[SPEED_STEPS.len() - 1] } else { SPEED_STEPS[0] };
+        self.set_speed(Speed::Fixed(next.unwrap_or(fallback)));
+    }
+
+    /// Sleeps until the next frame is due. While `paused` it waits one normal frame, so a
+    /// loop polling input does not spin; a frame advance requested meanwhile is only seen
+    /// at the next poll, up to one frame later.
+    pub fn wait(&mut self, paused: bool) {
+        let speed = match self.speed {
+            _ if paused => Some(1.0),
+            Speed::Fixed(speed) => Some(speed),
+            Speed::Turbo => None,
+        };
+        match speed {
+            Some(speed) => {
+                self.deadline += Duration::from_secs_f64(1.0 / (FRAME_RATE * speed));
+                let now = Instant::now();
+                if self.deadline > now {
+                    thread::sleep(self.deadline - now);
+                } else if now - self.deadline > MAX_LAG {
+                    self.deadline = now;
+                }
+            }
+            None => self.deadline = Instant::now(),
+        }
+        let now = Instant::now();
+        self.frame_duration = now - self.last_frame;
+        self.last_frame = now;
+    }
+
+    /// How many times faster than real time frames are running.
+    pub fn effective_speed(&self) -> f64 {
+        match self.speed {
+            Speed::Fixed(speed) => speed,
+            Speed::Turbo => 1.0 / (FRAME_RATE * self.frame_duration.as_secs_f64().max(1e-6)),
+        }
+    }
+
+    /// Adapts a frame's sound, as from `GameBoy::audio_samples`, to the speed it just ran
+    /// at, following `turbo_audio`.
+    pub fn audio(&self, samples: Vec<i16>) -> Vec<i16> {
+        let speed = self.effective_speed();
+        if (speed - 1.0).abs() < 1e-3 {
+            return samples;
+        }
+        match self.turbo_audio {
+            TurboAudio::Skip => Vec::new(),
+            TurboAudio::Stretch => stretch_audio(&samples, speed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stretch_changes_length_by_the_speed() {
+        let samples: Vec<i16> = (0..200).flat_map(|i| [i, -i]).collect();
+        let fast = stretch_audio(&samples, 2.0);
+        assert_eq!(fast.len(), 200);
+        assert_eq!(&fast[..4], &[0, 0, 2, -2]);
+        assert_eq!(stretch_audio(&samples, 0.5).len(), 800);
+        assert_eq!(stretch_audio(&samples, 1.0), samples);
+    }
+
+    #[test]
+    fn audio_follows_the_turbo_policy_away_from_normal_speed() {
+        let samples = vec![100i16; 96];
+        let mut pacer = Pacer::new(Speed::Fixed(1.0));
+        assert_eq!(pacer.audio(samples.clone()), samples);
+
+        pacer.set_speed(Speed::Fixed(2.0));
+        assert!(pacer.audio(samples.clone()).is_empty());
+        pacer.turbo_audio = TurboAudio::Stretch;
+        assert_eq!(pacer.audio(samples.clone()), vec![100i16; 48]);
+    }
+}
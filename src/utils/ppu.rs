@@ -0,0 +1,496 @@
+use crate::utils::bus::Interrupt;
+use crate::utils::state::{StateError, StateReader, StateWriter};
+
+pub const DOTS_PER_LINE: u16 = 456;
+pub const LINES_PER_FRAME: u8 = 154;
+pub const VISIBLE_LINES: u8 = 144;
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+/// Shades for DMG colour numbers 0-3 as 0x00RRGGBB, lightest first.
+pub const DMG_SHADES: [u32; 4] = [0xE0F8D0, 0x88C070, 0x346856, 0x081820];
+
+const OAM_SCAN_DOTS: u16 = 80;
+const DRAWING_DOTS: u16 = 172;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    Drawing = 3,
+}
+
+impl Mode {
+    pub fn from_bits(bits: u8) -> Mode {
+        match bits & 0x03 {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::OamScan,
+            _ => Mode::Drawing,
+        }
+    }
+}
+
+// A sprite selected for the current line during OAM scan.
+#[derive(Clone, Copy)]
+struct Sprite {
+    y: u8,
+    x: u8,
+    tile: u8,
+    attributes: u8,
+}
+
+pub struct Ppu {
+    pub cgb: bool,
+    pub vram: [u8; 0x4000],
+    pub vram_bank: u8,
+    pub oam: [u8; 0xA0],
+
+    pub lcdc: u8,
+    pub stat: u8,
+    pub scy: u8,
+    pub scx: u8,
+    pub ly: u8,
+    pub lyc: u8,
+    pub bgp: u8,
+    pub obp0: u8,
+    pub obp1: u8,
+    pub wy: u8,
+    pub wx: u8,
+
+    pub mode: Mode,
+    pub dot: u16,
+    stat_line: bool,
+
+    // Set for the tick in which the matching event happened.
+    pub entered_hblank: bool,
+    pub entered_vblank: bool,
+    /// Frames completed, counted at the start of each VBlank.
+    pub frames: u64,
+
+    // CGB palette memory behind BCPS/BCPD and OCPS/OCPD, as little-endian RGB555.
+    pub bg_palettes: [u8; 0x40],
+    pub obj_palettes: [u8; 0x40],
+    pub bcps: u8,
+    pub ocps: u8,
+
+    // Lines of the window drawn so far this frame.
+    window_line: u8,
+    /// The picture as 0x00RRGGBB, row by row.
+    pub framebuffer: Vec<u32>,
+}
+
+impl Ppu {
+    pub fn new(cgb: bool) -> Ppu {
+        Ppu {
+            cgb,
+            vram: [0; 0x4000],
+            vram_bank: 0,
+            oam: [0; 0xA0],
+            lcdc: 0x91,
+            stat: 0x00,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0xFC,
+            obp0: 0xFF,
+            obp1: 0xFF,
+            wy: 0,
+            wx: 0,
+            mode: Mode::OamScan,
+            dot: 0,
+            stat_line: false,
+            entered_hblank: false,
+            entered_vblank: false,
+            frames: 0,
+            bg_palettes: [0xFF; 0x40],
+            obj_palettes: [0xFF; 0x40],
+            bcps: 0,
+            ocps: 0,
+            window_line: 0,
+            framebuffer: vec![DMG_SHADES[0]; SCREEN_WIDTH * SCREEN_HEIGHT],
+        }
+    }
+
+    pub fn lcd_enabled(&self) -> bool {
+        self.lcdc & 0x80 != 0
+    }
+
+    /// Advances the PPU by `dots` and returns the interrupt flags it raised.
+    pub fn tick(&mut self, dots: u16) -> u8 {
+        self.entered_hblank = false;
+        self.entered_vblank = false;
+
+        if !self.lcd_enabled() {
+            return 0;
+        }
+
+        let mut interrupts = 0;
+        for _ in 0..dots {
+            interrupts |= self.step_dot();
+        }
+        interrupts
+    }
+
+    fn step_dot(&mut self) -> u8 {
+        let mut interrupts = 0;
+
+        self.dot += 1;
+        if self.dot == DOTS_PER_LINE {
+            self.dot = 0;
+            self.ly = (self.ly + 1) % LINES_PER_FRAME;
+            if self.ly == 0 {
+                self.window_line = 0;
+            }
+        }
+
+        let mode = if self.ly >= VISIBLE_LINES {
+            Mode::VBlank
+        } else if self.dot < OAM_SCAN_DOTS {
+            Mode::OamScan
+        } else if self.dot < OAM_SCAN_DOTS + DRAWING_DOTS {
+            Mode::Drawing
+        } else {
+            Mode::HBlank
+        };
+
+        if mode != self.mode {
+            self.mode = mode;
+            match mode {
+                Mode::HBlank => {
+                    self.entered_hblank = true;
+                    self.render_line();
+                }
+                Mode::VBlank => {
+                    self.entered_vblank = true;
+                    self.frames += 1;
+                    interrupts |= Interrupt::VBlank.bit();
+                }
+                _ => {}
+            }
+        }
+
+        if self.update_stat_line() {
+            interrupts |= Interrupt::LcdStat.bit();
+        }
+        interrupts
+    }
+
+    // The STAT interrupt fires on a rising edge of the OR of all enabled sources, so
+    // overlapping sources do not retrigger it ("STAT blocking").
+    fn update_stat_line(&mut self) -> bool {
+        let coincidence = self.ly == self.lyc;
+        let line = (coincidence && self.stat & 0x40 != 0)
+            || (self.mode == Mode::OamScan && self.stat & 0x20 != 0)
+            || (self.mode == Mode::VBlank && self.stat & 0x10 != 0)
+            || (self.mode == Mode::HBlank && self.stat & 0x08 != 0);
+
+        let rising = line && !self.stat_line;
+        self.stat_line = line;
+        rising
+    }
+
+    pub fn read_stat(&self) -> u8 {
+        let coincidence = if self.ly == self.lyc { 0x04 } else { 0 };
+        let mode = if self.lcd_enabled() { self.mode as u8 } else { 0 };
+        0x80 | (self.stat & 0x78) | coincidence | mode
+    }
+
+    pub fn write_stat(&mut self, value: u8) {
+        self.stat = value & 0x78;
+        if self.lcd_enabled() {
+            self.update_stat_line();
+        }
+    }
+
+    pub fn write_lcdc(&mut self, value: u8) {
+        let was_enabled = self.lcd_enabled();
+        self.lcdc = value;
+        if was_enabled && !self.lcd_enabled() {
+            self.ly = 0;
+            self.dot = 0;
+            self.mode = Mode::HBlank;
+            self.stat_line = false;
+            // A disabled LCD shows a blank screen.
+            let blank = if self.cgb { 0xFFFFFF } else { DMG_SHADES[0] };
+            self.framebuffer.fill(blank);
+        } else if !was_enabled && self.lcd_enabled() {
+            self.ly = 0;
+            self.dot = 0;
+            self.mode = Mode::OamScan;
+            self.window_line = 0;
+        }
+    }
+
+    pub fn vram_accessible(&self) -> bool {
+        !self.lcd_enabled() || self.mode != Mode::Drawing
+    }
+
+    pub fn oam_accessible(&self) -> bool {
+        !self.lcd_enabled() || matches!(self.mode, Mode::HBlank | Mode::VBlank)
+    }
+
+    pub fn read_vram(&self, address: u16) -> u8 {
+        self.vram[self.vram_bank as usize * 0x2000 + (address as usize & 0x1FFF)]
+    }
+
+    pub fn write_vram(&mut self, address: u16, value: u8) {
+        self.vram[self.vram_bank as usize * 0x2000 + (address as usize & 0x1FFF)] = value;
+    }
+
+    pub fn read_bcpd(&self) -> u8 {
+        if self.vram_accessible() { self.bg_palettes[(self.bcps & 0x3F) as usize] } else { 0xFF }
+    }
+
+    pub fn write_bcpd(&mut self, value: u8) {
+        if self.vram_accessible() {
+            self.bg_palettes[(self.bcps & 0x3F) as usize] = value;
+        }
+        // The index advances even when the write itself is blocked.
+        if self.bcps & 0x80 != 0 {
+            self.bcps = 0x80 | (self.bcps.wrapping_add(1) & 0x3F);
+        }
+    }
+
+    pub fn read_ocpd(&self) -> u8 {
+        if self.vram_accessible() { self.obj_palettes[(self.ocps & 0x3F) as usize] } else { 0xFF }
+    }
+
+    pub fn write_ocpd(&mut self, value: u8) {
+        if self.vram_accessible() {
+            self.obj_palettes[(self.ocps & 0x3F) as usize] = value;
+        }
+        if self.ocps & 0x80 != 0 {
+            self.ocps = 0x80 | (self.ocps.wrapping_add(1) & 0x3F);
+        }
+    }
+
+    fn cgb_color(palettes: &[u8; 0x40], palette: u8, color: u8) -> u32 {
+        let index = palette as usize * 8 + color as usize * 2;
+        let rgb555 = u16::from_le_bytes([palettes[index], palettes[index + 1]]);
+        let expand = |c: u16| {
+            let c = (c & 0x1F) as u32;
+            (c << 3) | (c >> 2)
+        };
+        (expand(rgb555) << 16) | (expand(rgb555 >> 5) << 8) | expand(rgb555 >> 10)
+    }
+
+    // Colour number (0-3) of a pixel in a tile. `bank` selects the VRAM bank on CGB.
+    fn tile_pixel(&self, address: u16, bank: u8, row: u8, column: u8) -> u8 {
+        let offset = bank as usize * 0x2000 + (address as usize - 0x8000) + row as usize * 2;
+        let low = self.vram[offset];
+        let high = self.vram[offset + 1];
+        let bit = 7 - column;
+        (((high >> bit) & 0x01) << 1) | ((low >> bit) & 0x01)
+    }
+
+    // Colour number, CGB attributes and colour for a pixel of the background or window.
+    fn map_pixel(&self, map: u16, x: u8, y: u8) -> (u8, u8, u32) {
+        let map_offset = (map - 0x8000) as usize + (y as usize / 8) * 32 + x as usize / 8;
+        let tile = self.vram[map_offset];
+        let attributes = if self.cgb { self.vram[0x2000 + map_offset] } else { 0 };
+
+        let address = if self.lcdc & 0x10 != 0 {
+            0x8000 + tile as u16 * 16
+        } else {
+            (0x9000 + (tile as i8 as i32) * 16) as u16
+        };
+        let mut row = y % 8;
+        let mut column = x % 8;
+        if attributes & 0x40 != 0 {
+            row = 7 - row;
+        }
+        if attributes & 0x20 != 0 {
+            column = 7 - column;
+        }
+        let color = self.tile_pixel(address, (attributes >> 3) & 0x01, row, column);
+
+        let rgb = if self.cgb {
+            Ppu::cgb_color(&self.bg_palettes, attributes & 0x07, color)
+        } else {
+            DMG_SHADES[((self.bgp >> (color * 2)) & 0x03) as usize]
+        };
+        (color, attributes, rgb)
+    }
+
+    fn render_line(&mut self) {
+        let ly = self.ly;
+        if ly >= VISIBLE_LINES {
+            return;
+        }
+
+        let mut line = [0u32; SCREEN_WIDTH];
+        // Background colour numbers and CGB priority bits, for sprite priority.
+        let mut bg_colors = [0u8; SCREEN_WIDTH];
+        let mut bg_priority = [false; SCREEN_WIDTH];
+
+        // On DMG LCDC bit 0 blanks the background and window; on CGB it only takes their
+        // priority over sprites away.
+        let bg_enabled = self.cgb || self.lcdc & 0x01 != 0;
+        let window_visible =
+            bg_enabled && self.lcdc & 0x20 != 0 && self.wy <= ly && self.wx <= 166;
+
+        if bg_enabled {
+            let bg_map = if self.lcdc & 0x08 != 0 { 0x9C00 } else { 0x9800 };
+            let window_map = if self.lcdc & 0x40 != 0 { 0x9C00 } else { 0x9800 };
+
+            for x in 0..SCREEN_WIDTH {
+                let in_window = window_visible && x as u16 + 7 >= self.wx as u16;
+                let (color, attributes, rgb) = if in_window {
+                    let window_x = (x as u16 + 7 - self.wx as u16) as u8;
+                    self.map_pixel(window_map, window_x, self.window_line)
+                } else {
+                    let bg_x = self.scx.wrapping_add(x as u8);
+                    self.map_pixel(bg_map, bg_x, self.scy.wrapping_add(ly))
+                };
+                line[x] = rgb;
+                bg_colors[x] = color;
+                bg_priority[x] = attributes & 0x80 != 0;
+            }
+        } else {
+            line.fill(DMG_SHADES[0]);
+        }
+        if window_visible {
+            self.window_line += 1;
+        }
+
+        if self.lcdc & 0x02 != 0 {
+            self.render_sprites(&mut line, &bg_colors, &bg_priority);
+        }
+
+        let start = ly as usize * SCREEN_WIDTH;
+        self.framebuffer[start..start + SCREEN_WIDTH].copy_from_slice(&line);
+    }
+
+    fn render_sprites(&self, line: &mut [u32; SCREEN_WIDTH], bg_colors: &[u8], bg_priority: &[bool]) {
+        let ly = self.ly as u16;
+        let height = if self.lcdc & 0x04 != 0 { 16 } else { 8 };
+
+        // OAM scan picks the first ten sprites on the line.
+        let mut sprites: Vec<Sprite> = self
+            .oam
+            .chunks(4)
+            .map(|entry| Sprite { y: entry[0], x: entry[1], tile: entry[2], attributes: entry[3] })
+            .filter(|sprite| ly + 16 >= sprite.y as u16 && ly + 16 < sprite.y as u16 + height)
+            .take(10)
+            .collect();
+        // DMG gives the leftmost sprite priority, then the earliest in OAM; CGB only uses
+        // OAM order. The sort is stable, so OAM order breaks ties.
+        if !self.cgb {
+            sprites.sort_by_key(|sprite| sprite.x);
+        }
+
+        let mut claimed = [false; SCREEN_WIDTH];
+        for sprite in sprites {
+            let mut row = (ly + 16 - sprite.y as u16) as u8;
+            if sprite.attributes & 0x40 != 0 {
+                row = height as u8 - 1 - row;
+            }
+            let tile = if height == 16 { sprite.tile & 0xFE } else { sprite.tile };
+            let address = 0x8000 + tile as u16 * 16;
+            let bank = if self.cgb { (sprite.attributes >> 3) & 0x01 } else { 0 };
+
+            for column in 0..8u8 {
+                let x = sprite.x as i16 - 8 + column as i16;
+                if !(0..SCREEN_WIDTH as i16).contains(&x) {
+                    continue;
+                }
+                let x = x as usize;
+                let pixel_column = if sprite.attributes & 0x20 != 0 { 7 - column } else { column };
+                let color = self.tile_pixel(address, bank, row, pixel_column);
+                if color == 0 || claimed[x] {
+                    continue;
+                }
+                // The highest-priority opaque sprite pixel wins even if the background
+                // then hides it.
+                claimed[x] = true;
+
+                let behind_bg = if self.cgb {
+                    self.lcdc & 0x01 != 0
+                        && (sprite.attributes & 0x80 != 0 || bg_priority[x])
+                        && bg_colors[x] != 0
+                } else {
+                    sprite.attributes & 0x80 != 0 && bg_colors[x] != 0
+                };
+                if behind_bg {
+                    continue;
+                }
+
+                line[x] = if self.cgb {
+                    Ppu::cgb_color(&self.obj_palettes, sprite.attributes & 0x07, color)
+                } else {
+                    let palette = if sprite.attributes & 0x10 != 0 { self.obp1 } else { self.obp0 };
+                    DMG_SHADES[((palette >> (color * 2)) & 0x03) as usize]
+                };
+            }
+        }
+    }
+
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.put_bytes(&self.vram);
+        out.put_u8(self.vram_bank);
+        out.put_bytes(&self.oam);
+        for register in [
+            self.lcdc, self.stat, self.scy, self.scx, self.ly, self.lyc, self.bgp, self.obp0,
+            self.obp1, self.wy, self.wx,
+        ] {
+            out.put_u8(register);
+        }
+        out.put_u8(self.mode as u8);
+        out.put_u16(self.dot);
+        out.put_bool(self.stat_line);
+        out.put_bool(self.entered_hblank);
+        out.put_bool(self.entered_vblank);
+        out.put_u64(self.frames);
+        out.put_bytes(&self.bg_palettes);
+        out.put_bytes(&self.obj_palettes);
+        out.put_u8(self.bcps);
+        out.put_u8(self.ocps);
+        out.put_u8(self.window_line);
+        for &pixel in &self.framebuffer {
+            out.put_u32(pixel);
+        }
+    }
+
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        input.take_bytes(&mut self.vram)?;
+        self.vram_bank = input.take_u8()? & 0x01;
+        input.take_bytes(&mut self.oam)?;
+        for register in [
+            &mut self.lcdc, &mut self.stat, &mut self.scy, &mut self.scx, &mut self.ly,
+            &mut self.lyc, &mut self.bgp, &mut self.obp0, &mut self.obp1, &mut self.wy,
+            &mut self.wx,
+        ] {
+            *register = input.take_u8()?;
+        }
+        if self.ly >= LINES_PER_FRAME {
+            return Err(StateError::Corrupt("LY"));
+        }
+        self.mode = Mode::from_bits(input.take_u8()?);
+        self.dot = input.take_u16()?;
+        if self.dot >= DOTS_PER_LINE {
+            return Err(StateError::Corrupt("PPU dot"));
+        }
+        self.stat_line = input.take_bool()?;
+        self.entered_hblank = input.take_bool()?;
+        self.entered_vblank = input.take_bool()?;
+        self.frames = input.take_u64()?;
+        input.take_bytes(&mut self.bg_palettes)?;
+        input.take_bytes(&mut self.obj_palettes)?;
+        self.bcps = input.take_u8()?;
+        self.ocps = input.take_u8()?;
+        self.window_line = input.take_u8()?;
+        for pixel in self.framebuffer.iter_mut() {
+            *pixel = input.take_u32()?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Ppu::new(false)
+    }
+}
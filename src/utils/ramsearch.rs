@@ -0,0 +1,442 @@
+use std::fmt;
+
+use crate::utils::bus::Bus;
+use crate::utils::cheats::RamBank;
+
+/// The RAM a search covers. Locations are offsets into the whole of each memory, so a
+/// result keeps meaning the same byte whichever bank is mapped in later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Region {
+    /// Work RAM: 8 KiB, or all eight 4 KiB banks on the CGB.
+    Wram,
+    Hram,
+    /// Cartridge RAM, all banks.
+    Sram,
+}
+
+impl Region {
+    pub const ALL: [Region; 3] = [Region::Wram, Region::Hram, Region::Sram];
+
+    fn memory(self, bus: &Bus) -> &[u8] {
+        match self {
+            Region::Wram => bus.wram(),
+            Region::Hram => bus.hram(),
+            Region::Sram => &bus.cartridge.ram,
+        }
+    }
+
+    fn memory_mut(self, bus: &mut Bus) -> &mut [u8] {
+        match self {
+            Region::Wram => bus.wram_mut(),
+            Region::Hram => bus.hram_mut(),
+            Region::Sram => &mut bus.cartridge.ram,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Location {
+    pub region: Region,
+    pub offset: usize,
+}
+
+impl Location {
+    /// Where a CPU address points with the banks mapped right now, if it is RAM a search
+    /// covers.
+    pub fn from_address(bus: &Bus, address: u16) -> Option<Location> {
+        let (region, offset) = match address {
+            0xA000..=0xBFFF => (Region::Sram, bus.cartridge.ram_offset(address)?),
+            0xC000..=0xFDFF => (Region::Wram, bus.wram_offset(address)),
+            0xFF80..=0xFFFE => (Region::Hram, (address - 0xFF80) as usize),
+            _ => return None,
+        };
+        (offset < region.memory(bus).len()).then_some(Location { region, offset })
+    }
+
+    /// The CPU address the location shows up at when its bank is mapped.
+    pub fn address(self) -> u16 {
+        match self.region {
+            Region::Wram if self.offset < 0x1000 => 0xC000 + self.offset as u16,
+            Region::Wram => 0xD000 + (self.offset & 0x0FFF) as u16,
+            Region::Hram => 0xFF80 + self.offset as u16,
+            Region::Sram => 0xA000 + (self.offset & 0x1FFF) as u16,
+        }
+    }
+
+    /// The bank to name in a GameShark code for this location.
+    pub fn bank(self) -> RamBank {
+        match self.region {
+            // Bank 1 is what 0xD000 maps by default, and the only one a DMG has.
+            Region::Wram if self.offset >= 0x2000 => RamBank::Work((self.offset / 0x1000) as u8),
+            Region::Sram => RamBank::Cartridge((self.offset / 0x2000) as u8),
+            _ => RamBank::Current,
+        }
+    }
+
+    /// A GameShark code that keeps `value` here.
+    pub fn gameshark_code(self, value: u8) -> String {
+        let kind = match self.bank() {
+            RamBank::Current => 0x01,
+            RamBank::Cartridge(bank) => 0x80 | bank,
+            RamBank::Work(bank) => 0x90 | bank,
+        };
+        let [low, high] = self.address().to_le_bytes();
+        format!("{:02X}{:02X}{:02X}{:02X}", kind, value, low, high)
+    }
+
+    /// `size` bytes from here, or None if they run off the end of the region.
+    pub fn read(self, bus: &Bus, size: usize) -> Option<&[u8]> {
+        self.region.memory(bus).get(self.offset..self.offset + size)
+    }
+
+    /// Writes `bytes` from here, as far as the region goes.
+    pub fn write(self, bus: &mut Bus, bytes: &[u8]) {
+        let memory = self.region.memory_mut(bus);
+        for (target, &byte) in memory.iter_mut().skip(self.offset).zip(bytes) {
+            *target = byte;
+        }
+    }
+}
+
+/// The address, with the bank in front as `bank:address` where it takes one.
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.bank() {
+            RamBank::Current => write!(f, "{:04X}", self.address()),
+            RamBank::Cartridge(bank) | RamBank::Work(bank) => write!(f, "{:X}:{:04X}", bank, self.address()),
+        }
+    }
+}
+
+/// How bytes are read as a number. 16-bit values are little-endian, like the CPU's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    U8,
+    I8,
+    U16,
+    I16,
+}
+
+impl ValueType {
+    pub fn parse(name: &str) -> Option<ValueType> {
+        match name {
+            "u8" => Some(ValueType::U8),
+            "i8" | "s8" => Some(ValueType::I8),
+            "u16" => Some(ValueType::U16),
+            "i16" | "s16" => Some(ValueType::I16),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ValueType::U8 => "u8",
+            ValueType::I8 => "i8",
+            ValueType::U16 => "u16",
+            ValueType::I16 => "i16",
+        }
+    }
+
+    pub fn size(self) -> usize {
+        match self {
+            ValueType::U8 | ValueType::I8 => 1,
+            ValueType::U16 | ValueType::I16 => 2,
+        }
+    }
+
+    pub fn decode(self, bytes: &[u8]) -> i32 {
+        match self {
+            ValueType::U8 => bytes[0] as i32,
+            ValueType::I8 => bytes[0] as i8 as i32,
+            ValueType::U16 => u16::from_le_bytes([bytes[0], bytes[1]]) as i32,
+            ValueType::I16 => i16::from_le_bytes([bytes[0], bytes[1]]) as i32,
+        }
+    }
+
+    /// The bytes for `value`, wrapped to the type's size.
+    pub fn encode(self, value: i32) -> Vec<u8> {
+        match self.size() {
+            1 => vec![value as u8],
+            _ => (value as u16).to_le_bytes().to_vec(),
+        }
+    }
+
+    // Differences wrap at the type's size, so a counter going from 255 to 0 went up by 1.
+    fn wrap(self, value: i32) -> i32 {
+        match self.size() {
+            1 => value & 0xFF,
+            _ => value & 0xFFFF,
+        }
+    }
+}
+
+/// A test each remaining candidate must pass to stay, comparing its value now with the
+/// value when the previous filter ran.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    Equal(i32),
+    NotEqual(i32),
+    Greater(i32),
+    Less(i32),
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+    /// Changed by exactly this much; negative for a decrease.
+    ChangedBy(i32),
+}
+
+impl Filter {
+    fn keeps(self, value_type: ValueType, previous: i32, current: i32) -> bool {
+        match self {
+            Filter::Equal(value) => value_type.wrap(current) == value_type.wrap(value),
+            Filter::NotEqual(value) => value_type.wrap(current) != value_type.wrap(value),
+            Filter::Greater(value) => current > value,
+            Filter::Less(value) => current < value,
+            Filter::Changed => current != previous,
+            Filter::Unchanged => current == previous,
+            Filter::Increased => current > previous,
+            Filter::Decreased => current < previous,
+            Filter::ChangedBy(delta) => value_type.wrap(current - previous) == value_type.wrap(delta),
+        }
+    }
+}
+
+/// Narrows RAM down to the locations that behave like a value in the game, such as the
+/// lives counter: start with everything, then filter as the value changes.
+pub struct RamSearch {
+    value_type: ValueType,
+    candidates: Vec<Location>,
+    // Each region as of the start or the last filter.
+    previous: Vec<(Region, Vec<u8>)>,
+}
+
+impl RamSearch {
+    /// Starts with every location in WRAM, HRAM and cartridge RAM as a candidate.
+    pub fn new(bus: &Bus, value_type: ValueType) -> RamSearch {
+        let size = value_type.size();
+        let mut candidates = Vec::new();
+        for region in Region::ALL {
+            let length = region.memory(bus).len();
+            candidates.extend((0..(length + 1).saturating_sub(size)).map(|offset| Location { region, offset }));
+        }
+        RamSearch { value_type, candidates, previous: RamSearch::snapshot(bus) }
+    }
+
+    fn snapshot(bus: &Bus) -> Vec<(Region, Vec<u8>)> {
+        Region::ALL.iter().map(|&region| (region, region.memory(bus).to_vec())).collect()
+    }
+
+    pub fn value_type(&self) -> ValueType {
+        self.value_type
+    }
+
+    pub fn candidates(&self) -> &[Location] {
+        &self.candidates
+    }
+
+    pub fn len(&self) -> usize {
+        self.candidates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+
+    /// A candidate's value as of the last filter.
+    pub fn previous(&self, location: Location) -> Option<i32> {
+        let (_, memory) = self.previous.iter().find(|(region, _)| *region == location.region)?;
+        let bytes = memory.get(location.offset..location.offset + self.value_type.size())?;
+        Some(self.value_type.decode(bytes))
+    }
+
+    /// A location's value now.
+    pub fn current(&self, bus: &Bus, location: Location) -> Option<i32> {
+        location.read(bus, self.value_type.size()).map(|bytes| self.value_type.decode(bytes))
+    }
+
+    /// Drops the candidates that fail `filter` and remembers the values for the next one.
+    /// Returns how many are left.
+    pub fn filter(&mut self, bus: &Bus, filter: Filter) -> usize {
+        let value_type = self.value_type;
+        let mut kept = Vec::with_capacity(self.candidates.len());
+        for &location in &self.candidates {
+            let values = self.previous(location).zip(self.current(bus, location));
+            if values.is_some_and(|(previous, current)| filter.keeps(value_type, previous, current)) {
+                kept.push(location);
+            }
+        }
+        self.candidates = kept;
+        self.previous = RamSearch::snapshot(bus);
+        self.candidates.len()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watch {
+    pub location: Location,
+    pub value_type: ValueType,
+    pub name: String,
+    /// Written back every VBlank while set.
+    pub frozen: Option<i32>,
+}
+
+impl Watch {
+    pub fn value(&self, bus: &Bus) -> Option<i32> {
+        self.location.read(bus, self.value_type.size()).map(|bytes| self.value_type.decode(bytes))
+    }
+}
+
+/// Locations to keep an eye on, some of them frozen. The bus applies the frozen ones at
+/// every VBlank, like GameShark codes.
+#[derive(Debug, Clone, Default)]
+pub struct WatchList {
+    watches: Vec<Watch>,
+}
+
+impl WatchList {
+    pub fn new() -> WatchList {
+        WatchList::default()
+    }
+
+    pub fn list(&self) -> &[Watch] {
+        &self.watches
+    }
+
+    pub fn len(&self) -> usize {
+        self.watches.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.watches.is_empty()
+    }
+
+    pub fn add(&mut self, watch: Watch) {
+        self.watches.push(watch);
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Watch> {
+        (index < self.watches.len()).then(|| self.watches.remove(index))
+    }
+
+    /// Returns false if there is no watch at `index`.
+    pub fn freeze(&mut self, index: usize, value: Option<i32>) -> bool {
+        match self.watches.get_mut(index) {
+            Some(watch) => {
+                watch.frozen = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn any_frozen(&self) -> bool {
+        self.watches.iter().any(|watch| watch.frozen.is_some())
+    }
+
+    /// Writes every frozen value back.
+    pub fn apply(&self, bus: &mut Bus) {
+        for watch in &self.watches {
+            if let Some(value) = watch.frozen {
+                watch.location.write(bus, &watch.value_type.encode(value));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::rom::test_rom;
+    use crate::{GameBoy, Model};
+
+    fn gameboy(model: Model) -> GameBoy {
+        GameBoy::new(model, test_rom(&[0x18, 0xFE])).unwrap()
+    }
+
+    #[test]
+    fn value_types() {
+        assert_eq!(ValueType::parse("s16"), Some(ValueType::I16));
+        assert_eq!(ValueType::parse("u32"), None);
+        assert_eq!(ValueType::I8.decode(&[0xFF]), -1);
+        assert_eq!(ValueType::U16.decode(&[0x34, 0x12]), 0x1234);
+        assert_eq!(ValueType::I16.decode(&[0xFE, 0xFF]), -2);
+        assert_eq!(ValueType::I16.encode(-2), [0xFE, 0xFF]);
+        assert_eq!(ValueType::U8.encode(0x1FF), [0xFF]);
+    }
+
+    #[test]
+    fn filters_narrow_down_to_the_changing_value() {
+        let mut gameboy = gameboy(Model::Dmg);
+        let bus = &mut gameboy.cpu_mut().bus;
+        bus.write_byte(0xC123, 5);
+        let mut search = RamSearch::new(bus, ValueType::U8);
+        assert_eq!(search.len(), 0x2000 + 0x7F);
+
+        search.filter(bus, Filter::Equal(5));
+        bus.write_byte(0xC123, 4);
+        assert_eq!(search.filter(bus, Filter::Decreased), 1);
+        bus.write_byte(0xC123, 3);
+        assert_eq!(search.filter(bus, Filter::ChangedBy(-1)), 1);
+        let location = search.candidates()[0];
+        assert_eq!(location, Location { region: Region::Wram, offset: 0x123 });
+        assert_eq!((search.previous(location), search.current(bus, location)), (Some(3), Some(3)));
+
+        assert_eq!(search.filter(bus, Filter::Changed), 0);
+        assert!(search.is_empty());
+    }
+
+    #[test]
+    fn differences_wrap_at_the_type_size() {
+        let mut gameboy = gameboy(Model::Dmg);
+        let bus = &mut gameboy.cpu_mut().bus;
+        bus.write_byte(0xC000, 0xFF);
+        bus.write_byte(0xC001, 0xFF);
+        let mut search = RamSearch::new(bus, ValueType::U16);
+        search.filter(bus, Filter::Equal(0xFFFF));
+        bus.write_byte(0xC000, 0x00);
+        bus.write_byte(0xC001, 0x00);
+        assert_eq!(search.filter(bus, Filter::ChangedBy(1)), 1);
+        assert_eq!(search.candidates()[0].address(), 0xC000);
+    }
+
+    #[test]
+    fn locations_keep_their_bank() {
+        let mut gameboy = gameboy(Model::Cgb);
+        let bus = &mut gameboy.cpu_mut().bus;
+        let low = Location::from_address(bus, 0xC010).unwrap();
+        assert_eq!((low.bank(), low.gameshark_code(0x63)), (RamBank::Current, "016310C0".to_string()));
+
+        bus.write_byte(0xFF70, 3);
+        let banked = Location::from_address(bus, 0xD010).unwrap();
+        assert_eq!(banked, Location { region: Region::Wram, offset: 0x3010 });
+        assert_eq!(banked.address(), 0xD010);
+        assert_eq!(banked.to_string(), "3:D010");
+        assert_eq!(banked.gameshark_code(0x63), "936310D0");
+
+        assert_eq!(Location::from_address(bus, 0xFF90).unwrap().to_string(), "FF90");
+        assert_eq!(Location::from_address(bus, 0x8000), None);
+        // No cartridge RAM on this ROM.
+        assert_eq!(Location::from_address(bus, 0xA000), None);
+    }
+
+    #[test]
+    fn frozen_watches_are_written_back_at_vblank() {
+        let mut gameboy = gameboy(Model::Dmg);
+        let location = Location::from_address(&gameboy.cpu().bus, 0xC200).unwrap();
+        let watch = Watch { location, value_type: ValueType::U16, name: "hp".to_string(), frozen: None };
+        gameboy.watches_mut().add(watch);
+        assert!(gameboy.watches_mut().freeze(0, Some(0x1234)));
+        assert!(!gameboy.watches_mut().freeze(1, None));
+
+        gameboy.cpu_mut().bus.write_byte(0xC200, 0);
+        gameboy.run_frame();
+        assert_eq!(gameboy.watches().list()[0].value(&gameboy.cpu().bus), Some(0x1234));
+
+        gameboy.watches_mut().freeze(0, None);
+        assert!(!gameboy.watches().any_frozen());
+        gameboy.cpu_mut().bus.write_byte(0xC200, 0);
+        gameboy.run_frame();
+        assert_eq!(gameboy.cpu().bus.read_byte(0xC200), 0);
+    }
+}
@@ -0,0 +1,353 @@
+use std::collections::VecDeque;
+
+use crate::utils::pacing::FRAME_RATE;
+use crate::GameBoy;
+
+// Zero runs shorter than this are cheaper to keep inside a literal.
+const MIN_ZERO_RUN: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RewindConfig {
+    /// How far back rewinding can go.
+    pub seconds: f64,
+    /// Frames between snapshots. Stepping back replays up to this many frames.
+    pub interval: u32,
+    /// Every this many snapshots, one is stored whole instead of as a delta, bounding the
+    /// work to rebuild any snapshot.
+    pub keyframe_interval: u32,
+    /// Upper bound on the memory used by snapshots, in bytes.
+    pub budget: usize,
+}
+
+impl Default for RewindConfig {
+    fn default() -> RewindConfig {
+        RewindConfig { seconds: 30.0, interval: 10, keyframe_interval: 16, budget: 64 << 20 }
+    }
+}
+
+struct Snapshot {
+    frame: u64,
+    keyframe: bool,
+    len: usize,
+    // Run-length encoded; for deltas, of the XOR with the previous snapshot.
+    data: Vec<u8>,
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn take_varint(data: &[u8], position: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    while let Some(&byte) = data.get(*position) {
+        *position += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    value
+}
+
+// Encodes `state` XOR `base` as pairs of (zero run, literal run) lengths, each followed
+// by the literal bytes. Bytes past the end of `base` are XORed with zero.
+fn encode(state: &[u8], base: &[u8]) -> Vec<u8> {
+    let xor = |i: usize| state[i] ^ base.get(i).copied().unwrap_or(0);
+    let zero_run = |start: usize| (start..state.len()).take_while(|&i| xor(i) == 0).count();
+
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < state.len() {
+        let zeros = zero_run(i);
+        i += zeros;
+        let start = i;
+        while i < state.len() && (xor(i) != 0 || zero_run(i) < MIN_ZERO_RUN.min(state.len() - i)) {
+            i += 1;
+        }
+        put_varint(&mut out, zeros);
+        put_varint(&mut out, i - start);
+        out.extend((start..i).map(xor));
+    }
+    out
+}
+
+fn decode(data: &[u8], base: &[u8], len: usize) -> Vec<u8> {
+    let mut state: Vec<u8> = (0..len).map(|i| base.get(i).copied().unwrap_or(0)).collect();
+    let mut position = 0;
+    let mut i = 0;
+    while position < data.len() {
+        i += take_varint(data, &mut position);
+        let literals = take_varint(data, &mut position);
+        for &byte in &data[position..position + literals] {
+            state[i] ^= byte;
+            i += 1;
+        }
+        position += literals;
+    }
+    state
+}
+
+/// Keeps a trail of compressed snapshots and the input of every frame since the oldest,
+/// so play can be stepped back one frame at a time.
+pub struct Rewind {
+    config: RewindConfig,
+    // Frames run since recording started; the machine is at the start of this frame.
+    frame: u64,
+    snapshots: VecDeque<Snapshot>,
+    // Joypad state of every frame from the oldest snapshot on.
+    inputs: VecDeque<u8>,
+    // The newest snapshot decoded, which the next delta is taken against.
+    newest: Vec<u8>,
+    bytes: usize,
+}
+
+impl Rewind {
+    pub fn new(config: RewindConfig) -> Rewind {
+        Rewind {
+            config,
+            frame: 0,
+            snapshots: VecDeque::new(),
+            inputs: VecDeque::new(),
+            newest: Vec::new(),
+            bytes: 0,
+        }
+    }
+
+    pub fn config(&self) -> RewindConfig {
+        self.config
+    }
+
+    /// Forgets everything, e.g. after loading a state.
+    pub fn clear(&mut self) {
+        *self = Rewind::new(self.config);
+    }
+
+    /// Memory held by snapshots, in bytes.
+    pub fn memory(&self) -> usize {
+        self.bytes
+    }
+
+    /// How many frames `step_back` can still undo.
+    pub fn available(&self) -> u64 {
+        self.snapshots.front().map_or(0, |oldest| self.frame - oldest.frame)
+    }
+
+    /// Runs one frame with the joypad as the frontend left it, recording what is needed
+    /// to come back here.
+    pub fn run_frame(&mut self, gameboy: &mut GameBoy) {
+        let due = self.frame.is_multiple_of(self.config.interval.max(1) as u64);
+        if due && self.snapshots.back().is_none_or(|newest| newest.frame != self.frame) {
+            self.push(gameboy.snapshot());
+        }
+        self.inputs.push_back(gameboy.cpu().bus.joypad.held_mask());
+        gameboy.run_frame();
+        self.frame += 1;
+    }
+
+    fn push(&mut self, state: Vec<u8>) {
+        let keyframe_interval = self.config.keyframe_interval.max(1) as usize;
+        let since_keyframe = self.snapshots.iter().rev().take_while(|snapshot| !snapshot.keyframe).count();
+        let keyframe = self.snapshots.is_empty() || since_keyframe + 1 >= keyframe_interval;
+        let data = if keyframe { encode(&state, &[]) } else { encode(&state, &self.newest) };
+
+        self.bytes += data.len();
+        self.snapshots.push_back(Snapshot { frame: self.frame, keyframe, len: state.len(), data });
+        self.newest = state;
+
+        let max_frames = (self.config.seconds.max(0.0) * FRAME_RATE) as u64;
+        while self.snapshots.len() > 1
+            && (self.bytes > self.config.budget || self.frame - self.snapshots[1].frame >= max_frames)
+        {
+            self.drop_oldest();
+        }
+    }
+
+    // The oldest snapshot must stay a keyframe, so the one after it is rebuilt whole
+    // before it goes.
+    fn drop_oldest(&mut self) {
+        if !self.snapshots[1].keyframe {
+            let state = self.decode(1);
+            let next = &mut self.snapshots[1];
+            self.bytes -= next.data.len();
+            next.data = encode(&state, &[]);
+            next.keyframe = true;
+            self.bytes += next.data.len();
+        }
+        let oldest = self.snapshots.pop_front().unwrap();
+        self.bytes -= oldest.data.len();
+        let dropped = self.snapshots[0].frame - oldest.frame;
+        self.inputs.drain(..dropped as usize);
+    }
+
+    // Rebuilds snapshot `index` from the nearest keyframe at or before it.
+    fn decode(&self, index: usize) -> Vec<u8> {
+        let start = (0..=index).rev().find(|&i| self.snapshots[i].keyframe).unwrap();
+        let mut state = Vec::new();
+        for snapshot in self.snapshots.range(start..=index) {
+            state = decode(&snapshot.data, &state, snapshot.len);
+        }
+        state
+    }
+
+    /// Goes back one frame: reloads the nearest snapshot at or before it and replays the
+    /// recorded input up to it, so the result matches what was shown then exactly.
+    /// Returns false when there is nothing left to undo.
+    pub fn step_back(&mut self, gameboy: &mut GameBoy) -> bool {
+        if self.available() == 0 {
+            return false;
+        }
+        let target = self.frame - 1;
+        while self.snapshots.back().is_some_and(|newest| newest.frame > target) {
+            let newest = self.snapshots.pop_back().unwrap();
+            self.bytes -= newest.data.len();
+        }
+
+        // The replayed frames were captured, logged and watched the first time through, so
+        // those sit out until the machine is back. Cheats stay on: they shaped those frames.
+        let bus = &mut gameboy.cpu_mut().bus;
+        let capture = bus.capture.take();
+        let vgm = bus.vgm.take();
+        let watchpoints = std::mem::take(&mut bus.watchpoints);
+
+        let index = self.snapshots.len() - 1;
+        self.newest = self.decode(index);
+        gameboy.load_state(&self.newest).expect("a snapshot of this machine loads");
+
+        let start = self.snapshots[index].frame;
+        let base = self.snapshots[0].frame;
+        for frame in start..target {
+            gameboy.cpu_mut().bus.joypad.set_mask(self.inputs[(frame - base) as usize]);
+            gameboy.run_frame();
+        }
+        // Leave the joypad as it was going into the target frame; `run_frame` records it
+        // again from there.
+        gameboy.cpu_mut().bus.joypad.set_mask(self.inputs[(target - base) as usize]);
+        self.inputs.truncate((target - base) as usize);
+        self.frame = target;
+
+        // Replayed frames were already heard once.
+        gameboy.audio_samples();
+        let bus = &mut gameboy.cpu_mut().bus;
+        bus.capture = capture.map(|mut capture| {
+            capture.resync(&bus.ppu);
+            capture
+        });
+        bus.vgm = vgm;
+        bus.watchpoints = watchpoints;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::capture::Capture;
+    use crate::utils::rom::test_rom;
+    use crate::Model;
+
+    fn gameboy() -> GameBoy {
+        // JR -2 forever, with the LCD left on by the boot ROM.
+        GameBoy::new(Model::Dmg, test_rom(&[0x18, 0xFE])).unwrap()
+    }
+
+    #[test]
+    fn rewinding_during_a_capture_writes_no_replayed_frames() {
+        let path = std::env::temp_dir().join(format!("rusty_boy_rewind_test_{}.rgb", std::process::id()));
+        let mut gameboy = gameboy();
+        let mut rewind = Rewind::new(RewindConfig { interval: 4, ..RewindConfig::default() });
+        gameboy.start_recording(&path).unwrap();
+        for _ in 0..10 {
+            rewind.run_frame(&mut gameboy);
+        }
+        for _ in 0..3 {
+            assert!(rewind.step_back(&mut gameboy));
+        }
+        for _ in 0..2 {
+            rewind.run_frame(&mut gameboy);
+        }
+        let frames = gameboy.stop_recording().unwrap();
+        let size = std::fs::metadata(&path).unwrap().len();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(Capture::audio_path(&path)).unwrap();
+        assert_eq!(frames, Some(12));
+        assert_eq!(size, 12 * 160 * 144 * 3);
+    }
+
+    #[test]
+    fn xor_delta_and_rle_round_trip() {
+        let base: Vec<u8> = (0..300).map(|i| (i * 7) as u8).collect();
+        let mut state = base.clone();
+        state[0] ^= 1;
+        state[2] ^= 0xFF; // a zero run too short to split the literal
+        state[150] = 0;
+        state.extend_from_slice(&[9, 0, 0, 0, 0, 0, 4]);
+
+        for (state, base) in [(&state, &base), (&base, &state), (&state, &Vec::new()), (&Vec::new(), &base)] {
+            let encoded = encode(state, base);
+            assert_eq!(&decode(&encoded, base, state.len()), state);
+        }
+
+        // Unchanged bytes cost nothing but the run length.
+        assert_eq!(encode(&base, &base), [0xAC, 0x02, 0x00]);
+        assert_eq!(encode(&state, &base)[..5], [0x00, 0x03, 0x01, 0x00, 0xFF]);
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        let mut out = Vec::new();
+        for value in [0, 0x7F, 0x80, 0x3FFF, usize::MAX >> 1] {
+            out.clear();
+            put_varint(&mut out, value);
+            let mut position = 0;
+            assert_eq!(take_varint(&out, &mut position), value);
+            assert_eq!(position, out.len());
+        }
+    }
+
+    #[test]
+    fn stepping_back_returns_to_the_earlier_frame() {
+        let mut gameboy = gameboy();
+        let config = RewindConfig { interval: 4, keyframe_interval: 2, ..RewindConfig::default() };
+        let mut rewind = Rewind::new(config);
+        let mut states = Vec::new();
+        for frame in 0..12 {
+            gameboy.cpu_mut().bus.joypad.set_mask(frame as u8 & 0x0F);
+            states.push(gameboy.snapshot());
+            rewind.run_frame(&mut gameboy);
+        }
+        for frame in (6..12).rev() {
+            assert!(rewind.step_back(&mut gameboy));
+            assert!(gameboy.snapshot() == states[frame], "frame {}", frame);
+        }
+        assert_eq!(rewind.available(), 6);
+    }
+
+    #[test]
+    fn history_is_trimmed_to_the_window() {
+        let mut gameboy = gameboy();
+        let seconds = 20.0 / FRAME_RATE;
+        let config = RewindConfig { seconds, interval: 5, keyframe_interval: 3, ..RewindConfig::default() };
+        let mut rewind = Rewind::new(config);
+        for _ in 0..100 {
+            rewind.run_frame(&mut gameboy);
+        }
+        // Snapshots go once the next one is a whole window back, so up to an interval more.
+        assert!((20..=25).contains(&rewind.available()), "{}", rewind.available());
+        assert!(rewind.snapshots[0].keyframe);
+        assert_eq!(rewind.inputs.len() as u64, rewind.available());
+        let stored: usize = rewind.snapshots.iter().map(|snapshot| snapshot.data.len()).sum();
+        assert_eq!(rewind.memory(), stored);
+
+        let mut rewind = Rewind::new(RewindConfig { interval: 1, budget: 1, ..RewindConfig::default() });
+        for _ in 0..10 {
+            rewind.run_frame(&mut gameboy);
+        }
+        assert_eq!(rewind.snapshots.len(), 1);
+    }
+}
@@ -0,0 +1,576 @@
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::utils::state::{StateError, StateReader, StateWriter};
+
+const TITLE_START: usize = 0x134;
+const TITLE_END: usize = 0x144;
+const CGB_FLAG: usize = 0x143;
+const CARTRIDGE_TYPE: usize = 0x147;
+const ROM_SIZE: usize = 0x148;
+const RAM_SIZE: usize = 0x149;
+const HEADER_CHECKSUM: usize = 0x14D;
+const GLOBAL_CHECKSUM: usize = 0x14E;
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+const RTC_FOOTER_SIZE: usize = 48;
+
+// The RTC counts emulated time, not host time, so runs stay reproducible.
+const T_CYCLES_PER_SECOND: u32 = 4_194_304;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CartridgeError {
+    TooSmall(usize),
+    UnsupportedType(u8),
+    BadRomSize(u8),
+    BadRamSize(u8),
+    HeaderChecksum { expected: u8, actual: u8 },
+    BadSaveSize { expected: usize, actual: usize },
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::TooSmall(len) => {
+                write!(f, "ROM is {} bytes, too small to contain a header", len)
+            }
+            CartridgeError::UnsupportedType(kind) => {
+                write!(f, "unsupported cartridge type 0x{:02X}", kind)
+            }
+            CartridgeError::BadRomSize(code) => write!(f, "invalid ROM size code 0x{:02X}", code),
+            CartridgeError::BadRamSize(code) => write!(f, "invalid RAM size code 0x{:02X}", code),
+            CartridgeError::HeaderChecksum { expected, actual } => write!(
+                f,
+                "header checksum mismatch (header says 0x{:02X}, computed 0x{:02X})",
+                expected, actual
+            ),
+            CartridgeError::BadSaveSize { expected, actual } => write!(
+                f,
+                "save file is {} bytes, expected {} for this cartridge",
+                actual, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapperKind {
+    None,
+    Mbc1,
+    Mbc2,
+    Mbc3,
+    Mbc5,
+}
+
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub title: String,
+    pub cgb_flag: u8,
+    pub cartridge_type: u8,
+    pub rom_banks: usize,
+    pub ram_size: usize,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+}
+
+impl Header {
+    pub fn parse(rom: &[u8]) -> Result<Header, CartridgeError> {
+        if rom.len() < 0x150 {
+            return Err(CartridgeError::TooSmall(rom.len()));
+        }
+
+        let title = rom[TITLE_START..TITLE_END]
+            .iter()
+            .take_while(|&&byte| byte != 0)
+            .filter(|byte| byte.is_ascii_graphic() || **byte == b' ')
+            .map(|&byte| byte as char)
+            .collect::<String>()
+            .trim_end()
+            .to_string();
+
+        let rom_banks = match rom[ROM_SIZE] {
+            code @ 0x00..=0x08 => 2 << code,
+            code => return Err(CartridgeError::BadRomSize(code)),
+        };
+
+        let ram_size = match rom[RAM_SIZE] {
+            0x00 | 0x01 => 0,
+            0x02 => 0x2000,
+            0x03 => 0x8000,
+            0x04 => 0x20000,
+            0x05 => 0x10000,
+            code => return Err(CartridgeError::BadRamSize(code)),
+        };
+
+        let actual = rom[TITLE_START..HEADER_CHECKSUM]
+            .iter()
+            .fold(0u8, |sum, &byte| sum.wrapping_sub(byte).wrapping_sub(1));
+        let expected = rom[HEADER_CHECKSUM];
+        if actual != expected {
+            return Err(CartridgeError::HeaderChecksum { expected, actual });
+        }
+
+        Ok(Header {
+            title,
+            cgb_flag: rom[CGB_FLAG],
+            cartridge_type: rom[CARTRIDGE_TYPE],
+            rom_banks,
+            ram_size,
+            header_checksum: expected,
+            global_checksum: u16::from_be_bytes([rom[GLOBAL_CHECKSUM], rom[GLOBAL_CHECKSUM + 1]]),
+        })
+    }
+
+    pub fn supports_cgb(&self) -> bool {
+        self.cgb_flag & 0x80 != 0
+    }
+
+    pub fn mapper(&self) -> Result<MapperKind, CartridgeError> {
+        match self.cartridge_type {
+            0x00 | 0x08 | 0x09 => Ok(MapperKind::None),
+            0x01..=0x03 => Ok(MapperKind::Mbc1),
+            0x05 | 0x06 => Ok(MapperKind::Mbc2),
+            0x0F..=0x13 => Ok(MapperKind::Mbc3),
+            0x19..=0x1E => Ok(MapperKind::Mbc5),
+            kind => Err(CartridgeError::UnsupportedType(kind)),
+        }
+    }
+
+    pub fn has_battery(&self) -> bool {
+        matches!(self.cartridge_type, 0x03 | 0x06 | 0x09 | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E)
+    }
+
+    pub fn has_rtc(&self) -> bool {
+        matches!(self.cartridge_type, 0x0F | 0x10)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Rtc {
+    pub seconds: u8,
+    pub minutes: u8,
+    pub hours: u8,
+    pub days: u16,
+    pub halted: bool,
+    pub day_carry: bool,
+    pub latched: [u8; 5],
+    pub latch_armed: bool,
+    pub sub_second: u32,
+}
+
+impl Rtc {
+    fn tick(&mut self, t_cycles: u32) {
+        if self.halted {
+            return;
+        }
+        self.sub_second += t_cycles;
+        while self.sub_second >= T_CYCLES_PER_SECOND {
+            self.sub_second -= T_CYCLES_PER_SECOND;
+            self.advance_second();
+        }
+    }
+
+    // Each counter wraps at its own bit width, so out-of-range values written by games roll
+    // over the same way they do on real hardware.
+    fn advance_second(&mut self) {
+        self.seconds = (self.seconds + 1) & 0x3F;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & 0x3F;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & 0x1F;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+        self.days += 1;
+        if self.days > 0x1FF {
+            self.days = 0;
+            self.day_carry = true;
+        }
+    }
+
+    fn latch(&mut self) {
+        self.latched = [
+            self.seconds,
+            self.minutes,
+            self.hours,
+            self.days as u8,
+            self.day_high(),
+        ];
+    }
+
+    fn day_high(&self) -> u8 {
+        ((self.days >> 8) as u8 & 0x01)
+            | (if self.halted { 0x40 } else { 0 })
+            | (if self.day_carry { 0x80 } else { 0 })
+    }
+
+    fn read(&self, register: u8) -> u8 {
+        match register {
+            0x08 => self.latched[0] | 0xC0,
+            0x09 => self.latched[1] | 0xC0,
+            0x0A => self.latched[2] | 0xE0,
+            0x0B => self.latched[3],
+            0x0C => self.latched[4] | 0x3E,
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        match register {
+            0x08 => {
+                self.seconds = value & 0x3F;
+                self.sub_second = 0;
+            }
+            0x09 => self.minutes = value & 0x3F,
+            0x0A => self.hours = value & 0x1F,
+            0x0B => self.days = (self.days & 0x100) | value as u16,
+            0x0C => {
+                self.days = (self.days & 0xFF) | (((value & 0x01) as u16) << 8);
+                self.halted = value & 0x40 != 0;
+                self.day_carry = value & 0x80 != 0;
+            }
+            _ => {}
+        }
+    }
+
+    // The 48-byte RTC footer other emulators append to .sav files: the live and latched
+    // registers as five little-endian u32 each, then a Unix timestamp.
+    fn battery_footer(&self) -> [u8; RTC_FOOTER_SIZE] {
+        let live = [self.seconds, self.minutes, self.hours, self.days as u8, self.day_high()];
+        let mut footer = [0; RTC_FOOTER_SIZE];
+        for (i, value) in live.iter().chain(self.latched.iter()).enumerate() {
+            footer[i * 4] = *value;
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+        footer[40..].copy_from_slice(&now.to_le_bytes());
+        footer
+    }
+
+    fn load_battery_footer(&mut self, footer: &[u8]) {
+        for register in 0..5 {
+            self.write(0x08 + register as u8, footer[register * 4]);
+            self.latched[register] = footer[20 + register * 4];
+        }
+    }
+
+    fn save_state(&self, out: &mut StateWriter) {
+        out.put_u8(self.seconds);
+        out.put_u8(self.minutes);
+        out.put_u8(self.hours);
+        out.put_u16(self.days);
+        out.put_bool(self.halted);
+        out.put_bool(self.day_carry);
+        out.put_bytes(&self.latched);
+        out.put_bool(self.latch_armed);
+        out.put_u32(self.sub_second);
+    }
+
+    // The counters are masked to their bit widths like register writes are, so no state
+    // can push them past what advance_second wraps.
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.seconds = input.take_u8()? & 0x3F;
+        self.minutes = input.take_u8()? & 0x3F;
+        self.hours = input.take_u8()? & 0x1F;
+        self.days = input.take_u16()? & 0x1FF;
+        self.halted = input.take_bool()?;
+        self.day_carry = input.take_bool()?;
+        input.take_bytes(&mut self.latched)?;
+        self.latch_armed = input.take_bool()?;
+        self.sub_second = input.take_u32()?;
+        if self.sub_second >= T_CYCLES_PER_SECOND {
+            return Err(StateError::Corrupt("RTC"));
+        }
+        Ok(())
+    }
+}
+
+pub struct Cartridge {
+    pub header: Header,
+    pub mapper: MapperKind,
+    rom: Vec<u8>,
+    pub ram: Vec<u8>,
+    pub rtc: Option<Rtc>,
+
+    // Mapper registers. Their meaning depends on `mapper`; unused ones stay at zero.
+    pub ram_enabled: bool,
+    pub rom_bank: u16,
+    pub ram_bank: u8,
+    pub banking_mode: bool,
+}
+
+impl Cartridge {
+    pub fn new(rom: Vec<u8>) -> Result<Cartridge, CartridgeError> {
+        let header = Header::parse(&rom)?;
+        let mapper = header.mapper()?;
+
+        let ram_size = match mapper {
+            // MBC2 has 512 half-bytes of built-in RAM regardless of the header.
+            MapperKind::Mbc2 => 0x200,
+            _ => header.ram_size,
+        };
+
+        let mut rom = rom;
+        let rom_size = header.rom_banks * ROM_BANK_SIZE;
+        if rom.len() < rom_size {
+            rom.resize(rom_size, 0xFF);
+        }
+
+        Ok(Cartridge {
+            rtc: if header.has_rtc() { Some(Rtc::default()) } else { None },
+            header,
+            mapper,
+            rom,
+            ram: vec![0xFF; ram_size],
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            banking_mode: false,
+        })
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    pub fn tick(&mut self, t_cycles: u32) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.tick(t_cycles);
+        }
+    }
+
+    fn rom_bank_count(&self) -> usize {
+        self.rom.len() / ROM_BANK_SIZE
+    }
+
+    /// The ROM bank currently mapped at 0x0000-0x3FFF.
+    pub fn low_bank(&self) -> usize {
+        match self.mapper {
+            MapperKind::Mbc1 if self.banking_mode => {
+                ((self.ram_bank as usize) << 5) % self.rom_bank_count()
+            }
+            _ => 0,
+        }
+    }
+
+    /// The ROM bank currently mapped at 0x4000-0x7FFF.
+    pub fn high_bank(&self) -> usize {
+        let bank = match self.mapper {
+            MapperKind::None => 1,
+            MapperKind::Mbc1 => {
+                let low = match self.rom_bank & 0x1F {
+                    0 => 1,
+                    bank => bank as usize,
+                };
+                ((self.ram_bank as usize & 0x03) << 5) | low
+            }
+            MapperKind::Mbc2 => match self.rom_bank & 0x0F {
+                0 => 1,
+                bank => bank as usize,
+            },
+            MapperKind::Mbc3 => match self.rom_bank & 0x7F {
+                0 => 1,
+                bank => bank as usize,
+            },
+            MapperKind::Mbc5 => self.rom_bank as usize,
+        };
+        bank % self.rom_bank_count()
+    }
+
+    pub fn read_rom(&self, address: u16) -> u8 {
+        let bank = if address < 0x4000 { self.low_bank() } else { self.high_bank() };
+        self.rom[bank * ROM_BANK_SIZE + (address as usize & 0x3FFF)]
+    }
+
+    pub fn write_rom(&mut self, address: u16, value: u8) {
+        match self.mapper {
+            MapperKind::None => {}
+            MapperKind::Mbc1 => match address {
+                0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+                0x2000..=0x3FFF => self.rom_bank = (value & 0x1F) as u16,
+                0x4000..=0x5FFF => self.ram_bank = value & 0x03,
+                _ => self.banking_mode = value & 0x01 != 0,
+            },
+            MapperKind::Mbc2 => {
+                if address < 0x4000 {
+                    // Bit 8 of the address selects between RAM enable and ROM bank.
+                    if address & 0x0100 == 0 {
+                        self.ram_enabled = value & 0x0F == 0x0A;
+                    } else {
+                        self.rom_bank = (value & 0x0F) as u16;
+                    }
+                }
+            }
+            MapperKind::Mbc3 => match address {
+                0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+                0x2000..=0x3FFF => self.rom_bank = (value & 0x7F) as u16,
+                0x4000..=0x5FFF => self.ram_bank = value,
+                _ => {
+                    if let Some(rtc) = &mut self.rtc {
+                        if value == 0x01 && rtc.latch_armed {
+                            rtc.latch();
+                        }
+                        rtc.latch_armed = value == 0x00;
+                    }
+                }
+            },
+            MapperKind::Mbc5 => match address {
+                0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+                0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | value as u16,
+                0x3000..=0x3FFF => {
+                    self.rom_bank = (self.rom_bank & 0xFF) | (((value & 0x01) as u16) << 8)
+                }
+                0x4000..=0x5FFF => self.ram_bank = value & 0x0F,
+                _ => {}
+            },
+        }
+    }
+
+    /// Where a 0xA000-0xBFFF address lands in `ram` with the current bank, if there is RAM.
+    pub fn ram_offset(&self, address: u16) -> Option<usize> {
+        if self.ram.is_empty() {
+            return None;
+        }
+        let bank = match self.mapper {
+            MapperKind::Mbc1 if self.banking_mode => self.ram_bank as usize & 0x03,
+            MapperKind::Mbc3 | MapperKind::Mbc5 => self.ram_bank as usize,
+            _ => 0,
+        };
+        let offset = match self.mapper {
+            MapperKind::Mbc2 => address as usize & 0x1FF,
+            _ => bank * RAM_BANK_SIZE + (address as usize & 0x1FFF),
+        };
+        Some(offset % self.ram.len())
+    }
+
+    pub fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        if self.mapper == MapperKind::Mbc3 && self.ram_bank >= 0x08 {
+            return self.rtc.as_ref().map_or(0xFF, |rtc| rtc.read(self.ram_bank));
+        }
+        match self.ram_offset(address) {
+            Some(offset) if self.mapper == MapperKind::Mbc2 => self.ram[offset] | 0xF0,
+            Some(offset) => self.ram[offset],
+            None => 0xFF,
+        }
+    }
+
+    pub fn write_ram(&mut self, address: u16, value: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+        if self.mapper == MapperKind::Mbc3 && self.ram_bank >= 0x08 {
+            if let Some(rtc) = &mut self.rtc {
+                rtc.write(self.ram_bank, value);
+            }
+            return;
+        }
+        if let Some(offset) = self.ram_offset(address) {
+            self.ram[offset] = value;
+        }
+    }
+
+    /// The contents of a .sav file: battery-backed RAM, plus the RTC footer on carts
+    /// with a clock.
+    pub fn battery_data(&self) -> Vec<u8> {
+        let mut data = self.ram.clone();
+        if let Some(rtc) = &self.rtc {
+            data.extend_from_slice(&rtc.battery_footer());
+        }
+        data
+    }
+
+    /// Restores a .sav file. A missing RTC footer is tolerated, since many tools drop it.
+    pub fn load_battery_data(&mut self, data: &[u8]) -> Result<(), CartridgeError> {
+        let ram_size = self.ram.len();
+        let with_footer = ram_size + RTC_FOOTER_SIZE;
+        if data.len() != ram_size && !(self.rtc.is_some() && data.len() == with_footer) {
+            return Err(CartridgeError::BadSaveSize { expected: ram_size, actual: data.len() });
+        }
+        self.ram.copy_from_slice(&data[..ram_size]);
+        if let Some(rtc) = &mut self.rtc {
+            if data.len() == with_footer {
+                rtc.load_battery_footer(&data[ram_size..]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Saves RAM, the RTC and the mapper registers. The ROM is not included.
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.put_vec(&self.ram);
+        if let Some(rtc) = &self.rtc {
+            rtc.save_state(out);
+        }
+        out.put_bool(self.ram_enabled);
+        out.put_u16(self.rom_bank);
+        out.put_u8(self.ram_bank);
+        out.put_bool(self.banking_mode);
+    }
+
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        let ram = input.take_vec()?;
+        if ram.len() != self.ram.len() {
+            return Err(StateError::Mismatch("cartridge RAM size"));
+        }
+        self.ram = ram;
+        if let Some(rtc) = &mut self.rtc {
+            rtc.load_state(input)?;
+        }
+        self.ram_enabled = input.take_bool()?;
+        self.rom_bank = input.take_u16()?;
+        self.ram_bank = input.take_u8()?;
+        self.banking_mode = input.take_bool()?;
+        Ok(())
+    }
+}
+
+/// A 32 KiB ROM-only cartridge with `code` at the entry point and a valid header checksum.
+#[cfg(test)]
+pub(crate) fn test_rom(code: &[u8]) -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x100 + code.len()].copy_from_slice(code);
+    rom[0x14D] = rom[0x134..0x14D].iter().fold(0u8, |sum, &byte| sum.wrapping_sub(byte).wrapping_sub(1));
+    rom
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rtc_state(seconds: u8, minutes: u8, hours: u8, days: u16, sub_second: u32) -> Vec<u8> {
+        let rtc = Rtc { seconds, minutes, hours, days, sub_second, ..Rtc::default() };
+        let mut out = StateWriter::new();
+        rtc.save_state(&mut out);
+        out.into_bytes()
+    }
+
+    #[test]
+    fn rtc_state_is_masked_to_register_widths() {
+        let state = rtc_state(0xFF, 0xFF, 0xFF, 0xFFFF, T_CYCLES_PER_SECOND - 1);
+        let mut rtc = Rtc::default();
+        rtc.load_state(&mut StateReader::new(&state)).unwrap();
+        assert_eq!((rtc.seconds, rtc.minutes, rtc.hours, rtc.days), (0x3F, 0x3F, 0x1F, 0x1FF));
+        // Out-of-range values wrap on the next second instead of overflowing.
+        rtc.tick(1);
+        assert_eq!((rtc.seconds, rtc.minutes, rtc.hours, rtc.days), (0x00, 0x3F, 0x1F, 0x1FF));
+    }
+
+    #[test]
+    fn rtc_state_rejects_a_second_too_long() {
+        let state = rtc_state(0, 0, 0, 0, T_CYCLES_PER_SECOND);
+        let result = Rtc::default().load_state(&mut StateReader::new(&state));
+        assert!(matches!(result, Err(StateError::Corrupt("RTC"))));
+    }
+}
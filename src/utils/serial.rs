@@ -0,0 +1,346 @@
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::utils::bus::Interrupt;
+use crate::utils::state::{StateError, StateReader, StateWriter};
+
+/// Whatever is plugged into the other end of the link cable.
+///
+/// `cycle` is the number of M-cycles the serial port has run, which networked devices use to
+/// timestamp transfers.
+pub trait SerialDevice: Send {
+    /// We drive the clock and have started shifting `outgoing` out; at the selected clock
+    /// speed the last bit goes out `duration` M-cycles later.
+    fn start_transfer(&mut self, outgoing: u8, cycle: u64, duration: u64);
+
+    /// Our clock has shifted all eight bits. Returns the byte that came in, or `None` to
+    /// hold the transfer open, in which case this is called again on the next M-cycle.
+    fn finish_transfer(&mut self, cycle: u64) -> Option<u8>;
+
+    /// The other end drives the clock. Called every M-cycle while a transfer is waiting;
+    /// returns the incoming byte once the other side has clocked a whole byte through.
+    fn external_clock(&mut self, outgoing: u8, cycle: u64) -> Option<u8> {
+        let _ = (outgoing, cycle);
+        None
+    }
+
+    /// Called every M-cycle, transfer or not.
+    fn tick(&mut self, cycle: u64) {
+        let _ = cycle;
+    }
+}
+
+/// Nothing connected: the data line floats high and nobody ever clocks us.
+pub struct NullDevice;
+
+impl SerialDevice for NullDevice {
+    fn start_transfer(&mut self, _outgoing: u8, _cycle: u64, _duration: u64) {}
+
+    fn finish_transfer(&mut self, _cycle: u64) -> Option<u8> {
+        Some(0xFF)
+    }
+}
+
+/// Writes every byte we send, which is how Blargg's test ROMs report their results.
+pub struct Logger<W: Write + Send> {
+    out: W,
+}
+
+impl<W: Write + Send> Logger<W> {
+    pub fn new(out: W) -> Logger<W> {
+        Logger { out }
+    }
+}
+
+impl Logger<io::Stdout> {
+    pub fn stdout() -> Logger<io::Stdout> {
+        Logger::new(io::stdout())
+    }
+}
+
+impl<W: Write + Send> SerialDevice for Logger<W> {
+    fn start_transfer(&mut self, outgoing: u8, _cycle: u64, _duration: u64) {
+        // Logging must never take the emulator down, so I/O errors are dropped.
+        let _ = self.out.write_all(&[outgoing]);
+        if outgoing == b'\n' {
+            let _ = self.out.flush();
+        }
+    }
+
+    fn finish_transfer(&mut self, _cycle: u64) -> Option<u8> {
+        Some(0xFF)
+    }
+}
+
+#[derive(Default)]
+struct LinkState {
+    // A byte each side has ready while waiting on the other side's clock.
+    armed: [Option<u8>; 2],
+    // A byte each side has been sent and not yet picked up.
+    delivered: [Option<u8>; 2],
+}
+
+/// One end of an in-process cable between two emulator instances.
+pub struct LinkEnd {
+    side: usize,
+    state: Arc<Mutex<LinkState>>,
+    reply: u8,
+}
+
+impl LinkEnd {
+    pub fn pair() -> (LinkEnd, LinkEnd) {
+        let state = Arc::new(Mutex::new(LinkState::default()));
+        (
+            LinkEnd { side: 0, state: Arc::clone(&state), reply: 0xFF },
+            LinkEnd { side: 1, state, reply: 0xFF },
+        )
+    }
+}
+
+impl SerialDevice for LinkEnd {
+    fn start_transfer(&mut self, outgoing: u8, _cycle: u64, _duration: u64) {
+        let mut state = self.state.lock().unwrap();
+        let other = 1 - self.side;
+        self.reply = match state.armed[other].take() {
+            Some(incoming) => {
+                state.delivered[other] = Some(outgoing);
+                incoming
+            }
+            None => 0xFF,
+        };
+    }
+
+    fn finish_transfer(&mut self, _cycle: u64) -> Option<u8> {
+        Some(self.reply)
+    }
+
+    fn external_clock(&mut self, outgoing: u8, _cycle: u64) -> Option<u8> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(incoming) = state.delivered[self.side].take() {
+            return Some(incoming);
+        }
+        state.armed[self.side] = Some(outgoing);
+        None
+    }
+}
+
+// Internal clock sources, as bits of the timer's 16-bit counter: 8192 Hz normally and
+// 262144 Hz with the CGB fast clock. Both double along with the CPU in double speed.
+const NORMAL_CLOCK_BIT: u16 = 1 << 8;
+const FAST_CLOCK_BIT: u16 = 1 << 3;
+
+/// SB/SC (0xFF01/0xFF02).
+pub struct Serial {
+    pub sb: u8,
+    pub sc: u8,
+    cgb: bool,
+    device: Box<dyn SerialDevice>,
+
+    pub bits_remaining: u8,
+    clock_high: bool,
+    /// M-cycles since power-on, used to timestamp transfers for the device.
+    pub cycles: u64,
+}
+
+impl Serial {
+    pub fn new(cgb: bool) -> Serial {
+        Serial {
+            sb: 0x00,
+            sc: 0x00,
+            cgb,
+            device: Box::new(NullDevice),
+            bits_remaining: 0,
+            clock_high: false,
+            cycles: 0,
+        }
+    }
+
+    pub fn connect(&mut self, device: Box<dyn SerialDevice>) -> Box<dyn SerialDevice> {
+        std::mem::replace(&mut self.device, device)
+    }
+
+    fn transferring(&self) -> bool {
+        self.sc & 0x80 != 0
+    }
+
+    fn internal_clock(&self) -> bool {
+        self.sc & 0x01 != 0
+    }
+
+    fn clock_bit(&self) -> u16 {
+        if self.cgb && self.sc & 0x02 != 0 {
+            FAST_CLOCK_BIT
+        } else {
+            NORMAL_CLOCK_BIT
+        }
+    }
+
+    // M-cycles to shift a whole byte: the counter advances 4 per M-cycle and each bit takes
+    // a full period of the clock bit.
+    fn transfer_cycles(&self) -> u64 {
+        8 * u64::from(self.clock_bit()) / 2
+    }
+
+    /// Advances one M-cycle given the timer's internal counter and returns raised interrupts.
+    pub fn tick(&mut self, counter: u16) -> u8 {
+        self.cycles += 1;
+        let interrupts = self.step_transfer(counter);
+        self.device.tick(self.cycles);
+        interrupts
+    }
+
+    fn step_transfer(&mut self, counter: u16) -> u8 {
+        let clock_high = counter & self.clock_bit() != 0;
+        let falling_edge = self.clock_high && !clock_high;
+        self.clock_high = clock_high;
+
+        if !self.transferring() {
+            return 0;
+        }
+
+        if !self.internal_clock() {
+            return match self.device.external_clock(self.sb, self.cycles) {
+                Some(incoming) => self.finish(incoming),
+                None => 0,
+            };
+        }
+
+        if self.bits_remaining > 0 {
+            if !falling_edge {
+                return 0;
+            }
+            // The incoming byte is only known once the device has it, so the line reads
+            // as idle (high) while the bits are shifted.
+            self.bits_remaining -= 1;
+            self.sb = (self.sb << 1) | 0x01;
+            if self.bits_remaining > 0 {
+                return 0;
+            }
+        }
+
+        match self.device.finish_transfer(self.cycles) {
+            Some(incoming) => self.finish(incoming),
+            None => 0,
+        }
+    }
+
+    fn finish(&mut self, incoming: u8) -> u8 {
+        self.sb = incoming;
+        self.bits_remaining = 0;
+        self.sc &= 0x7F;
+        Interrupt::Serial.bit()
+    }
+
+    pub fn read_control(&self) -> u8 {
+        if self.cgb {
+            0x7C | self.sc
+        } else {
+            0x7E | self.sc
+        }
+    }
+
+    pub fn write_control(&mut self, value: u8) {
+        self.sc = value & if self.cgb { 0x83 } else { 0x81 };
+        if self.transferring() && self.internal_clock() {
+            self.device.start_transfer(self.sb, self.cycles, self.transfer_cycles());
+            self.bits_remaining = 8;
+        }
+    }
+
+    /// The connected device is left alone; only the port itself is saved.
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.put_u8(self.sb);
+        out.put_u8(self.sc);
+        out.put_u8(self.bits_remaining);
+        out.put_bool(self.clock_high);
+        out.put_u64(self.cycles);
+    }
+
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.sb = input.take_u8()?;
+        self.sc = input.take_u8()?;
+        self.bits_remaining = input.take_u8()?;
+        self.clock_high = input.take_bool()?;
+        self.cycles = input.take_u64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs the port against a timer counter that advances 4 per M-cycle, returning the
+    // M-cycles taken until the serial interrupt, if it comes within `limit`.
+    fn cycles_to_interrupt(serial: &mut Serial, counter: &mut u16, limit: u32) -> Option<u32> {
+        for cycle in 1..=limit {
+            *counter = counter.wrapping_add(4);
+            if serial.tick(*counter) & Interrupt::Serial.bit() != 0 {
+                return Some(cycle);
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn internal_clock_shifts_a_byte_at_8192_hz() {
+        let mut serial = Serial::new(false);
+        let mut counter = 0;
+        serial.sb = 0x42;
+        serial.write_control(0x81);
+        assert_eq!(cycles_to_interrupt(&mut serial, &mut counter, 2000), Some(1024));
+        assert_eq!((serial.sb, serial.read_control()), (0xFF, 0x7F));
+    }
+
+    #[test]
+    fn fast_clock_only_on_cgb() {
+        let mut serial = Serial::new(true);
+        let mut counter = 0;
+        serial.write_control(0x83);
+        assert_eq!(serial.transfer_cycles(), 32);
+        assert_eq!(cycles_to_interrupt(&mut serial, &mut counter, 2000), Some(32));
+
+        let mut serial = Serial::new(false);
+        serial.write_control(0x83);
+        assert_eq!(serial.read_control(), 0xFF);
+        assert_eq!(serial.transfer_cycles(), 1024);
+    }
+
+    #[test]
+    fn external_clock_waits_for_the_other_side() {
+        let mut serial = Serial::new(false);
+        let mut counter = 0;
+        serial.write_control(0x80);
+        assert_eq!(cycles_to_interrupt(&mut serial, &mut counter, 5000), None);
+        assert_eq!(serial.read_control() & 0x80, 0x80);
+    }
+
+    #[test]
+    fn link_ends_trade_bytes() {
+        let (first, second) = LinkEnd::pair();
+        let mut master = Serial::new(false);
+        let mut slave = Serial::new(false);
+        master.connect(Box::new(first));
+        slave.connect(Box::new(second));
+        let (mut master_counter, mut slave_counter) = (0, 0);
+
+        slave.sb = 0x99;
+        slave.write_control(0x80);
+        assert_eq!(cycles_to_interrupt(&mut slave, &mut slave_counter, 1), None);
+        master.sb = 0x42;
+        master.write_control(0x81);
+        assert!(cycles_to_interrupt(&mut master, &mut master_counter, 2000).is_some());
+        assert_eq!(cycles_to_interrupt(&mut slave, &mut slave_counter, 1), Some(1));
+        assert_eq!((master.sb, slave.sb), (0x99, 0x42));
+    }
+
+    #[test]
+    fn logger_writes_what_goes_out() {
+        let mut logger = Logger::new(Vec::new());
+        for byte in b"ok\n" {
+            logger.start_transfer(*byte, 0, 1024);
+            assert_eq!(logger.finish_transfer(0), Some(0xFF));
+        }
+        assert_eq!(logger.out, b"ok\n");
+    }
+}
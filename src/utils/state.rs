@@ -0,0 +1,398 @@
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::utils::bus::Model;
+use crate::utils::image::Image;
+
+const MAGIC: &[u8; 4] = b"RBST";
+// Only changes if the framing itself does; each chunk carries its own layout version.
+const FORMAT_VERSION: u16 = 1;
+
+const META: &Tag = b"META";
+const META_VERSION: u16 = 1;
+const THUMBNAIL: &Tag = b"THMB";
+const THUMBNAIL_VERSION: u16 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateError {
+    Truncated,
+    BadMagic,
+    /// The state was saved with a different model or cartridge.
+    Mismatch(&'static str),
+    Corrupt(&'static str),
+    /// A chunk the emulator needs is not in the state.
+    MissingChunk(Tag),
+    /// The state was written by a newer emulator than this one.
+    NewerFormat(u16),
+    /// A chunk has a layout newer than this emulator knows.
+    UnsupportedVersion { chunk: Tag, version: u16 },
+}
+
+/// Identifies a chunk of a save state, such as `*b"CPU "`.
+pub type Tag = [u8; 4];
+
+fn tag_name(tag: &Tag) -> String {
+    String::from_utf8_lossy(tag).trim_end().to_string()
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::Truncated => write!(f, "save state ends early"),
+            StateError::BadMagic => write!(f, "not a save state"),
+            StateError::Mismatch(what) => write!(f, "save state is for a different {}", what),
+            StateError::Corrupt(what) => write!(f, "save state has an invalid {}", what),
+            StateError::MissingChunk(tag) => {
+                write!(f, "save state has no {} section", tag_name(tag))
+            }
+            StateError::NewerFormat(version) => {
+                write!(f, "save state format {} is newer than this emulator supports", version)
+            }
+            StateError::UnsupportedVersion { chunk, version } => write!(
+                f,
+                "save state has version {} of the {} section, newer than this emulator supports",
+                version,
+                tag_name(chunk)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// Serializes emulator state as a flat little-endian byte stream. Every component writes
+/// its fields in a fixed order and reads them back in the same order.
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter::default()
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn put_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn put_bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn put_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn put_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn put_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Writes a fixed-size block; the reader must know its length.
+    pub fn put_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// Writes a block prefixed with its length.
+    pub fn put_vec(&mut self, bytes: &[u8]) {
+        self.put_u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// Writes a chunk: its tag, the version of its layout, its length and then whatever
+    /// `write` puts in it. Readers skip chunks they do not know.
+    pub fn put_chunk(&mut self, tag: &Tag, version: u16, write: impl FnOnce(&mut StateWriter)) {
+        let mut chunk = StateWriter::new();
+        write(&mut chunk);
+        self.put_bytes(tag);
+        self.put_u16(version);
+        self.put_vec(&chunk.data);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data, position: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.position == self.data.len()
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], StateError> {
+        let end = self.position.checked_add(count).ok_or(StateError::Truncated)?;
+        let bytes = self.data.get(self.position..end).ok_or(StateError::Truncated)?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    pub fn take_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn take_bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.take_u8()? != 0)
+    }
+
+    pub fn take_u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn take_u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn take_u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// Fills `out` from a block written by `put_bytes`.
+    pub fn take_bytes(&mut self, out: &mut [u8]) -> Result<(), StateError> {
+        out.copy_from_slice(self.take(out.len())?);
+        Ok(())
+    }
+
+    /// Reads a block written by `put_vec`.
+    pub fn take_vec(&mut self) -> Result<Vec<u8>, StateError> {
+        let len = self.take_u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+}
+
+/// Starts a save state: the magic and the format version, ready for chunks.
+pub fn begin_state() -> StateWriter {
+    let mut out = StateWriter::new();
+    out.put_bytes(MAGIC);
+    out.put_u16(FORMAT_VERSION);
+    out
+}
+
+/// Checks the magic and format version of a save state and splits it into chunks.
+pub fn parse_state(data: &[u8]) -> Result<Chunks<'_>, StateError> {
+    let mut input = StateReader::new(data);
+    let mut magic = [0; 4];
+    input.take_bytes(&mut magic).map_err(|_| StateError::BadMagic)?;
+    if &magic != MAGIC {
+        return Err(StateError::BadMagic);
+    }
+    let version = input.take_u16()?;
+    if version > FORMAT_VERSION {
+        return Err(StateError::NewerFormat(version));
+    }
+    Chunks::parse(&mut input)
+}
+
+/// What a save state says about itself, readable without loading it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateInfo {
+    pub title: String,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+    pub model: Model,
+    /// Seconds since the Unix epoch when the state was saved.
+    pub timestamp: u64,
+    /// The screen when the state was saved, at reduced size.
+    pub thumbnail: Option<Image>,
+}
+
+impl StateInfo {
+    pub fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0)
+    }
+
+    /// Reads the metadata of a state written by `GameBoy::save_state`.
+    pub fn read(data: &[u8]) -> Result<StateInfo, StateError> {
+        StateInfo::from_chunks(&parse_state(data)?)
+    }
+
+    pub fn from_chunks(chunks: &Chunks) -> Result<StateInfo, StateError> {
+        let mut info = None;
+        chunks.load(META, META_VERSION, |input, _| {
+            let title = String::from_utf8(input.take_vec()?).map_err(|_| StateError::Corrupt("title"))?;
+            let header_checksum = input.take_u8()?;
+            let global_checksum = input.take_u16()?;
+            let model = Model::from_id(input.take_u8()?).ok_or(StateError::Corrupt("model"))?;
+            let timestamp = input.take_u64()?;
+            info = Some(StateInfo { title, header_checksum, global_checksum, model, timestamp, thumbnail: None });
+            Ok(())
+        })?;
+        let mut info = info.unwrap();
+
+        // The thumbnail is optional; a state without one still loads.
+        if chunks.get(THUMBNAIL).is_some() {
+            chunks.load(THUMBNAIL, THUMBNAIL_VERSION, |input, _| {
+                let width = input.take_u16()? as usize;
+                let height = input.take_u16()? as usize;
+                let rgb = input.take(width * height * 3)?;
+                let pixels = rgb
+                    .chunks(3)
+                    .map(|rgb| (rgb[0] as u32) << 16 | (rgb[1] as u32) << 8 | rgb[2] as u32)
+                    .collect();
+                info.thumbnail = Some(Image::new(width, height, pixels));
+                Ok(())
+            })?;
+        }
+        Ok(info)
+    }
+
+    pub fn save(&self, out: &mut StateWriter) {
+        out.put_chunk(META, META_VERSION, |out| {
+            out.put_vec(self.title.as_bytes());
+            out.put_u8(self.header_checksum);
+            out.put_u16(self.global_checksum);
+            out.put_u8(self.model as u8);
+            out.put_u64(self.timestamp);
+        });
+        if let Some(thumbnail) = &self.thumbnail {
+            out.put_chunk(THUMBNAIL, THUMBNAIL_VERSION, |out| {
+                out.put_u16(thumbnail.width as u16);
+                out.put_u16(thumbnail.height as u16);
+                for &pixel in &thumbnail.pixels {
+                    out.put_bytes(&[(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8]);
+                }
+            });
+        }
+    }
+}
+
+/// The chunks of a state written with `put_chunk`, found by tag.
+pub struct Chunks<'a> {
+    chunks: Vec<(Tag, u16, &'a [u8])>,
+}
+
+impl<'a> Chunks<'a> {
+    /// Splits everything left in `input` into chunks.
+    pub fn parse(input: &mut StateReader<'a>) -> Result<Chunks<'a>, StateError> {
+        let mut chunks = Vec::new();
+        while !input.is_empty() {
+            let tag = input.take(4)?.try_into().unwrap();
+            let version = input.take_u16()?;
+            let len = input.take_u32()? as usize;
+            chunks.push((tag, version, input.take(len)?));
+        }
+        Ok(Chunks { chunks })
+    }
+
+    pub fn get(&self, tag: &Tag) -> Option<(u16, &'a [u8])> {
+        self.chunks.iter().find(|(found, ..)| found == tag).map(|&(_, version, data)| (version, data))
+    }
+
+    /// Hands the chunk tagged `tag` and its version to `load`, which must read all of it.
+    /// Versions above `latest` are refused, since their layout is unknown.
+    pub fn load(
+        &self,
+        tag: &Tag,
+        latest: u16,
+        load: impl FnOnce(&mut StateReader<'a>, u16) -> Result<(), StateError>,
+    ) -> Result<(), StateError> {
+        let (version, data) = self.get(tag).ok_or(StateError::MissingChunk(*tag))?;
+        if version > latest {
+            return Err(StateError::UnsupportedVersion { chunk: *tag, version });
+        }
+        let mut input = StateReader::new(data);
+        load(&mut input, version)?;
+        if !input.is_empty() {
+            return Err(StateError::Corrupt("section length"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST: &Tag = b"TEST";
+
+    fn info() -> StateInfo {
+        StateInfo {
+            title: "TETRIS".to_string(),
+            header_checksum: 0x0A,
+            global_checksum: 0x16BF,
+            model: Model::Cgb,
+            timestamp: 1_700_000_000,
+            thumbnail: Some(Image::new(2, 1, vec![0x123456, 0xABCDEF])),
+        }
+    }
+
+    // A state with the metadata and one TEST chunk at `version` holding `payload`.
+    fn state(version: u16, payload: &[u8]) -> Vec<u8> {
+        let mut out = begin_state();
+        info().save(&mut out);
+        out.put_chunk(TEST, version, |out| out.put_bytes(payload));
+        out.into_bytes()
+    }
+
+    fn load_test_chunk(data: &[u8]) -> Result<u32, StateError> {
+        let mut value = 0;
+        parse_state(data)?.load(TEST, 1, |input, _| {
+            value = input.take_u32()?;
+            Ok(())
+        })?;
+        Ok(value)
+    }
+
+    #[test]
+    fn values_and_metadata_round_trip() {
+        let mut out = StateWriter::new();
+        out.put_bool(true);
+        out.put_u16(0xBEEF);
+        out.put_u64(u64::MAX - 1);
+        out.put_vec(b"abc");
+        let data = out.into_bytes();
+        let mut input = StateReader::new(&data);
+        assert!(input.take_bool().unwrap());
+        assert_eq!(input.take_u16(), Ok(0xBEEF));
+        assert_eq!(input.take_u64(), Ok(u64::MAX - 1));
+        assert_eq!(input.take_vec().unwrap(), b"abc");
+        assert!(input.is_empty());
+        assert_eq!(input.take_u8(), Err(StateError::Truncated));
+
+        let data = state(1, &7u32.to_le_bytes());
+        assert_eq!(StateInfo::read(&data), Ok(info()));
+        assert_eq!(load_test_chunk(&data), Ok(7));
+    }
+
+    #[test]
+    fn bad_magic_and_newer_formats_are_refused() {
+        let mut data = state(1, &[0; 4]);
+        data[0] = b'X';
+        assert_eq!(load_test_chunk(&data), Err(StateError::BadMagic));
+        assert_eq!(parse_state(b"RB").err(), Some(StateError::BadMagic));
+
+        let mut data = state(1, &[0; 4]);
+        data[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert_eq!(load_test_chunk(&data), Err(StateError::NewerFormat(FORMAT_VERSION + 1)));
+    }
+
+    #[test]
+    fn chunks_are_checked_for_version_and_length() {
+        assert_eq!(
+            load_test_chunk(&state(2, &[0; 4])),
+            Err(StateError::UnsupportedVersion { chunk: *TEST, version: 2 })
+        );
+        assert_eq!(load_test_chunk(&state(1, &[0; 5])), Err(StateError::Corrupt("section length")));
+        assert_eq!(load_test_chunk(&state(1, &[0; 3])), Err(StateError::Truncated));
+
+        let mut data = state(1, &[0; 4]);
+        data.truncate(data.len() - 1);
+        assert_eq!(parse_state(&data).err(), Some(StateError::Truncated));
+
+        let mut out = begin_state();
+        info().save(&mut out);
+        assert_eq!(load_test_chunk(&out.into_bytes()), Err(StateError::MissingChunk(*TEST)));
+    }
+}
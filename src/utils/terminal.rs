@@ -0,0 +1,389 @@
+use std::env;
+use std::fmt::Write as _;
+use std::io::{self, IsTerminal, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use crate::utils::joypad::Button;
+use crate::utils::ppu::{DMG_SHADES, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::GameBoy;
+
+// Each character cell shows two pixels stacked: the top one as the foreground of '▀',
+// the bottom one as the background.
+const ROWS: usize = SCREEN_HEIGHT / 2;
+const HALF_BLOCK: &str = "\u{2580}";
+
+// Terminals only report key presses, and auto-repeat starts after a delay, so a press
+// holds its button for this many frames and every repeat extends it. Frames only count
+// while the game runs, so presses made while paused land on the next advanced frame.
+const HOLD_FRAMES: u8 = 15;
+
+const ENTER_SCREEN: &str = "\x1b[?1049h\x1b[?25l\x1b[2J";
+const LEAVE_SCREEN: &str = "\x1b[0m\x1b[?25h\x1b[?1049l";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorMode {
+    TrueColor,
+    /// The four DMG shades, matched to the nearest entries of the xterm 256-colour palette.
+    Ansi256,
+}
+
+impl ColorMode {
+    /// Truecolor when the terminal advertises it through COLORTERM, as most do.
+    pub fn detect() -> ColorMode {
+        match env::var("COLORTERM") {
+            Ok(value) if value == "truecolor" || value == "24bit" => ColorMode::TrueColor,
+            _ => ColorMode::Ansi256,
+        }
+    }
+}
+
+// Index of the closest colour in the xterm 6×6×6 cube or grey ramp.
+fn nearest_xterm(rgb: u32) -> u8 {
+    const LEVELS: [i32; 6] = [0, 95, 135, 175, 215, 255];
+    let channels = [(rgb >> 16) as i32 & 0xFF, (rgb >> 8) as i32 & 0xFF, rgb as i32 & 0xFF];
+    let distance = |color: [i32; 3]| -> i32 {
+        color.iter().zip(channels).map(|(a, b)| (a - b) * (a - b)).sum()
+    };
+
+    let cube = channels.map(|c| {
+        (0..6).min_by_key(|&i| (LEVELS[i] - c).abs()).unwrap()
+    });
+    let cube_index = 16 + 36 * cube[0] + 6 * cube[1] + cube[2];
+    let cube_distance = distance(cube.map(|i| LEVELS[i]));
+
+    let mean = channels.iter().sum::<i32>() / 3;
+    let grey = ((mean - 8).max(0) / 10).min(23);
+    let grey_level = 8 + 10 * grey;
+    let grey_distance = distance([grey_level; 3]);
+
+    if grey_distance < cube_distance { 232 + grey as u8 } else { cube_index as u8 }
+}
+
+fn luma(rgb: u32) -> u32 {
+    let (r, g, b) = ((rgb >> 16) & 0xFF, (rgb >> 8) & 0xFF, rgb & 0xFF);
+    (2 * r + 5 * g + b) / 8
+}
+
+/// Turns framebuffers into ANSI escape sequences, only redrawing the cells that changed
+/// since the previous frame.
+pub struct TerminalRenderer {
+    mode: ColorMode,
+    shade_colors: [u8; 4],
+    // Top and bottom colour of every cell as last drawn; None forces a redraw.
+    cells: Vec<Option<(u32, u32)>>,
+}
+
+impl TerminalRenderer {
+    pub fn new(mode: ColorMode) -> TerminalRenderer {
+        TerminalRenderer {
+            mode,
+            shade_colors: DMG_SHADES.map(nearest_xterm),
+            cells: vec![None; SCREEN_WIDTH * ROWS],
+        }
+    }
+
+    /// Forgets what is on screen, so the next frame is drawn in full.
+    pub fn invalidate(&mut self) {
+        self.cells.fill(None);
+    }
+
+    // Colours are reduced to what the mode can show before diffing, so changes the
+    // terminal could not display anyway cost nothing.
+    fn reduce(&self, rgb: u32) -> u32 {
+        match self.mode {
+            ColorMode::TrueColor => rgb,
+            ColorMode::Ansi256 => {
+                let level = luma(rgb);
+                let shade = (0..4).min_by_key(|&i| luma(DMG_SHADES[i]).abs_diff(level)).unwrap();
+                self.shade_colors[shade] as u32
+            }
+        }
+    }
+
+    fn color(&self, out: &mut String, layer: u8, color: u32) {
+        let _ = match self.mode {
+            ColorMode::TrueColor => write!(
+                out,
+                "\x1b[{}8;2;{};{};{}m",
+                layer,
+                (color >> 16) & 0xFF,
+                (color >> 8) & 0xFF,
+                color & 0xFF
+            ),
+            ColorMode::Ansi256 => write!(out, "\x1b[{}8;5;{}m", layer, color),
+        };
+    }
+
+    /// The escape sequences that bring the terminal from the last frame to `framebuffer`.
+    /// Empty when nothing changed.
+    pub fn render(&mut self, framebuffer: &[u32]) -> String {
+        let mut out = String::new();
+        let mut cursor = None;
+        let mut pen = (None, None);
+
+        for row in 0..ROWS {
+            for column in 0..SCREEN_WIDTH {
+                let top = self.reduce(framebuffer[row * 2 * SCREEN_WIDTH + column]);
+                let bottom = self.reduce(framebuffer[(row * 2 + 1) * SCREEN_WIDTH + column]);
+                let cell = &mut self.cells[row * SCREEN_WIDTH + column];
+                if *cell == Some((top, bottom)) {
+                    continue;
+                }
+                *cell = Some((top, bottom));
+
+                if cursor != Some((row, column)) {
+                    let _ = write!(out, "\x1b[{};{}H", row + 1, column + 1);
+                }
+                if pen.0 != Some(top) {
+                    self.color(&mut out, 3, top);
+                }
+                if pen.1 != Some(bottom) {
+                    self.color(&mut out, 4, bottom);
+                }
+                pen = (Some(top), Some(bottom));
+                out += HALF_BLOCK;
+                cursor = Some((row, column + 1));
+            }
+        }
+
+        if !out.is_empty() {
+            out += "\x1b[0m";
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Button(Button),
+    Screenshot,
+    Record,
+    /// Held like a button: play runs backwards while it is down.
+    Rewind,
+    Pause,
+    FrameAdvance,
+    Turbo,
+    Faster,
+    Slower,
+    NormalSpeed,
+    /// Switches all cheats on or off.
+    Cheats,
+    Quit,
+}
+
+/// Decodes raw terminal input into keys. Escape sequences may arrive split across reads.
+#[derive(Default)]
+pub struct KeyDecoder {
+    pending: Vec<u8>,
+}
+
+impl KeyDecoder {
+    pub fn new() -> KeyDecoder {
+        KeyDecoder::default()
+    }
+
+    pub fn decode(&mut self, bytes: &[u8]) -> Vec<Key> {
+        self.pending.extend_from_slice(bytes);
+        let mut keys = Vec::new();
+
+        let mut i = 0;
+        while i < self.pending.len() {
+            let byte = self.pending[i];
+            if byte == 0x1B {
+                // ESC [ X or ESC O X; wait for the rest if it has not arrived yet.
+                match self.pending.get(i + 1..i + 3) {
+                    None => break,
+                    Some(&[b'[' | b'O', code]) => {
+                        let button = match code {
+                            b'A' => Some(Button::Up),
+                            b'B' => Some(Button::Down),
+                            b'C' => Some(Button::Right),
+                            b'D' => Some(Button::Left),
+                            _ => None,
+                        };
+                        keys.extend(button.map(Key::Button));
+                        i += 3;
+                    }
+                    Some(_) => i += 1,
+                }
+                continue;
+            }
+
+            let key = match byte.to_ascii_lowercase() {
+                b'w' => Some(Key::Button(Button::Up)),
+                b'a' => Some(Key::Button(Button::Left)),
+                b's' => Some(Key::Button(Button::Down)),
+                b'd' => Some(Key::Button(Button::Right)),
+                b'x' | b'k' => Some(Key::Button(Button::A)),
+                b'z' | b'j' => Some(Key::Button(Button::B)),
+                b'\r' | b'\n' => Some(Key::Button(Button::Start)),
+                b' ' => Some(Key::Button(Button::Select)),
+                0x7F | 0x08 => Some(Key::Rewind),
+                b'h' => Some(Key::Pause),
+                b'n' => Some(Key::FrameAdvance),
+                b'\t' => Some(Key::Turbo),
+                b'+' | b'=' => Some(Key::Faster),
+                b'-' => Some(Key::Slower),
+                b'0' => Some(Key::NormalSpeed),
+                b'c' => Some(Key::Cheats),
+                b'p' => Some(Key::Screenshot),
+                b'r' => Some(Key::Record),
+                b'q' | 0x03 => Some(Key::Quit),
+                _ => None,
+            };
+            keys.extend(key);
+            i += 1;
+        }
+
+        self.pending.drain(..i);
+        keys
+    }
+}
+
+fn stty(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("stty").args(args).stdin(Stdio::inherit()).output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Puts the controlling terminal in raw mode until dropped. Goes through `stty` so no
+/// platform bindings are needed.
+pub struct RawMode {
+    saved: String,
+}
+
+impl RawMode {
+    pub fn enable() -> io::Result<RawMode> {
+        let saved = stty(&["-g"])?;
+        stty(&["raw", "-echo"])?;
+        Ok(RawMode { saved })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = stty(&[&self.saved]);
+    }
+}
+
+/// Plays a game in the terminal: draws frames to stdout and reads the keyboard from stdin.
+pub struct TerminalFrontend {
+    renderer: TerminalRenderer,
+    decoder: KeyDecoder,
+    input: Option<Receiver<Vec<u8>>>,
+    held: [u8; 8],
+    rewind_held: u8,
+    status: String,
+    shown_status: String,
+    // Declared last so the terminal is restored after everything else is dropped.
+    _raw: Option<RawMode>,
+}
+
+impl TerminalFrontend {
+    /// Takes over the terminal. Input is only read when stdin is a terminal.
+    pub fn new(mode: ColorMode) -> io::Result<TerminalFrontend> {
+        let raw = if io::stdin().is_terminal() { Some(RawMode::enable()?) } else { None };
+        let input = raw.as_ref().map(|_| {
+            let (sender, receiver) = mpsc::channel();
+            thread::spawn(move || {
+                let mut buffer = [0; 64];
+                let mut stdin = io::stdin();
+                while let Ok(count @ 1..) = stdin.read(&mut buffer) {
+                    if sender.send(buffer[..count].to_vec()).is_err() {
+                        break;
+                    }
+                }
+            });
+            receiver
+        });
+
+        let mut stdout = io::stdout();
+        stdout.write_all(ENTER_SCREEN.as_bytes())?;
+        stdout.flush()?;
+        Ok(TerminalFrontend {
+            renderer: TerminalRenderer::new(mode),
+            decoder: KeyDecoder::new(),
+            input,
+            held: [0; 8],
+            rewind_held: 0,
+            status: String::new(),
+            shown_status: String::new(),
+            _raw: raw,
+        })
+    }
+
+    /// Applies pending button presses to the joypad and returns the other keys pressed,
+    /// in order. Closing stdin counts as quitting.
+    pub fn poll(&mut self, gameboy: &mut GameBoy) -> Vec<Key> {
+        let mut commands = Vec::new();
+        if let Some(input) = &self.input {
+            loop {
+                match input.try_recv() {
+                    Ok(bytes) => {
+                        for key in self.decoder.decode(&bytes) {
+                            match key {
+                                Key::Button(button) => self.held[button as usize] = HOLD_FRAMES,
+                                Key::Rewind => self.rewind_held = HOLD_FRAMES,
+                                _ => commands.push(key),
+                            }
+                        }
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        commands.push(Key::Quit);
+                        break;
+                    }
+                }
+            }
+        }
+
+        for button in Button::ALL {
+            gameboy.set_button(button, self.held[button as usize] > 0);
+        }
+        self.rewind_held = self.rewind_held.saturating_sub(1);
+        commands
+    }
+
+    /// Counts a frame off every held button. Call it after each frame that actually ran.
+    pub fn frame_done(&mut self) {
+        for frames in &mut self.held {
+            *frames = frames.saturating_sub(1);
+        }
+    }
+
+    /// Whether the rewind key is down, as of the last `poll`.
+    pub fn rewinding(&self) -> bool {
+        self.rewind_held > 0
+    }
+
+    /// Shows `status` on the line under the screen, e.g. the speed or "paused".
+    pub fn set_status(&mut self, status: &str) {
+        self.status = status.to_string();
+    }
+
+    pub fn draw(&mut self, framebuffer: &[u32]) -> io::Result<()> {
+        let mut frame = self.renderer.render(framebuffer);
+        if self.status != self.shown_status {
+            let _ = write!(frame, "\x1b[{};1H\x1b[0m\x1b[2K{}", ROWS + 1, self.status);
+            self.shown_status = self.status.clone();
+        }
+        if frame.is_empty() {
+            return Ok(());
+        }
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(frame.as_bytes())?;
+        stdout.flush()
+    }
+}
+
+impl Drop for TerminalFrontend {
+    fn drop(&mut self) {
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(LEAVE_SCREEN.as_bytes());
+        let _ = stdout.flush();
+    }
+}
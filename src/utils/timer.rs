@@ -0,0 +1,254 @@
+use crate::utils::bus::Interrupt;
+use crate::utils::state::{StateError, StateReader, StateWriter};
+
+/// DIV/TIMA/TMA/TAC, driven by the 16-bit internal counter whose upper byte is DIV.
+///
+/// TIMA counts falling edges of the counter bit selected by TAC (ANDed with the enable bit),
+/// so resetting DIV or rewriting TAC can produce an extra increment. On overflow TIMA reads
+/// 0x00 for one M-cycle before it is reloaded from TMA and the interrupt is requested.
+pub struct Timer {
+    pub counter: u16,
+    pub tima: u8,
+    pub tma: u8,
+    pub tac: u8,
+
+    // TIMA overflowed during the previous M-cycle and is waiting to be reloaded.
+    pub overflow_pending: bool,
+    // TIMA was reloaded from TMA during the current M-cycle.
+    pub reloading: bool,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer {
+            counter: 0xABCC,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            overflow_pending: false,
+            reloading: false,
+        }
+    }
+
+    fn selected_bit(&self) -> u16 {
+        match self.tac & 0x03 {
+            0b00 => 1 << 9,
+            0b01 => 1 << 3,
+            0b10 => 1 << 5,
+            _ => 1 << 7,
+        }
+    }
+
+    fn signal(&self) -> bool {
+        self.tac & 0x04 != 0 && self.counter & self.selected_bit() != 0
+    }
+
+    fn increment_tima(&mut self) {
+        let (tima, overflowed) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        if overflowed {
+            self.overflow_pending = true;
+        }
+    }
+
+    /// Advances the timer by one M-cycle and returns the interrupt flags it raised.
+    pub fn tick(&mut self) -> u8 {
+        let mut interrupts = 0;
+
+        self.reloading = false;
+        if self.overflow_pending {
+            self.overflow_pending = false;
+            self.reloading = true;
+            self.tima = self.tma;
+            interrupts |= Interrupt::Timer.bit();
+        }
+
+        let before = self.signal();
+        self.counter = self.counter.wrapping_add(4);
+        if before && !self.signal() {
+            self.increment_tima();
+        }
+
+        interrupts
+    }
+
+    pub fn div(&self) -> u8 {
+        (self.counter >> 8) as u8
+    }
+
+    pub fn write_div(&mut self) {
+        let before = self.signal();
+        self.counter = 0;
+        if before {
+            self.increment_tima();
+        }
+    }
+
+    pub fn write_tima(&mut self, value: u8) {
+        // A write in the cycle after overflow cancels the reload; a write in the reload
+        // cycle itself loses to TMA.
+        if self.reloading {
+            return;
+        }
+        self.overflow_pending = false;
+        self.tima = value;
+    }
+
+    pub fn write_tma(&mut self, value: u8) {
+        self.tma = value;
+        if self.reloading {
+            self.tima = value;
+        }
+    }
+
+    pub fn read_tac(&self) -> u8 {
+        0xF8 | self.tac
+    }
+
+    pub fn write_tac(&mut self, value: u8) {
+        let before = self.signal();
+        self.tac = value & 0x07;
+        if before && !self.signal() {
+            self.increment_tima();
+        }
+    }
+
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.put_u16(self.counter);
+        out.put_u8(self.tima);
+        out.put_u8(self.tma);
+        out.put_u8(self.tac);
+        out.put_bool(self.overflow_pending);
+        out.put_bool(self.reloading);
+    }
+
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.counter = input.take_u16()?;
+        self.tima = input.take_u8()?;
+        self.tma = input.take_u8()?;
+        self.tac = input.take_u8()?;
+        self.overflow_pending = input.take_bool()?;
+        self.reloading = input.take_bool()?;
+        Ok(())
+    }
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Timer::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Counting every 16 clocks (bit 3), with the counter one M-cycle before that bit falls.
+    fn about_to_tick(tima: u8) -> Timer {
+        let mut timer = Timer::new();
+        timer.counter = 0x000C;
+        timer.tac = 0x05;
+        timer.tima = tima;
+        timer
+    }
+
+    #[test]
+    fn counts_falling_edges_of_the_selected_bit() {
+        let mut timer = about_to_tick(0);
+        timer.tick();
+        assert_eq!(timer.tima, 1);
+        for _ in 0..3 {
+            timer.tick();
+        }
+        assert_eq!(timer.tima, 1);
+        timer.tick();
+        assert_eq!(timer.tima, 2);
+    }
+
+    #[test]
+    fn div_write_while_the_selected_bit_is_high_increments() {
+        let mut timer = about_to_tick(0);
+        timer.write_div();
+        assert_eq!((timer.counter, timer.tima), (0, 1));
+
+        // With the bit low there is no edge.
+        timer.counter = 0x0004;
+        timer.write_div();
+        assert_eq!(timer.tima, 1);
+
+        // Nor while the timer is stopped.
+        timer.counter = 0x0008;
+        timer.tac = 0x01;
+        timer.write_div();
+        assert_eq!(timer.tima, 1);
+    }
+
+    #[test]
+    fn tac_write_that_drops_the_signal_increments() {
+        // Selecting a low bit.
+        let mut timer = about_to_tick(0);
+        timer.write_tac(0x04);
+        assert_eq!(timer.tima, 1);
+
+        // Disabling the timer.
+        let mut timer = about_to_tick(0);
+        timer.write_tac(0x01);
+        assert_eq!(timer.tima, 1);
+
+        // Selecting another high bit keeps the signal up.
+        let mut timer = about_to_tick(0);
+        timer.counter = 0x0028;
+        timer.write_tac(0x06);
+        assert_eq!(timer.tima, 0);
+
+        // Enabling with the bit high is a rising edge, which does not count.
+        let mut timer = about_to_tick(0);
+        timer.tac = 0x01;
+        timer.write_tac(0x05);
+        assert_eq!(timer.tima, 0);
+    }
+
+    #[test]
+    fn overflow_reads_zero_for_a_cycle_before_the_reload() {
+        let mut timer = about_to_tick(0xFF);
+        timer.tma = 0x23;
+        assert_eq!(timer.tick(), 0);
+        assert_eq!(timer.tima, 0x00);
+        assert_eq!(timer.tick(), Interrupt::Timer.bit());
+        assert_eq!(timer.tima, 0x23);
+        assert_eq!(timer.tick(), 0);
+    }
+
+    #[test]
+    fn tima_write_during_the_delay_cancels_the_reload() {
+        let mut timer = about_to_tick(0xFF);
+        timer.tma = 0x23;
+        timer.tick();
+        timer.write_tima(0x50);
+        assert_eq!(timer.tick(), 0);
+        assert_eq!(timer.tima, 0x50);
+    }
+
+    #[test]
+    fn tima_write_in_the_reload_cycle_loses_to_tma() {
+        let mut timer = about_to_tick(0xFF);
+        timer.tma = 0x23;
+        timer.tick();
+        timer.tick();
+        timer.write_tima(0x50);
+        assert_eq!(timer.tima, 0x23);
+    }
+
+    #[test]
+    fn tma_write_in_the_reload_cycle_reaches_tima() {
+        let mut timer = about_to_tick(0xFF);
+        timer.tma = 0x23;
+        timer.tick();
+        timer.tick();
+        timer.write_tma(0x77);
+        assert_eq!(timer.tima, 0x77);
+        timer.tick();
+        timer.write_tma(0x11);
+        assert_eq!(timer.tima, 0x77);
+    }
+}
@@ -0,0 +1,191 @@
+use crate::utils::bus::Model;
+use crate::utils::movie::{ForeignMovie, Movie, MovieError, MovieStart, RomId};
+
+const MAGIC: &[u8; 4] = b"VBM\x1A";
+// The fixed header, then 64 bytes of author and 128 of description.
+const HEADER_SIZE: usize = 0x40;
+const INPUT_OFFSET: usize = 0x100;
+
+// Start flags.
+const FROM_SAVESTATE: u8 = 0x01;
+const FROM_SRAM: u8 = 0x02;
+// System flags; none of them set means a plain Game Boy.
+const SYSTEM_GBA: u8 = 0x01;
+const SYSTEM_GBC: u8 = 0x02;
+// Emulator option flags. With a BIOS file in use and not skipped, the movie starts at the
+// boot ROM; the others have to describe this emulator's behaviour for a movie to sync.
+const USE_BIOS_FILE: u8 = 0x01;
+const SKIP_BIOS_FILE: u8 = 0x02;
+const RTC_ENABLE: u8 = 0x04;
+const INVALID_OPTION: u8 = 0x08;
+const GBC_HDMA5_FIX: u8 = 0x20;
+const ECHO_RAM_FIX: u8 = 0x40;
+// VBA's own GB emulator type option.
+const EMULATOR_GBC: u32 = 1;
+const EMULATOR_GB: u32 = 3;
+// Both the old and the current reset bit of a controller word.
+const RESET: u16 = 0x0C00;
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+// VBA packs A, B, Select, Start, Right, Left, Up and Down from bit 0; `Button::mask` has
+// the two nibbles the other way round.
+fn to_mask(word: u16) -> u8 {
+    (word as u8).rotate_left(4)
+}
+
+fn from_mask(mask: u8) -> u16 {
+    mask.rotate_left(4) as u16
+}
+
+/// Reads a VisualBoyAdvance movie, following the first controller. Movies that start
+/// from a VBA save state cannot be reproduced; ones that start from SRAM can.
+pub fn import(data: &[u8]) -> Result<ForeignMovie, MovieError> {
+    if data.len() < INPUT_OFFSET || &data[..4] != MAGIC {
+        return Err(MovieError::BadMagic);
+    }
+    if u32_at(data, 0x04) != 1 {
+        return Err(MovieError::NewerFormat(u32_at(data, 0x04) as u16));
+    }
+    let frames = u32_at(data, 0x0C) as usize;
+    let start_flags = data[0x14];
+    let controllers = (data[0x15] & 0x0F).count_ones() as usize;
+    let system = data[0x16];
+    let options = data[0x17];
+    let save_offset = u32_at(data, 0x38) as usize;
+    let input_offset = u32_at(data, 0x3C) as usize;
+
+    if system & SYSTEM_GBA != 0 {
+        return Err(MovieError::Unsupported("Game Boy Advance movies"));
+    }
+    if start_flags & FROM_SAVESTATE != 0 {
+        return Err(MovieError::Unsupported("VBA save state starts"));
+    }
+    if options & INVALID_OPTION != 0 {
+        return Err(MovieError::Invalid("emulator options"));
+    }
+    // VBA's clock follows the host's time, which a replay cannot.
+    if options & RTC_ENABLE != 0 {
+        return Err(MovieError::Unsupported("movies recorded with VBA's real-time clock"));
+    }
+    if options & ECHO_RAM_FIX == 0 {
+        return Err(MovieError::Unsupported("movies recorded with VBA's old echo RAM behaviour"));
+    }
+    if system & SYSTEM_GBC != 0 && options & GBC_HDMA5_FIX == 0 {
+        return Err(MovieError::Unsupported("movies recorded with VBA's old HDMA5 timing"));
+    }
+    let save_ram = match start_flags & FROM_SRAM != 0 {
+        true => Some(data.get(save_offset..input_offset).ok_or(MovieError::Invalid("SRAM offset"))?.to_vec()),
+        false => None,
+    };
+
+    let stride = controllers.max(1) * 2;
+    let input = data
+        .get(input_offset..)
+        .filter(|input| input.len() >= frames * stride)
+        .ok_or(MovieError::Invalid("input shorter than the frame count"))?;
+    let mut inputs = Vec::with_capacity(frames);
+    for (frame, bytes) in input.chunks(stride).take(frames).enumerate() {
+        let word = u16::from_le_bytes([bytes[0], bytes[1]]);
+        if word & RESET != 0 && frame > 0 {
+            return Err(MovieError::Unsupported("resets during a movie"));
+        }
+        inputs.push(to_mask(word));
+    }
+
+    Ok(ForeignMovie {
+        emulator: format!("VisualBoyAdvance (VBM 1.{})", data[0x30]),
+        // The global checksum is kept in the ROM's byte order.
+        rom: RomId::Checksums {
+            header_checksum: data[0x31],
+            global_checksum: u16::from_be_bytes([data[0x32], data[0x33]]),
+        },
+        model: Some(if system & SYSTEM_GBC != 0 { Model::Cgb } else { Model::Dmg }),
+        boot_rom: options & USE_BIOS_FILE != 0 && options & SKIP_BIOS_FILE == 0,
+        rerecords: Some(u32_at(data, 0x10)),
+        save_ram,
+        inputs,
+    })
+}
+
+/// Writes `movie` as a single-controller VisualBoyAdvance movie.
+pub fn export(movie: &Movie) -> Result<Vec<u8>, MovieError> {
+    if movie.start != MovieStart::PowerOn {
+        return Err(MovieError::Unsupported("exports of movies that start from a save state"));
+    }
+    let cgb = movie.model == Model::Cgb;
+
+    let mut out = Vec::with_capacity(INPUT_OFFSET + movie.inputs.len() * 2);
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&1u32.to_le_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes()); // uid, which ties save states to the movie
+    out.extend_from_slice(&(movie.inputs.len() as u32).to_le_bytes());
+    out.extend_from_slice(&movie.rerecords.unwrap_or(0).to_le_bytes());
+    out.push(0); // starts from power-on without SRAM
+    out.push(0x01); // controller 1
+    out.push(if cgb { SYSTEM_GBC } else { 0 });
+    out.push(GBC_HDMA5_FIX | ECHO_RAM_FIX | if movie.boot_rom { USE_BIOS_FILE } else { 0 });
+    out.extend_from_slice(&0u32.to_le_bytes()); // save type
+    out.extend_from_slice(&0x10000u32.to_le_bytes()); // flash size
+    out.extend_from_slice(&(if cgb { EMULATOR_GBC } else { EMULATOR_GB }).to_le_bytes());
+    let mut title = [0; 12];
+    for (byte, &source) in title.iter_mut().zip(movie.title.as_bytes()) {
+        *byte = source;
+    }
+    out.extend_from_slice(&title);
+    out.push(1); // minor version
+    out.push(movie.header_checksum);
+    out.extend_from_slice(&movie.global_checksum.to_be_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes()); // game code
+    out.extend_from_slice(&0u32.to_le_bytes()); // SRAM offset
+    out.extend_from_slice(&(INPUT_OFFSET as u32).to_le_bytes());
+    debug_assert_eq!(out.len(), HEADER_SIZE);
+    out.resize(INPUT_OFFSET, 0);
+
+    for &mask in &movie.inputs {
+        out.extend_from_slice(&from_mask(mask).to_le_bytes());
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A one-frame DMG movie from power-on with these emulator options.
+    fn movie_with_options(system: u8, options: u8) -> Vec<u8> {
+        let mut data = vec![0; INPUT_OFFSET + 2];
+        data[..4].copy_from_slice(MAGIC);
+        data[0x04] = 1;
+        data[0x0C] = 1;
+        data[0x15] = 0x01;
+        data[0x16] = system;
+        data[0x17] = options;
+        data[0x3C..0x40].copy_from_slice(&(INPUT_OFFSET as u32).to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn boot_rom_start_follows_the_bios_flags() {
+        let fixes = GBC_HDMA5_FIX | ECHO_RAM_FIX;
+        assert!(!import(&movie_with_options(0, fixes)).unwrap().boot_rom);
+        assert!(import(&movie_with_options(0, fixes | USE_BIOS_FILE)).unwrap().boot_rom);
+        assert!(!import(&movie_with_options(0, fixes | USE_BIOS_FILE | SKIP_BIOS_FILE)).unwrap().boot_rom);
+    }
+
+    #[test]
+    fn rejects_options_this_core_cannot_reproduce() {
+        let unsupported = |system, options| matches!(import(&movie_with_options(system, options)), Err(MovieError::Unsupported(_)));
+        assert!(unsupported(0, GBC_HDMA5_FIX | ECHO_RAM_FIX | RTC_ENABLE));
+        assert!(unsupported(0, GBC_HDMA5_FIX));
+        assert!(unsupported(SYSTEM_GBC, ECHO_RAM_FIX));
+        // The HDMA5 fix only matters to the CGB.
+        assert!(import(&movie_with_options(0, ECHO_RAM_FIX)).is_ok());
+        assert!(matches!(
+            import(&movie_with_options(0, GBC_HDMA5_FIX | ECHO_RAM_FIX | INVALID_OPTION)),
+            Err(MovieError::Invalid(_))
+        ));
+    }
+}
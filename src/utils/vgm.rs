@@ -0,0 +1,255 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::utils::apu::{Apu, NR10, NR52, WAVE_RAM};
+use crate::utils::audio::CLOCK_RATE;
+
+const VERSION: u32 = 0x0171;
+const HEADER_SIZE: usize = 0x100;
+// VGM timestamps are always in 44.1 kHz samples.
+const VGM_RATE: u64 = 44_100;
+
+const CMD_GB_WRITE: u8 = 0xB3;
+const CMD_WAIT: u8 = 0x61;
+const CMD_WAIT_NTSC: u8 = 0x62;
+const CMD_WAIT_PAL: u8 = 0x63;
+const CMD_WAIT_SHORT: u8 = 0x70;
+const CMD_END: u8 = 0x66;
+
+/// Records sound register writes as a VGM 1.71 log.
+pub struct VgmLogger {
+    data: Vec<u8>,
+    // APU T-cycles since logging started.
+    cycles: u64,
+    samples: u64,
+    loop_point: Option<(usize, u64)>,
+
+    pub track: String,
+    pub game: String,
+    pub author: String,
+}
+
+impl VgmLogger {
+    /// Starts a log from the APU's current state, so playback doesn't begin from
+    /// power-on defaults the game never saw.
+    pub fn new(apu: &Apu) -> VgmLogger {
+        let mut logger = VgmLogger {
+            data: Vec::new(),
+            cycles: 0,
+            samples: 0,
+            loop_point: None,
+            track: String::new(),
+            game: String::new(),
+            author: String::new(),
+        };
+
+        logger.command(NR52, if apu.powered { 0x80 } else { 0x00 });
+        if !apu.powered {
+            return logger;
+        }
+
+        // Wave RAM is only writable with the wave channel stopped.
+        logger.command(0xFF1A, 0x00);
+        for (offset, &value) in apu.wave.ram.iter().enumerate() {
+            logger.command(WAVE_RAM + offset as u16, value);
+        }
+        for address in NR10..NR52 {
+            let value = apu.register(address);
+            match address {
+                // Restore the high frequency bits and length enable without retriggering.
+                0xFF14 | 0xFF19 | 0xFF1E | 0xFF23 => logger.command(address, value & 0x7F),
+                0xFF15 | 0xFF1F => {}
+                _ => logger.command(address, value),
+            }
+        }
+        logger
+    }
+
+    /// Seconds of APU time covered by the log so far.
+    pub fn elapsed(&self) -> f64 {
+        self.cycles as f64 / CLOCK_RATE
+    }
+
+    pub fn tick(&mut self, t_cycles: u32) {
+        self.cycles += t_cycles as u64;
+    }
+
+    /// Logs a CPU write to 0xFF10-0xFF3F at the current time.
+    pub fn write(&mut self, address: u16, value: u8) {
+        self.sync();
+        self.command(address, value);
+    }
+
+    /// Marks the current position as the start of the looping part.
+    pub fn mark_loop(&mut self) {
+        self.sync();
+        self.loop_point = Some((self.data.len(), self.samples));
+    }
+
+    fn command(&mut self, address: u16, value: u8) {
+        self.data.extend_from_slice(&[CMD_GB_WRITE, (address - NR10) as u8, value]);
+    }
+
+    // Emits waits up to the current cycle.
+    fn sync(&mut self) {
+        let target = self.cycles * VGM_RATE / CLOCK_RATE as u64;
+        let mut remaining = target - self.samples;
+        self.samples = target;
+
+        while remaining > 0 {
+            let wait = remaining.min(0xFFFF);
+            match wait {
+                735 => self.data.push(CMD_WAIT_NTSC),
+                882 => self.data.push(CMD_WAIT_PAL),
+                1..=16 => self.data.push(CMD_WAIT_SHORT + wait as u8 - 1),
+                _ => {
+                    self.data.push(CMD_WAIT);
+                    self.data.extend_from_slice(&(wait as u16).to_le_bytes());
+                }
+            }
+            remaining -= wait;
+        }
+    }
+
+    fn gd3(&self) -> Vec<u8> {
+        let fields = [
+            self.track.as_str(),
+            "",
+            self.game.as_str(),
+            "",
+            "Nintendo Game Boy",
+            "",
+            self.author.as_str(),
+            "",
+            "",
+            "rusty_boy",
+            "",
+        ];
+        let mut strings = Vec::new();
+        for field in fields {
+            for unit in field.encode_utf16().chain(std::iter::once(0)) {
+                strings.extend_from_slice(&unit.to_le_bytes());
+            }
+        }
+
+        let mut gd3 = Vec::new();
+        gd3.extend_from_slice(b"Gd3 ");
+        gd3.extend_from_slice(&0x0100u32.to_le_bytes());
+        gd3.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+        gd3.extend_from_slice(&strings);
+        gd3
+    }
+
+    /// Ends the log and returns the complete file.
+    pub fn finish(mut self) -> Vec<u8> {
+        self.sync();
+        self.data.push(CMD_END);
+
+        let mut header = vec![0u8; HEADER_SIZE];
+        let mut put = |offset: usize, value: u32| {
+            header[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        };
+
+        let gd3_offset = HEADER_SIZE + self.data.len();
+        let gd3 = self.gd3();
+        let total = gd3_offset + gd3.len();
+
+        put(0x00, u32::from_le_bytes(*b"Vgm "));
+        put(0x04, (total - 0x04) as u32);
+        put(0x08, VERSION);
+        put(0x14, (gd3_offset - 0x14) as u32);
+        put(0x18, self.samples as u32);
+        if let Some((offset, samples)) = self.loop_point {
+            put(0x1C, (HEADER_SIZE + offset - 0x1C) as u32);
+            put(0x20, (self.samples - samples) as u32);
+        }
+        put(0x34, (HEADER_SIZE - 0x34) as u32);
+        put(0x80, CLOCK_RATE as u32);
+
+        let mut file = header;
+        file.extend_from_slice(&self.data);
+        file.extend_from_slice(&gd3);
+        file
+    }
+
+    pub fn save<P: AsRef<Path>>(self, path: P) -> io::Result<()> {
+        fs::write(path, self.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u32_at(file: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(file[offset..offset + 4].try_into().unwrap())
+    }
+
+    // T-cycles that make up `samples` VGM samples.
+    fn cycles_for(samples: u64) -> u32 {
+        (samples * CLOCK_RATE as u64).div_ceil(VGM_RATE) as u32
+    }
+
+    fn quiet_logger() -> VgmLogger {
+        let mut apu = Apu::new(false);
+        apu.powered = false;
+        VgmLogger::new(&apu)
+    }
+
+    #[test]
+    fn starts_from_the_current_registers_without_retriggering() {
+        assert_eq!(quiet_logger().data, [CMD_GB_WRITE, 0x16, 0x00]);
+
+        let logger = VgmLogger::new(&Apu::new(false));
+        assert_eq!(&logger.data[..3], [CMD_GB_WRITE, 0x16, 0x80]);
+        let writes: Vec<_> = logger.data.chunks(3).map(|command| (command[1], command[2])).collect();
+        assert!(writes.contains(&(0x04, 0xBF & 0x7F)));
+        assert!(!writes.iter().any(|&(register, _)| register == 0x05 || register == 0x0F));
+    }
+
+    #[test]
+    fn writes_wait_out_the_time_since_the_last_one() {
+        let mut logger = quiet_logger();
+        logger.tick(cycles_for(735));
+        logger.write(0xFF12, 0xF0);
+        logger.tick(cycles_for(745) - cycles_for(735));
+        logger.write(0xFF12, 0x00);
+        logger.tick(cycles_for(1745) - cycles_for(745));
+        logger.write(0xFF12, 0x0F);
+        assert_eq!(
+            logger.data[3..],
+            [
+                CMD_WAIT_NTSC, CMD_GB_WRITE, 0x02, 0xF0,
+                CMD_WAIT_SHORT + 9, CMD_GB_WRITE, 0x02, 0x00,
+                CMD_WAIT, 0xE8, 0x03, CMD_GB_WRITE, 0x02, 0x0F,
+            ]
+        );
+    }
+
+    #[test]
+    fn header_points_at_the_data_loop_and_tags() {
+        let mut logger = quiet_logger();
+        logger.game = "Test".to_string();
+        logger.tick(cycles_for(100));
+        logger.mark_loop();
+        logger.tick(cycles_for(300) - cycles_for(100));
+        let file = logger.finish();
+
+        assert_eq!(&file[..4], b"Vgm ");
+        assert_eq!(u32_at(&file, 0x04) as usize, file.len() - 0x04);
+        assert_eq!(u32_at(&file, 0x08), VERSION);
+        assert_eq!(u32_at(&file, 0x18), 300);
+        assert_eq!(u32_at(&file, 0x20), 200);
+        assert_eq!(u32_at(&file, 0x80), 4_194_304);
+        assert_eq!(u32_at(&file, 0x34) as usize + 0x34, HEADER_SIZE);
+
+        let loop_offset = u32_at(&file, 0x1C) as usize + 0x1C;
+        assert_eq!(file[loop_offset], CMD_WAIT);
+        assert_eq!(file[HEADER_SIZE..HEADER_SIZE + 3], [CMD_GB_WRITE, 0x16, 0x00]);
+        let gd3 = u32_at(&file, 0x14) as usize + 0x14;
+        assert_eq!(&file[gd3..gd3 + 4], b"Gd3 ");
+        assert_eq!(file[gd3 - 1], CMD_END);
+        assert_eq!(u32_at(&file, gd3 + 8) as usize, file.len() - gd3 - 12);
+    }
+}
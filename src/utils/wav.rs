@@ -0,0 +1,67 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const HEADER_SIZE: u32 = 44;
+
+/// Streams 16-bit PCM to a RIFF WAVE file. The chunk sizes are patched in by `finish`.
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    channels: u16,
+    sample_rate: u32,
+    data_bytes: u32,
+}
+
+impl WavWriter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        sample_rate: u32,
+        channels: u16,
+    ) -> io::Result<WavWriter<BufWriter<File>>> {
+        WavWriter::new(BufWriter::new(File::create(path)?), sample_rate, channels)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(out: W, sample_rate: u32, channels: u16) -> io::Result<WavWriter<W>> {
+        let mut writer = WavWriter { out, channels, sample_rate, data_bytes: 0 };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let block_align = self.channels * 2;
+        let byte_rate = self.sample_rate * block_align as u32;
+
+        self.out.write_all(b"RIFF")?;
+        self.out.write_all(&(HEADER_SIZE - 8 + self.data_bytes).to_le_bytes())?;
+        self.out.write_all(b"WAVE")?;
+        self.out.write_all(b"fmt ")?;
+        self.out.write_all(&16u32.to_le_bytes())?;
+        self.out.write_all(&1u16.to_le_bytes())?; // PCM
+        self.out.write_all(&self.channels.to_le_bytes())?;
+        self.out.write_all(&self.sample_rate.to_le_bytes())?;
+        self.out.write_all(&byte_rate.to_le_bytes())?;
+        self.out.write_all(&block_align.to_le_bytes())?;
+        self.out.write_all(&16u16.to_le_bytes())?;
+        self.out.write_all(b"data")?;
+        self.out.write_all(&self.data_bytes.to_le_bytes())
+    }
+
+    /// Appends interleaved samples.
+    pub fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        for sample in samples {
+            self.out.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_bytes += samples.len() as u32 * 2;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
@@ -0,0 +1,168 @@
+use std::fmt;
+
+use crate::utils::deflate::{crc32, deflate, inflate, InflateError};
+
+const LOCAL_HEADER: u32 = 0x04034B50;
+const CENTRAL_HEADER: u32 = 0x02014B50;
+const END_OF_DIRECTORY: u32 = 0x06054B50;
+// The end record is 22 bytes, followed by a comment of up to 65535.
+const END_SIZE: usize = 22;
+
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+// 1980-01-01, the earliest date zip can hold, so archives do not depend on the clock.
+const DOS_DATE: u16 = 0x21;
+
+#[derive(Debug)]
+pub enum ZipError {
+    NotZip,
+    Truncated,
+    Corrupt(&'static str),
+    /// Encryption, zip64 or a compression method other than deflate.
+    Unsupported(&'static str),
+    Inflate(InflateError),
+}
+
+impl fmt::Display for ZipError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ZipError::NotZip => write!(f, "not a zip archive"),
+            ZipError::Truncated => write!(f, "zip archive is truncated"),
+            ZipError::Corrupt(what) => write!(f, "zip archive is corrupt: {}", what),
+            ZipError::Unsupported(what) => write!(f, "zip archive uses {}, which is not supported", what),
+            ZipError::Inflate(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for ZipError {}
+
+impl From<InflateError> for ZipError {
+    fn from(err: InflateError) -> ZipError {
+        ZipError::Inflate(err)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZipEntry {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, ZipError> {
+    let bytes = data.get(offset..offset + 2).ok_or(ZipError::Truncated)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, ZipError> {
+    let bytes = data.get(offset..offset + 4).ok_or(ZipError::Truncated)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Reads every file in a zip archive, going by its central directory. Files may be
+/// stored or deflated.
+pub fn read_zip(data: &[u8]) -> Result<Vec<ZipEntry>, ZipError> {
+    let search_start = data.len().saturating_sub(END_SIZE + 0xFFFF);
+    let end = (search_start..=data.len().saturating_sub(END_SIZE))
+        .rev()
+        .find(|&offset| u32_at(data, offset).ok() == Some(END_OF_DIRECTORY))
+        .ok_or(ZipError::NotZip)?;
+    let count = u16_at(data, end + 10)?;
+    let mut offset = u32_at(data, end + 16)? as usize;
+    if offset == 0xFFFFFFFF || count == 0xFFFF {
+        return Err(ZipError::Unsupported("zip64"));
+    }
+
+    let mut entries = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        if u32_at(data, offset)? != CENTRAL_HEADER {
+            return Err(ZipError::Corrupt("central directory"));
+        }
+        let flags = u16_at(data, offset + 8)?;
+        let method = u16_at(data, offset + 10)?;
+        let crc = u32_at(data, offset + 16)?;
+        let compressed_size = u32_at(data, offset + 20)? as usize;
+        let size = u32_at(data, offset + 24)? as usize;
+        let name_length = u16_at(data, offset + 28)? as usize;
+        let extra_length = u16_at(data, offset + 30)? as usize;
+        let comment_length = u16_at(data, offset + 32)? as usize;
+        let local = u32_at(data, offset + 42)? as usize;
+        let name = data.get(offset + 46..offset + 46 + name_length).ok_or(ZipError::Truncated)?;
+        let name = String::from_utf8_lossy(name).into_owned();
+        offset += 46 + name_length + extra_length + comment_length;
+
+        if flags & 1 != 0 {
+            return Err(ZipError::Unsupported("encryption"));
+        }
+        if u32_at(data, local)? != LOCAL_HEADER {
+            return Err(ZipError::Corrupt("local header"));
+        }
+        // The local header may carry a different extra field than the central one.
+        let start = local + 30 + u16_at(data, local + 26)? as usize + u16_at(data, local + 28)? as usize;
+        let raw = data.get(start..start + compressed_size).ok_or(ZipError::Truncated)?;
+        let contents = match method {
+            STORED => raw.to_vec(),
+            DEFLATED => inflate(raw)?,
+            _ => return Err(ZipError::Unsupported("a compression method other than deflate")),
+        };
+        if contents.len() != size || crc32(&[&contents]) != crc {
+            return Err(ZipError::Corrupt("checksum mismatch"));
+        }
+        entries.push(ZipEntry { name, data: contents });
+    }
+    Ok(entries)
+}
+
+/// Packs files into a zip archive, deflating those that shrink. The same entries always
+/// give the same bytes.
+pub fn write_zip(entries: &[ZipEntry]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut directory = Vec::new();
+    for entry in entries {
+        let compressed = deflate(&entry.data);
+        let (method, contents) = if compressed.len() < entry.data.len() {
+            (DEFLATED, compressed.as_slice())
+        } else {
+            (STORED, entry.data.as_slice())
+        };
+        let crc = crc32(&[&entry.data]);
+        let offset = out.len() as u32;
+
+        // Fields shared by the local and central headers, from "version needed" on.
+        let mut common = Vec::new();
+        common.extend_from_slice(&20u16.to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes());
+        common.extend_from_slice(&method.to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes());
+        common.extend_from_slice(&DOS_DATE.to_le_bytes());
+        common.extend_from_slice(&crc.to_le_bytes());
+        common.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+        common.extend_from_slice(&(entry.data.len() as u32).to_le_bytes());
+        common.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes());
+
+        out.extend_from_slice(&LOCAL_HEADER.to_le_bytes());
+        out.extend_from_slice(&common);
+        out.extend_from_slice(entry.name.as_bytes());
+        out.extend_from_slice(contents);
+
+        directory.extend_from_slice(&CENTRAL_HEADER.to_le_bytes());
+        directory.extend_from_slice(&20u16.to_le_bytes());
+        directory.extend_from_slice(&common);
+        // Comment length, disk number, internal and external attributes.
+        directory.extend_from_slice(&[0; 10]);
+        directory.extend_from_slice(&offset.to_le_bytes());
+        directory.extend_from_slice(entry.name.as_bytes());
+    }
+
+    let directory_offset = out.len() as u32;
+    out.extend_from_slice(&directory);
+    out.extend_from_slice(&END_OF_DIRECTORY.to_le_bytes());
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    out.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    out.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+    out.extend_from_slice(&directory_offset.to_le_bytes());
+    out.extend_from_slice(&0u16.to_le_bytes());
+    out
+}